@@ -0,0 +1,75 @@
+//! Compares the buffered and the memory-mapped storages. Run with
+//! `cargo bench --features mmap --bench storage`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::path::PathBuf;
+use torrentino::storage::{FileStorage, MmapStorage, Storage, StorageLayout};
+
+const PIECE_LENGTH: u64 = 256 * 1024;
+const PIECES: u32 = 64;
+const BLOCK_SIZE: usize = 16 * 1024;
+
+fn layout() -> StorageLayout {
+    StorageLayout::new(
+        vec![
+            (
+                PathBuf::from("bench/a.bin"),
+                PIECE_LENGTH * PIECES as u64 / 2 + 1000,
+            ),
+            (
+                PathBuf::from("bench/b.bin"),
+                PIECE_LENGTH * PIECES as u64 / 2 - 1000,
+            ),
+        ],
+        PIECE_LENGTH,
+    )
+}
+
+fn storages(dir: &std::path::Path) -> Vec<(&'static str, Box<dyn Storage>)> {
+    vec![
+        (
+            "buffered",
+            Box::new(FileStorage::new(dir.join("buffered"), layout())),
+        ),
+        (
+            "mmap",
+            Box::new(MmapStorage::new(dir.join("mmap"), layout())),
+        ),
+    ]
+}
+
+fn write_blocks(storage: &dyn Storage, block: &[u8]) {
+    for piece in 0..PIECES {
+        for offset in (0..PIECE_LENGTH as usize).step_by(BLOCK_SIZE) {
+            storage.write(piece, offset as u32, block).unwrap();
+        }
+    }
+    storage.flush().unwrap();
+}
+
+fn storage_benchmark(criterion: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let block = vec![0x5a; BLOCK_SIZE];
+    let mut group = criterion.benchmark_group("storage");
+    group.throughput(Throughput::Bytes(PIECE_LENGTH * PIECES as u64));
+    group.sample_size(10);
+
+    for (name, storage) in storages(dir.path()) {
+        group.bench_with_input(BenchmarkId::new("write", name), &storage, |b, storage| {
+            b.iter(|| write_blocks(storage.as_ref(), &block))
+        });
+
+        group.bench_with_input(BenchmarkId::new("read", name), &storage, |b, storage| {
+            b.iter(|| {
+                for piece in 0..PIECES {
+                    storage.read(piece, 0, PIECE_LENGTH as u32).unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, storage_benchmark);
+criterion_main!(benches);
@@ -1,4 +1,7 @@
-use clap::Parser;
+use crate::engine::ListenPort;
+use crate::protocol::net::EncryptionPolicy;
+use crate::storage::{Preallocation, StorageBackend};
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -8,17 +11,22 @@ pub struct Arguments {
     #[arg(short, long, value_name = "FILE")]
     pub file: PathBuf,
 
-    /// The thread number for downloading torrent files in parallel
+    /// The thread number for downloading torrent files in parallel. Every thread serves a single
+    /// peer connection, so this is the maximum number of peers a torrent is downloaded from.
     #[arg(short, long, default_value_t = 1, value_name = "THREAD NUMBER")]
     pub threads: usize,
 
-    /// A regexp like selector for selecting specific part of torrents. It might be helpful in
-    /// cases, where you want to download specific file(s), instead of downloading the full torrent.
+    /// The maximum number of peer connections across all torrents
+    #[arg(long, default_value_t = 200, value_name = "CONNECTIONS")]
+    pub max_connections: usize,
+
+    /// A regular expression selecting the files to download, e.g. `\.mkv$`. It's matched
+    /// against the file path within the output folder. All files are downloaded if not set.
     #[arg(short, long, value_name = "REGEXP EXPRESSION")]
     pub select: Option<String>,
 
-    /// Allows you to exclude some files from the torrent file list. It  might be used with
-    /// [select] option as well.
+    /// A regular expression of the files, which should not be downloaded. It might be used with
+    /// the `select` option as well.
     #[arg(short, long, value_name = "REGEXP EXPRESSION")]
     pub exclude: Option<String>,
 
@@ -26,6 +34,110 @@ pub struct Arguments {
     /// parameter is set to the current command location
     #[arg(short, long, default_value = ".", value_name = "OUTPUT FOLDER")]
     pub output: Option<String>,
+
+    /// The folder for the files being downloaded. A file is moved to the output folder as soon
+    /// as it's complete.
+    #[arg(long, value_name = "INCOMPLETE FOLDER")]
+    pub incomplete_dir: Option<PathBuf>,
+
+    /// Add the `.part` extension to the files being downloaded. It's removed as soon as the
+    /// file is complete.
+    #[arg(long)]
+    pub part_suffix: bool,
+
+    /// The folder for the fast-resume files, which let an interrupted download continue where it
+    /// stopped. The `.torrentino` folder within the output folder is used by default.
+    #[arg(long, value_name = "RESUME FOLDER")]
+    pub resume_dir: Option<PathBuf>,
+
+    /// Ignore the fast-resume data and hash the files already in the output folder before
+    /// downloading
+    #[arg(long)]
+    pub recheck: bool,
+
+    /// How the disk space is reserved for the selected files before downloading: `none`
+    /// creates the files as the data arrives, `sparse` sets the file sizes up front and `full`
+    /// writes the files with zeros, so the download never fails on a full disk.
+    #[arg(long, default_value = "none", value_name = "MODE")]
+    pub preallocation: Preallocation,
+
+    /// How the files are accessed: `buffered` reads and writes them as usual, `mmap` maps them
+    /// into memory, if the client is built with the mmap feature
+    #[arg(long, default_value = "buffered", value_name = "BACKEND")]
+    pub storage: StorageBackend,
+
+    /// The memory, in MiB, for the downloaded blocks waiting to be written to disk. Zero writes
+    /// every block immediately.
+    #[arg(long, default_value_t = 16, value_name = "MIB")]
+    pub write_cache: usize,
+
+    /// The memory, in MiB, for the pieces read from disk to be sent to the peers
+    #[arg(long, default_value_t = 16, value_name = "MIB")]
+    pub read_cache: usize,
+
+    /// The number of peers uploaded to at once. The fastest peers get the slots, except for one
+    /// slot rotated between the others.
+    #[arg(long, default_value_t = 4, value_name = "SLOTS")]
+    pub upload_slots: usize,
+
+    /// The encryption of the connections to the peers: `disabled` connects in plaintext,
+    /// `enabled` tries the encryption first and `forced` drops the peers not supporting it
+    #[arg(long, default_value = "disabled", value_name = "POLICY")]
+    pub outgoing_encryption: EncryptionPolicy,
+
+    /// The encryption of the connections from the peers: `disabled` accepts the plaintext
+    /// ones only, `enabled` accepts both and `forced` accepts the encrypted ones only
+    #[arg(long, default_value = "enabled", value_name = "POLICY")]
+    pub incoming_encryption: EncryptionPolicy,
+
+    /// Connect the peers over uTP first and accept the uTP connections, TCP is used for the
+    /// peers not supporting it
+    #[arg(long)]
+    pub utp: bool,
+
+    /// The port the peers connect to us on, or a range like `49152-65535` to listen on a
+    /// random port of it. The bound port is told to the trackers and the peers.
+    #[arg(long, default_value = "6881", value_name = "PORT")]
+    pub port: ListenPort,
+
+    /// The two characters of the client code our peer id starts with, e.g. `-TO0010-`. Some
+    /// private trackers let the whitelisted clients in only.
+    #[arg(long, default_value = "TO", value_name = "CODE", value_parser = parse_client_code)]
+    pub client_code: [u8; 2],
+
+    /// The download rate in KiB per second, zero is unlimited
+    #[arg(long, default_value_t = 0, value_name = "KIB/S")]
+    pub download_limit: u64,
+
+    /// The upload rate in KiB per second, zero is unlimited
+    #[arg(long, default_value_t = 0, value_name = "KIB/S")]
+    pub upload_limit: u64,
+
+    /// Count the whole peer messages against the rate limits, not only the downloaded and
+    /// uploaded data
+    #[arg(long)]
+    pub limit_overhead: bool,
+
+    /// Keep uploading to the other peers once the download is complete, until interrupted
+    #[arg(long)]
+    pub seed: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+fn parse_client_code(code: &str) -> Result<[u8; 2], String> {
+    match code.as_bytes() {
+        [first, second] if code.bytes().all(|b| b.is_ascii_alphanumeric()) => Ok([*first, *second]),
+        _ => Err("The client code should be two letters or digits".to_string()),
+    }
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    /// Checks the files in the output folder against the torrent without downloading anything.
+    /// Prints the completeness of every file and fails if any piece is missing or corrupted.
+    Verify,
 }
 
 #[cfg(test)]
@@ -1,10 +1,16 @@
 mod cli_args;
 
-pub use cli_args::Arguments;
+pub use cli_args::{Arguments, Command};
 
-use crate::engine::TorrentEngine;
+use crate::engine::blocking::TorrentEngine;
+use crate::engine::{
+    recheck, select_files, verified_bytes, EngineConfig, Priority, TorrentOptions,
+};
 use crate::protocol::entities::Torrent;
+use crate::storage::{CacheConfig, IncompleteFiles, StorageLayout};
 use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 pub struct Cli {
     args: Arguments,
@@ -28,6 +34,13 @@ impl Cli {
         Ok(())
     }
 
+    fn incomplete_files(&self) -> IncompleteFiles {
+        IncompleteFiles {
+            dir: self.args.incomplete_dir.clone(),
+            suffix: self.args.part_suffix.then(|| ".part".to_string()),
+        }
+    }
+
     fn parse_torrent_file(&self) -> Result<Torrent, String> {
         let file_path = self
             .args
@@ -43,14 +56,105 @@ impl Cli {
         Ok(torrent)
     }
 
+    /// Hashes the data in the output folder and prints how much of every file is complete. The
+    /// storage is opened the same way as for downloading, so the data of the skipped files is
+    /// read from the part file.
+    fn verify(&self, torrent: &Torrent, output: PathBuf) -> Result<(), String> {
+        let layout = StorageLayout::from_torrent(torrent)?;
+        let skipped: Vec<bool> = select_files(
+            &layout,
+            self.args.select.as_deref(),
+            self.args.exclude.as_deref(),
+        )?
+        .iter()
+        .map(|p| *p == Priority::Skip)
+        .collect();
+        let storage = self
+            .args
+            .storage
+            .open(output, layout, self.incomplete_files())?;
+        storage.set_skipped_files(&skipped)?;
+        let bitfield = recheck(torrent, storage.as_ref());
+        let layout = storage.layout();
+
+        for (file, verified) in layout.files.iter().zip(verified_bytes(layout, &bitfield)) {
+            let percent = if file.length == 0 {
+                100.0
+            } else {
+                verified as f64 * 100.0 / file.length as f64
+            };
+            println!("{:>6.1}%  {}", percent, file.path.display());
+        }
+
+        let valid = bitfield.iter().filter(|v| **v).count();
+        println!("{} of {} pieces are valid", valid, bitfield.len());
+
+        if valid < bitfield.len() {
+            return Err(format!(
+                "{} pieces are missing or corrupted",
+                bitfield.len() - valid
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn process(&self) -> Result<(), String> {
         self.check_file_existence()?;
 
         let torrent = self.parse_torrent_file()?;
-        let mut torrent_engine = TorrentEngine::start();
+        let output = PathBuf::from(self.args.output.as_deref().unwrap_or("."));
+        if self.args.command == Some(Command::Verify) {
+            return self.verify(&torrent, output);
+        }
 
-        torrent_engine.add_new_torrent(torrent);
+        let resume_dir = self
+            .args
+            .resume_dir
+            .clone()
+            .unwrap_or_else(|| output.join(".torrentino"));
 
-        Ok(())
+        let config = EngineConfig {
+            max_connections: self.args.max_connections,
+            max_connections_per_torrent: self.args.threads.max(1),
+            resume_dir: Some(resume_dir),
+            upload_slots: self.args.upload_slots,
+            outgoing_encryption: self.args.outgoing_encryption,
+            incoming_encryption: self.args.incoming_encryption,
+            utp: self.args.utp,
+            listen_port: self.args.port,
+            client_code: self.args.client_code,
+            download_limit: self.args.download_limit * 1024,
+            upload_limit: self.args.upload_limit * 1024,
+            limit_overhead: self.args.limit_overhead,
+            cache: CacheConfig {
+                write_size: self.args.write_cache * 1024 * 1024,
+                read_size: self.args.read_cache * 1024 * 1024,
+            },
+            ..EngineConfig::default()
+        };
+        let file_priorities = select_files(
+            &StorageLayout::from_torrent(&torrent)?,
+            self.args.select.as_deref(),
+            self.args.exclude.as_deref(),
+        )?;
+        let options = TorrentOptions {
+            output,
+            force_recheck: self.args.recheck,
+            file_priorities,
+            preallocation: self.args.preallocation,
+            backend: self.args.storage,
+            incomplete: self.incomplete_files(),
+            seed: self.args.seed,
+            ..TorrentOptions::default()
+        };
+        let mut torrent_engine = TorrentEngine::with_config(config);
+        // The download goes on without the incoming connections, e.g. if the port is taken
+        match torrent_engine.listen_on(IpAddr::from([0, 0, 0, 0])) {
+            Ok(addr) => println!("Listening for peers on {}", addr),
+            Err(msg) => println!("{}", msg),
+        }
+
+        torrent_engine.add_new_torrent(torrent, options)
     }
 }
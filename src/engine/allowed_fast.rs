@@ -0,0 +1,79 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+/// The number of pieces a choked peer may request, see [allowed_fast_set]
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// Generates the pieces the peer may request while we choke it, as defined by the fast
+/// extension (BEP 6). The set depends on the /24 network of the peer, so reconnecting from
+/// another address of the same network doesn't give more pieces. The set is defined for IPv4
+/// peers only, the others get none.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8; 20],
+    pieces_count: u32,
+    size: usize,
+) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return vec![],
+        },
+    };
+
+    let size = size.min(pieces_count as usize);
+    let mut set = Vec::with_capacity(size);
+    let mut x: Vec<u8> = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+
+    while set.len() < size {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks(4) {
+            if set.len() == size {
+                break;
+            }
+
+            let index = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) % pieces_count;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_specification() {
+        let ip: IpAddr = "80.4.4.200".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+
+        // The same network gets the same set
+        let neighbour: IpAddr = "80.4.4.1".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(neighbour, &[0xaa; 20], 1313, 7),
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7)
+        );
+    }
+
+    #[test]
+    fn small_torrents_allow_every_piece() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut set = allowed_fast_set(ip, &[1; 20], 3, ALLOWED_FAST_SET_SIZE);
+        set.sort();
+        assert_eq!(set, vec![0, 1, 2]);
+
+        assert!(allowed_fast_set("::1".parse().unwrap(), &[1; 20], 100, 10).is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// The blocks of a failed piece: who sent every block and the hash of what was sent
+type FailedBlocks = Vec<Option<(IpAddr, [u8; 20])>>;
+
+/// Finds the peers sending corrupt data and bans them.
+///
+/// A piece failing the hash check with all its blocks from a single IP proves that IP guilty.
+/// If the blocks came from several IPs, the hashes of the blocks are kept and the piece is
+/// downloaded once again from a single peer. Once the piece passes, the blocks differing from
+/// the kept ones point to the IPs to blame. An IP is banned after it is proven guilty the given
+/// number of times, as a single corrupt piece might be an accident.
+#[derive(Debug)]
+pub struct BanList {
+    max_strikes: u32,
+    /// The number of corrupt pieces proven for every IP
+    strikes: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+    /// The pieces failed with the blocks from several IPs
+    failed: HashMap<u32, FailedBlocks>,
+}
+
+impl BanList {
+    pub fn new(max_strikes: u32) -> Self {
+        BanList {
+            max_strikes: max_strikes.max(1),
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+            failed: HashMap::new(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    /// The banned IPs in a stable order
+    pub fn banned(&self) -> Vec<IpAddr> {
+        let mut banned: Vec<IpAddr> = self.banned.iter().copied().collect();
+        banned.sort();
+        banned
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip);
+    }
+
+    /// Lifts the ban and forgets the strikes of the IP
+    pub fn unban(&mut self, ip: &IpAddr) {
+        self.banned.remove(ip);
+        self.strikes.remove(ip);
+    }
+
+    /// Returns true if the piece failed with the blocks from several IPs, so it should be
+    /// downloaded from a single peer
+    pub fn is_suspicious(&self, piece: u32) -> bool {
+        self.failed.contains_key(&piece)
+    }
+
+    /// Takes the piece, which failed the hash check. The `senders` and the `hashes` go by the
+    /// block index, the sender is unknown for the blocks restored after a restart. Returns the
+    /// IPs banned because of this piece.
+    pub fn piece_failed(
+        &mut self,
+        piece: u32,
+        senders: &[Option<IpAddr>],
+        hashes: &[[u8; 20]],
+    ) -> Vec<IpAddr> {
+        let known: HashSet<IpAddr> = senders.iter().flatten().copied().collect();
+        let single_sender = known.len() == 1 && senders.iter().all(Option::is_some);
+        if single_sender {
+            return known.into_iter().filter_map(|ip| self.strike(ip)).collect();
+        }
+
+        // The first failure is kept, a repeated one isn't any better to compare with
+        if !known.is_empty() && hashes.len() == senders.len() {
+            self.failed.entry(piece).or_insert_with(|| {
+                senders
+                    .iter()
+                    .zip(hashes)
+                    .map(|(sender, hash)| sender.map(|ip| (ip, *hash)))
+                    .collect()
+            });
+        }
+        vec![]
+    }
+
+    /// Takes the piece, which passed the hash check, with the hashes of its blocks. The IPs,
+    /// which sent different blocks before, are to blame. Returns the IPs banned because of
+    /// this piece.
+    pub fn piece_passed(&mut self, piece: u32, hashes: &[[u8; 20]]) -> Vec<IpAddr> {
+        let failed = match self.failed.remove(&piece) {
+            Some(failed) if failed.len() == hashes.len() => failed,
+            _ => return vec![],
+        };
+
+        let guilty: HashSet<IpAddr> = failed
+            .iter()
+            .zip(hashes)
+            .filter_map(|(block, hash)| match block {
+                Some((ip, sent)) if sent != hash => Some(*ip),
+                _ => None,
+            })
+            .collect();
+        guilty
+            .into_iter()
+            .filter_map(|ip| self.strike(ip))
+            .collect()
+    }
+
+    /// Counts one more corrupt piece of the IP. Returns the IP if it's banned now.
+    fn strike(&mut self, ip: IpAddr) -> Option<IpAddr> {
+        let strikes = self.strikes.entry(ip).or_default();
+        *strikes += 1;
+        (*strikes >= self.max_strikes && self.banned.insert(ip)).then_some(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn single_sender_is_banned_after_repeated_failures() {
+        let mut bans = BanList::new(2);
+        let senders = [Some(ip(1)), Some(ip(1))];
+
+        assert!(bans
+            .piece_failed(0, &senders, &[[1; 20], [2; 20]])
+            .is_empty());
+        assert!(!bans.is_suspicious(0));
+        assert!(!bans.is_banned(&ip(1)));
+
+        assert_eq!(bans.piece_failed(3, &senders, &[]), vec![ip(1)]);
+        assert!(bans.is_banned(&ip(1)));
+        assert!(bans.piece_failed(4, &senders, &[]).is_empty());
+
+        bans.unban(&ip(1));
+        assert!(bans.banned().is_empty());
+        assert!(bans.piece_failed(5, &senders, &[]).is_empty());
+    }
+
+    #[test]
+    fn redownload_finds_the_corrupt_block() {
+        let mut bans = BanList::new(1);
+        let senders = [Some(ip(1)), Some(ip(2)), Some(ip(3))];
+        let sent = [[1; 20], [6; 20], [3; 20]];
+
+        assert!(bans.piece_failed(7, &senders, &sent).is_empty());
+        assert!(bans.is_suspicious(7));
+
+        // The second peer sent a different block
+        assert_eq!(
+            bans.piece_passed(7, &[[1; 20], [2; 20], [3; 20]]),
+            vec![ip(2)]
+        );
+        assert!(!bans.is_suspicious(7));
+        assert_eq!(bans.banned(), vec![ip(2)]);
+    }
+
+    #[test]
+    fn unknown_senders_prove_nothing() {
+        let mut bans = BanList::new(1);
+        assert!(bans
+            .piece_failed(0, &[None, None], &[[1; 20], [2; 20]])
+            .is_empty());
+        assert!(!bans.is_suspicious(0));
+
+        // A block restored after a restart might be the corrupt one
+        assert!(bans
+            .piece_failed(1, &[None, Some(ip(1))], &[[1; 20], [2; 20]])
+            .is_empty());
+        assert!(bans.is_suspicious(1));
+        assert!(bans.piece_passed(1, &[[9; 20], [2; 20]]).is_empty());
+        assert!(bans.piece_passed(2, &[[9; 20]]).is_empty());
+    }
+}
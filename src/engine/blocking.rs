@@ -0,0 +1,63 @@
+//! The blocking torrent engine for simple callers, which don't want to deal with the async
+//! runtime. It wraps the asynchronous [crate::engine::TorrentEngine] and its own runtime.
+
+use crate::engine::{EngineConfig, TorrentOptions};
+use crate::protocol::entities::Torrent;
+use std::net::{IpAddr, SocketAddr};
+use tokio::runtime::{Builder, Runtime};
+
+pub struct TorrentEngine {
+    runtime: Runtime,
+    engine: super::TorrentEngine,
+}
+
+impl TorrentEngine {
+    pub fn start() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start the async runtime");
+
+        TorrentEngine {
+            runtime,
+            engine: super::TorrentEngine::with_config(config),
+        }
+    }
+
+    /// Starts accepting the incoming peer connections, see
+    /// [crate::engine::TorrentEngine::listen]
+    pub fn listen(&self, addr: SocketAddr) -> Result<SocketAddr, String> {
+        self.runtime.block_on(self.engine.listen(addr))
+    }
+
+    /// Starts accepting the incoming peer connections on the port of the config, see
+    /// [crate::engine::TorrentEngine::listen_on]
+    pub fn listen_on(&self, ip: IpAddr) -> Result<SocketAddr, String> {
+        self.runtime.block_on(self.engine.listen_on(ip))
+    }
+
+    /// Downloads the torrent and blocks until it's finished
+    pub fn add_new_torrent(
+        &mut self,
+        torrent: Torrent,
+        options: TorrentOptions,
+    ) -> Result<(), String> {
+        self.runtime
+            .block_on(self.engine.add_new_torrent(torrent, options))
+    }
+
+    /// Changes the rates of all torrents together, see
+    /// [crate::engine::TorrentEngine::set_rate_limits]
+    pub fn set_rate_limits(&self, download: u64, upload: u64) {
+        self.engine.set_rate_limits(download, upload)
+    }
+
+    /// Stops all running torrents
+    pub fn shutdown(&self) {
+        self.engine.shutdown()
+    }
+}
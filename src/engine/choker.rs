@@ -0,0 +1,348 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long the optimistically unchoked peer keeps its slot
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The peers connected this recently are three times as likely to get the optimistic unchoke,
+/// as they have nothing to offer yet
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+/// What the choker knows about a connected peer
+#[derive(Debug, Clone)]
+pub struct ChokerPeer {
+    pub addr: SocketAddr,
+    /// The peer is interested in our pieces
+    pub interested: bool,
+    /// Bytes per second we receive from the peer
+    pub download_rate: u64,
+    /// Bytes per second we send to the peer
+    pub upload_rate: u64,
+    pub connected_at: Instant,
+    /// The peer doesn't send us the blocks, so it gets the optimistic unchoke only
+    pub snubbed: bool,
+}
+
+/// Decides which peers we upload to
+pub trait Choker: Send {
+    /// Picks the peers to unchoke, all the others get choked. It's called every rechoke
+    /// interval with the connected peers, `seeding` tells that we have the whole torrent.
+    fn rechoke(&mut self, peers: &[ChokerPeer], seeding: bool, now: Instant) -> Vec<SocketAddr>;
+}
+
+/// Creates the choker of every torrent, see [crate::engine::TorrentOptions::choker]
+pub type ChokerFactory = Arc<dyn Fn() -> Box<dyn Choker> + Send + Sync>;
+
+/// The standard BitTorrent choker. The interested peers giving us the best download rate get
+/// all the slots but one, the peers we upload the fastest to get them while seeding. The last
+/// slot goes to a random peer, which changes every [OPTIMISTIC_UNCHOKE_INTERVAL], so the new
+/// peers get a chance to show their rate. The snubbing peers are never reciprocated, they
+/// might get the optimistic slot only.
+pub struct TitForTat {
+    slots: usize,
+    optimistic_interval: Duration,
+    /// The optimistically unchoked peer and when it got the slot
+    optimistic: Option<(SocketAddr, Instant)>,
+    rng: StdRng,
+}
+
+impl TitForTat {
+    pub fn new(slots: usize) -> Self {
+        TitForTat {
+            slots,
+            optimistic_interval: OPTIMISTIC_UNCHOKE_INTERVAL,
+            optimistic: None,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Makes the optimistic unchoke predictable, for the tests
+    pub fn with_seed(self, seed: u64) -> Self {
+        TitForTat {
+            rng: StdRng::seed_from_u64(seed),
+            ..self
+        }
+    }
+
+    pub fn with_optimistic_interval(self, interval: Duration) -> Self {
+        TitForTat {
+            optimistic_interval: interval,
+            ..self
+        }
+    }
+
+    /// Picks a random candidate, preferring the newly connected ones. The previous optimistic
+    /// peer is skipped, unless there is nobody else.
+    fn pick_optimistic(&mut self, candidates: &[&ChokerPeer], now: Instant) -> Option<SocketAddr> {
+        let previous = self.optimistic.map(|(addr, _)| addr);
+        let candidates: Vec<&ChokerPeer> = match candidates
+            .iter()
+            .copied()
+            .filter(|peer| Some(peer.addr) != previous)
+            .collect::<Vec<_>>()
+        {
+            others if others.is_empty() => candidates.to_vec(),
+            others => others,
+        };
+
+        let weight = |peer: &ChokerPeer| {
+            if now.saturating_duration_since(peer.connected_at) < NEW_PEER_AGE {
+                3
+            } else {
+                1
+            }
+        };
+        let total: u32 = candidates.iter().map(|peer| weight(peer)).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = self.rng.gen_range(0..total);
+        for peer in candidates {
+            if point < weight(peer) {
+                return Some(peer.addr);
+            }
+            point -= weight(peer);
+        }
+        None
+    }
+}
+
+impl Choker for TitForTat {
+    fn rechoke(&mut self, peers: &[ChokerPeer], seeding: bool, now: Instant) -> Vec<SocketAddr> {
+        if self.slots == 0 {
+            return vec![];
+        }
+
+        let rate = |peer: &ChokerPeer| {
+            if seeding {
+                peer.upload_rate
+            } else {
+                peer.download_rate
+            }
+        };
+        let mut interested: Vec<&ChokerPeer> = peers.iter().filter(|p| p.interested).collect();
+        interested.sort_by_key(|peer| Reverse(rate(peer)));
+
+        // The snubbing peers go last, whatever their rate is
+        interested.sort_by_key(|peer| peer.snubbed);
+
+        let regular = interested
+            .iter()
+            .filter(|peer| !peer.snubbed)
+            .count()
+            .min(self.slots - 1);
+        let mut unchoked: Vec<SocketAddr> = interested[..regular].iter().map(|p| p.addr).collect();
+        let others = &interested[regular..];
+
+        // The optimistic peer keeps the slot for the whole interval, unless it has earned a
+        // regular one or lost the interest
+        let current = self.optimistic.filter(|(addr, since)| {
+            now.saturating_duration_since(*since) < self.optimistic_interval
+                && others.iter().any(|peer| peer.addr == *addr)
+        });
+        self.optimistic = match current {
+            Some(current) => Some(current),
+            None => self.pick_optimistic(others, now).map(|addr| (addr, now)),
+        };
+
+        if let Some((addr, _)) = self.optimistic {
+            unchoked.push(addr);
+        }
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Drives a choker with made up peers and rates, the time goes by the rechoke intervals
+    struct Simulation {
+        choker: TitForTat,
+        peers: Vec<ChokerPeer>,
+        start: Instant,
+        now: Instant,
+        seeding: bool,
+    }
+
+    impl Simulation {
+        fn new(slots: usize, seed: u64) -> Self {
+            let start = Instant::now();
+            Simulation {
+                choker: TitForTat::new(slots).with_seed(seed),
+                peers: vec![],
+                start,
+                now: start + Duration::from_secs(3600),
+                seeding: false,
+            }
+        }
+
+        /// Adds an interested peer, connected long ago
+        fn peer(mut self, port: u16, download_rate: u64, upload_rate: u64) -> Self {
+            self.peers.push(ChokerPeer {
+                addr: addr(port),
+                interested: true,
+                download_rate,
+                upload_rate,
+                connected_at: self.start,
+                snubbed: false,
+            });
+            self
+        }
+
+        fn connect(&mut self, port: u16) {
+            self.peers.push(ChokerPeer {
+                addr: addr(port),
+                interested: true,
+                download_rate: 0,
+                upload_rate: 0,
+                connected_at: self.now,
+                snubbed: false,
+            });
+        }
+
+        fn set(&mut self, port: u16, change: impl FnOnce(&mut ChokerPeer)) {
+            change(
+                self.peers
+                    .iter_mut()
+                    .find(|p| p.addr == addr(port))
+                    .unwrap(),
+            );
+        }
+
+        /// Runs the next rechoke, returns the unchoked ports
+        fn step(&mut self) -> Vec<u16> {
+            self.now += RECHOKE_INTERVAL;
+            let mut ports: Vec<u16> = self
+                .choker
+                .rechoke(&self.peers, self.seeding, self.now)
+                .iter()
+                .map(|addr| addr.port())
+                .collect();
+            ports.sort();
+            ports
+        }
+
+        fn optimistic(&self) -> Option<u16> {
+            self.choker.optimistic.map(|(addr, _)| addr.port())
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn unchokes_fastest_peers_and_one_optimistic() {
+        let mut simulation = Simulation::new(3, 1)
+            .peer(1, 500, 0)
+            .peer(2, 100, 0)
+            .peer(3, 900, 0)
+            .peer(4, 50, 0);
+
+        let unchoked = simulation.step();
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&1) && unchoked.contains(&3));
+        assert!(matches!(simulation.optimistic(), Some(2 | 4)));
+
+        // The slow peer speeds up and wins a regular slot at the next rechoke
+        simulation.set(4, |peer| peer.download_rate = 1000);
+        let unchoked = simulation.step();
+        assert!(unchoked.contains(&3) && unchoked.contains(&4));
+    }
+
+    #[test]
+    fn seeding_reciprocates_upload_rate() {
+        let mut simulation = Simulation::new(2, 1)
+            .peer(1, 900, 10)
+            .peer(2, 0, 800)
+            .peer(3, 0, 20);
+        simulation.seeding = true;
+
+        let unchoked = simulation.step();
+        assert!(unchoked.contains(&2));
+        assert!(!unchoked.contains(&1) || simulation.optimistic() == Some(1));
+    }
+
+    #[test]
+    fn optimistic_slot_rotates_every_thirty_seconds() {
+        let mut simulation = Simulation::new(2, 7)
+            .peer(1, 1000, 0)
+            .peer(2, 10, 0)
+            .peer(3, 10, 0)
+            .peer(4, 10, 0);
+
+        let mut history = vec![];
+        for _ in 0..9 {
+            simulation.step();
+            history.push(simulation.optimistic().unwrap());
+        }
+
+        // Three rechokes per interval, and the slot always changes hands
+        for window in history.chunks(3) {
+            assert!(window.iter().all(|port| *port == window[0]));
+        }
+        for pair in history.chunks(3).collect::<Vec<_>>().windows(2) {
+            assert_ne!(pair[0][0], pair[1][0]);
+        }
+    }
+
+    #[test]
+    fn snubbing_peers_get_optimistic_unchoke_only() {
+        let mut simulation = Simulation::new(3, 1)
+            .peer(1, 5000, 0)
+            .peer(2, 100, 0)
+            .peer(3, 50, 0)
+            .peer(4, 10, 0);
+        simulation.set(1, |peer| peer.snubbed = true);
+
+        let unchoked = simulation.step();
+        assert!(unchoked.contains(&2) && unchoked.contains(&3));
+        assert!(!unchoked.contains(&1) || simulation.optimistic() == Some(1));
+
+        // The snubbing peer is the only candidate for the optimistic slot now
+        simulation.set(4, |peer| peer.interested = false);
+        for _ in 0..3 {
+            simulation.step();
+        }
+        assert_eq!(simulation.optimistic(), Some(1));
+        assert_eq!(simulation.step(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let mut simulation = Simulation::new(4, 1).peer(1, 1000, 0).peer(2, 10, 0);
+        simulation.set(1, |peer| peer.interested = false);
+        assert_eq!(simulation.step(), vec![2]);
+
+        assert!(Simulation::new(0, 1).peer(1, 10, 0).step().is_empty());
+    }
+
+    #[test]
+    fn new_peers_are_favoured_for_optimistic_unchoke() {
+        let mut picked: HashMap<u16, u32> = HashMap::new();
+        for seed in 0..1000 {
+            let mut simulation = Simulation::new(2, seed)
+                .peer(1, 1000, 0)
+                .peer(2, 0, 0)
+                .peer(3, 0, 0)
+                .peer(4, 0, 0);
+            simulation.connect(5);
+            simulation.step();
+            *picked.entry(simulation.optimistic().unwrap()).or_default() += 1;
+        }
+
+        // The new peer weighs as much as the three old ones together
+        assert!(picked[&5] > 400, "{:?}", picked);
+        assert!(picked[&2] > 100 && picked[&2] < 250, "{:?}", picked);
+        assert!(!picked.contains_key(&1));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::engine::{ChokerFactory, Priority, CLIENT_CODE};
+use crate::protocol::net::EncryptionPolicy;
+use crate::storage::{CacheConfig, IncompleteFiles, Preallocation, Storage, StorageBackend};
+use rand::seq::index::sample;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The port the listener binds, see [crate::engine::TorrentEngine::listen_on]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ListenPort {
+    /// Zero lets the OS pick a free port
+    Fixed(u16),
+    /// A random port of the inclusive range. Some networks throttle the well-known ports, and
+    /// a random one is free more likely.
+    Random(u16, u16),
+}
+
+impl ListenPort {
+    /// The ports to try in turn, a few random ones of the range at most
+    pub fn candidates(&self, attempts: usize) -> Vec<u16> {
+        match *self {
+            ListenPort::Fixed(port) => vec![port],
+            ListenPort::Random(first, last) => {
+                let count = (last - first) as usize + 1;
+                sample(&mut rand::thread_rng(), count, attempts.min(count))
+                    .iter()
+                    .map(|offset| first + offset as u16)
+                    .collect()
+            }
+        }
+    }
+}
+
+impl FromStr for ListenPort {
+    type Err = String;
+
+    /// Parses a port, like `6881`, or a range of ports, like `49152-65535`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid port {}: {}", port, e))
+        };
+
+        match s.split_once('-') {
+            None => Ok(ListenPort::Fixed(parse(s)?)),
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first == 0 || first > last {
+                    return Err(format!("Invalid port range {}", s));
+                }
+                Ok(ListenPort::Random(first, last))
+            }
+        }
+    }
+}
+
+impl Display for ListenPort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ListenPort::Fixed(port) => write!(f, "{}", port),
+            ListenPort::Random(first, last) => write!(f, "{}-{}", first, last),
+        }
+    }
+}
+
+/// Engine wide settings
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// The maximum number of open peer connections across all torrents
+    pub max_connections: usize,
+
+    /// The maximum number of open peer connections of a single torrent
+    pub max_connections_per_torrent: usize,
+
+    /// How long do we wait for a peer to accept the TCP connection and answer the handshake
+    pub connect_timeout: Duration,
+
+    /// The delay before the first retry of a failed peer. Every next failure doubles the delay
+    /// until it reaches `max_retry_backoff`.
+    pub retry_backoff: Duration,
+
+    pub max_retry_backoff: Duration,
+
+    /// Peers failed this many times in a row are not retried anymore
+    pub max_connect_attempts: u32,
+
+    /// The folder for the fast-resume files. The progress isn't saved if not set.
+    pub resume_dir: Option<PathBuf>,
+
+    /// How often the resume data is saved while the torrent is running
+    pub resume_interval: Duration,
+
+    /// The memory limits of the disk cache, every torrent has its own cache
+    pub cache: CacheConfig,
+
+    /// The number of peers a torrent uploads to at once, one of them is unchoked
+    /// optimistically
+    pub upload_slots: usize,
+
+    /// How often the choker picks the peers to upload to
+    pub rechoke_interval: Duration,
+
+    /// A peer, which unchokes us but sends no block for this long, is snubbing us. It's also
+    /// the time a new peer has before it might be dropped for a better one.
+    pub snub_timeout: Duration,
+
+    /// An IP is banned once it's proven to have sent corrupt data this many times
+    pub max_corrupt_pieces: u32,
+
+    /// The encryption of the connections we open. It's disabled by default, since a peer not
+    /// supporting the encryption costs one more connection attempt.
+    pub outgoing_encryption: EncryptionPolicy,
+
+    /// The encryption of the connections accepted by the listener
+    pub incoming_encryption: EncryptionPolicy,
+
+    /// Connects the peers over uTP first and falls back to TCP, the listener accepts the uTP
+    /// connections on the same port as well. uTP yields to the other traffic of the link, but a
+    /// peer not supporting it costs a connection timeout, so it's disabled by default.
+    pub utp: bool,
+
+    /// The port [crate::engine::TorrentEngine::listen_on] binds. The bound port is told to the
+    /// trackers and to the peers supporting the extension protocol.
+    pub listen_port: ListenPort,
+
+    /// The client code of our peer ids, see [crate::engine::generate_peer_id]. Some private
+    /// trackers let the whitelisted clients in only.
+    pub client_code: [u8; 2],
+
+    /// The download and upload rates of all torrents together, in bytes per second. Zero is
+    /// unlimited. The limits might be changed at runtime, see
+    /// [crate::engine::TorrentEngine::set_rate_limits].
+    pub download_limit: u64,
+
+    pub upload_limit: u64,
+
+    /// The rates of every single peer, in bytes per second. Zero is unlimited.
+    pub peer_download_limit: u64,
+
+    pub peer_upload_limit: u64,
+
+    /// The rate limits count the whole messages, not only the blocks of the pieces
+    pub limit_overhead: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            max_connections: 200,
+            max_connections_per_torrent: 8,
+            connect_timeout: Duration::from_secs(5),
+            retry_backoff: Duration::from_secs(5),
+            max_retry_backoff: Duration::from_secs(300),
+            max_connect_attempts: 5,
+            resume_dir: None,
+            resume_interval: Duration::from_secs(60),
+            cache: CacheConfig::default(),
+            upload_slots: 4,
+            rechoke_interval: Duration::from_secs(10),
+            snub_timeout: Duration::from_secs(60),
+            max_corrupt_pieces: 2,
+            outgoing_encryption: EncryptionPolicy::Disabled,
+            incoming_encryption: EncryptionPolicy::Enabled,
+            utp: false,
+            listen_port: ListenPort::Fixed(6881),
+            client_code: CLIENT_CODE,
+            download_limit: 0,
+            upload_limit: 0,
+            peer_download_limit: 0,
+            peer_upload_limit: 0,
+            limit_overhead: false,
+        }
+    }
+}
+
+/// Settings of a single torrent
+#[derive(Clone)]
+pub struct TorrentOptions {
+    /// The folder the torrent content is saved to
+    pub output: PathBuf,
+
+    /// A custom storage backend. If not set, the content is stored in the regular files under
+    /// the `output` folder.
+    pub storage: Option<Arc<dyn Storage>>,
+
+    /// How the regular files are accessed, unless the custom storage is given
+    pub backend: StorageBackend,
+
+    /// Where the files are kept until they are complete, unless the custom storage is given
+    pub incomplete: IncompleteFiles,
+
+    /// Ignore the resume data and hash the data already in the storage
+    pub force_recheck: bool,
+
+    /// The priority of every torrent file, see [crate::engine::select_files]. All the files
+    /// are downloaded if empty.
+    pub file_priorities: Vec<Priority>,
+
+    /// How the disk space is reserved for the selected files before the download
+    pub preallocation: Preallocation,
+
+    /// Keep serving the peers once the download is complete, until the torrent is stopped
+    pub seed: bool,
+
+    /// Decides which peers we upload to, [crate::engine::TitForTat] with the engine's upload
+    /// slots if not set
+    pub choker: Option<ChokerFactory>,
+
+    /// The download and upload rates of the torrent, in bytes per second. Zero is unlimited.
+    pub download_limit: u64,
+
+    pub upload_limit: u64,
+}
+
+impl Default for TorrentOptions {
+    fn default() -> Self {
+        TorrentOptions {
+            output: PathBuf::from("."),
+            storage: None,
+            backend: StorageBackend::Buffered,
+            incomplete: IncompleteFiles::default(),
+            force_recheck: false,
+            file_priorities: vec![],
+            preallocation: Preallocation::None,
+            seed: false,
+            choker: None,
+            download_limit: 0,
+            upload_limit: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_port() {
+        assert_eq!("6881".parse(), Ok(ListenPort::Fixed(6881)));
+        assert_eq!("0".parse(), Ok(ListenPort::Fixed(0)));
+        assert_eq!("6881-6889".parse(), Ok(ListenPort::Random(6881, 6889)));
+        assert_eq!(ListenPort::Random(6881, 6889).to_string(), "6881-6889");
+        assert!("6889-6881".parse::<ListenPort>().is_err());
+        assert!("0-10".parse::<ListenPort>().is_err());
+        assert!("port".parse::<ListenPort>().is_err());
+        assert!("6881-70000".parse::<ListenPort>().is_err());
+    }
+
+    #[test]
+    fn random_port_candidates() {
+        assert_eq!(ListenPort::Fixed(6881).candidates(10), vec![6881]);
+
+        let candidates = ListenPort::Random(6881, 6883).candidates(10);
+        assert_eq!(candidates.len(), 3);
+        assert!(candidates.iter().all(|port| (6881..=6883).contains(port)));
+        assert!((6881..=6883).all(|port| candidates.contains(&port)));
+
+        let candidates = ListenPort::Random(1024, 65535).candidates(10);
+        assert_eq!(candidates.len(), 10);
+        assert!(candidates.iter().all(|port| *port >= 1024));
+    }
+}
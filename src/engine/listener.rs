@@ -0,0 +1,160 @@
+use crate::engine::torrent_session::SessionEvent;
+use crate::protocol::entities::BIT_TORRENT_PROTOCOL_STRING;
+use crate::protocol::net::{
+    accept_encryption, accept_handshake, EncryptedStream, EncryptionPolicy, PeerCodec, PeerStream,
+    UtpSocket, UtpStream,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+
+/// A running torrent, which accepts the incoming connections
+#[derive(Clone)]
+struct Route {
+    peer_id: [u8; 20],
+    events: mpsc::Sender<SessionEvent>,
+}
+
+/// The running torrents by their info hash. The listener passes every accepted connection to
+/// the torrent the remote peer asks for.
+#[derive(Clone, Default)]
+pub(crate) struct IncomingRoutes {
+    routes: Arc<Mutex<HashMap<[u8; 20], Route>>>,
+}
+
+impl IncomingRoutes {
+    fn routes(&self) -> MutexGuard<'_, HashMap<[u8; 20], Route>> {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn register(
+        &self,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        events: mpsc::Sender<SessionEvent>,
+    ) -> Result<(), String> {
+        let mut routes = self.routes();
+        if routes.contains_key(&info_hash) {
+            return Err("The torrent is already running".to_string());
+        }
+
+        routes.insert(info_hash, Route { peer_id, events });
+        Ok(())
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.routes().remove(info_hash);
+    }
+
+    fn get(&self, info_hash: &[u8; 20]) -> Option<Route> {
+        self.routes().get(info_hash).cloned()
+    }
+
+    fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.routes().keys().copied().collect()
+    }
+}
+
+/// Accepts the incoming TCP and uTP connections until the token is cancelled
+pub(crate) async fn listen(
+    listener: TcpListener,
+    utp: Option<UtpSocket>,
+    routes: IncomingRoutes,
+    encryption: EncryptionPolicy,
+    handshake_timeout: Duration,
+    token: CancellationToken,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = token.cancelled() => return,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => (PeerStream::Tcp(stream), addr),
+                Err(e) => {
+                    println!("Unable to accept the connection: {}", e);
+                    continue;
+                }
+            },
+            Some(stream) = accept_utp(&utp) => {
+                let addr = stream.peer_addr();
+                (PeerStream::Utp(stream), addr)
+            }
+        };
+
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            let result = timeout(handshake_timeout, accept(stream, addr, routes, encryption))
+                .await
+                .unwrap_or_else(|_| Err("Handshake timed out".to_string()));
+            if let Err(msg) = result {
+                println!("Incoming connection from {} dropped: {}", addr, msg);
+            }
+        });
+    }
+}
+
+async fn accept_utp(utp: &Option<UtpSocket>) -> Option<UtpStream> {
+    match utp {
+        Some(utp) => utp.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Answers the handshake and passes the connection to the torrent. The first bytes tell the
+/// plaintext handshake from the encrypted one, which is answered if the policy allows it.
+async fn accept(
+    mut stream: PeerStream,
+    addr: SocketAddr,
+    routes: IncomingRoutes,
+    encryption: EncryptionPolicy,
+) -> Result<(), String> {
+    let mut header = [0u8; 20];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| format!("Unable to read from TCP connection: {}", e))?;
+
+    let plaintext = header[0] as usize == BIT_TORRENT_PROTOCOL_STRING.len()
+        && &header[1..] == BIT_TORRENT_PROTOCOL_STRING.as_bytes();
+    let (mut stream, encrypted_for) = match (plaintext, encryption) {
+        (true, EncryptionPolicy::Forced) => {
+            return Err("Plaintext connections are not allowed".to_string())
+        }
+        (true, _) => (EncryptedStream::plaintext(stream, header.to_vec()), None),
+        (false, EncryptionPolicy::Disabled) => {
+            return Err("Encrypted connections are not allowed".to_string())
+        }
+        (false, _) => {
+            let (stream, info_hash) =
+                accept_encryption(stream, &header, &routes.info_hashes(), encryption).await?;
+            (stream, Some(info_hash))
+        }
+    };
+
+    let mut route = None;
+    let remote = accept_handshake(&mut stream, |info_hash| {
+        if encrypted_for.is_some_and(|encrypted_for| encrypted_for != *info_hash) {
+            return None;
+        }
+        route = routes.get(info_hash);
+        route.as_ref().map(|route| route.peer_id)
+    })
+    .await?;
+
+    let route = route.ok_or_else(|| "Unknown info hash".to_string())?;
+    route
+        .events
+        .send(SessionEvent::Incoming(
+            addr,
+            Box::new(Framed::new(stream, PeerCodec::default())),
+            remote,
+        ))
+        .await
+        .map_err(|_| "The torrent is not running".to_string())
+}
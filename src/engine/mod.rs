@@ -1,22 +1,34 @@
+mod allowed_fast;
+mod ban_list;
+pub mod blocking;
+mod choker;
+mod config;
 mod engine_events;
+mod listener;
+mod peer_id;
+mod peer_pool;
+mod peer_quality;
+mod peer_session;
+mod piece_picker;
+mod priority;
+mod rate_limit;
+mod recheck;
+mod resume;
+mod statistics;
 mod torrent_engine;
+mod torrent_session;
 
-use rand::distributions::Alphanumeric;
-use rand::Rng;
-pub use torrent_engine::TorrentEngine;
-
-pub fn generate_peer_id() -> [u8; 20] {
-    let chars: Vec<char> = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(20)
-        .map(char::from)
-        .collect();
-
-    let mut result = [0u8; 20];
-
-    for i in 0..result.len() {
-        result[i] = chars[i] as u8;
-    }
-
-    result
-}
+pub use allowed_fast::{allowed_fast_set, ALLOWED_FAST_SET_SIZE};
+pub use ban_list::BanList;
+pub use choker::{Choker, ChokerFactory, ChokerPeer, TitForTat, OPTIMISTIC_UNCHOKE_INTERVAL};
+pub use config::{EngineConfig, ListenPort, TorrentOptions};
+pub use peer_id::{generate_peer_id, ClientVersion, CLIENT_CODE};
+pub use peer_pool::{ConnectionLimit, PeerPool};
+pub use peer_quality::PeerQuality;
+pub use piece_picker::{BlockInfo, BlockOutcome, PiecePicker, BLOCK_SIZE};
+pub use priority::{piece_priorities, select_files, Priority};
+pub use rate_limit::{RateLimiter, RateLimits};
+pub use recheck::{check_piece, recheck, verified_bytes};
+pub use resume::{FileState, PartialPiece, ResumeData, TrackerState, RESUME_VERSION};
+pub use statistics::{PeerInfo, TorrentStatistics};
+pub use torrent_engine::{TorrentEngine, TorrentHandle};
@@ -0,0 +1,236 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The client code of our peer ids, see [generate_peer_id]
+pub const CLIENT_CODE: [u8; 2] = *b"TO";
+
+/// The clients of the Azureus-style peer ids, `-AZ2060-`, by the client code
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BF", "Bitflu"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("MG", "MediaGet"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TO", "Torrentino"),
+    ("TR", "Transmission"),
+    ("TX", "Tixati"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// The clients of the Shadow-style peer ids, `S58B-----`, by the first byte
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Encodes a version number as a single character of the peer id: the digits, then the capital
+/// letters for 10 to 35. The bigger numbers don't fit and are encoded as `Z`.
+fn version_char(number: &str) -> u8 {
+    match number.parse::<u8>().unwrap_or(0) {
+        n @ 0..=9 => b'0' + n,
+        n @ 10..=35 => b'A' + n - 10,
+        _ => b'Z',
+    }
+}
+
+/// Generates an Azureus-style peer id, which tells the client and its version to the trackers
+/// and the peers: `-TO0010-` for the version 0.0.1, followed by 12 random characters. Some
+/// private trackers accept the whitelisted clients only.
+pub fn generate_peer_id(client_code: [u8; 2]) -> [u8; 20] {
+    let mut result = [0u8; 20];
+    result[0] = b'-';
+    result[1..3].copy_from_slice(&client_code);
+    result[3] = version_char(env!("CARGO_PKG_VERSION_MAJOR"));
+    result[4] = version_char(env!("CARGO_PKG_VERSION_MINOR"));
+    result[5] = version_char(env!("CARGO_PKG_VERSION_PATCH"));
+    result[6] = b'0';
+    result[7] = b'-';
+
+    let random = rand::thread_rng().sample_iter(&Alphanumeric).take(12);
+    for (byte, char) in result[8..].iter_mut().zip(random) {
+        *byte = char;
+    }
+
+    result
+}
+
+/// The client of a remote peer, as told by its peer id
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientVersion {
+    pub name: String,
+    pub version: String,
+}
+
+impl ClientVersion {
+    /// Recognizes the Azureus-style and the Shadow-style peer ids. The unknown Azureus-style
+    /// clients are named by the client code. Returns `None` for the other peer ids.
+    pub fn from_peer_id(peer_id: &[u8; 20]) -> Option<Self> {
+        Self::azureus(peer_id).or_else(|| Self::shadow(peer_id))
+    }
+
+    /// `-` followed by two characters of the client code, four characters of the version and
+    /// another `-`
+    fn azureus(peer_id: &[u8; 20]) -> Option<Self> {
+        if peer_id[0] != b'-' || peer_id[7] != b'-' {
+            return None;
+        }
+
+        let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        let version = &peer_id[3..7];
+        if !code.bytes().all(|b| b.is_ascii_alphanumeric())
+            || !version.iter().all(|b| b.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| code.to_string());
+
+        // The trailing zeros are dropped, but the major and the minor versions are kept
+        let mut numbers: Vec<u32> = version
+            .iter()
+            .map(|b| (*b as char).to_digit(36).unwrap_or(0))
+            .collect();
+        while numbers.len() > 2 && numbers.last() == Some(&0) {
+            numbers.pop();
+        }
+
+        Some(ClientVersion {
+            name,
+            version: join_version(&numbers),
+        })
+    }
+
+    /// A character of the client, up to five characters of the version padded with `-`, and
+    /// `---`
+    fn shadow(peer_id: &[u8; 20]) -> Option<Self> {
+        let name = SHADOW_CLIENTS
+            .iter()
+            .find(|(known, _)| *known == peer_id[0])
+            .map(|(_, name)| name.to_string())?;
+        if &peer_id[6..9] != b"---" {
+            return None;
+        }
+
+        let version = peer_id[1..6].split(|b| *b == b'-').next()?;
+        if version.is_empty() || peer_id[1 + version.len()..6].iter().any(|b| *b != b'-') {
+            return None;
+        }
+
+        let numbers = version
+            .iter()
+            .map(|b| match b {
+                b'0'..=b'9' => Some((b - b'0') as u32),
+                b'A'..=b'Z' => Some((b - b'A') as u32 + 10),
+                b'a'..=b'z' => Some((b - b'a') as u32 + 36),
+                b'.' => Some(62),
+                _ => None,
+            })
+            .collect::<Option<Vec<u32>>>()?;
+
+        Some(ClientVersion {
+            name,
+            version: join_version(&numbers),
+        })
+    }
+}
+
+fn join_version(numbers: &[u32]) -> String {
+    numbers
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+impl Display for ClientVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(prefix: &[u8]) -> [u8; 20] {
+        let mut peer_id = [b'x'; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    #[test]
+    fn generates_azureus_style_id() {
+        let first = generate_peer_id(CLIENT_CODE);
+        let second = generate_peer_id(*b"XY");
+        assert_eq!(&first[..3], b"-TO");
+        assert_eq!(&second[..3], b"-XY");
+        assert_eq!(first[7], b'-');
+        assert!(first.iter().all(|b| b.is_ascii_graphic()));
+        assert_ne!(first[8..], second[8..]);
+
+        let version = format!(
+            "{}.{}.{}",
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH")
+        );
+        let client = ClientVersion::from_peer_id(&first).unwrap();
+        assert_eq!(client.name, "Torrentino");
+        assert!(version.starts_with(&client.version));
+    }
+
+    #[test]
+    fn parses_azureus_style_id() {
+        let client = ClientVersion::from_peer_id(&peer_id(b"-qB4250-")).unwrap();
+        assert_eq!(client.to_string(), "qBittorrent 4.2.5");
+
+        let client = ClientVersion::from_peer_id(&peer_id(b"-TR3000-")).unwrap();
+        assert_eq!(client.to_string(), "Transmission 3.0");
+
+        let client = ClientVersion::from_peer_id(&peer_id(b"-ZZ1A00-")).unwrap();
+        assert_eq!(client.to_string(), "ZZ 1.10");
+
+        assert_eq!(ClientVersion::from_peer_id(&peer_id(b"-qB4250x")), None);
+        assert_eq!(ClientVersion::from_peer_id(&peer_id(b"-q 4250-")), None);
+    }
+
+    #[test]
+    fn parses_shadow_style_id() {
+        let client = ClientVersion::from_peer_id(&peer_id(b"S58B-----")).unwrap();
+        assert_eq!(client.to_string(), "Shadow 5.8.11");
+
+        let client = ClientVersion::from_peer_id(&peer_id(b"T03I-----")).unwrap();
+        assert_eq!(client.to_string(), "BitTornado 0.3.18");
+
+        assert_eq!(ClientVersion::from_peer_id(&peer_id(b"S58B--x--")), None);
+        assert_eq!(ClientVersion::from_peer_id(&peer_id(b"S-58B----")), None);
+        assert_eq!(ClientVersion::from_peer_id(&[0u8; 20]), None);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::engine::EngineConfig;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The connection slots shared by all torrents of the engine
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    limit: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(limit: usize) -> Self {
+        ConnectionLimit {
+            limit,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a free slot. Returns false if all slots are in use.
+    pub fn try_acquire(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used < self.limit).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_sub(1)
+            });
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PeerState {
+    /// Ready for a connection attempt at the given moment
+    Idle {
+        retry_at: Instant,
+    },
+    Connecting,
+    Connected,
+    /// Failed too many times, never retried
+    GaveUp,
+}
+
+#[derive(Debug)]
+struct PeerEntry {
+    state: PeerState,
+    /// Failed connection attempts in a row
+    failures: u32,
+}
+
+/// Keeps track of the known peers of a torrent: which of them are connected, which failed and
+/// when the failed ones might be retried.
+#[derive(Debug)]
+pub struct PeerPool {
+    peers: HashMap<SocketAddr, PeerEntry>,
+    /// The order the peers were added in, so the candidates are picked deterministically
+    order: Vec<SocketAddr>,
+    retry_backoff: Duration,
+    max_retry_backoff: Duration,
+    max_attempts: u32,
+}
+
+impl PeerPool {
+    pub fn new(config: &EngineConfig) -> Self {
+        PeerPool {
+            peers: HashMap::new(),
+            order: vec![],
+            retry_backoff: config.retry_backoff,
+            max_retry_backoff: config.max_retry_backoff,
+            max_attempts: config.max_connect_attempts,
+        }
+    }
+
+    pub fn add(&mut self, addr: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&addr) {
+            self.order.push(addr);
+            self.peers.insert(
+                addr,
+                PeerEntry {
+                    state: PeerState::Idle { retry_at: now },
+                    failures: 0,
+                },
+            );
+        }
+    }
+
+    /// Picks the next peer ready for a connection attempt and marks it as connecting
+    pub fn next_candidate(&mut self, now: Instant) -> Option<SocketAddr> {
+        let addr = *self.order.iter().find(|addr| {
+            matches!(self.peers[*addr].state, PeerState::Idle { retry_at } if retry_at <= now)
+        })?;
+
+        self.peers.get_mut(&addr)?.state = PeerState::Connecting;
+        Some(addr)
+    }
+
+    /// Returns true if a peer is ready for a connection attempt
+    pub fn has_candidate(&self, now: Instant) -> bool {
+        self.peers
+            .values()
+            .any(|e| matches!(e.state, PeerState::Idle { retry_at } if retry_at <= now))
+    }
+
+    pub fn connected(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.state = PeerState::Connected;
+            entry.failures = 0;
+        }
+    }
+
+    /// The connection attempt failed or an established connection was closed. The peer is
+    /// retried later with an exponential backoff.
+    pub fn failed(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.failures += 1;
+            entry.state = if entry.failures >= self.max_attempts {
+                PeerState::GaveUp
+            } else {
+                let factor = 1u32 << (entry.failures - 1).min(16);
+                let backoff = self
+                    .retry_backoff
+                    .saturating_mul(factor)
+                    .min(self.max_retry_backoff);
+                PeerState::Idle {
+                    retry_at: now + backoff,
+                }
+            };
+        }
+    }
+
+    /// Never tries the peers with the IP again, e.g. the IP is banned
+    pub fn give_up(&mut self, ip: IpAddr) {
+        for (_, entry) in self.peers.iter_mut().filter(|(addr, _)| addr.ip() == ip) {
+            entry.state = PeerState::GaveUp;
+        }
+    }
+
+    /// The number of peers we're connecting or connected to
+    pub fn active(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|e| matches!(e.state, PeerState::Connecting | PeerState::Connected))
+            .count()
+    }
+
+    /// The peers worth trying again later, e.g. after a restart
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.order
+            .iter()
+            .filter(|addr| self.peers[*addr].state != PeerState::GaveUp)
+            .copied()
+            .collect()
+    }
+
+    /// Returns true if there are no connected peers and no peers left to try
+    pub fn is_exhausted(&self) -> bool {
+        self.peers.values().all(|e| e.state == PeerState::GaveUp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn pool() -> PeerPool {
+        PeerPool::new(&EngineConfig {
+            retry_backoff: Duration::from_secs(1),
+            max_retry_backoff: Duration::from_secs(3),
+            max_connect_attempts: 4,
+            ..EngineConfig::default()
+        })
+    }
+
+    #[test]
+    fn failed_peers_are_retried_with_backoff() {
+        let now = Instant::now();
+        let mut pool = pool();
+        pool.add(peer(1), now);
+
+        assert_eq!(pool.next_candidate(now), Some(peer(1)));
+        assert_eq!(pool.next_candidate(now), None);
+
+        pool.failed(&peer(1), now);
+        assert_eq!(pool.next_candidate(now), None);
+        assert_eq!(
+            pool.next_candidate(now + Duration::from_secs(1)),
+            Some(peer(1))
+        );
+
+        // The second failure doubles the delay
+        pool.failed(&peer(1), now);
+        assert_eq!(pool.next_candidate(now + Duration::from_secs(1)), None);
+        assert_eq!(
+            pool.next_candidate(now + Duration::from_secs(2)),
+            Some(peer(1))
+        );
+
+        // The delay is capped
+        pool.failed(&peer(1), now);
+        assert_eq!(
+            pool.next_candidate(now + Duration::from_secs(3)),
+            Some(peer(1))
+        );
+
+        pool.failed(&peer(1), now);
+        assert!(pool.is_exhausted());
+    }
+
+    #[test]
+    fn successful_connection_resets_failures() {
+        let now = Instant::now();
+        let mut pool = pool();
+        pool.add(peer(1), now);
+        pool.add(peer(2), now);
+
+        pool.next_candidate(now);
+        pool.failed(&peer(1), now);
+        assert!(pool.has_candidate(now));
+        assert_eq!(pool.next_candidate(now), Some(peer(2)));
+        assert!(!pool.has_candidate(now));
+        pool.connected(&peer(2));
+        assert_eq!(pool.active(), 1);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(pool.next_candidate(later), Some(peer(1)));
+        pool.connected(&peer(1));
+        pool.failed(&peer(1), later);
+        assert_eq!(
+            pool.next_candidate(later + Duration::from_secs(1)),
+            Some(peer(1))
+        );
+    }
+
+    #[test]
+    fn connection_limit_is_shared() {
+        let limit = ConnectionLimit::new(2);
+        let other = limit.clone();
+
+        assert!(limit.try_acquire());
+        assert!(other.try_acquire());
+        assert!(!limit.try_acquire());
+
+        other.release();
+        assert_eq!(limit.used(), 1);
+        assert!(limit.try_acquire());
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+/// What a peer has shown us so far. The score ranks the peers when a connection has to be
+/// dropped to make room for a better candidate.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PeerQuality {
+    /// Bytes per second we receive from the peer
+    pub download_rate: u64,
+    /// The average time between a request and the block, `None` until the first block
+    pub latency: Option<Duration>,
+    /// The number of pieces failing the hash check the peer has sent blocks of
+    pub hash_failures: u32,
+    /// The peer unchokes us, but doesn't send the requested blocks
+    pub snubbed: bool,
+}
+
+impl PeerQuality {
+    /// The latency is averaged, so a single slow block doesn't ruin it
+    pub fn record_latency(&mut self, sample: Duration) {
+        self.latency = Some(match self.latency {
+            Some(average) => (average * 7 + sample * 3) / 10,
+            None => sample,
+        });
+    }
+
+    /// The higher the better. It's the download rate in KiB/s, divided by the latency in
+    /// seconds plus one and halved for every failed piece. A snubbing peer scores zero.
+    pub fn score(&self) -> f64 {
+        if self.snubbed {
+            return 0.0;
+        }
+
+        let throughput = self.download_rate as f64 / 1024.0 + 1.0;
+        let latency = self.latency.unwrap_or_default().as_secs_f64() + 1.0;
+        throughput / latency / 2f64.powi(self.hash_failures.min(64) as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quality(download_rate: u64, latency_ms: u64, hash_failures: u32) -> PeerQuality {
+        PeerQuality {
+            download_rate,
+            latency: Some(Duration::from_millis(latency_ms)),
+            hash_failures,
+            snubbed: false,
+        }
+    }
+
+    #[test]
+    fn ranks_peers_by_throughput_latency_and_failures() {
+        let fast = quality(1024 * 1024, 100, 0);
+        let slow = quality(64 * 1024, 100, 0);
+        let laggy = quality(1024 * 1024, 5000, 0);
+        let corrupt = quality(1024 * 1024, 100, 5);
+
+        assert!(fast.score() > slow.score());
+        assert!(fast.score() > laggy.score());
+        assert!(slow.score() > corrupt.score());
+        assert_eq!(corrupt.score() * 32.0, fast.score());
+
+        let snubbed = PeerQuality {
+            snubbed: true,
+            ..fast
+        };
+        assert!(snubbed.score() < PeerQuality::default().score());
+    }
+
+    #[test]
+    fn averages_latency() {
+        let mut quality = PeerQuality::default();
+        quality.record_latency(Duration::from_millis(100));
+        assert_eq!(quality.latency, Some(Duration::from_millis(100)));
+
+        quality.record_latency(Duration::from_millis(1100));
+        assert_eq!(quality.latency, Some(Duration::from_millis(400)));
+    }
+}
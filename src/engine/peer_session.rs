@@ -0,0 +1,244 @@
+use crate::engine::piece_picker::BlockInfo;
+use crate::engine::rate_limit::{charged_bytes, LimitChain};
+use crate::engine::torrent_session::SessionEvent;
+use crate::protocol::entities::{HandshakeRequest, MessageType};
+use crate::protocol::net::{connect, EncryptionPolicy, PeerConnection, Transport};
+use futures::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Peers that send nothing (not even keep-alive) for this long are dropped
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// We send keep-alive if we haven't sent anything for this long
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// How often the timeouts are checked
+const TIMER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A transfer held back by the rate limits is checked this often, so the changed limits apply
+/// soon enough
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A single peer connection, driven by its own task. The session doesn't make any decisions on
+/// its own: the received messages are forwarded to the torrent coordinator, and the messages
+/// produced by the coordinator are sent to the peer.
+pub(crate) struct PeerSession {
+    pub addr: SocketAddr,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub connect_timeout: Duration,
+    pub encryption: EncryptionPolicy,
+    pub transport: Transport,
+    /// The connection accepted by the listener with the peer's handshake. The session connects
+    /// to the peer if not set.
+    pub connection: Option<(PeerConnection, HandshakeRequest)>,
+    /// The rate limits of the received messages and the sent blocks
+    pub download: LimitChain,
+    pub upload: LimitChain,
+    /// The limits count the whole messages, not only the blocks
+    pub limit_overhead: bool,
+}
+
+impl PeerSession {
+    /// Connects to the peer, unless it's an incoming connection, and exchanges messages until
+    /// the connection is closed or the session is cancelled. The coordinator is always notified
+    /// when the session ends.
+    pub async fn run(
+        mut self,
+        commands: mpsc::UnboundedReceiver<MessageType>,
+        events: mpsc::Sender<SessionEvent>,
+        token: CancellationToken,
+    ) {
+        let result = tokio::select! {
+            _ = token.cancelled() => Ok(()),
+            result = self.exchange(commands, &events) => result,
+        };
+
+        let _ = events.send(SessionEvent::Closed(self.addr, result)).await;
+    }
+
+    /// *Protocol overview*
+    /// Once a tcp connection is established the messages you send and receive have to follow the
+    /// following protocol.
+    ///
+    /// The first thing you want to do is let your peer know which files you are interested
+    /// in downloading from them, as well as some identifying info. If the peer doesn’t have the
+    /// files you want they will close the connection, but if they do have the files they should
+    /// send back a similar message as confirmation. This is called the “handshake”.
+    ///
+    /// The most likely thing that will happen next is that the peer will let you know what
+    /// pieces they have. This happens through the “have” and “bitfield” messages. Each “have”
+    /// message contains a piece index as its payload. This means you will receive multiple
+    /// have messages, one for each piece that your peer has.
+    ///
+    /// The bitfield message serves a similar purpose, but does it in a different way. The
+    /// bitfield message can tell you all the pieces that the peer has in just one message. It does
+    /// this by sending a string of bits, one for each piece in the file. The index of each bit is the
+    /// same as the piece index, and if they have that piece it will be set to 1, if not it will be set to 0.
+    /// For example if you receive a bitfield that starts with 011001… that means they have the pieces at
+    /// index 1, 2, and 5, but not the pieces at index 0, 3,and 4.
+    ///
+    /// It’s possible to receive both “have” messages and a bitfield message, if which case you
+    /// should combine them to get the full list of pieces.
+    ///
+    /// Actually it’s possible to recieve another kind of message, the peer might decide they
+    /// don’t want to share with you! That’s what the choke, unchoke, interested, and not interested
+    /// messages are for. If you are choked, that means the peer does not want to share with you, if
+    /// you are unchoked then the peer is willing to share. On the other hand, interested means you want
+    /// what your peer has, whereas not interested means you don’t want what they have.
+    ///
+    /// You always start out choked and not interested. So the first message you send should be
+    /// the interested message. Then hopefully they will send you an unchoke message and you can move
+    /// to the next step. If you receive a choke message message instead you can just let the connection drop.
+    ///
+    /// Finally you will receive a piece message, which will contain the bytes of data that you
+    /// requested.
+    async fn exchange(
+        &mut self,
+        mut commands: mpsc::UnboundedReceiver<MessageType>,
+        events: &mpsc::Sender<SessionEvent>,
+    ) -> Result<(), String> {
+        let (mut connection, remote) = match self.connection.take() {
+            Some(connection) => connection,
+            None => {
+                connect(
+                    self.addr,
+                    self.info_hash,
+                    self.peer_id,
+                    self.encryption,
+                    &self.transport,
+                    self.connect_timeout,
+                )
+                .await?
+            }
+        };
+
+        let fast = remote.supports_fast_extension();
+        events
+            .send(SessionEvent::Connected(self.addr, remote))
+            .await
+            .map_err(|_| "Torrent session is closed".to_string())?;
+
+        let mut timer = interval(TIMER_INTERVAL);
+        let mut last_received = Instant::now();
+        let mut last_sent = Instant::now();
+        // The blocks held back by the upload limits. The other messages are sent right away,
+        // so the requests and the keep-alives don't wait for the blocks. The blocks are dropped
+        // along with their requests: on the choke, the reject or the peer's cancel.
+        let mut blocks: VecDeque<MessageType> = VecDeque::new();
+
+        loop {
+            while let Some(block) = blocks.pop_front() {
+                if !self.upload.wait_time(Instant::now().into_std()).is_zero() {
+                    blocks.push_front(block);
+                    break;
+                }
+                last_sent = Instant::now();
+                let sent = block_info(&block);
+                self.send(&mut connection, block).await?;
+                if let Some(block) = sent {
+                    events
+                        .send(SessionEvent::BlockSent(self.addr, block))
+                        .await
+                        .map_err(|_| "Torrent session is closed".to_string())?;
+                }
+            }
+
+            // The messages are not read until the rate limits allow. The peer stops sending,
+            // once the unread data fills the socket buffers.
+            let now = Instant::now().into_std();
+            let upload_wait = match blocks.is_empty() {
+                true => Duration::ZERO,
+                false => self.upload.wait_time(now),
+            };
+            let download_wait = self.download.wait_time(now);
+            let limited = upload_wait.max(download_wait);
+
+            tokio::select! {
+                _ = sleep(limited.min(RATE_LIMIT_INTERVAL)), if !limited.is_zero() => {}
+
+                command = commands.recv() => match command {
+                    Some(block @ MessageType::Piece(..)) => blocks.push_back(block),
+                    Some(message) => {
+                        match &message {
+                            // A fast peer is sent a reject for every block it loses
+                            MessageType::Choke if !fast => blocks.clear(),
+                            MessageType::RejectRequest(piece, offset, length) => {
+                                drop_block(&mut blocks, *piece, *offset, *length);
+                            }
+                            _ => {}
+                        }
+                        last_sent = Instant::now();
+                        self.send(&mut connection, message).await?;
+                    }
+                    None => return Ok(()),
+                },
+
+                message = connection.next(), if download_wait.is_zero() => match message {
+                    Some(Ok(message)) => {
+                        last_received = Instant::now();
+                        if let MessageType::Cancel(piece, offset, length) = message {
+                            drop_block(&mut blocks, piece, offset, length);
+                        }
+                        self.download.take(charged_bytes(&message, self.limit_overhead), now);
+                        events
+                            .send(SessionEvent::Message(self.addr, message))
+                            .await
+                            .map_err(|_| "Torrent session is closed".to_string())?;
+                    }
+                    Some(Err(e)) => {
+                        return Err(format!("Unable to read from the peer {}: {}", self.addr, e))
+                    }
+                    None => return Err(format!("Connection closed by the peer {}", self.addr)),
+                },
+
+                _ = timer.tick() => {
+                    // Nothing is received while the download is held back
+                    if download_wait.is_zero() && last_received.elapsed() > PEER_IDLE_TIMEOUT {
+                        return Err(format!("Peer {} is idle for too long", self.addr));
+                    }
+
+                    if last_sent.elapsed() > KEEP_ALIVE_INTERVAL {
+                        last_sent = Instant::now();
+                        self.send(&mut connection, MessageType::KeepAlive).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the message, counting it against the upload limits
+    async fn send(
+        &self,
+        connection: &mut PeerConnection,
+        message: MessageType,
+    ) -> Result<(), String> {
+        let bytes = charged_bytes(&message, self.limit_overhead);
+        self.upload.take(bytes, Instant::now().into_std());
+        connection
+            .send(message)
+            .await
+            .map_err(|e| format!("Unable send message to the peer {}: {}", self.addr, e))
+    }
+}
+
+/// The block of the piece message
+fn block_info(message: &MessageType) -> Option<BlockInfo> {
+    match message {
+        MessageType::Piece(piece, offset, data) => {
+            Some(BlockInfo::new(*piece, *offset, data.len() as u32))
+        }
+        _ => None,
+    }
+}
+
+/// Drops the block held back by the upload limits
+fn drop_block(blocks: &mut VecDeque<MessageType>, piece: u32, offset: u32, length: u32) {
+    let block = BlockInfo::new(piece, offset, length);
+    blocks.retain(|message| block_info(message) != Some(block));
+}
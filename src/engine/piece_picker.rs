@@ -0,0 +1,768 @@
+use crate::engine::Priority;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// The de facto standard size of a block requested from a peer. Most of the clients drop the
+/// connection if a bigger block is requested.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// A single block request: the piece index, the offset within the piece and the block length.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct BlockInfo {
+    pub piece: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl BlockInfo {
+    pub fn new(piece: u32, offset: u32, length: u32) -> Self {
+        BlockInfo {
+            piece,
+            offset,
+            length,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum BlockState {
+    Missing,
+    /// The block is requested from the listed peers. Outside the end-game mode there is only
+    /// one peer in the list.
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+#[derive(Debug)]
+struct Piece {
+    length: u32,
+    have: bool,
+    priority: Priority,
+    /// The number of connected peers having this piece
+    availability: u32,
+    /// Empty until the first block of the piece is picked
+    blocks: Vec<BlockState>,
+    /// All the blocks have to come from a single peer, see
+    /// [PiecePicker::download_from_single_peer]
+    single_peer: bool,
+    /// The peer the single peer piece is downloaded from
+    owner: Option<SocketAddr>,
+}
+
+impl Piece {
+    fn blocks_count(&self) -> usize {
+        self.length.div_ceil(BLOCK_SIZE) as usize
+    }
+
+    fn block(&self, piece: u32, block: usize) -> BlockInfo {
+        let offset = block as u32 * BLOCK_SIZE;
+        BlockInfo::new(piece, offset, BLOCK_SIZE.min(self.length - offset))
+    }
+
+    fn is_wanted(&self) -> bool {
+        !self.have && self.priority != Priority::Skip
+    }
+
+    fn has_missing_blocks(&self) -> bool {
+        self.is_wanted() && (self.blocks.is_empty() || self.blocks.contains(&BlockState::Missing))
+    }
+
+    fn is_complete(&self) -> bool {
+        !self.blocks.is_empty() && self.blocks.iter().all(|b| *b == BlockState::Received)
+    }
+
+    /// The single peer pieces are picked by their owner only
+    fn is_available_to(&self, peer: &SocketAddr) -> bool {
+        !self.single_peer || self.owner.is_none_or(|owner| owner == *peer)
+    }
+
+    /// Drops the blocks of the single peer piece, once its owner has no more requests. The
+    /// piece starts over with another peer then.
+    fn release_owner(&mut self) {
+        let requested = self
+            .blocks
+            .iter()
+            .any(|b| matches!(b, BlockState::Requested(_)));
+        if self.single_peer && self.owner.is_some() && !requested && !self.is_complete() {
+            self.owner = None;
+            self.blocks.clear();
+        }
+    }
+}
+
+/// The result of the [PiecePicker::block_received] call
+#[derive(Debug, Eq, PartialEq)]
+pub enum BlockOutcome {
+    /// The first copy of the block has been received. In the end-game mode the same block might
+    /// be requested from the other peers as well, these peers should receive the `Cancel`
+    /// message.
+    Accepted {
+        cancel: Vec<SocketAddr>,
+        piece_complete: bool,
+    },
+    /// The block has already been received from another peer, or it isn't one of the blocks
+    /// of the piece, the bytes are wasted
+    Duplicate,
+}
+
+/// The piece picker decides which blocks should be requested from which peer.
+///
+/// Pieces are picked using the rarest first strategy: the pieces, which are owned by the
+/// smallest number of connected peers, are downloaded first. Pieces that are already in progress
+/// are always finished first, so we don't end up with many partially downloaded pieces.
+///
+/// *End-game mode*
+/// Once every remaining block has been requested, the picker switches to the end-game mode. In
+/// this mode the outstanding blocks are requested from every peer that has them, so a single slow
+/// peer doesn't hold the final pieces. When a block arrives, the other peers are sent the `Cancel`
+/// message for it.
+#[derive(Debug)]
+pub struct PiecePicker {
+    pieces: Vec<Piece>,
+    peers: HashMap<SocketAddr, Vec<bool>>,
+    end_game: bool,
+}
+
+impl PiecePicker {
+    pub fn new(piece_length: u64, total_length: u64) -> Self {
+        let pieces_count = total_length.div_ceil(piece_length) as usize;
+        let pieces = (0..pieces_count)
+            .map(|index| {
+                let offset = index as u64 * piece_length;
+                Piece {
+                    length: (total_length - offset).min(piece_length) as u32,
+                    have: false,
+                    priority: Priority::Normal,
+                    availability: 0,
+                    blocks: vec![],
+                    single_peer: false,
+                    owner: None,
+                }
+            })
+            .collect();
+
+        PiecePicker {
+            pieces,
+            peers: HashMap::new(),
+            end_game: false,
+        }
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn is_end_game(&self) -> bool {
+        self.end_game
+    }
+
+    /// Returns true if all the pieces we want are verified
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|p| !p.is_wanted())
+    }
+
+    pub fn have_piece(&self, index: u32) -> bool {
+        self.pieces
+            .get(index as usize)
+            .map(|p| p.have)
+            .unwrap_or(false)
+    }
+
+    /// The bitfield of the verified pieces
+    pub fn bitfield(&self) -> Vec<bool> {
+        self.pieces.iter().map(|p| p.have).collect()
+    }
+
+    /// Registers the bitfield received from the peer. The spare bits at the end of the bitfield
+    /// are ignored.
+    pub fn add_peer_bitfield(&mut self, peer: SocketAddr, bitfield: &[bool]) {
+        self.remove_peer_availability(&peer);
+
+        let mut pieces = vec![false; self.pieces.len()];
+        for (index, have) in bitfield.iter().take(self.pieces.len()).enumerate() {
+            if *have {
+                pieces[index] = true;
+                self.pieces[index].availability += 1;
+            }
+        }
+
+        self.peers.insert(peer, pieces);
+    }
+
+    /// Registers the `Have` message received from the peer
+    pub fn add_peer_piece(&mut self, peer: SocketAddr, index: u32) {
+        let pieces_count = self.pieces.len();
+        let index = index as usize;
+        if index >= pieces_count {
+            return;
+        }
+
+        let pieces = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| vec![false; pieces_count]);
+
+        if !pieces[index] {
+            pieces[index] = true;
+            self.pieces[index].availability += 1;
+        }
+    }
+
+    /// Forgets everything about the peer. All blocks requested from this peer become available
+    /// for picking again.
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.remove_peer_availability(peer);
+        self.peers.remove(peer);
+
+        for piece in self.pieces.iter_mut() {
+            for block in piece.blocks.iter_mut() {
+                release_block(block, peer);
+            }
+            if piece.owner == Some(*peer) {
+                piece.release_owner();
+            }
+        }
+    }
+
+    fn remove_peer_availability(&mut self, peer: &SocketAddr) {
+        if let Some(pieces) = self.peers.get(peer) {
+            for (index, have) in pieces.iter().enumerate() {
+                if *have {
+                    self.pieces[index].availability -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns true if the peer has at least one piece we don't have
+    pub fn is_interesting(&self, peer: &SocketAddr) -> bool {
+        self.peers
+            .get(peer)
+            .map(|pieces| {
+                pieces
+                    .iter()
+                    .zip(self.pieces.iter())
+                    .any(|(has, piece)| *has && piece.is_wanted())
+            })
+            .unwrap_or(false)
+    }
+
+    /// Picks up to `count` blocks to be requested from the peer and marks them as requested.
+    pub fn pick_blocks(&mut self, peer: SocketAddr, count: usize) -> Vec<BlockInfo> {
+        self.pick(peer, count, None)
+    }
+
+    /// Picks the blocks of the given pieces only, e.g. the ones a choking peer allows to
+    /// request with the fast extension
+    pub fn pick_blocks_from(
+        &mut self,
+        peer: SocketAddr,
+        count: usize,
+        pieces: &HashSet<u32>,
+    ) -> Vec<BlockInfo> {
+        self.pick(peer, count, Some(pieces))
+    }
+
+    fn pick(
+        &mut self,
+        peer: SocketAddr,
+        count: usize,
+        allowed: Option<&HashSet<u32>>,
+    ) -> Vec<BlockInfo> {
+        let mut result = Vec::with_capacity(count);
+        let is_allowed =
+            |index: usize| allowed.is_none_or(|pieces| pieces.contains(&(index as u32)));
+        let peer_pieces = match self.peers.get(&peer) {
+            Some(pieces) => pieces.clone(),
+            None => return result,
+        };
+
+        // The higher priority pieces go first, then the pieces in progress, then the rarest ones
+        let mut candidates: Vec<usize> = (0..self.pieces.len())
+            .filter(|i| peer_pieces[*i] && self.pieces[*i].has_missing_blocks())
+            .filter(|i| self.pieces[*i].is_available_to(&peer) && is_allowed(*i))
+            .collect();
+        candidates.sort_by_key(|i| {
+            (
+                Reverse(self.pieces[*i].priority),
+                self.pieces[*i].blocks.is_empty(),
+                self.pieces[*i].availability,
+            )
+        });
+
+        for index in candidates {
+            let piece = &mut self.pieces[index];
+            if piece.blocks.is_empty() {
+                piece.blocks = vec![BlockState::Missing; piece.blocks_count()];
+            }
+            if piece.single_peer {
+                piece.owner = Some(peer);
+            }
+
+            for block in 0..piece.blocks.len() {
+                if result.len() == count {
+                    return result;
+                }
+
+                if piece.blocks[block] == BlockState::Missing {
+                    piece.blocks[block] = BlockState::Requested(vec![peer]);
+                    result.push(piece.block(index as u32, block));
+                }
+            }
+        }
+
+        if result.len() < count && !self.end_game {
+            self.end_game = !self.pieces.iter().any(|p| p.has_missing_blocks());
+        }
+
+        if self.end_game {
+            let peer_pieces: Vec<bool> = peer_pieces
+                .iter()
+                .enumerate()
+                .map(|(index, has)| *has && is_allowed(index))
+                .collect();
+            self.pick_end_game_blocks(peer, &peer_pieces, count, &mut result);
+        }
+
+        result
+    }
+
+    fn pick_end_game_blocks(
+        &mut self,
+        peer: SocketAddr,
+        peer_pieces: &[bool],
+        count: usize,
+        result: &mut Vec<BlockInfo>,
+    ) {
+        for (index, piece) in self.pieces.iter_mut().enumerate() {
+            if !piece.is_wanted() || !peer_pieces[index] || piece.single_peer {
+                continue;
+            }
+
+            for block in 0..piece.blocks.len() {
+                if result.len() == count {
+                    return;
+                }
+
+                let info = piece.block(index as u32, block);
+                match &mut piece.blocks[block] {
+                    BlockState::Requested(peers) if !peers.contains(&peer) => {
+                        peers.push(peer);
+                        result.push(info);
+                    }
+                    state @ BlockState::Missing => {
+                        *state = BlockState::Requested(vec![peer]);
+                        result.push(info);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Returns the request back to the picker, e.g. when the peer chokes us or rejects it
+    pub fn cancel_request(&mut self, peer: &SocketAddr, block: &BlockInfo) {
+        if let Some(state) = self.block_state_mut(block) {
+            release_block(state, peer);
+            self.pieces[block.piece as usize].release_owner();
+        }
+    }
+
+    /// Marks the block as received from the peer
+    pub fn block_received(&mut self, peer: &SocketAddr, block: &BlockInfo) -> BlockOutcome {
+        let piece_index = block.piece as usize;
+        let state = match self.block_state_mut(block) {
+            Some(state) => state,
+            None => return BlockOutcome::Duplicate,
+        };
+
+        let cancel = match state {
+            BlockState::Received => return BlockOutcome::Duplicate,
+            BlockState::Requested(peers) => peers.iter().filter(|p| *p != peer).copied().collect(),
+            BlockState::Missing => vec![],
+        };
+        *state = BlockState::Received;
+
+        BlockOutcome::Accepted {
+            cancel,
+            piece_complete: self.pieces[piece_index].is_complete(),
+        }
+    }
+
+    /// Marks the piece as successfully downloaded and verified
+    pub fn piece_verified(&mut self, index: u32) {
+        if let Some(piece) = self.pieces.get_mut(index as usize) {
+            piece.have = true;
+            piece.blocks.clear();
+            piece.single_peer = false;
+            piece.owner = None;
+        }
+    }
+
+    /// The piece didn't pass the hash check, so it should be downloaded once again. Its blocks
+    /// are missing again, so the end-game mode is over until they are requested.
+    pub fn piece_failed(&mut self, index: u32) {
+        if let Some(piece) = self.pieces.get_mut(index as usize) {
+            piece.blocks.clear();
+            piece.owner = None;
+        }
+        self.end_game = false;
+    }
+
+    /// Makes the piece downloaded from a single peer, so the peer is to blame if the piece
+    /// fails again. The first peer picking a block of the piece gets all of it, unless it
+    /// disconnects or takes back all the requests. The end-game mode doesn't apply.
+    pub fn download_from_single_peer(&mut self, index: u32) {
+        if let Some(piece) = self.pieces.get_mut(index as usize) {
+            piece.single_peer = true;
+        }
+        self.end_game = false;
+    }
+
+    /// Sets the priorities of the pieces. The pieces with the [Priority::Skip] priority are never
+    /// picked, and the torrent is complete without them.
+    pub fn set_priorities(&mut self, priorities: &[Priority]) {
+        for (piece, priority) in self.pieces.iter_mut().zip(priorities) {
+            piece.priority = *priority;
+        }
+        self.end_game = false;
+    }
+
+    /// The pieces being downloaded, with the flags of the blocks received so far
+    pub fn partial_pieces(&self) -> Vec<(u32, Vec<bool>)> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| !piece.have && piece.blocks.contains(&BlockState::Received))
+            .map(|(index, piece)| {
+                let blocks = piece
+                    .blocks
+                    .iter()
+                    .map(|b| *b == BlockState::Received)
+                    .collect();
+                (index as u32, blocks)
+            })
+            .collect()
+    }
+
+    /// Restores the state saved with [PiecePicker::bitfield] and [PiecePicker::partial_pieces].
+    /// Returns the pieces having all the blocks received, they still need the hash check.
+    pub fn restore(&mut self, bitfield: &[bool], partial: &[(u32, Vec<bool>)]) -> Vec<u32> {
+        for (piece, have) in self.pieces.iter_mut().zip(bitfield) {
+            piece.have = *have;
+            piece.blocks.clear();
+        }
+
+        let mut complete = vec![];
+        for (index, received) in partial {
+            let piece = match self.pieces.get_mut(*index as usize) {
+                Some(piece) if !piece.have && piece.blocks_count() == received.len() => piece,
+                _ => continue,
+            };
+
+            piece.blocks = received
+                .iter()
+                .map(|r| {
+                    if *r {
+                        BlockState::Received
+                    } else {
+                        BlockState::Missing
+                    }
+                })
+                .collect();
+
+            if piece.is_complete() {
+                complete.push(*index);
+            }
+        }
+
+        complete
+    }
+
+    /// Whether the block is one of those the piece is split into: it starts at a block
+    /// boundary, and it's as long as the block there
+    pub fn is_valid_block(&self, block: &BlockInfo) -> bool {
+        match self.pieces.get(block.piece as usize) {
+            Some(piece) => {
+                block.offset < piece.length
+                    && block.offset.is_multiple_of(BLOCK_SIZE)
+                    && piece.block(block.piece, (block.offset / BLOCK_SIZE) as usize) == *block
+            }
+            None => false,
+        }
+    }
+
+    fn block_state_mut(&mut self, block: &BlockInfo) -> Option<&mut BlockState> {
+        if !self.is_valid_block(block) {
+            return None;
+        }
+
+        let piece = self.pieces.get_mut(block.piece as usize)?;
+        if piece.have {
+            return None;
+        }
+
+        piece.blocks.get_mut((block.offset / BLOCK_SIZE) as usize)
+    }
+}
+
+fn release_block(state: &mut BlockState, peer: &SocketAddr) {
+    if let BlockState::Requested(peers) = state {
+        peers.retain(|p| p != peer);
+        if peers.is_empty() {
+            *state = BlockState::Missing;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn picks_rarest_pieces_first() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64 * 3);
+        picker.add_peer_bitfield(peer(1), &[true, true, true]);
+        picker.add_peer_bitfield(peer(2), &[true, false, true]);
+
+        let blocks = picker.pick_blocks(peer(1), 1);
+        assert_eq!(blocks, vec![BlockInfo::new(1, 0, BLOCK_SIZE)]);
+    }
+
+    #[test]
+    fn last_block_is_shorter() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64 * 2, BLOCK_SIZE as u64 + 10);
+        picker.add_peer_bitfield(peer(1), &[true]);
+
+        let blocks = picker.pick_blocks(peer(1), 10);
+        assert_eq!(
+            blocks,
+            vec![
+                BlockInfo::new(0, 0, BLOCK_SIZE),
+                BlockInfo::new(0, BLOCK_SIZE, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn end_game_requests_blocks_from_every_peer() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64 * 2, BLOCK_SIZE as u64 * 2);
+        picker.add_peer_bitfield(peer(1), &[true]);
+        picker.add_peer_bitfield(peer(2), &[true]);
+
+        let mut first = picker.pick_blocks(peer(1), 1);
+        first.extend(picker.pick_blocks(peer(1), 1));
+        assert_eq!(first.len(), 2);
+        assert!(!picker.is_end_game());
+
+        let second = picker.pick_blocks(peer(2), 10);
+        assert!(picker.is_end_game());
+        assert_eq!(first, second);
+
+        // Nothing left to duplicate for the same peer
+        assert!(picker.pick_blocks(peer(2), 10).is_empty());
+    }
+
+    #[test]
+    fn received_block_cancels_duplicates() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64);
+        picker.add_peer_bitfield(peer(1), &[true]);
+        picker.add_peer_bitfield(peer(2), &[true]);
+        picker.add_peer_bitfield(peer(3), &[true]);
+
+        let block = picker.pick_blocks(peer(1), 1)[0];
+        picker.pick_blocks(peer(2), 1);
+        picker.pick_blocks(peer(3), 1);
+
+        assert_eq!(
+            picker.block_received(&peer(2), &block),
+            BlockOutcome::Accepted {
+                cancel: vec![peer(1), peer(3)],
+                piece_complete: true
+            }
+        );
+        assert_eq!(
+            picker.block_received(&peer(1), &block),
+            BlockOutcome::Duplicate
+        );
+    }
+
+    #[test]
+    fn truncated_block_is_not_received() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64 * 2, BLOCK_SIZE as u64 + 10);
+        picker.add_peer_bitfield(peer(1), &[true]);
+        let blocks = picker.pick_blocks(peer(1), 2);
+
+        let short = BlockInfo::new(0, 0, BLOCK_SIZE - 1);
+        let long = BlockInfo::new(0, BLOCK_SIZE, 11);
+        assert!(!picker.is_valid_block(&short));
+        assert!(!picker.is_valid_block(&long));
+        assert!(!picker.is_valid_block(&BlockInfo::new(0, 10, BLOCK_SIZE)));
+        assert!(!picker.is_valid_block(&BlockInfo::new(1, 0, BLOCK_SIZE)));
+        assert_eq!(
+            picker.block_received(&peer(1), &short),
+            BlockOutcome::Duplicate
+        );
+        assert_eq!(
+            picker.block_received(&peer(1), &long),
+            BlockOutcome::Duplicate
+        );
+
+        assert!(blocks.iter().all(|block| picker.is_valid_block(block)));
+        picker.block_received(&peer(1), &blocks[0]);
+        assert_eq!(
+            picker.block_received(&peer(1), &blocks[1]),
+            BlockOutcome::Accepted {
+                cancel: vec![],
+                piece_complete: true
+            }
+        );
+    }
+
+    #[test]
+    fn failed_piece_is_picked_again() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64);
+        picker.add_peer_bitfield(peer(1), &[true]);
+
+        let block = picker.pick_blocks(peer(1), 1)[0];
+        picker.block_received(&peer(1), &block);
+        picker.piece_failed(0);
+
+        assert_eq!(picker.pick_blocks(peer(1), 1), vec![block]);
+        picker.block_received(&peer(1), &block);
+        picker.piece_verified(0);
+
+        assert!(picker.is_complete());
+        assert!(!picker.is_interesting(&peer(1)));
+    }
+
+    #[test]
+    fn failed_piece_ends_end_game() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64);
+        picker.add_peer_bitfield(peer(1), &[true]);
+        picker.add_peer_bitfield(peer(2), &[true]);
+
+        let block = picker.pick_blocks(peer(1), 1)[0];
+        assert_eq!(picker.pick_blocks(peer(2), 1), vec![block]);
+        assert!(picker.is_end_game());
+
+        picker.block_received(&peer(1), &block);
+        picker.piece_failed(0);
+        assert!(!picker.is_end_game());
+        assert_eq!(picker.pick_blocks(peer(1), 1), vec![block]);
+        assert!(!picker.is_end_game());
+
+        picker.download_from_single_peer(0);
+        assert!(!picker.is_end_game());
+        assert!(picker.pick_blocks(peer(2), 1).is_empty());
+    }
+
+    #[test]
+    fn restores_partial_pieces() {
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 6 * BLOCK_SIZE as u64);
+        let complete = picker.restore(
+            &[true, false, false],
+            &[(1, vec![true, false]), (2, vec![true, true])],
+        );
+
+        assert_eq!(complete, vec![2]);
+        assert!(picker.have_piece(0));
+        assert_eq!(
+            picker.partial_pieces(),
+            vec![(1, vec![true, false]), (2, vec![true, true])]
+        );
+
+        picker.add_peer_bitfield(peer(1), &[true, true, true]);
+        assert_eq!(
+            picker.pick_blocks(peer(1), 5),
+            vec![BlockInfo::new(1, BLOCK_SIZE, BLOCK_SIZE)]
+        );
+    }
+
+    #[test]
+    fn skipped_pieces_are_not_picked() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 3 * BLOCK_SIZE as u64);
+        picker.set_priorities(&[Priority::Skip, Priority::Normal, Priority::Skip]);
+        picker.add_peer_bitfield(peer(1), &[true, false, true]);
+        assert!(!picker.is_interesting(&peer(1)));
+
+        picker.add_peer_piece(peer(1), 1);
+        assert_eq!(
+            picker.pick_blocks(peer(1), 5),
+            vec![BlockInfo::new(1, 0, BLOCK_SIZE)]
+        );
+
+        picker.block_received(&peer(1), &BlockInfo::new(1, 0, BLOCK_SIZE));
+        picker.piece_verified(1);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn higher_priority_pieces_go_first() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 3 * BLOCK_SIZE as u64);
+        picker.set_priorities(&[Priority::Low, Priority::Normal, Priority::High]);
+        picker.add_peer_bitfield(peer(1), &[true, true, true]);
+        // The rarest piece still loses to the higher priority ones
+        picker.add_peer_bitfield(peer(2), &[false, true, true]);
+
+        let picked: Vec<u32> = picker
+            .pick_blocks(peer(1), 3)
+            .iter()
+            .map(|b| b.piece)
+            .collect();
+        assert_eq!(picked, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn removed_peer_releases_requests() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64);
+        picker.add_peer_bitfield(peer(1), &[true]);
+        picker.add_peer_bitfield(peer(2), &[true]);
+
+        let block = picker.pick_blocks(peer(1), 1)[0];
+        picker.remove_peer(&peer(1));
+
+        assert!(!picker.is_end_game());
+        assert_eq!(picker.pick_blocks(peer(2), 1), vec![block]);
+    }
+
+    #[test]
+    fn single_peer_piece_belongs_to_its_owner() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64 * 2, BLOCK_SIZE as u64 * 2);
+        picker.add_peer_bitfield(peer(1), &[true]);
+        picker.add_peer_bitfield(peer(2), &[true]);
+        picker.download_from_single_peer(0);
+
+        let first = picker.pick_blocks(peer(1), 1)[0];
+        picker.block_received(&peer(1), &first);
+        assert!(picker.pick_blocks(peer(2), 2).is_empty());
+        assert!(!picker.is_end_game());
+
+        // The owner takes back its request, so the piece starts over with another peer
+        let second = picker.pick_blocks(peer(1), 1)[0];
+        picker.cancel_request(&peer(1), &second);
+        assert_eq!(picker.pick_blocks(peer(2), 2), vec![first, second]);
+        assert!(picker.pick_blocks(peer(1), 2).is_empty());
+
+        picker.remove_peer(&peer(2));
+        assert_eq!(picker.pick_blocks(peer(1), 2).len(), 2);
+    }
+
+    #[test]
+    fn picks_allowed_fast_pieces_only() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64 * 4);
+        picker.add_peer_bitfield(peer(1), &[true, true, false, true]);
+
+        let allowed = HashSet::from([2, 3]);
+        assert_eq!(
+            picker.pick_blocks_from(peer(1), 4, &allowed),
+            vec![BlockInfo::new(3, 0, BLOCK_SIZE)]
+        );
+        assert_eq!(picker.pick_blocks(peer(1), 4).len(), 2);
+    }
+}
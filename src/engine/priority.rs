@@ -0,0 +1,150 @@
+use crate::storage::StorageLayout;
+use regex::Regex;
+
+/// The download priority of a file or a piece. The pieces of the higher priority are always
+/// picked first, no matter how rare they are.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Priority {
+    /// Not downloaded at all
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl From<Priority> for u8 {
+    fn from(priority: Priority) -> Self {
+        priority as u8
+    }
+}
+
+impl TryFrom<u8> for Priority {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Priority::Skip),
+            1 => Ok(Priority::Low),
+            2 => Ok(Priority::Normal),
+            3 => Ok(Priority::High),
+            _ => Err(format!("Unknown priority {}", value)),
+        }
+    }
+}
+
+/// Applies the `--select` and `--exclude` regular expressions to the torrent files. The
+/// expressions are matched against the file path within the output folder, like
+/// `Some.Show/Season 1/E01.mkv`. If `select` is given, only the matching files are
+/// downloaded, and the files matching `exclude` are never downloaded.
+pub fn select_files(
+    layout: &StorageLayout,
+    select: Option<&str>,
+    exclude: Option<&str>,
+) -> Result<Vec<Priority>, String> {
+    let compile = |pattern: Option<&str>| {
+        pattern
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("Invalid file filter: {}", e))
+    };
+    let select = compile(select)?;
+    let exclude = compile(exclude)?;
+
+    Ok(layout
+        .files
+        .iter()
+        .map(|file| {
+            let path: Vec<String> = file
+                .path
+                .iter()
+                .map(|c| c.to_string_lossy().to_string())
+                .collect();
+            let path = path.join("/");
+
+            let selected = select.as_ref().map(|r| r.is_match(&path)).unwrap_or(true);
+            let excluded = exclude.as_ref().map(|r| r.is_match(&path)).unwrap_or(false);
+            if selected && !excluded {
+                Priority::Normal
+            } else {
+                Priority::Skip
+            }
+        })
+        .collect())
+}
+
+/// A piece gets the highest priority of the files it belongs to
+pub fn piece_priorities(layout: &StorageLayout, files: &[Priority]) -> Vec<Priority> {
+    let mut pieces = vec![Priority::Skip; layout.pieces_count()];
+    for (file, priority) in files.iter().enumerate().take(layout.files.len()) {
+        let (first, last) = layout.file_pieces(file);
+        for piece in &mut pieces[first as usize..last as usize] {
+            *piece = (*piece).max(*priority);
+        }
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn layout() -> StorageLayout {
+        StorageLayout::new(
+            vec![
+                (PathBuf::from("show/e01.mkv"), 10),
+                (PathBuf::from("show/e01.srt"), 4),
+                (PathBuf::from("show/sample/e01.mkv"), 10),
+            ],
+            8,
+        )
+    }
+
+    #[test]
+    fn selects_and_excludes_files() {
+        let layout = layout();
+        use Priority::*;
+
+        assert_eq!(
+            select_files(&layout, None, None).unwrap(),
+            vec![Normal, Normal, Normal]
+        );
+        assert_eq!(
+            select_files(&layout, Some(r"\.mkv$"), Some("sample/")).unwrap(),
+            vec![Normal, Skip, Skip]
+        );
+        assert_eq!(
+            select_files(&layout, None, Some(r"\.srt$")).unwrap(),
+            vec![Normal, Skip, Normal]
+        );
+        assert!(select_files(&layout, Some("("), None).is_err());
+    }
+
+    #[test]
+    fn shared_pieces_take_highest_priority() {
+        use Priority::*;
+
+        assert_eq!(
+            piece_priorities(&layout(), &[Skip, Normal, Skip]),
+            vec![Skip, Normal, Skip]
+        );
+        assert_eq!(
+            piece_priorities(&layout(), &[High, Skip, Low]),
+            vec![High, High, Low]
+        );
+    }
+
+    #[test]
+    fn priority_roundtrip() {
+        for priority in [
+            Priority::Skip,
+            Priority::Low,
+            Priority::Normal,
+            Priority::High,
+        ] {
+            assert_eq!(Priority::try_from(u8::from(priority)), Ok(priority));
+        }
+        assert!(Priority::try_from(4).is_err());
+    }
+}
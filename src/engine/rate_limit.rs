@@ -0,0 +1,276 @@
+use crate::protocol::entities::MessageType;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The length prefix, the id, the index and the offset of a piece message
+const PIECE_HEADER: usize = 13;
+
+/// A token bucket: the tokens are added at the rate, one per byte, up to a second's worth of
+/// them. A transfer takes the tokens, even if there are not enough of them, and the next
+/// transfer waits until the debt is paid off. So a message is never split, while the average
+/// rate stays within the limit.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second, zero is unlimited
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = self.updated.max(now);
+    }
+
+    fn take(&mut self, bytes: usize, now: Instant) {
+        if self.rate > 0 {
+            self.refill(now);
+            self.tokens -= bytes as f64;
+        }
+    }
+
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+
+        self.refill(now);
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// The debt is kept at the new rate, but lifting the limit forgives it
+    fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = match rate {
+            0 => 0.0,
+            rate => self.tokens.min(rate as f64),
+        };
+    }
+}
+
+/// Limits the transfer rate of everything, which shares it: e.g. all the peers of a torrent.
+/// The clones share the same bucket, so the rate might be changed by any of them at any time.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(0)
+    }
+}
+
+impl RateLimiter {
+    /// The rate is in bytes per second, zero is unlimited
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now()))),
+        }
+    }
+
+    fn bucket(&self) -> MutexGuard<'_, TokenBucket> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket().rate
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.set_rate_at(rate, Instant::now())
+    }
+
+    pub fn set_rate_at(&self, rate: u64, now: Instant) {
+        self.bucket().set_rate(rate, now)
+    }
+
+    /// Counts the bytes transferred at the moment
+    pub fn take(&self, bytes: usize, now: Instant) {
+        self.bucket().take(bytes, now)
+    }
+
+    /// How long the next transfer has to wait, zero if it might go right away
+    pub fn wait_time(&self, now: Instant) -> Duration {
+        self.bucket().wait_time(now)
+    }
+}
+
+/// The download and the upload limits of a peer, a torrent or the whole engine
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(download: u64, upload: u64) -> Self {
+        RateLimits {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+
+    pub fn set(&self, download: u64, upload: u64) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+/// The limiters a transfer of a peer goes through: the peer's own one, the torrent's and the
+/// engine's. The transfer waits for the slowest of them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LimitChain(Vec<RateLimiter>);
+
+impl LimitChain {
+    pub fn new(limiters: Vec<RateLimiter>) -> Self {
+        LimitChain(limiters)
+    }
+
+    pub fn take(&self, bytes: usize, now: Instant) {
+        if bytes > 0 {
+            self.0.iter().for_each(|limiter| limiter.take(bytes, now));
+        }
+    }
+
+    pub fn wait_time(&self, now: Instant) -> Duration {
+        self.0
+            .iter()
+            .map(|limiter| limiter.wait_time(now))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// The bytes of the message counted by the rate limits: the block of a piece message, or the
+/// whole message, if the protocol overhead is limited as well
+pub(crate) fn charged_bytes(message: &MessageType, overhead: bool) -> usize {
+    match message {
+        MessageType::Piece(_, _, block) if overhead => PIECE_HEADER + block.len(),
+        MessageType::Piece(_, _, block) => block.len(),
+        message if overhead => message.to_bytes().len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn assert_wait(limiter: &impl Fn(Instant) -> Duration, now: Instant, expected: Duration) {
+        let wait = limiter(now);
+        assert!(
+            wait.abs_diff(expected) < Duration::from_micros(1),
+            "waits {:?} instead of {:?}",
+            wait,
+            expected
+        );
+    }
+
+    #[test]
+    fn bucket_keeps_the_rate() {
+        let limiter = RateLimiter::new(1000);
+        let wait = |now| limiter.wait_time(now);
+        let start = Instant::now();
+        limiter.set_rate_at(1000, start);
+
+        // A second's worth of burst goes right away
+        limiter.take(1000, start);
+        assert_wait(&wait, start, Duration::ZERO);
+
+        // The debt is paid off at the rate
+        limiter.take(500, start);
+        assert_wait(&wait, start, 500 * MS);
+        assert_wait(&wait, start + 200 * MS, 300 * MS);
+        assert_wait(&wait, start + 500 * MS, Duration::ZERO);
+
+        // The transfers sent as soon as allowed keep the rate: 10 KB take 10 seconds
+        let mut now = start + 500 * MS;
+        for _ in 0..100 {
+            now += limiter.wait_time(now);
+            limiter.take(100, now);
+        }
+        now += limiter.wait_time(now);
+        assert!(now.duration_since(start) >= Duration::from_millis(10_499));
+        assert!(now.duration_since(start) <= Duration::from_millis(10_501));
+
+        // The idle bucket fills up to a second's worth of tokens only
+        now += Duration::from_secs(60);
+        limiter.take(1500, now);
+        assert_wait(&wait, now, 500 * MS);
+    }
+
+    #[test]
+    fn rate_changes_at_runtime() {
+        let limiter = RateLimiter::new(1000);
+        let wait = |now| limiter.wait_time(now);
+        let start = Instant::now();
+        limiter.set_rate_at(1000, start);
+        limiter.take(2000, start);
+        assert_wait(&wait, start, Duration::from_secs(1));
+
+        // The debt is paid off faster at the higher rate
+        limiter.set_rate_at(4000, start);
+        assert_eq!(limiter.rate(), 4000);
+        assert_wait(&wait, start, 250 * MS);
+
+        // Lifting the limit forgives the debt
+        limiter.take(8000, start);
+        limiter.set_rate_at(0, start);
+        assert_wait(&wait, start, Duration::ZERO);
+        limiter.take(1_000_000, start);
+        assert_wait(&wait, start, Duration::ZERO);
+
+        // The new limit doesn't give a burst bigger than its own
+        let later = start + Duration::from_secs(10);
+        limiter.set_rate_at(100, later);
+        limiter.take(200, later);
+        assert_wait(&wait, later, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn chain_waits_for_slowest_limiter() {
+        let peer = RateLimiter::new(0);
+        let torrent = RateLimiter::new(2000);
+        let engine = RateLimiter::new(1000);
+        let start = Instant::now();
+        torrent.set_rate_at(2000, start);
+        engine.set_rate_at(1000, start);
+        let chain = LimitChain::new(vec![peer, torrent.clone(), engine.clone()]);
+
+        chain.take(3000, start);
+        assert_wait(&|now| chain.wait_time(now), start, Duration::from_secs(2));
+        assert_wait(&|now| torrent.wait_time(now), start, 500 * MS);
+
+        // Another peer of the same engine shares the engine's limit
+        let other = LimitChain::new(vec![RateLimiter::new(0), engine]);
+        assert_wait(&|now| other.wait_time(now), start, Duration::from_secs(2));
+        assert_eq!(LimitChain::default().wait_time(start), Duration::ZERO);
+    }
+
+    #[test]
+    fn overhead_is_counted_optionally() {
+        let piece = MessageType::Piece(1, 0, Bytes::from(vec![0u8; 100]));
+        assert_eq!(charged_bytes(&piece, false), 100);
+        assert_eq!(charged_bytes(&piece, true), piece.to_bytes().len());
+        assert_eq!(charged_bytes(&MessageType::Have(1), false), 0);
+        assert_eq!(charged_bytes(&MessageType::Have(1), true), 9);
+    }
+}
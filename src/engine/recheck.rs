@@ -0,0 +1,100 @@
+use crate::engine::BLOCK_SIZE;
+use crate::protocol::entities::Torrent;
+use crate::storage::{Storage, StorageLayout};
+use sha1::{Digest, Sha1};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+/// Reads the piece from the storage and compares its hash with the one in the torrent. Pieces
+/// that can't be read, e.g. the file is missing, are invalid.
+pub fn check_piece(torrent: &Torrent, storage: &dyn Storage, piece: u32) -> bool {
+    let size = torrent.piece_size(piece as usize) as u32;
+    match (
+        storage.read(piece, 0, size),
+        torrent.piece_hash(piece as usize),
+    ) {
+        (Ok(data), Some(hash)) => Sha1::digest(&data).as_slice() == hash,
+        _ => false,
+    }
+}
+
+/// The SHA-1 of every block of the piece, they tell which blocks differ between two downloads
+/// of the same piece. Empty if the piece can't be read.
+pub(crate) fn block_hashes(storage: &dyn Storage, piece: u32) -> Vec<[u8; 20]> {
+    let size = storage.layout().piece_size(piece) as u32;
+    match storage.read(piece, 0, size) {
+        Ok(data) => data
+            .chunks(BLOCK_SIZE as usize)
+            .map(|block| Sha1::digest(block).into())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Hashes every piece found in the storage. The pieces are spread over as many threads as
+/// there are cores. Returns the bitfield of the valid pieces.
+pub fn recheck(torrent: &Torrent, storage: &dyn Storage) -> Vec<bool> {
+    let pieces_count = torrent.pieces_count();
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(pieces_count.max(1));
+
+    let next = AtomicUsize::new(0);
+    let bitfield: Vec<AtomicBool> = (0..pieces_count).map(|_| AtomicBool::new(false)).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let piece = next.fetch_add(1, Ordering::Relaxed);
+                if piece >= pieces_count {
+                    break;
+                }
+
+                let valid = check_piece(torrent, storage, piece as u32);
+                bitfield[piece].store(valid, Ordering::Relaxed);
+            });
+        }
+    });
+
+    bitfield.into_iter().map(AtomicBool::into_inner).collect()
+}
+
+/// The number of verified bytes of every file
+pub fn verified_bytes(layout: &StorageLayout, bitfield: &[bool]) -> Vec<u64> {
+    (0..layout.files.len())
+        .map(|file| {
+            let entry = &layout.files[file];
+            let (first, last) = layout.file_pieces(file);
+            (first..last)
+                .filter(|piece| bitfield.get(*piece as usize).copied().unwrap_or(false))
+                .map(|piece| {
+                    let start = piece as u64 * layout.piece_length;
+                    let end = start + layout.piece_size(piece);
+                    end.min(entry.offset + entry.length) - start.max(entry.offset)
+                })
+                .sum()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn counts_verified_bytes_per_file() {
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("a"), 10),
+                (PathBuf::from("empty"), 0),
+                (PathBuf::from("b"), 14),
+            ],
+            8,
+        );
+
+        assert_eq!(verified_bytes(&layout, &[true, false, true]), vec![8, 0, 8]);
+        assert_eq!(verified_bytes(&layout, &[true; 3]), vec![10, 0, 14]);
+    }
+}
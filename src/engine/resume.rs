@@ -0,0 +1,240 @@
+use crate::engine::Priority;
+use crate::storage::{FileMetadata, Storage};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The version of the resume file format. Files of the other versions are ignored.
+pub const RESUME_VERSION: u32 = 1;
+
+/// A piece, which was being downloaded when the resume data was saved
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub piece: u32,
+    /// One bit per block, set for the received blocks
+    pub blocks: ByteBuf,
+}
+
+/// The state of a file on disk. Both fields are missing if the file doesn't exist.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    /// Nanoseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<i64>,
+}
+
+impl From<Option<FileMetadata>> for FileState {
+    fn from(metadata: Option<FileMetadata>) -> Self {
+        FileState {
+            length: metadata.map(|m| m.length),
+            modified: metadata.map(|m| m.modified),
+        }
+    }
+}
+
+/// The last successful announce to a tracker
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TrackerState {
+    pub url: String,
+    /// Seconds since the Unix epoch
+    pub last_announce: i64,
+}
+
+/// Everything needed to continue the torrent after a restart without re-downloading or
+/// re-hashing the data. The data is saved as a bencoded dictionary, one file per torrent.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub version: u32,
+    pub info_hash: ByteBuf,
+    /// One bit per piece, set for the verified pieces
+    pub pieces: ByteBuf,
+    #[serde(default)]
+    pub partial: Vec<PartialPiece>,
+    /// The state of every torrent file at the moment the data was saved
+    #[serde(default)]
+    pub files: Vec<FileState>,
+    /// Peer addresses, like `10.0.0.1:6881`
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default)]
+    pub trackers: Vec<TrackerState>,
+    /// The root folder the files were moved to, see [Storage::move_to]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// The paths of the files relative to the root, they differ from the torrent ones if the
+    /// files were renamed
+    #[serde(default)]
+    pub file_paths: Vec<String>,
+    /// One byte per file, see [Priority]
+    #[serde(default)]
+    pub file_priorities: ByteBuf,
+    /// One byte per piece
+    #[serde(default)]
+    pub piece_priorities: ByteBuf,
+    /// The IPs banned for sending corrupt data
+    #[serde(default)]
+    pub banned: Vec<String>,
+}
+
+impl ResumeData {
+    /// The resume file of the torrent within the resume folder
+    pub fn path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+        let name: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        dir.join(format!("{}.resume", name))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes =
+            fs::read(path).map_err(|e| format!("Unable read resume file {:?}: {}", path, e))?;
+        let data: ResumeData = serde_bencode::from_bytes(&bytes)
+            .map_err(|e| format!("Invalid resume file {:?}: {}", path, e))?;
+
+        if data.version != RESUME_VERSION {
+            return Err(format!(
+                "Unsupported version {} of resume file {:?}",
+                data.version, path
+            ));
+        }
+
+        Ok(data)
+    }
+
+    /// Writes the data to a temporary file first, so a crash never leaves a broken file behind
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let bytes = serde_bencode::to_bytes(self)
+            .map_err(|e| format!("Unable encode resume data: {}", e))?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Unable create folder {:?}: {}", parent, e))?;
+        }
+
+        let temporary = path.with_extension("resume.tmp");
+        fs::write(&temporary, bytes)
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|e| format!("Unable write resume file {:?}: {}", path, e))
+    }
+
+    /// The data can be trusted only if it belongs to the torrent and none of the files was
+    /// changed since the data was saved
+    pub fn matches(&self, info_hash: &[u8; 20], storage: &dyn Storage) -> bool {
+        let files = &storage.layout().files;
+
+        self.info_hash.as_slice() == info_hash
+            && self.files.len() == files.len()
+            && self
+                .files
+                .iter()
+                .enumerate()
+                .all(|(index, state)| *state == FileState::from(storage.metadata(index)))
+    }
+
+    pub fn piece_bitfield(&self, pieces_count: usize) -> Vec<bool> {
+        unpack_bits(&self.pieces, pieces_count)
+    }
+
+    pub fn partial_pieces(&self, blocks_count: impl Fn(u32) -> usize) -> Vec<(u32, Vec<bool>)> {
+        self.partial
+            .iter()
+            .map(|p| (p.piece, unpack_bits(&p.blocks, blocks_count(p.piece))))
+            .collect()
+    }
+}
+
+pub fn pack_priorities(priorities: &[Priority]) -> ByteBuf {
+    ByteBuf::from(priorities.iter().map(|p| u8::from(*p)).collect::<Vec<u8>>())
+}
+
+/// Returns `None` if the number of the priorities doesn't match or any of them is unknown
+pub fn unpack_priorities(bytes: &[u8], count: usize) -> Option<Vec<Priority>> {
+    if bytes.len() != count {
+        return None;
+    }
+
+    bytes.iter().map(|b| Priority::try_from(*b).ok()).collect()
+}
+
+/// Packs the flags into bytes, the highest bit of the first byte is the first flag
+pub fn pack_bits(bits: &[bool]) -> ByteBuf {
+    let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+    for (index, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+    ByteBuf::from(bytes)
+}
+
+pub fn unpack_bits(bytes: &[u8], count: usize) -> Vec<bool> {
+    (0..count)
+        .map(|index| {
+            bytes
+                .get(index / 8)
+                .map(|byte| byte & (0x80 >> (index % 8)) != 0)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_data_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = ResumeData::path(dir.path(), &[0xab; 20]);
+        let data = ResumeData {
+            version: RESUME_VERSION,
+            info_hash: ByteBuf::from(vec![0xab; 20]),
+            pieces: pack_bits(&[true, false, true]),
+            partial: vec![PartialPiece {
+                piece: 1,
+                blocks: pack_bits(&[true, false]),
+            }],
+            files: vec![
+                FileState::default(),
+                FileState {
+                    length: Some(10),
+                    modified: Some(1_000_000),
+                },
+            ],
+            peers: vec!["127.0.0.1:6881".to_string()],
+            trackers: vec![TrackerState {
+                url: "udp://tracker:80".to_string(),
+                last_announce: 100,
+            }],
+            root: Some("/downloads".to_string()),
+            file_paths: vec!["t/a".to_string(), "t/b".to_string()],
+            file_priorities: pack_priorities(&[Priority::Skip, Priority::High]),
+            piece_priorities: pack_priorities(&[Priority::Low; 3]),
+            banned: vec!["10.0.0.1".to_string()],
+        };
+
+        data.save(&path).unwrap();
+
+        assert!(path.ends_with(format!("{}.resume", "ab".repeat(20))));
+        assert_eq!(ResumeData::load(&path).unwrap(), data);
+        assert_eq!(data.piece_bitfield(3), vec![true, false, true]);
+        assert_eq!(data.partial_pieces(|_| 2), vec![(1, vec![true, false])]);
+        assert_eq!(
+            unpack_priorities(&data.file_priorities, 2),
+            Some(vec![Priority::Skip, Priority::High])
+        );
+        assert_eq!(unpack_priorities(&data.piece_priorities, 4), None);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.resume");
+        let data = ResumeData {
+            version: RESUME_VERSION + 1,
+            ..ResumeData::default()
+        };
+
+        data.save(&path).unwrap();
+        assert!(ResumeData::load(&path).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::engine::ClientVersion;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Transfer statistics of a single torrent
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct TorrentStatistics {
+    /// Payload bytes received from peers, duplicates included
+    pub downloaded: u64,
+    /// Payload bytes sent to peers
+    pub uploaded: u64,
+    /// Bytes of blocks that had already been received from another peer. These are mostly
+    /// produced by the end-game mode.
+    pub duplicate: u64,
+    /// Bytes of pieces that failed the hash check
+    pub hash_failed: u64,
+    /// The number of successfully verified pieces
+    pub pieces_verified: u32,
+}
+
+impl TorrentStatistics {
+    pub fn record_block(&mut self, length: u32) {
+        self.downloaded += length as u64;
+    }
+
+    pub fn record_duplicate(&mut self, length: u32) {
+        self.downloaded += length as u64;
+        self.duplicate += length as u64;
+    }
+
+    pub fn record_upload(&mut self, length: u32) {
+        self.uploaded += length as u64;
+    }
+
+    pub fn record_piece(&mut self, length: u64, valid: bool) {
+        if valid {
+            self.pieces_verified += 1;
+        } else {
+            self.hash_failed += length;
+        }
+    }
+}
+
+/// A connected peer of a torrent, as shown in the peer lists
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    /// The client of the peer, if its peer id tells it
+    pub client: Option<ClientVersion>,
+    /// The peer has connected to us
+    pub incoming: bool,
+    /// Bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+/// The transfer rate of a single peer. The bytes are counted between the updates, and the new
+/// rate is averaged with the previous one, so a short pause doesn't drop it to zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TransferRate {
+    bytes: u64,
+    rate: u64,
+}
+
+impl TransferRate {
+    pub fn record(&mut self, bytes: u32) {
+        self.bytes += bytes as u64;
+    }
+
+    /// Takes the bytes counted since the previous update
+    pub fn update(&mut self, elapsed: Duration) {
+        let current = self.bytes * 1000 / (elapsed.as_millis() as u64).max(1);
+        self.rate = (self.rate + current) / 2;
+        self.bytes = 0;
+    }
+
+    /// Bytes per second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+}
+
+impl Display for TorrentStatistics {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            formatter,
+            "pieces: {}, downloaded: {} B, uploaded: {} B, duplicate: {} B, hash failed: {} B",
+            self.pieces_verified, self.downloaded, self.uploaded, self.duplicate, self.hash_failed
+        )
+    }
+}
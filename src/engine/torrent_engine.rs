@@ -1,188 +1,273 @@
-#![allow(dead_code)]
-
 use crate::engine::generate_peer_id;
-use crate::protocol::entities::{
-    HandshakeRequest, MessageType, Torrent, TrackerProtocol, TrackerUrl, HANDSHAKE_SIZE,
-};
-use crate::protocol::net::{HttpClient, NetworkClient, Peer, UdpClient};
+use crate::engine::listener::{self, IncomingRoutes};
+use crate::engine::peer_pool::ConnectionLimit;
+use crate::engine::statistics::{PeerInfo, TorrentStatistics};
+use crate::engine::torrent_session::{TorrentCommand, TorrentSession};
+use crate::engine::{EngineConfig, Priority, RateLimits, ResumeData, TorrentOptions, TrackerState};
+use crate::protocol::entities::{Torrent, TrackerProtocol, TrackerUrl};
+use crate::protocol::net::{HttpClient, NetworkClient, Peer, Transport, UdpClient, UtpSocket};
+use crate::storage::CacheStatistics;
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+type NetworkClients = HashMap<TrackerProtocol, Box<dyn NetworkClient>>;
+
+/// The number of random ports of the range tried by [TorrentEngine::listen_on]
+const LISTEN_ATTEMPTS: usize = 10;
+
+/// A running torrent
+pub struct TorrentHandle {
+    info_hash: [u8; 20],
+    token: CancellationToken,
+    commands: mpsc::UnboundedSender<TorrentCommand>,
+    /// The rate limits of the torrent, shared with the running torrent
+    limits: RateLimits,
+    task: JoinHandle<Result<TorrentStatistics, String>>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Sends the command to the torrent and waits for the answer
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> TorrentCommand,
+    ) -> Result<T, String> {
+        let (reply, answer) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .map_err(|_| "Torrent is not running".to_string())?;
+        answer
+            .await
+            .map_err(|_| "Torrent is not running".to_string())
+    }
+
+    /// Changes the priority of the file, the index is the one in the torrent file list. The
+    /// pieces of the file are re-prioritized immediately.
+    pub async fn set_file_priority(&self, file: usize, priority: Priority) -> Result<(), String> {
+        self.request(|reply| TorrentCommand::SetFilePriority(file, priority, reply))
+            .await?
+    }
+
+    /// Changes the priority of a single piece. It lasts until the priority of a file the piece
+    /// belongs to is changed.
+    pub async fn set_piece_priority(&self, piece: u32, priority: Priority) -> Result<(), String> {
+        self.request(|reply| TorrentCommand::SetPiecePriority(piece, priority, reply))
+            .await?
+    }
+
+    pub async fn file_priorities(&self) -> Result<Vec<Priority>, String> {
+        let (files, _) = self.request(TorrentCommand::Priorities).await?;
+        Ok(files)
+    }
+
+    pub async fn piece_priorities(&self) -> Result<Vec<Priority>, String> {
+        let (_, pieces) = self.request(TorrentCommand::Priorities).await?;
+        Ok(pieces)
+    }
+
+    /// The statistics of the disk cache, `None` if the cache is disabled
+    pub async fn cache_statistics(&self) -> Result<Option<CacheStatistics>, String> {
+        self.request(TorrentCommand::CacheStatistics).await
+    }
+
+    /// Moves the torrent files to another folder. It's a rename within the same file system,
+    /// otherwise the files are copied and the old ones are removed. The torrent keeps running.
+    pub async fn move_storage(&self, root: impl Into<PathBuf>) -> Result<(), String> {
+        let root = root.into();
+        self.request(|reply| TorrentCommand::MoveStorage(root, reply))
+            .await?
+    }
+
+    /// Renames the file, the path is relative to the storage root and includes the torrent
+    /// name, e.g. `name/folder/file.mkv`
+    pub async fn rename_file(&self, file: usize, path: impl Into<PathBuf>) -> Result<(), String> {
+        let path = path.into();
+        self.request(|reply| TorrentCommand::RenameFile(file, path, reply))
+            .await?
+    }
 
+    /// Renames the root folder of a multi-file torrent, or the file of a single-file one
+    pub async fn rename_root(&self, name: &str) -> Result<(), String> {
+        let name = name.to_string();
+        self.request(|reply| TorrentCommand::RenameRoot(name, reply))
+            .await?
+    }
+
+    /// The connected peers and their clients
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, String> {
+        self.request(TorrentCommand::Peers).await
+    }
+
+    /// The IPs banned for sending corrupt data
+    pub async fn banned_peers(&self) -> Result<Vec<IpAddr>, String> {
+        self.request(TorrentCommand::BannedPeers).await
+    }
+
+    /// Bans the IP, its connections are closed. The ban is saved with the resume data.
+    pub async fn ban_peer(&self, ip: IpAddr) -> Result<(), String> {
+        self.request(|reply| TorrentCommand::BanPeer(ip, reply))
+            .await
+    }
+
+    pub async fn unban_peer(&self, ip: IpAddr) -> Result<(), String> {
+        self.request(|reply| TorrentCommand::UnbanPeer(ip, reply))
+            .await
+    }
+
+    /// Changes the download and upload rates of the torrent, in bytes per second. Zero is
+    /// unlimited.
+    pub fn set_rate_limits(&self, download: u64, upload: u64) {
+        self.limits.set(download, upload);
+    }
+
+    /// Changes the download and upload rates of every peer of the torrent, in bytes per second.
+    /// Zero is unlimited.
+    pub async fn set_peer_rate_limits(&self, download: u64, upload: u64) -> Result<(), String> {
+        self.request(|reply| TorrentCommand::SetPeerRateLimits(download, upload, reply))
+            .await
+    }
+
+    /// Asks the torrent to stop. Use [TorrentHandle::wait] to wait until it's stopped.
+    pub fn stop(&self) {
+        self.token.cancel();
+    }
+
+    /// Waits until the torrent is downloaded or stopped
+    pub async fn wait(self) -> Result<TorrentStatistics, String> {
+        self.task
+            .await
+            .map_err(|e| format!("Torrent task failed: {}", e))?
+    }
+}
+
+/// The asynchronous torrent engine. It has to be used within the tokio runtime, see the
+/// [crate::engine::blocking] module for the blocking alternative.
 pub struct TorrentEngine {
-    is_active: bool,
-    torrents_queue: Vec<Torrent>,
-    network_clients: HashMap<TrackerProtocol, Box<dyn NetworkClient>>,
+    config: EngineConfig,
+    /// Connection slots shared by all torrents
+    connection_limit: ConnectionLimit,
+    network_clients: Arc<NetworkClients>,
+    /// The running torrents, which take the incoming connections
+    routes: IncomingRoutes,
+    /// Opens the peer connections of all torrents
+    transport: Transport,
+    /// The rate limits shared by all torrents
+    limits: RateLimits,
+    /// Cancelled when the engine shuts down, stops all torrents
+    token: CancellationToken,
 }
 
 impl TorrentEngine {
     pub fn start() -> Self {
-        let mut network_clients: HashMap<TrackerProtocol, Box<dyn NetworkClient>> = HashMap::new();
+        Self::with_config(EngineConfig::default())
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        let mut network_clients: NetworkClients = HashMap::new();
 
         network_clients.insert(TrackerProtocol::UDP, Box::new(UdpClient::default()));
         network_clients.insert(TrackerProtocol::HTTP, Box::new(HttpClient::default()));
 
         TorrentEngine {
-            is_active: true,
-            torrents_queue: vec![],
-            network_clients,
+            connection_limit: ConnectionLimit::new(config.max_connections),
+            network_clients: Arc::new(network_clients),
+            routes: IncomingRoutes::default(),
+            transport: Transport::new(config.utp),
+            limits: RateLimits::new(config.download_limit, config.upload_limit),
+            token: CancellationToken::new(),
+            config,
         }
     }
 
-    /// *Protocol overview*
-    /// Once a tcp connection is established the messages you send and receive have to follow the
-    /// following protocol.
-    ///
-    /// The first thing you want to do is let your peer know which files you are interested
-    /// in downloading from them, as well as some identifying info. If the peer doesn’t have the
-    /// files you want they will close the connection, but if they do have the files they should
-    /// send back a similar message as confirmation. This is called the “handshake”.
-    ///
-    /// The most likely thing that will happen next is that the peer will let you know what
-    /// pieces they have. This happens through the “have” and “bitfield” messages. Each “have”
-    /// message contains a piece index as its payload. This means you will receive multiple
-    /// have messages, one for each piece that your peer has.
-    ///
-    /// The bitfield message serves a similar purpose, but does it in a different way. The
-    /// bitfield message can tell you all the pieces that the peer has in just one message. It does
-    /// this by sending a string of bits, one for each piece in the file. The index of each bit is the
-    /// same as the piece index, and if they have that piece it will be set to 1, if not it will be set to 0.
-    /// For example if you receive a bitfield that starts with 011001… that means they have the pieces at
-    /// index 1, 2, and 5, but not the pieces at index 0, 3,and 4.
-    ///
-    /// It’s possible to receive both “have” messages and a bitfield message, if which case you
-    /// should combine them to get the full list of pieces.
-    ///
-    /// Actually it’s possible to recieve another kind of message, the peer might decide they
-    /// don’t want to share with you! That’s what the choke, unchoke, interested, and not interested
-    /// messages are for. If you are choked, that means the peer does not want to share with you, if
-    /// you are unchoked then the peer is willing to share. On the other hand, interested means you want
-    /// what your peer has, whereas not interested means you don’t want what they have.
-    ///
-    /// You always start out choked and not interested. So the first message you send should be
-    /// the interested message. Then hopefully they will send you an unchoke message and you can move
-    /// to the next step. If you receive a choke message message instead you can just let the connection drop.
-    ///
-    /// Finally you will receive a piece message, which will contain the bytes of data that you
-    /// requested.
-
-    fn download_portions(
-        &self,
-        _peer_id: &[u8; 20],
-        torrent: &Torrent,
-        stream: &mut TcpStream,
-        bitfield: Vec<bool>,
-    ) -> Result<(), String> {
-        println!("Pieces {:?}", torrent.info.piece_length);
-
-        // if bitfield.len() != torrent.info.piece_length as usize {
-        //     dbg!(bitfield.len());
-        //     dbg!(torrent.info.piece_length);
-        //     println!("The pieces length is not equal")
-        // } else {
-        for (index, have) in bitfield.iter().enumerate() {
-            if *have {
-                println!("Processing... {}", index);
-
-                let request = MessageType::Request(index as u32, 0, u32::MAX);
-                stream
-                    .write_all(&request.to_bytes())
-                    .map_err(|_| "Unable send request".to_string())?;
-
-                let mut buff = [0u8; 1024];
-                let bytes_read_cnt = stream
-                    .read(&mut buff)
-                    .map_err(|e| format!("Unable to read from the peer: {}", e))?;
-
-                println!("Response: {}", bytes_read_cnt);
-                println!("Content: {:?}", &buff[0..bytes_read_cnt]);
-            } else {
-                println!("Peer doesn't have {} piece", index)
-            }
-        }
-        // }
-
-        Ok(())
+    /// Changes the download and upload rates of all torrents together, in bytes per second.
+    /// Zero is unlimited.
+    pub fn set_rate_limits(&self, download: u64, upload: u64) {
+        self.limits.set(download, upload);
     }
 
-    fn download_from_peer(
-        &self,
-        peer_id: &[u8; 20],
-        torrent: &Torrent,
-        peer: &Peer,
-    ) -> Result<(), String> {
-        println!("Connecting with {}", peer);
-        let addr: SocketAddr = format!("{}", peer)
-            .parse()
-            .map_err(|e| format!("Unable create Socket address {}", e))?;
-
-        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
-            .map_err(|e| format!("Unable open TCP connection to host {}", e))?;
-
-        let info_hash: [u8; 20] = torrent.info_hash()?;
-        // make handshake
-        let handshake = HandshakeRequest::create(info_hash, *peer_id);
-
-        stream
-            .write_all(&handshake.as_bytes())
-            .map_err(|e| format!("Unable to write to TCP connection: {}", e))?;
-
-        let mut buff = [0u8; 1024];
-        let bytes_read_cnt = stream
-            .read(&mut buff)
-            .map_err(|e| format!("Unable to read from TCP connection: {}", e))?;
-
-        if bytes_read_cnt < HANDSHAKE_SIZE {
-            Err("Unexpected response length from peer".to_string())
-        } else if !handshake.is_valid_response(&buff[0..HANDSHAKE_SIZE]) {
-            Err("Invalid response from peer".to_string())
-        } else {
-            if bytes_read_cnt >= HANDSHAKE_SIZE {
-                // make interest request
-                let interested_request = MessageType::Interested.to_bytes();
-
-                stream
-                    .write_all(&interested_request)
-                    .map_err(|e| format!("Unable write interested request to the peer {}", e))?;
-
-                let bytes_read_cnt = stream
-                    .read(&mut buff)
-                    .map_err(|e| format!("Unable to read from the peer: {}", e))?;
-
-                let response = &buff[0..bytes_read_cnt];
-                let message_type = MessageType::from_bytes(response)?;
-                match message_type {
-                    MessageType::Have(_) => {}
-                    MessageType::Bitfield(portions) => {
-                        self.download_portions(peer_id, torrent, &mut stream, portions)?;
-                    }
-                    _ => {}
+    /// Starts accepting the incoming peer connections on the address. The connections are
+    /// passed to the running torrents by the info hash of the handshake. With uTP enabled the
+    /// UDP port of the same number takes the uTP connections, and the outgoing ones are opened
+    /// from it. Returns the bound address, which tells the port if the given one is 0.
+    pub async fn listen(&self, addr: SocketAddr) -> Result<SocketAddr, String> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+        let bound = listener
+            .local_addr()
+            .map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+
+        let utp = match self.config.utp {
+            true => match UtpSocket::bind(bound) {
+                Ok(socket) => {
+                    self.transport.set_utp_socket(socket.clone());
+                    Some(socket)
                 }
-            }
-            todo!()
-        }
+                Err(msg) => {
+                    println!("{}, accepting TCP connections only", msg);
+                    None
+                }
+            },
+            false => None,
+        };
+
+        self.transport.set_listen_port(bound.port());
+        tokio::spawn(listener::listen(
+            listener,
+            utp,
+            self.routes.clone(),
+            self.config.incoming_encryption,
+            self.config.connect_timeout,
+            self.token.child_token(),
+        ));
+        Ok(bound)
     }
 
-    fn download_from_peers(&self, torrent: &Torrent, peers: &Vec<Peer>) -> Result<(), String> {
-        println!("Start downloading torrent content from peers");
-        assert!(!peers.is_empty());
-
-        let peer_id = generate_peer_id();
-        println!("Main peer: {:?}", String::from_utf8(peer_id.to_vec()));
-
-        for peer in peers.iter() {
-            match self.download_from_peer(&peer_id, torrent, peer) {
-                Err(msg) => println!("{}", msg),
-                _ => return Ok(()),
+    /// Starts accepting the incoming peer connections on the IP and the port of the config,
+    /// see [TorrentEngine::listen]. A few random ports of the range are tried, until one is
+    /// free. Returns the bound address.
+    pub async fn listen_on(&self, ip: IpAddr) -> Result<SocketAddr, String> {
+        let mut result = Err(format!("Unable to listen on {}", self.config.listen_port));
+        for port in self.config.listen_port.candidates(LISTEN_ATTEMPTS) {
+            result = self.listen(SocketAddr::new(ip, port)).await;
+            if result.is_ok() {
+                break;
             }
         }
-
-        Ok(())
+        result
     }
 
-    fn get_peers_list(&self, torrent: &Torrent) -> Result<Vec<Peer>, String> {
-        for tracker in torrent.trackers_list() {
+    /// Asks the trackers for the peers. The trackers, which answered recently, are asked first.
+    /// Returns the peers and the tracker, which has given them.
+    async fn get_peers_list(
+        &self,
+        torrent: &Torrent,
+        known: &[TrackerState],
+        peer_id: [u8; 20],
+    ) -> Result<(Vec<Peer>, String), String> {
+        // The trackers tell the peers to connect to this port, there is nothing to connect to
+        // if we don't listen
+        let port = self.transport.listen_port().unwrap_or_default();
+        let mut trackers = torrent.trackers_list();
+        trackers.sort_by_key(|tracker| {
+            let last_announce = known
+                .iter()
+                .find(|state| state.url == *tracker)
+                .map(|state| state.last_announce);
+            std::cmp::Reverse(last_announce)
+        });
+
+        for tracker in trackers {
             println!("Trying for {}", tracker);
             let tracker_url = TrackerUrl::try_from(tracker.as_str());
             if tracker_url.is_err() {
@@ -200,27 +285,173 @@ impl TorrentEngine {
 
             let client = client.unwrap();
 
-            if let Ok(peers_list) = client.get_peers_list(torrent, &tracker_url) {
-                println!("# of peers {}", peers_list.len());
-                return Ok(peers_list);
+            match client
+                .get_peers_list(torrent, &tracker_url, peer_id, port)
+                .await
+            {
+                Ok(peers_list) => {
+                    println!("# of peers {}", peers_list.len());
+                    return Ok((peers_list, tracker));
+                }
+                Err(msg) => println!("{}", msg),
             }
         }
 
-        // Hopefully will be never reached
-        Ok(vec![])
+        Err("Unable to get peers from any tracker".to_string())
     }
 
-    fn download(&self, torrent: Torrent) -> Result<(), String> {
+    /// Loads the resume data saved by the previous run, if there is any
+    fn load_resume(&self, torrent: &Torrent) -> Option<ResumeData> {
+        let dir = self.config.resume_dir.as_ref()?;
+        let path = ResumeData::path(dir, &torrent.info_hash().ok()?);
+        if !path.exists() {
+            return None;
+        }
+
+        ResumeData::load(&path)
+            .map_err(|msg| println!("{}, the data will be rechecked", msg))
+            .ok()
+    }
+
+    fn spawn_torrent(
+        &self,
+        torrent: Torrent,
+        options: TorrentOptions,
+        resume: Option<ResumeData>,
+        trackers: Vec<TrackerState>,
+        peers: &[SocketAddr],
+        peer_id: [u8; 20],
+    ) -> Result<TorrentHandle, String> {
+        let token = self.token.child_token();
+
+        let mut session = TorrentSession::new(
+            Arc::new(torrent),
+            options,
+            peer_id,
+            self.config.clone(),
+            self.connection_limit.clone(),
+            self.transport.clone(),
+            token.clone(),
+        )?;
+
+        for addr in peers {
+            session.add_peer(*addr);
+        }
+        if let Some(resume) = resume {
+            session.resume_from(resume);
+        }
+        session.set_trackers(trackers);
+        session.set_engine_limits(self.limits.clone());
+
+        let info_hash = session.info_hash();
+        self.routes.register(info_hash, peer_id, session.events())?;
+        let commands = session.commands();
+        let limits = session.rate_limits();
+        let routes = self.routes.clone();
+        let task = tokio::spawn(async move {
+            let result = session.run().await;
+            routes.unregister(&info_hash);
+            result
+        });
+
+        Ok(TorrentHandle {
+            info_hash,
+            token,
+            commands,
+            limits,
+            task,
+        })
+    }
+
+    /// Starts downloading the torrent from the given peers, without asking the trackers
+    pub fn add_torrent_with_peers(
+        &self,
+        torrent: Torrent,
+        options: TorrentOptions,
+        peers: &[SocketAddr],
+    ) -> Result<TorrentHandle, String> {
+        let resume = self.load_resume(&torrent);
+        let trackers = resume
+            .as_ref()
+            .map(|r| r.trackers.clone())
+            .unwrap_or_default();
+        self.spawn_torrent(
+            torrent,
+            options,
+            resume,
+            trackers,
+            peers,
+            generate_peer_id(self.config.client_code),
+        )
+    }
+
+    /// Asks the trackers for the peers and starts downloading the torrent. The peers saved in
+    /// the resume data are used as well, so the torrent might start even if no tracker answers.
+    /// A seeding torrent starts without any peers, as it waits for the incoming ones.
+    pub async fn add_torrent(
+        &self,
+        torrent: Torrent,
+        options: TorrentOptions,
+    ) -> Result<TorrentHandle, String> {
+        let resume = self.load_resume(&torrent);
+        let mut trackers = resume
+            .as_ref()
+            .map(|r| r.trackers.clone())
+            .unwrap_or_default();
+
+        // The trackers know the torrent's peer id, the peers get the same one in the handshake
+        let peer_id = generate_peer_id(self.config.client_code);
         println!("Getting peers list");
-        let peers_list_result = self.get_peers_list(&torrent)?;
+        let announced = self.get_peers_list(&torrent, &trackers, peer_id).await;
+        let mut peers: Vec<SocketAddr> = match announced {
+            Ok((peers, url)) => {
+                let last_announce = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or_default();
+                trackers.retain(|state| state.url != url);
+                trackers.insert(0, TrackerState { url, last_announce });
+
+                peers
+                    .iter()
+                    .filter_map(|peer| peer.socket_addr().ok())
+                    .collect()
+            }
+            Err(msg) if resume.is_some() || options.seed => {
+                println!("{}", msg);
+                vec![]
+            }
+            Err(msg) => return Err(msg),
+        };
+
+        if let Some(resume) = &resume {
+            peers.extend(
+                resume
+                    .peers
+                    .iter()
+                    .filter_map(|p| p.parse::<SocketAddr>().ok()),
+            );
+        }
+
+        if peers.is_empty() && !options.seed {
+            return Err("No peers to download from".to_string());
+        }
 
-        self.download_from_peers(&torrent, &peers_list_result)
+        self.spawn_torrent(torrent, options, resume, trackers, &peers, peer_id)
     }
 
-    pub fn add_new_torrent(&mut self, torrent: Torrent) {
-        // self.torrents_queue.push(torrent);
+    /// Downloads the torrent and waits until it's finished
+    pub async fn add_new_torrent(
+        &mut self,
+        torrent: Torrent,
+        options: TorrentOptions,
+    ) -> Result<(), String> {
+        let handle = self.add_torrent(torrent, options).await?;
+        handle.wait().await.map(|_| ())
+    }
 
-        // This code will be replaced to async function
-        self.download(torrent).expect("Download failed")
+    /// Stops all running torrents
+    pub fn shutdown(&self) {
+        self.token.cancel();
     }
 }
@@ -0,0 +1,1648 @@
+use crate::engine::peer_pool::{ConnectionLimit, PeerPool};
+use crate::engine::peer_session::PeerSession;
+use crate::engine::piece_picker::{BlockInfo, BlockOutcome, PiecePicker, BLOCK_SIZE};
+use crate::engine::rate_limit::LimitChain;
+use crate::engine::recheck::block_hashes;
+use crate::engine::resume;
+use crate::engine::statistics::{PeerInfo, TorrentStatistics, TransferRate};
+use crate::engine::{
+    allowed_fast_set, check_piece, piece_priorities, recheck, BanList, Choker, ChokerPeer,
+    ClientVersion, EngineConfig, FileState, PartialPiece, PeerQuality, Priority, RateLimits,
+    ResumeData, TitForTat, TorrentOptions, TrackerState, ALLOWED_FAST_SET_SIZE, RESUME_VERSION,
+};
+use crate::protocol::entities::{
+    ExtensionHandshake, HandshakeRequest, MessageType, Torrent, EXTENSION_HANDSHAKE_ID,
+};
+use crate::protocol::net::{PeerConnection, Transport};
+use crate::storage::{
+    check_disk_space, CacheStatistics, CachedStorage, Preallocation, Storage, StorageLayout,
+};
+use bytes::Bytes;
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, interval_at, timeout};
+use tokio_util::sync::CancellationToken;
+
+/// The number of requests kept in flight for a single peer
+const MAX_PENDING_REQUESTS: usize = 5;
+
+/// The number of requests a peer may queue with us, the ones over the limit are ignored
+const MAX_QUEUED_UPLOADS: usize = 250;
+
+/// How often the coordinator looks for new connection candidates
+const DISPATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The capacity of the channel between the peer sessions and the coordinator
+const EVENTS_CHANNEL_SIZE: usize = 256;
+
+/// Events sent to the torrent coordinator by the peer sessions and the background jobs
+#[derive(Debug)]
+pub(crate) enum SessionEvent {
+    /// The handshake with the peer is done, the peer's handshake tells the extensions it
+    /// supports
+    Connected(SocketAddr, HandshakeRequest),
+    /// The listener has accepted a connection for this torrent
+    Incoming(SocketAddr, Box<PeerConnection>, HandshakeRequest),
+    Message(SocketAddr, MessageType),
+    Closed(SocketAddr, Result<(), String>),
+    /// The hash check of the piece has finished and the storage was told about it. The hashes
+    /// of the blocks are given, if the piece failed or is being downloaded again after a failure.
+    PieceChecked(u32, bool, Vec<[u8; 20]>, Result<(), String>),
+    /// The downloaded block has been written to the storage
+    BlockWritten(BlockInfo, Result<(), String>),
+    /// The block requested by the peer has been read from the storage
+    BlockRead(SocketAddr, BlockInfo, Result<Vec<u8>, String>),
+    /// The peer session has written the block to the connection
+    BlockSent(SocketAddr, BlockInfo),
+    /// The files were moved or renamed, the reply is sent after the resume data is saved
+    StorageChanged(Result<(), String>, oneshot::Sender<Result<(), String>>),
+    /// The complete file has been moved to its final path
+    FileCompleted(usize, Result<(), String>),
+    /// The storage was flushed and the resume data saved, the replies waiting for it are sent
+    Checkpointed(Vec<oneshot::Sender<Result<(), String>>>),
+}
+
+/// Requests sent to the torrent coordinator by the [crate::engine::TorrentHandle]
+#[derive(Debug)]
+pub(crate) enum TorrentCommand {
+    SetFilePriority(usize, Priority, oneshot::Sender<Result<(), String>>),
+    SetPiecePriority(u32, Priority, oneshot::Sender<Result<(), String>>),
+    /// Asks for the file and the piece priorities
+    Priorities(oneshot::Sender<(Vec<Priority>, Vec<Priority>)>),
+    CacheStatistics(oneshot::Sender<Option<CacheStatistics>>),
+    /// Moves the files to the new root folder
+    MoveStorage(PathBuf, oneshot::Sender<Result<(), String>>),
+    RenameFile(usize, PathBuf, oneshot::Sender<Result<(), String>>),
+    /// Renames the first component of every file path
+    RenameRoot(String, oneshot::Sender<Result<(), String>>),
+    /// Asks for the connected peers
+    Peers(oneshot::Sender<Vec<PeerInfo>>),
+    /// Sets the download and upload rates of every peer, the new peers included
+    SetPeerRateLimits(u64, u64, oneshot::Sender<()>),
+    BannedPeers(oneshot::Sender<Vec<IpAddr>>),
+    /// Bans the IP and closes its connections
+    BanPeer(IpAddr, oneshot::Sender<()>),
+    UnbanPeer(IpAddr, oneshot::Sender<()>),
+}
+
+/// What the coordinator knows about a peer session
+struct PeerState {
+    sender: mpsc::UnboundedSender<MessageType>,
+    connected: bool,
+    connected_at: Instant,
+    /// The remote peer has connected to us
+    incoming: bool,
+    /// The remote peer chokes us
+    choked: bool,
+    /// We've told the peer that we're interested
+    interested: bool,
+    /// The blocks requested from the peer and when the requests were sent
+    requests: Vec<(BlockInfo, Instant)>,
+    /// The last block received, or the moment we could start waiting for one: the unchoke or
+    /// our interest
+    last_block: Instant,
+    quality: PeerQuality,
+    /// We choke the remote peer
+    choking: bool,
+    /// The remote peer is interested in our pieces
+    peer_interested: bool,
+    /// The blocks requested by the peer, in the order they are sent
+    uploads: VecDeque<BlockInfo>,
+    /// The first of the uploads is being read from the storage
+    reading: bool,
+    /// The blocks passed to the peer session, which it hasn't sent yet, e.g. as the upload
+    /// limits hold them back
+    sending: VecDeque<BlockInfo>,
+    download_rate: TransferRate,
+    upload_rate: TransferRate,
+    /// The peer supports the fast extension
+    fast: bool,
+    /// The pieces the peer lets us request while it chokes us
+    allowed_fast: HashSet<u32>,
+    /// The pieces we let the peer request while we choke it, see [allowed_fast_set]
+    fast_set: HashSet<u32>,
+    /// The client of the peer, told by the peer id of its handshake
+    client: Option<ClientVersion>,
+    /// The rate limits of this peer only, shared with its session
+    limits: RateLimits,
+}
+
+impl PeerState {
+    fn new(sender: mpsc::UnboundedSender<MessageType>, incoming: bool, limits: RateLimits) -> Self {
+        PeerState {
+            sender,
+            connected: false,
+            connected_at: Instant::now(),
+            incoming,
+            choked: true,
+            interested: false,
+            requests: vec![],
+            last_block: Instant::now(),
+            quality: PeerQuality::default(),
+            choking: true,
+            peer_interested: false,
+            uploads: VecDeque::new(),
+            reading: false,
+            sending: VecDeque::new(),
+            download_rate: TransferRate::default(),
+            upload_rate: TransferRate::default(),
+            fast: false,
+            allowed_fast: HashSet::new(),
+            fast_set: HashSet::new(),
+            client: None,
+            limits,
+        }
+    }
+
+    fn send(&self, message: MessageType) {
+        // The session might be already closed, the Closed event will clean it up
+        let _ = self.sender.send(message);
+    }
+}
+
+/// The coordinator of a single torrent. It owns the piece picker and all the torrent state,
+/// while every peer connection is served by its own [PeerSession] task. The sessions and the
+/// coordinator talk to each other through channels only.
+pub(crate) struct TorrentSession {
+    torrent: Arc<Torrent>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    config: EngineConfig,
+    picker: PiecePicker,
+    statistics: TorrentStatistics,
+    storage: Arc<dyn Storage>,
+    file_priorities: Vec<Priority>,
+    /// The priorities of the pieces follow the files, unless they are set explicitly
+    piece_priorities: Vec<Priority>,
+    /// The priorities were given in the options, so the saved ones are ignored
+    explicit_priorities: bool,
+    commands: mpsc::UnboundedSender<TorrentCommand>,
+    commands_receiver: mpsc::UnboundedReceiver<TorrentCommand>,
+    /// An error, which doesn't allow the torrent to continue, e.g. the disk is full
+    error: Option<String>,
+    /// The progress saved by the previous run, see [TorrentSession::restore]
+    resume: Option<ResumeData>,
+    force_recheck: bool,
+    preallocation: Preallocation,
+    /// Keep running once the download is complete
+    seed: bool,
+    resume_path: Option<PathBuf>,
+    trackers: Vec<TrackerState>,
+    pool: PeerPool,
+    peers: HashMap<SocketAddr, PeerState>,
+    /// The number of running peer sessions, both outgoing and incoming
+    sessions: usize,
+    choker: Box<dyn Choker>,
+    last_rechoke: Instant,
+    /// The peer, which sent every block of the pieces being downloaded, by the block offset
+    contributors: HashMap<u32, HashMap<u32, SocketAddr>>,
+    /// The number of blocks of every piece being written to the storage
+    writes: HashMap<u32, usize>,
+    /// The complete pieces, which are checked as soon as all their blocks are written
+    unchecked: HashSet<u32>,
+    /// The complete files being moved to their final paths
+    completing: HashSet<usize>,
+    /// The resume data is being saved
+    checkpointing: bool,
+    /// Another checkpoint was asked for meanwhile, with the replies waiting for it
+    next_checkpoint: Option<Vec<oneshot::Sender<Result<(), String>>>>,
+    bans: BanList,
+    /// An incoming connection was refused since the last rechoke, as the torrent had no room
+    refused_incoming: bool,
+    connection_limit: ConnectionLimit,
+    transport: Transport,
+    /// The rate limits of the torrent
+    limits: RateLimits,
+    /// The rate limits shared by all torrents of the engine
+    engine_limits: RateLimits,
+    /// The download and upload rates of every peer
+    peer_limits: (u64, u64),
+    events: mpsc::Sender<SessionEvent>,
+    events_receiver: mpsc::Receiver<SessionEvent>,
+    /// Stops the whole torrent
+    token: CancellationToken,
+    /// Stops the peer sessions only
+    peers_token: CancellationToken,
+}
+
+impl TorrentSession {
+    pub fn new(
+        torrent: Arc<Torrent>,
+        options: TorrentOptions,
+        peer_id: [u8; 20],
+        config: EngineConfig,
+        connection_limit: ConnectionLimit,
+        transport: Transport,
+        token: CancellationToken,
+    ) -> Result<Self, String> {
+        let info_hash = torrent.info_hash()?;
+        let layout = StorageLayout::from_torrent(&torrent)?;
+        let picker = PiecePicker::new(layout.piece_length, layout.total_length);
+        let (events, events_receiver) = mpsc::channel(EVENTS_CHANNEL_SIZE);
+        let (commands, commands_receiver) = mpsc::unbounded_channel();
+        let choker = match &options.choker {
+            Some(factory) => factory(),
+            None => Box::new(TitForTat::new(config.upload_slots)),
+        };
+        let mut storage: Arc<dyn Storage> = match options.storage {
+            Some(storage) => storage,
+            None => options
+                .backend
+                .open(options.output, layout, options.incomplete)?,
+        };
+        if config.cache.write_size > 0 || config.cache.read_size > 0 {
+            storage = Arc::new(CachedStorage::new(storage, config.cache));
+        }
+
+        let files_count = storage.layout().files.len();
+        let explicit_priorities = !options.file_priorities.is_empty();
+        let file_priorities = match options.file_priorities {
+            priorities if priorities.is_empty() => vec![Priority::Normal; files_count],
+            priorities if priorities.len() == files_count => priorities,
+            priorities => {
+                return Err(format!(
+                    "Expected {} file priorities, got {}",
+                    files_count,
+                    priorities.len()
+                ))
+            }
+        };
+        let piece_priorities = piece_priorities(storage.layout(), &file_priorities);
+
+        let mut session = TorrentSession {
+            info_hash,
+            file_priorities,
+            piece_priorities,
+            explicit_priorities,
+            commands,
+            commands_receiver,
+            peer_id,
+            picker,
+            statistics: TorrentStatistics::default(),
+            storage,
+            error: None,
+            resume: None,
+            force_recheck: options.force_recheck,
+            preallocation: options.preallocation,
+            seed: options.seed,
+            resume_path: config
+                .resume_dir
+                .as_ref()
+                .map(|dir| ResumeData::path(dir, &info_hash)),
+            trackers: vec![],
+            pool: PeerPool::new(&config),
+            peers: HashMap::new(),
+            sessions: 0,
+            choker,
+            last_rechoke: Instant::now(),
+            contributors: HashMap::new(),
+            writes: HashMap::new(),
+            unchecked: HashSet::new(),
+            completing: HashSet::new(),
+            checkpointing: false,
+            next_checkpoint: None,
+            bans: BanList::new(config.max_corrupt_pieces),
+            refused_incoming: false,
+            connection_limit,
+            transport,
+            limits: RateLimits::new(options.download_limit, options.upload_limit),
+            engine_limits: RateLimits::default(),
+            peer_limits: (config.peer_download_limit, config.peer_upload_limit),
+            events,
+            events_receiver,
+            peers_token: token.child_token(),
+            token,
+            config,
+            torrent,
+        };
+
+        session.apply_priorities()?;
+        Ok(session)
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr) {
+        self.pool.add(addr, Instant::now());
+    }
+
+    /// The sender of the commands, used by the torrent handle
+    pub fn commands(&self) -> mpsc::UnboundedSender<TorrentCommand> {
+        self.commands.clone()
+    }
+
+    /// The sender of the events, used by the listener to pass the incoming connections
+    pub fn events(&self) -> mpsc::Sender<SessionEvent> {
+        self.events.clone()
+    }
+
+    /// The rate limits of the torrent, they might be changed while it's running
+    pub fn rate_limits(&self) -> RateLimits {
+        self.limits.clone()
+    }
+
+    /// Limits the torrent by the limits shared with the other torrents as well
+    pub fn set_engine_limits(&mut self, limits: RateLimits) {
+        self.engine_limits = limits;
+    }
+
+    pub fn resume_from(&mut self, data: ResumeData) {
+        self.resume = Some(data);
+    }
+
+    /// The trackers, which answered recently. They are saved with the resume data.
+    pub fn set_trackers(&mut self, trackers: Vec<TrackerState>) {
+        self.trackers = trackers;
+    }
+
+    /// Downloads the torrent until it's complete, there are no more peers to download from, or
+    /// the session is cancelled. The requests of the peers are served meanwhile. A seeding
+    /// torrent keeps serving them after the download until it's cancelled.
+    pub async fn run(mut self) -> Result<TorrentStatistics, String> {
+        self.restore().await;
+        self.check_disk_space();
+        self.preallocate().await;
+        self.complete_files(0..self.storage.layout().files.len());
+
+        let mut dispatch_timer = interval(DISPATCH_INTERVAL);
+        let resume_interval = self.config.resume_interval;
+        let mut resume_timer = interval_at(
+            tokio::time::Instant::now() + resume_interval,
+            resume_interval,
+        );
+        let rechoke_interval = self.config.rechoke_interval;
+        let mut rechoke_timer = interval_at(
+            tokio::time::Instant::now() + rechoke_interval,
+            rechoke_interval,
+        );
+
+        let mut seeding = false;
+        let result = loop {
+            if let Some(msg) = self.error.take() {
+                break Err(msg);
+            }
+
+            if self.picker.is_complete() && !seeding {
+                if !self.seed {
+                    break Ok(());
+                }
+                seeding = true;
+                println!("Download finished, seeding: {}", self.statistics);
+            }
+
+            if !self.seed && self.sessions == 0 && self.pool.is_exhausted() {
+                break Err("No more peers to download from".to_string());
+            }
+
+            tokio::select! {
+                _ = self.token.cancelled() => {
+                    break Err("Torrent session has been stopped".to_string());
+                }
+                _ = dispatch_timer.tick() => self.dispatch(),
+                _ = resume_timer.tick() => self.checkpoint(vec![]),
+                _ = rechoke_timer.tick() => self.rechoke(),
+                Some(command) = self.commands_receiver.recv() => self.handle_command(command),
+                Some(event) = self.events_receiver.recv() => self.handle_event(event),
+            }
+        };
+
+        self.shutdown().await;
+        println!("Download finished: {}", self.statistics);
+        result.map(|_| self.statistics.clone())
+    }
+
+    /// Restores the progress from the resume data, if none of the files was changed since it
+    /// was saved. Otherwise the progress is found by hashing the data already in the storage.
+    async fn restore(&mut self) {
+        if let Some(data) = self.resume.clone() {
+            self.restore_location(&data);
+            self.restore_priorities(&data);
+            self.restore_bans(&data);
+        }
+
+        let storage = self.storage.clone();
+        let trusted = self
+            .resume
+            .take()
+            .filter(|_| !self.force_recheck)
+            .filter(|data| data.matches(&self.info_hash, storage.as_ref()));
+
+        match trusted {
+            Some(data) => {
+                println!("Resuming the torrent from the saved progress");
+                let bitfield = data.piece_bitfield(self.picker.pieces_count());
+                let partial = data.partial_pieces(|piece| {
+                    self.torrent
+                        .piece_size(piece as usize)
+                        .div_ceil(BLOCK_SIZE as u64) as usize
+                });
+
+                for piece in self.picker.restore(&bitfield, &partial) {
+                    self.verify_piece(piece);
+                }
+            }
+            None => {
+                let torrent = self.torrent.clone();
+                let bitfield =
+                    tokio::task::spawn_blocking(move || recheck(&torrent, storage.as_ref()))
+                        .await
+                        .unwrap_or_default();
+                self.picker.restore(&bitfield, &[]);
+            }
+        }
+    }
+
+    /// Makes sure the selected files fit into the free space. It's done after the restore, so
+    /// the files skipped by the previous run don't count.
+    fn check_disk_space(&mut self) {
+        let skipped: Vec<bool> = self
+            .file_priorities
+            .iter()
+            .map(|p| *p == Priority::Skip)
+            .collect();
+        if let Err(msg) = check_disk_space(self.storage.as_ref(), &skipped) {
+            self.error = Some(msg);
+        }
+    }
+
+    /// Reserves the disk space for the selected files. It's done after the restore, as it
+    /// changes the files, so the resume data wouldn't match them anymore.
+    async fn preallocate(&mut self) {
+        if self.preallocation == Preallocation::None {
+            return;
+        }
+
+        let storage = self.storage.clone();
+        let mode = self.preallocation;
+        let result = tokio::task::spawn_blocking(move || storage.preallocate(mode))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result);
+
+        if let Err(msg) = result {
+            self.error = Some(format!("Unable to preallocate the files: {}", msg));
+        }
+    }
+
+    /// Points the storage to the files moved or renamed by the previous run
+    fn restore_location(&mut self, data: &ResumeData) {
+        let root = match &data.root {
+            Some(root) if data.info_hash.as_slice() == self.info_hash => PathBuf::from(root),
+            _ => return,
+        };
+
+        let files_count = self.storage.layout().files.len();
+        let result = self.storage.move_to(&root).and_then(|_| {
+            data.file_paths
+                .iter()
+                .take_while(|_| data.file_paths.len() == files_count)
+                .enumerate()
+                .try_for_each(|(file, path)| self.storage.rename_file(file, Path::new(path)))
+        });
+
+        if let Err(msg) = result {
+            println!("Unable to find the moved files: {}", msg);
+        }
+    }
+
+    /// The saved priorities are user's choice, so they are restored even if the data has to be
+    /// rechecked. The priorities given in the options win though.
+    fn restore_priorities(&mut self, data: &ResumeData) {
+        if self.explicit_priorities || data.info_hash.as_slice() != self.info_hash {
+            return;
+        }
+
+        let files = resume::unpack_priorities(&data.file_priorities, self.file_priorities.len());
+        let pieces = resume::unpack_priorities(&data.piece_priorities, self.piece_priorities.len());
+        if let (Some(files), Some(pieces)) = (files, pieces) {
+            self.file_priorities = files;
+            self.piece_priorities = pieces;
+            if let Err(msg) = self.apply_priorities() {
+                self.error = Some(msg);
+            }
+        }
+    }
+
+    /// The bans don't depend on the files, so they are restored even if the data has to be
+    /// rechecked
+    fn restore_bans(&mut self, data: &ResumeData) {
+        if data.info_hash.as_slice() != self.info_hash {
+            return;
+        }
+
+        for ip in data
+            .banned
+            .iter()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+        {
+            self.bans.ban(ip);
+        }
+    }
+
+    /// Passes the priorities to the picker and the storage. Returns the skipped files.
+    fn apply_priorities(&mut self) -> Result<Vec<bool>, String> {
+        self.picker.set_priorities(&self.piece_priorities);
+        let skipped: Vec<bool> = self
+            .file_priorities
+            .iter()
+            .map(|p| *p == Priority::Skip)
+            .collect();
+        self.storage.set_skipped_files(&skipped)?;
+        Ok(skipped)
+    }
+
+    fn handle_command(&mut self, command: TorrentCommand) {
+        match command {
+            TorrentCommand::SetFilePriority(file, priority, reply) => {
+                let _ = reply.send(self.set_file_priority(file, priority));
+            }
+            TorrentCommand::SetPiecePriority(piece, priority, reply) => {
+                let _ = reply.send(self.set_piece_priority(piece, priority));
+            }
+            TorrentCommand::Priorities(reply) => {
+                let _ = reply.send((self.file_priorities.clone(), self.piece_priorities.clone()));
+            }
+            TorrentCommand::CacheStatistics(reply) => {
+                let _ = reply.send(self.storage.cache_statistics());
+            }
+            TorrentCommand::MoveStorage(root, reply) => {
+                self.change_storage(reply, move |storage| storage.move_to(&root))
+            }
+            TorrentCommand::RenameFile(file, path, reply) => {
+                self.change_storage(reply, move |storage| storage.rename_file(file, &path))
+            }
+            TorrentCommand::RenameRoot(name, reply) => {
+                self.change_storage(reply, move |storage| rename_root(storage, &name))
+            }
+            TorrentCommand::Peers(reply) => {
+                let peers = self
+                    .peers
+                    .iter()
+                    .filter(|(_, peer)| peer.connected)
+                    .map(|(addr, peer)| PeerInfo {
+                        addr: *addr,
+                        client: peer.client.clone(),
+                        incoming: peer.incoming,
+                        download_rate: peer.download_rate.rate(),
+                        upload_rate: peer.upload_rate.rate(),
+                    })
+                    .collect();
+                let _ = reply.send(peers);
+            }
+            TorrentCommand::SetPeerRateLimits(download, upload, reply) => {
+                self.peer_limits = (download, upload);
+                for peer in self.peers.values() {
+                    peer.limits.set(download, upload);
+                }
+                let _ = reply.send(());
+            }
+            TorrentCommand::BannedPeers(reply) => {
+                let _ = reply.send(self.bans.banned());
+            }
+            TorrentCommand::BanPeer(ip, reply) => {
+                self.ban_ip(ip);
+                let _ = reply.send(());
+            }
+            TorrentCommand::UnbanPeer(ip, reply) => {
+                self.bans.unban(&ip);
+                self.checkpoint(vec![]);
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    /// Tells the storage about the files having all their pieces verified. It's a rename
+    /// usually, but it might be a copy to another file system, so it's done on the blocking
+    /// thread pool.
+    fn complete_files(&mut self, files: impl Iterator<Item = usize>) {
+        for file in files {
+            let (first, last) = self.storage.layout().file_pieces(file);
+            if !(first..last).all(|piece| self.picker.have_piece(piece))
+                || !self.completing.insert(file)
+            {
+                continue;
+            }
+
+            let storage = self.storage.clone();
+            let events = self.events.clone();
+            tokio::task::spawn_blocking(move || {
+                let result = storage.file_completed(file);
+                let _ = events.blocking_send(SessionEvent::FileCompleted(file, result));
+            });
+        }
+    }
+
+    fn file_completed(&mut self, file: usize, result: Result<(), String>) {
+        self.completing.remove(&file);
+        if let Err(msg) = result {
+            self.error = Some(format!("Unable to move the complete file: {}", msg));
+        }
+    }
+
+    /// Moves or renames the files on the blocking thread pool, so the torrent keeps running.
+    /// The storage takes care of the reads and writes meanwhile.
+    fn change_storage(
+        &self,
+        reply: oneshot::Sender<Result<(), String>>,
+        change: impl FnOnce(&dyn Storage) -> Result<(), String> + Send + 'static,
+    ) {
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let result = change(storage.as_ref());
+            let _ = events.blocking_send(SessionEvent::StorageChanged(result, reply));
+        });
+    }
+
+    /// Changes the priority of the file and of all its pieces. The pieces shared with the other
+    /// files get the highest priority of the files.
+    fn set_file_priority(&mut self, file: usize, priority: Priority) -> Result<(), String> {
+        if file >= self.file_priorities.len() {
+            return Err(format!("There is no file {}", file));
+        }
+
+        self.file_priorities[file] = priority;
+        let layout = self.storage.layout();
+        let (first, last) = layout.file_pieces(file);
+        let range = first as usize..last as usize;
+        let pieces = piece_priorities(layout, &self.file_priorities);
+        self.piece_priorities[range.clone()].copy_from_slice(&pieces[range]);
+
+        self.apply_priorities()?;
+        self.refresh_peers();
+        Ok(())
+    }
+
+    fn set_piece_priority(&mut self, piece: u32, priority: Priority) -> Result<(), String> {
+        match self.piece_priorities.get_mut(piece as usize) {
+            Some(current) => *current = priority,
+            None => return Err(format!("There is no piece {}", piece)),
+        }
+
+        self.apply_priorities()?;
+        self.refresh_peers();
+        Ok(())
+    }
+
+    /// Flushes the storage and saves the resume data on the blocking thread pool. The storage
+    /// goes first, so the files aren't modified after their state is saved. A single checkpoint
+    /// runs at a time, so an older state never overwrites a newer one.
+    fn checkpoint(&mut self, replies: Vec<oneshot::Sender<Result<(), String>>>) {
+        if self.checkpointing {
+            self.next_checkpoint
+                .get_or_insert_with(Vec::new)
+                .extend(replies);
+            return;
+        }
+
+        self.checkpointing = true;
+        let save = self.checkpoint_job();
+        let events = self.events.clone();
+        tokio::task::spawn_blocking(move || {
+            save();
+            let _ = events.blocking_send(SessionEvent::Checkpointed(replies));
+        });
+    }
+
+    fn checkpointed(&mut self, replies: Vec<oneshot::Sender<Result<(), String>>>) {
+        self.checkpointing = false;
+        for reply in replies {
+            let _ = reply.send(Ok(()));
+        }
+
+        if let Some(replies) = self.next_checkpoint.take() {
+            self.checkpoint(replies);
+        }
+    }
+
+    /// Captures the state of the torrent, the job flushes the storage and saves it with the
+    /// state of the files
+    fn checkpoint_job(&self) -> impl FnOnce() + Send + 'static {
+        let storage = self.storage.clone();
+        let path = self.resume_path.clone();
+        let mut data = ResumeData {
+            version: RESUME_VERSION,
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            pieces: resume::pack_bits(&self.picker.bitfield()),
+            partial: self
+                .picker
+                .partial_pieces()
+                .into_iter()
+                .map(|(piece, blocks)| PartialPiece {
+                    piece,
+                    blocks: resume::pack_bits(&blocks),
+                })
+                .collect(),
+            files: vec![],
+            peers: self
+                .pool
+                .addresses()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect(),
+            trackers: self.trackers.clone(),
+            root: None,
+            file_paths: vec![],
+            file_priorities: resume::pack_priorities(&self.file_priorities),
+            piece_priorities: resume::pack_priorities(&self.piece_priorities),
+            banned: self.bans.banned().iter().map(|ip| ip.to_string()).collect(),
+        };
+
+        move || {
+            if let Err(msg) = storage.flush() {
+                println!("{}", msg);
+            }
+
+            let path = match path {
+                Some(path) => path,
+                None => return,
+            };
+
+            let location = storage.location();
+            data.files = (0..storage.layout().files.len())
+                .map(|file| FileState::from(storage.metadata(file)))
+                .collect();
+            data.root = location
+                .as_ref()
+                .map(|location| location.root.to_string_lossy().to_string());
+            data.file_paths = location
+                .map(|location| {
+                    location
+                        .files
+                        .iter()
+                        .map(|path| path.to_string_lossy().to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            if let Err(msg) = data.save(&path) {
+                println!("{}", msg);
+            }
+        }
+    }
+
+    /// Opens new connections while the per-torrent and the global limits allow
+    fn dispatch(&mut self) {
+        let max_connections = self.config.max_connections_per_torrent;
+
+        while self.pool.active() < max_connections && self.connection_limit.try_acquire() {
+            match self.pool.next_candidate(Instant::now()) {
+                Some(addr) if self.bans.is_banned(&addr.ip()) => {
+                    self.connection_limit.release();
+                    self.pool.give_up(addr.ip());
+                }
+                Some(addr) => self.spawn_session(addr, None),
+                None => {
+                    self.connection_limit.release();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Takes the connection accepted by the listener, if the limits allow. Otherwise the
+    /// connection is just dropped.
+    fn accept_peer(
+        &mut self,
+        addr: SocketAddr,
+        connection: PeerConnection,
+        remote: HandshakeRequest,
+    ) {
+        let incoming = self.peers.values().filter(|peer| peer.incoming).count();
+        if incoming >= self.config.max_connections_per_torrent {
+            self.refused_incoming = true;
+            return;
+        }
+
+        if self.bans.is_banned(&addr.ip())
+            || self.peers.contains_key(&addr)
+            || !self.connection_limit.try_acquire()
+        {
+            return;
+        }
+
+        println!("Accepted connection from {}", addr);
+        self.spawn_session(addr, Some((connection, remote)));
+    }
+
+    /// Starts the session of an outgoing connection, or of an incoming one if the connection
+    /// is given. The connection slot has to be acquired already.
+    fn spawn_session(
+        &mut self,
+        addr: SocketAddr,
+        connection: Option<(PeerConnection, HandshakeRequest)>,
+    ) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let limits = RateLimits::new(self.peer_limits.0, self.peer_limits.1);
+        let download = LimitChain::new(vec![
+            limits.download.clone(),
+            self.limits.download.clone(),
+            self.engine_limits.download.clone(),
+        ]);
+        let upload = LimitChain::new(vec![
+            limits.upload.clone(),
+            self.limits.upload.clone(),
+            self.engine_limits.upload.clone(),
+        ]);
+        self.peers
+            .insert(addr, PeerState::new(sender, connection.is_some(), limits));
+        self.sessions += 1;
+
+        let session = PeerSession {
+            addr,
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            connect_timeout: self.config.connect_timeout,
+            encryption: self.config.outgoing_encryption,
+            transport: self.transport.clone(),
+            connection,
+            download,
+            upload,
+            limit_overhead: self.config.limit_overhead,
+        };
+
+        tokio::spawn(session.run(
+            receiver,
+            self.events.clone(),
+            self.peers_token.child_token(),
+        ));
+    }
+
+    fn handle_event(&mut self, event: SessionEvent) {
+        match event {
+            SessionEvent::Connected(addr, remote) => self.peer_connected(addr, &remote),
+            SessionEvent::Incoming(addr, connection, remote) => {
+                self.accept_peer(addr, *connection, remote)
+            }
+            SessionEvent::Message(addr, message) => {
+                if let Err(msg) = self.handle_message(addr, message) {
+                    println!("{}", msg);
+                    self.disconnect(&addr);
+                }
+            }
+            SessionEvent::Closed(addr, result) => {
+                if let Err(msg) = result {
+                    println!("{}", msg);
+                }
+                self.peers.remove(&addr);
+                self.picker.remove_peer(&addr);
+                self.session_closed(&addr);
+            }
+            SessionEvent::PieceChecked(index, valid, hashes, stored) => {
+                self.piece_checked(index, valid, hashes, stored)
+            }
+            SessionEvent::BlockWritten(block, result) => self.block_written(block, result),
+            SessionEvent::BlockRead(addr, block, data) => self.block_read(addr, block, data),
+            SessionEvent::BlockSent(addr, block) => self.block_sent(addr, block),
+            SessionEvent::StorageChanged(Ok(()), reply) => self.checkpoint(vec![reply]),
+            SessionEvent::StorageChanged(result, reply) => {
+                let _ = reply.send(result);
+            }
+            SessionEvent::FileCompleted(file, result) => self.file_completed(file, result),
+            SessionEvent::Checkpointed(replies) => self.checkpointed(replies),
+        }
+    }
+
+    /// Tells the new peer which pieces we have. A fast peer gets Have All or Have None instead
+    /// of the bitfield, and the pieces it may request while we choke it. A peer supporting the
+    /// extension protocol gets the extension handshake with our listen port first.
+    fn peer_connected(&mut self, addr: SocketAddr, remote: &HandshakeRequest) {
+        println!("Connected with {}", addr);
+        self.pool.connected(&addr);
+        let bitfield = self.picker.bitfield();
+        let pieces_count = bitfield.len();
+        let listen_port = self.transport.listen_port();
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        peer.connected = true;
+        peer.connected_at = Instant::now();
+        peer.client = ClientVersion::from_peer_id(&remote.peer_id());
+        if remote.supports_extension_protocol() {
+            let handshake = ExtensionHandshake {
+                p: listen_port,
+                v: Some(format!("Torrentino {}", env!("CARGO_PKG_VERSION"))),
+                ..ExtensionHandshake::default()
+            };
+            match handshake.to_bytes() {
+                Ok(payload) => peer.send(MessageType::Extended(EXTENSION_HANDSHAKE_ID, payload)),
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        peer.fast = remote.supports_fast_extension();
+        if !peer.fast {
+            if bitfield.contains(&true) {
+                peer.send(MessageType::Bitfield(bitfield));
+            }
+            return;
+        }
+
+        if !bitfield.contains(&false) {
+            peer.send(MessageType::HaveAll);
+        } else if !bitfield.contains(&true) {
+            peer.send(MessageType::HaveNone);
+        } else {
+            peer.send(MessageType::Bitfield(bitfield));
+        }
+
+        // Choking means nothing, if every piece is allowed
+        if pieces_count > ALLOWED_FAST_SET_SIZE {
+            let fast_set = allowed_fast_set(
+                addr.ip(),
+                &self.info_hash,
+                pieces_count as u32,
+                ALLOWED_FAST_SET_SIZE,
+            );
+            for piece in &fast_set {
+                peer.send(MessageType::AllowedFast(*piece));
+            }
+            peer.fast_set = fast_set.into_iter().collect();
+        }
+    }
+
+    fn handle_message(&mut self, addr: SocketAddr, message: MessageType) -> Result<(), String> {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        let fast_message = matches!(
+            message,
+            MessageType::SuggestPiece(_)
+                | MessageType::HaveAll
+                | MessageType::HaveNone
+                | MessageType::RejectRequest(..)
+                | MessageType::AllowedFast(_)
+        );
+        if fast_message && !peer.fast {
+            return Err(format!(
+                "Peer {} sent a fast extension message without supporting it",
+                addr
+            ));
+        }
+
+        match message {
+            MessageType::Choke => {
+                peer.choked = true;
+                // The peer discards all pending requests once it chokes us. A fast peer rejects
+                // them explicitly instead, but might still serve the allowed fast pieces.
+                let requests = std::mem::take(&mut peer.requests);
+                for (block, sent) in requests {
+                    if peer.allowed_fast.contains(&block.piece) {
+                        peer.requests.push((block, sent));
+                    } else {
+                        self.picker.cancel_request(&addr, &block);
+                    }
+                }
+            }
+            MessageType::Unchoke => {
+                peer.choked = false;
+                peer.last_block = Instant::now();
+            }
+            MessageType::Interested => {
+                peer.peer_interested = true;
+                // A free slot is taken right away, the choker decides at the next rechoke
+                let unchoked = self.peers.values().filter(|p| !p.choking).count();
+                if unchoked < self.config.upload_slots {
+                    self.set_choking(&addr, false);
+                }
+            }
+            MessageType::NotInterested => {
+                peer.peer_interested = false;
+                self.set_choking(&addr, true);
+            }
+            MessageType::Request(piece, offset, length) => {
+                let block = BlockInfo::new(piece, offset, length);
+                if !is_valid_request(self.storage.layout(), &block) {
+                    return Err(format!("Peer {} requested an invalid block", addr));
+                }
+
+                // The requests of a choked peer are dropped, as the peer will request them
+                // again after the unchoke. A fast peer is told about that, and may request the
+                // allowed fast pieces while choked.
+                let duplicate = peer.uploads.contains(&block) || peer.sending.contains(&block);
+                if (!peer.choking || peer.fast_set.contains(&piece))
+                    && self.picker.have_piece(piece)
+                    && peer.uploads.len() + peer.sending.len() < MAX_QUEUED_UPLOADS
+                    && !duplicate
+                {
+                    peer.uploads.push_back(block);
+                    self.serve_uploads(addr);
+                } else if peer.fast && !duplicate {
+                    peer.send(MessageType::RejectRequest(piece, offset, length));
+                }
+                return Ok(());
+            }
+            // The peer session has dropped the block, if it was held back, and the block is sent
+            // already otherwise
+            MessageType::Cancel(piece, offset, length) => {
+                let block = BlockInfo::new(piece, offset, length);
+                let queued = peer.uploads.len() + peer.sending.len();
+                peer.uploads.retain(|b| *b != block);
+                peer.sending.retain(|b| *b != block);
+                // A fast peer gets either the block or the reject for every request
+                if peer.fast && peer.uploads.len() + peer.sending.len() < queued {
+                    peer.send(MessageType::RejectRequest(piece, offset, length));
+                }
+                return Ok(());
+            }
+            MessageType::Have(piece) => self.picker.add_peer_piece(addr, piece),
+            MessageType::Bitfield(pieces) => self.picker.add_peer_bitfield(addr, &pieces),
+            MessageType::HaveAll => {
+                let pieces = vec![true; self.picker.pieces_count()];
+                self.picker.add_peer_bitfield(addr, &pieces);
+            }
+            MessageType::HaveNone => {
+                let pieces = vec![false; self.picker.pieces_count()];
+                self.picker.add_peer_bitfield(addr, &pieces);
+            }
+            MessageType::AllowedFast(piece) if (piece as usize) < self.picker.pieces_count() => {
+                peer.allowed_fast.insert(piece);
+            }
+            MessageType::RejectRequest(piece, offset, length) => {
+                let block = BlockInfo::new(piece, offset, length);
+                if let Some(position) = peer.requests.iter().position(|(b, _)| *b == block) {
+                    peer.requests.remove(position);
+                    self.picker.cancel_request(&addr, &block);
+                }
+            }
+            MessageType::Piece(piece, offset, data) => {
+                let block = BlockInfo::new(piece, offset, data.len() as u32);
+                let piece_size = self.torrent.piece_size(piece as usize);
+                if offset as u64 + data.len() as u64 > piece_size || block.length > BLOCK_SIZE {
+                    return Err(format!(
+                        "Peer {} sent a block out of the piece bounds",
+                        addr
+                    ));
+                }
+                // A truncated block would fail the piece, and wouldn't match the request
+                if !self.picker.is_valid_block(&block) {
+                    return Err(format!(
+                        "Peer {} sent a block of unexpected length {} at {}:{}",
+                        addr, block.length, piece, offset
+                    ));
+                }
+
+                if let Some(position) = peer.requests.iter().position(|(b, _)| *b == block) {
+                    let (_, sent) = peer.requests.remove(position);
+                    peer.quality.record_latency(sent.elapsed());
+                    peer.quality.snubbed = false;
+                    peer.last_block = Instant::now();
+                }
+                peer.download_rate.record(block.length);
+                self.block_received(addr, block, data);
+            }
+            _ => {}
+        }
+
+        self.update_interest(&addr);
+        self.request_blocks(&addr);
+        Ok(())
+    }
+
+    /// Updates the transfer rates, finds the snubbing peers and lets the choker pick the peers
+    /// we upload to. The worst peers are dropped then, if there are better candidates.
+    fn rechoke(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_rechoke);
+        self.last_rechoke = now;
+
+        let snub_timeout = self.config.snub_timeout;
+        let mut snubbing = vec![];
+        let peers: Vec<ChokerPeer> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| peer.connected)
+            .map(|(addr, peer)| {
+                peer.download_rate.update(elapsed);
+                peer.upload_rate.update(elapsed);
+                peer.quality.download_rate = peer.download_rate.rate();
+                if !peer.choked
+                    && peer.interested
+                    && !peer.quality.snubbed
+                    && now.duration_since(peer.last_block) > snub_timeout
+                {
+                    peer.quality.snubbed = true;
+                    snubbing.push(*addr);
+                }
+
+                ChokerPeer {
+                    addr: *addr,
+                    interested: peer.peer_interested,
+                    download_rate: peer.download_rate.rate(),
+                    upload_rate: peer.upload_rate.rate(),
+                    connected_at: peer.connected_at,
+                    snubbed: peer.quality.snubbed,
+                }
+            })
+            .collect();
+
+        for addr in snubbing {
+            println!("Peer {} is snubbing us", addr);
+            self.release_requests(&addr);
+        }
+
+        let unchoked = self.choker.rechoke(&peers, self.picker.is_complete(), now);
+        for peer in peers {
+            self.set_choking(&peer.addr, !unchoked.contains(&peer.addr));
+        }
+
+        self.drop_worst_peers(now);
+    }
+
+    /// Takes back the requests sent to the peer, so the blocks are requested from the others
+    fn release_requests(&mut self, addr: &SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            for (block, _) in std::mem::take(&mut peer.requests) {
+                self.picker.cancel_request(addr, &block);
+                peer.send(MessageType::Cancel(block.piece, block.offset, block.length));
+            }
+        }
+        self.refresh_peers();
+    }
+
+    /// Makes room for the waiting candidates, if the torrent uses all its connections: the
+    /// peer with the worst quality score is dropped. The outgoing and the incoming connections
+    /// have their own limits, so they make room for their own kind only. The peers connected
+    /// for less than the snub timeout haven't shown their quality yet, so they are kept.
+    fn drop_worst_peers(&mut self, now: Instant) {
+        let outgoing_waiting = self.pool.active() >= self.config.max_connections_per_torrent
+            && self.pool.has_candidate(now);
+        let incoming_waiting = std::mem::take(&mut self.refused_incoming);
+
+        for (incoming, waiting) in [(false, outgoing_waiting), (true, incoming_waiting)] {
+            if !waiting {
+                continue;
+            }
+
+            let worst = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.connected && peer.incoming == incoming)
+                .filter(|(_, peer)| {
+                    now.duration_since(peer.connected_at) >= self.config.snub_timeout
+                })
+                .min_by(|(_, a), (_, b)| a.quality.score().total_cmp(&b.quality.score()))
+                .map(|(addr, _)| *addr);
+
+            if let Some(addr) = worst {
+                println!("Dropping peer {} to make room for a better one", addr);
+                self.disconnect(&addr);
+            }
+        }
+    }
+
+    /// Closes the connection with the peer. The session reports it closed afterwards.
+    fn disconnect(&mut self, addr: &SocketAddr) {
+        // Closing the commands channel stops the session
+        self.peers.remove(addr);
+        self.picker.remove_peer(addr);
+    }
+
+    /// Closes the connections to the IP and never connects to it again
+    fn ban_ip(&mut self, ip: IpAddr) {
+        println!("Banning {} for sending corrupt data", ip);
+        self.bans.ban(ip);
+        self.pool.give_up(ip);
+
+        let peers: Vec<SocketAddr> = self
+            .peers
+            .keys()
+            .filter(|addr| addr.ip() == ip)
+            .copied()
+            .collect();
+        for addr in peers {
+            self.disconnect(&addr);
+        }
+        self.checkpoint(vec![]);
+    }
+
+    /// Chokes or unchokes the peer, if it's not done yet. The requests of a choked peer are
+    /// dropped.
+    fn set_choking(&mut self, addr: &SocketAddr, choking: bool) {
+        let peer = match self.peers.get_mut(addr) {
+            Some(peer) if peer.choking != choking => peer,
+            _ => return,
+        };
+
+        peer.choking = choking;
+        if choking {
+            // A fast peer keeps the requests of the allowed fast pieces, the others are rejected.
+            // The peer session drops the blocks it holds back along with the requests.
+            let fast_set = &peer.fast_set;
+            let is_kept = |block: &BlockInfo| peer.fast && fast_set.contains(&block.piece);
+            let (kept, mut rejected): (VecDeque<BlockInfo>, VecDeque<BlockInfo>) =
+                std::mem::take(&mut peer.sending)
+                    .into_iter()
+                    .partition(is_kept);
+            peer.sending = kept;
+            let (kept, uploads): (VecDeque<BlockInfo>, VecDeque<BlockInfo>) =
+                std::mem::take(&mut peer.uploads)
+                    .into_iter()
+                    .partition(is_kept);
+            peer.uploads = kept;
+            rejected.extend(uploads);
+            peer.send(MessageType::Choke);
+            if peer.fast {
+                for block in rejected {
+                    peer.send(MessageType::RejectRequest(
+                        block.piece,
+                        block.offset,
+                        block.length,
+                    ));
+                }
+            }
+        } else {
+            peer.send(MessageType::Unchoke);
+        }
+    }
+
+    /// Reads the next requested block on the blocking thread pool. A peer gets its blocks one
+    /// by one, so a slow peer doesn't take all the threads.
+    fn serve_uploads(&mut self, addr: SocketAddr) {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) if !peer.reading => peer,
+            _ => return,
+        };
+        let block = match peer.uploads.front() {
+            Some(block) => *block,
+            None => return,
+        };
+
+        peer.reading = true;
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        tokio::task::spawn_blocking(move || {
+            let data = storage.read(block.piece, block.offset, block.length);
+            let _ = events.blocking_send(SessionEvent::BlockRead(addr, block, data));
+        });
+    }
+
+    /// Sends the block to the peer, unless it was cancelled or the peer was choked meanwhile
+    fn block_read(&mut self, addr: SocketAddr, block: BlockInfo, data: Result<Vec<u8>, String>) {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.reading = false;
+
+        if peer.uploads.front() != Some(&block) {
+            self.serve_uploads(addr);
+            return;
+        }
+        peer.uploads.pop_front();
+
+        match data {
+            Ok(data) => {
+                peer.sending.push_back(block);
+                peer.send(MessageType::Piece(block.piece, block.offset, data.into()));
+            }
+            // The request is dropped, the torrent goes on serving the other ones
+            Err(msg) => {
+                println!("Unable to read the data for {}: {}", addr, msg);
+                if peer.fast {
+                    peer.send(MessageType::RejectRequest(
+                        block.piece,
+                        block.offset,
+                        block.length,
+                    ));
+                }
+            }
+        }
+
+        self.serve_uploads(addr);
+    }
+
+    /// The upload is counted once the block is actually sent
+    fn block_sent(&mut self, addr: SocketAddr, block: BlockInfo) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if let Some(position) = peer.sending.iter().position(|b| *b == block) {
+                peer.sending.remove(position);
+            }
+            peer.upload_rate.record(block.length);
+        }
+        self.statistics.record_upload(block.length);
+    }
+
+    fn block_received(&mut self, addr: SocketAddr, block: BlockInfo, data: Bytes) {
+        match self.picker.block_received(&addr, &block) {
+            BlockOutcome::Accepted {
+                cancel,
+                piece_complete,
+            } => {
+                self.statistics.record_block(block.length);
+                self.contributors
+                    .entry(block.piece)
+                    .or_default()
+                    .insert(block.offset, addr);
+                self.write_block(block, data);
+
+                for other in cancel {
+                    if let Some(peer) = self.peers.get_mut(&other) {
+                        peer.requests.retain(|(b, _)| *b != block);
+                        peer.send(MessageType::Cancel(block.piece, block.offset, block.length));
+                    }
+                }
+
+                if piece_complete {
+                    self.unchecked.insert(block.piece);
+                }
+            }
+            BlockOutcome::Duplicate => self.statistics.record_duplicate(block.length),
+        }
+    }
+
+    /// Writes the block on the blocking thread pool, so the coordinator keeps serving the peers
+    /// while the disk is busy
+    fn write_block(&mut self, block: BlockInfo, data: Bytes) {
+        *self.writes.entry(block.piece).or_default() += 1;
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let result = storage.write(block.piece, block.offset, &data);
+            let _ = events.blocking_send(SessionEvent::BlockWritten(block, result));
+        });
+    }
+
+    /// The complete piece is checked once all its blocks are written
+    fn block_written(&mut self, block: BlockInfo, result: Result<(), String>) {
+        if let Err(msg) = result {
+            self.error = Some(format!("Unable to store the downloaded data: {}", msg));
+        }
+
+        if self.write_finished(block.piece) && self.unchecked.remove(&block.piece) {
+            self.verify_piece(block.piece);
+        }
+    }
+
+    /// Counts the finished write. Returns whether all blocks of the piece are written.
+    fn write_finished(&mut self, piece: u32) -> bool {
+        match self.writes.get_mut(&piece) {
+            Some(writes) if *writes > 1 => {
+                *writes -= 1;
+                false
+            }
+            Some(_) => {
+                self.writes.remove(&piece);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The piece is read back from the storage and hashed on the blocking thread pool, so the
+    /// coordinator keeps serving the peers. The storage is told about the result right there,
+    /// before the piece can be downloaded again.
+    fn verify_piece(&mut self, index: u32) {
+        let torrent = self.torrent.clone();
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let suspicious = self.bans.is_suspicious(index);
+
+        tokio::task::spawn_blocking(move || {
+            let valid = check_piece(&torrent, storage.as_ref(), index);
+            let hashes = if !valid || suspicious {
+                block_hashes(storage.as_ref(), index)
+            } else {
+                vec![]
+            };
+            let stored = storage.piece_checked(index, valid);
+            let _ = events.blocking_send(SessionEvent::PieceChecked(index, valid, hashes, stored));
+        });
+    }
+
+    fn piece_checked(
+        &mut self,
+        index: u32,
+        valid: bool,
+        hashes: Vec<[u8; 20]>,
+        stored: Result<(), String>,
+    ) {
+        let piece_size = self.torrent.piece_size(index as usize);
+        self.statistics.record_piece(piece_size, valid);
+        if let Err(msg) = stored {
+            self.error = Some(format!("Unable to store the downloaded data: {}", msg));
+        }
+
+        let contributors = self.contributors.remove(&index).unwrap_or_default();
+        if !valid {
+            let distinct: HashSet<&SocketAddr> = contributors.values().collect();
+            for addr in distinct {
+                if let Some(peer) = self.peers.get_mut(addr) {
+                    peer.quality.hash_failures += 1;
+                }
+            }
+        }
+
+        let banned = if valid {
+            self.bans.piece_passed(index, &hashes)
+        } else {
+            let senders: Vec<Option<IpAddr>> = (0..piece_size.div_ceil(BLOCK_SIZE as u64))
+                .map(|block| contributors.get(&(block as u32 * BLOCK_SIZE)))
+                .map(|addr| addr.map(SocketAddr::ip))
+                .collect();
+            self.bans.piece_failed(index, &senders, &hashes)
+        };
+
+        if valid {
+            self.picker.piece_verified(index);
+            let size = self.storage.layout().piece_size(index);
+            let files: Vec<usize> = match self.storage.layout().map(index, 0, size) {
+                Ok(slices) => slices.iter().map(|slice| slice.file).collect(),
+                Err(_) => vec![],
+            };
+            self.complete_files(files.into_iter());
+            for peer in self.peers.values().filter(|p| p.connected) {
+                peer.send(MessageType::Have(index));
+            }
+        } else {
+            println!("Piece {} failed the hash check", index);
+            self.picker.piece_failed(index);
+            if self.bans.is_suspicious(index) {
+                self.picker.download_from_single_peer(index);
+            }
+        }
+
+        for ip in banned {
+            self.ban_ip(ip);
+        }
+
+        self.refresh_peers();
+    }
+
+    /// Updates the interest in every peer and requests more blocks if possible
+    fn refresh_peers(&mut self) {
+        let peers: Vec<SocketAddr> = self.peers.keys().copied().collect();
+        for addr in peers {
+            self.update_interest(&addr);
+            self.request_blocks(&addr);
+        }
+    }
+
+    fn update_interest(&mut self, addr: &SocketAddr) {
+        let interesting = self.picker.is_interesting(addr);
+        if let Some(peer) = self.peers.get_mut(addr) {
+            if peer.connected && interesting != peer.interested {
+                peer.interested = interesting;
+                peer.last_block = Instant::now();
+                peer.send(if interesting {
+                    MessageType::Interested
+                } else {
+                    MessageType::NotInterested
+                });
+            }
+        }
+    }
+
+    fn request_blocks(&mut self, addr: &SocketAddr) {
+        let peer = match self.peers.get_mut(addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        // A snubbing peer gets a single request, in case it wakes up
+        let limit = if peer.quality.snubbed {
+            1
+        } else {
+            MAX_PENDING_REQUESTS
+        };
+        if !peer.interested || peer.requests.len() >= limit {
+            return;
+        }
+
+        // A choking fast peer still serves the allowed fast pieces
+        let count = limit - peer.requests.len();
+        let blocks = if !peer.choked {
+            self.picker.pick_blocks(*addr, count)
+        } else if !peer.allowed_fast.is_empty() {
+            self.picker
+                .pick_blocks_from(*addr, count, &peer.allowed_fast)
+        } else {
+            return;
+        };
+        for block in blocks {
+            peer.send(MessageType::Request(
+                block.piece,
+                block.offset,
+                block.length,
+            ));
+            peer.requests.push((block, Instant::now()));
+        }
+    }
+
+    /// Releases the connection slot of the finished session. The incoming peers aren't in the
+    /// pool, so the pool ignores them.
+    fn session_closed(&mut self, addr: &SocketAddr) {
+        self.sessions -= 1;
+        self.connection_limit.release();
+        self.pool.failed(addr, Instant::now());
+    }
+
+    /// Stops all sessions and waits until they release their connection slots, and the blocks,
+    /// the complete files and the resume data being written are stored. The final checkpoint is
+    /// saved then.
+    async fn shutdown(&mut self) {
+        self.peers_token.cancel();
+
+        let mut replies = self.next_checkpoint.take().unwrap_or_default();
+        let deadline = self.config.connect_timeout;
+        let _ = timeout(deadline, async {
+            while self.sessions > 0
+                || !self.writes.is_empty()
+                || !self.completing.is_empty()
+                || self.checkpointing
+            {
+                match self.events_receiver.recv().await {
+                    Some(SessionEvent::Closed(addr, _)) => self.session_closed(&addr),
+                    Some(SessionEvent::BlockWritten(block, _)) => {
+                        self.write_finished(block.piece);
+                    }
+                    Some(SessionEvent::FileCompleted(file, result)) => {
+                        self.completing.remove(&file);
+                        if let Err(msg) = result {
+                            println!("Unable to move the complete file: {}", msg);
+                        }
+                    }
+                    Some(SessionEvent::Checkpointed(done)) => {
+                        self.checkpointing = false;
+                        replies.extend(done);
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        })
+        .await;
+
+        self.peers.clear();
+        let _ = tokio::task::spawn_blocking(self.checkpoint_job()).await;
+        for reply in replies {
+            let _ = reply.send(Ok(()));
+        }
+    }
+}
+
+/// The requested block has to lie within a piece and be no longer than the block we request
+/// ourselves, which is what every client asks for
+fn is_valid_request(layout: &StorageLayout, block: &BlockInfo) -> bool {
+    (block.piece as usize) < layout.pieces_count()
+        && block.length > 0
+        && block.length <= BLOCK_SIZE
+        && block.offset as u64 + block.length as u64 <= layout.piece_size(block.piece)
+}
+
+/// Replaces the first component of every file path, which is the torrent name
+fn rename_root(storage: &dyn Storage, name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(format!("Invalid torrent name {:?}", name));
+    }
+
+    let location = storage
+        .location()
+        .ok_or_else(|| "The storage files can't be renamed".to_string())?;
+    for (file, path) in location.files.iter().enumerate() {
+        let mut components = path.components();
+        components.next();
+        storage.rename_file(file, &Path::new(name).join(components.as_path()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let layout = StorageLayout::new(vec![(PathBuf::from("file.bin"), 40_000)], 32_768);
+        let valid = |piece, offset, length| {
+            is_valid_request(&layout, &BlockInfo::new(piece, offset, length))
+        };
+
+        assert!(valid(0, 0, BLOCK_SIZE));
+        assert!(valid(0, 16_384, BLOCK_SIZE));
+        assert!(valid(1, 0, 7_232));
+        assert!(!valid(0, 0, BLOCK_SIZE + 1));
+        assert!(!valid(0, 0, 0));
+        assert!(!valid(0, 20_000, BLOCK_SIZE));
+        assert!(!valid(1, 0, 7_233));
+        assert!(!valid(2, 0, 1));
+        assert!(!valid(0, u32::MAX, BLOCK_SIZE));
+    }
+}
@@ -3,3 +3,4 @@ extern crate core;
 pub mod cli;
 pub mod engine;
 pub mod protocol;
+pub mod storage;
@@ -3,7 +3,16 @@ use torrentino::cli::{Arguments, Cli};
 use clap::Parser;
 
 fn main() -> Result<(), String> {
-    let arguments = Arguments::parse();
+    let arguments = match Arguments::try_parse() {
+        Ok(arguments) => arguments,
+        // Help and version requests are not errors
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(1)
+        }
+    };
+
     let cli = Cli::new(arguments);
     cli.process()
 }
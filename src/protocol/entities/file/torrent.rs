@@ -1,7 +1,7 @@
 use crate::protocol::entities::file::torrent_node::TorrentNode;
 use crate::protocol::entities::TorrentInfo;
 
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use serde_bencode::de;
 use serde_derive::Deserialize;
 use sha1::{Digest, Sha1};
@@ -64,9 +64,28 @@ impl Torrent {
         if let Some(ref files) = self.info.files {
             files.iter().map(|f| f.length).sum()
         } else {
-            0
+            self.info.length.unwrap_or_default() as u64
         }
     }
+
+    /// The number of pieces the torrent content is split into. Each piece has its own 20 bytes
+    /// SHA-1 hash in the `pieces` field of the info dictionary.
+    pub fn pieces_count(&self) -> usize {
+        self.info.pieces.len() / 20
+    }
+
+    /// The expected SHA-1 hash of the piece with the given index
+    pub fn piece_hash(&self, index: usize) -> Option<&[u8]> {
+        self.info.pieces.get(index * 20..(index + 1) * 20)
+    }
+
+    /// The size of the piece with the given index. All pieces have the same size, except the
+    /// last one, which might be shorter.
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let piece_length = self.info.piece_length as u64;
+        let offset = index as u64 * piece_length;
+        self.total_size().saturating_sub(offset).min(piece_length)
+    }
 }
 
 impl TryFrom<PathBuf> for Torrent {
@@ -106,7 +125,7 @@ impl TryFrom<String> for Torrent {
 impl Display for Torrent {
     #[allow(unused_must_use)]
     fn fmt(&self, formatter: &mut Formatter<'_>) -> FmtResult {
-        fn write_announce_list(announce_list: &Vec<Vec<String>>, formatter: &mut Formatter<'_>) {
+        fn write_announce_list(announce_list: &[Vec<String>], formatter: &mut Formatter<'_>) {
             if !announce_list.is_empty() && !announce_list[0].is_empty() {
                 write(
                     &format!("Tier 1: {}", &announce_list[0][0]),
@@ -140,13 +159,10 @@ impl Display for Torrent {
             self.creation_date
                 .as_ref()
                 .map(|timestamp| {
-                    // Create a NaiveDateTime from the timestamp
-                    let naive = NaiveDateTime::from_timestamp_opt(*timestamp, 0)
+                    // Create a DateTime from the timestamp
+                    let datetime: DateTime<Utc> = DateTime::from_timestamp(*timestamp, 0)
                         .expect("invalid or out-of-range datetime");
 
-                    // Create a normal DateTime from the NaiveDateTime
-                    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-
                     // Format the datetime how you want
                     let newdate = datetime.format("%Y-%m-%d %H:%M:%S UTC");
                     format!("{}", newdate)
@@ -216,9 +232,7 @@ mod tests {
         println!("encoding:\t{:?}", torrent.encoding);
         println!("piece length:\t{:?}", torrent.info.piece_length);
         println!("private:\t{:?}", torrent.info.private);
-        println!("root hash:\t{:?}", torrent.info.root_hash);
         println!("md5sum:\t\t{:?}", torrent.info.md5sum);
-        println!("path:\t\t{:?}", torrent.info.path);
 
         if let Some(files) = &torrent.info.files {
             for f in files {
@@ -1,4 +1,4 @@
 use serde_derive::Deserialize;
 
 #[derive(Debug, Deserialize)]
-pub struct TorrentNode(String, i64);
+pub struct TorrentNode(pub String, pub i64);
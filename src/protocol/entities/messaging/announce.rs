@@ -36,13 +36,13 @@ impl AnnounceRequest {
             info_hash,
             peer_id,
             downloaded: 0,
-            left: total_size,
+            left: u64::to_be(total_size),
             uploaded: 0,
             event: 0, // 0: none; 1: completed; 2: started; 3: stopped,
             ip_address: 0,
             key: random(),
             num_want: u32::to_be(200),
-            port,
+            port: u16::to_be(port),
         }
     }
 }
@@ -55,3 +55,22 @@ pub struct AnnounceResponse {
     leechers: u32,
     seeders: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_is_big_endian() {
+        let request = AnnounceRequest::announce(1, [1u8; 20], [2u8; 20], 0x0102, 51413);
+        let bytes = bincode::serialize(&request).unwrap();
+
+        assert_eq!(bytes.len(), 98);
+        assert_eq!(&bytes[8..12], &[0, 0, 0, 1]);
+        assert_eq!(&bytes[16..36], &[1u8; 20]);
+        assert_eq!(&bytes[36..56], &[2u8; 20]);
+        assert_eq!(&bytes[64..72], &[0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(&bytes[92..96], &200u32.to_be_bytes());
+        assert_eq!(&bytes[96..98], &51413u16.to_be_bytes());
+    }
+}
@@ -0,0 +1,57 @@
+use bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The id of the extended message, which carries the extension handshake
+pub const EXTENSION_HANDSHAKE_ID: u8 = 0;
+
+/// The payload of the extension protocol handshake (BEP 10), the first extended message sent
+/// to a peer supporting the extension protocol. The unknown keys sent by the peer are ignored.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// The extensions we support and the message ids the peer should use for them
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// The TCP port the peer listens on, the port of an incoming connection is a random one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// The client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+}
+
+impl ExtensionHandshake {
+    pub fn to_bytes(&self) -> Result<Bytes, String> {
+        serde_bencode::to_bytes(self)
+            .map(Bytes::from)
+            .map_err(|e| format!("Unable to encode extension handshake: {}", e))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_bencode::from_bytes(bytes)
+            .map_err(|e| format!("Malformed extension handshake: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_roundtrip() {
+        let handshake = ExtensionHandshake {
+            p: Some(51413),
+            v: Some("Torrentino".to_string()),
+            ..ExtensionHandshake::default()
+        };
+        let bytes = handshake.to_bytes().unwrap();
+        assert_eq!(&bytes[..], &b"d1:mde1:pi51413e1:v10:Torrentinoe"[..]);
+        assert_eq!(ExtensionHandshake::from_bytes(&bytes).unwrap(), handshake);
+
+        let other = b"d1:md6:ut_pexi1ee6:yourip4:\x7f\x00\x00\x011:pi6881e4:reqqi250ee";
+        let parsed = ExtensionHandshake::from_bytes(other).unwrap();
+        assert_eq!(parsed.p, Some(6881));
+        assert_eq!(parsed.m.get("ut_pex"), Some(&1));
+        assert!(ExtensionHandshake::from_bytes(b"i1e").is_err());
+    }
+}
@@ -4,14 +4,78 @@ pub(crate) const HANDSHAKE_SIZE: usize = 68;
 
 pub(crate) const BIT_TORRENT_PROTOCOL_STRING: &str = "BitTorrent protocol";
 
+/// The bit of the last reserved byte, which tells that the peer supports the fast extension
+/// (BEP 6)
+const FAST_EXTENSION_BIT: u8 = 0x04;
+
+/// The bit of the sixth reserved byte, which tells that the peer supports the extension
+/// protocol (BEP 10)
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+#[derive(Debug, Clone)]
 pub struct HandshakeRequest {
+    /// The extensions supported by the peer, one bit per extension
+    reserved: [u8; 8],
     info_hash: [u8; 20],
     peer_id: [u8; 20],
 }
 
 impl HandshakeRequest {
+    /// A plain handshake, which doesn't advertise any extension
     pub fn create(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
-        HandshakeRequest { info_hash, peer_id }
+        HandshakeRequest {
+            reserved: [0u8; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn with_fast_extension(mut self) -> Self {
+        self.reserved[7] |= FAST_EXTENSION_BIT;
+        self
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[7] & FAST_EXTENSION_BIT != 0
+    }
+
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[5] |= EXTENSION_PROTOCOL_BIT;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    /// Parses the handshake received from a remote peer
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HANDSHAKE_SIZE
+            || bytes[0] as usize != BIT_TORRENT_PROTOCOL_STRING.len()
+            || &bytes[1..=19] != BIT_TORRENT_PROTOCOL_STRING.as_bytes()
+        {
+            return Err("Invalid handshake".to_string());
+        }
+
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&bytes[20..28]);
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&bytes[28..48]);
+        let mut peer_id = [0u8; 20];
+        peer_id.copy_from_slice(&bytes[48..68]);
+        Ok(HandshakeRequest {
+            reserved,
+            info_hash,
+            peer_id,
+        })
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
     }
 
     pub fn as_bytes(&self) -> Bytes {
@@ -23,7 +87,7 @@ impl HandshakeRequest {
         protocol.copy_from_slice(BIT_TORRENT_PROTOCOL_STRING.as_bytes());
 
         handshake.extend_from_slice(&protocol);
-        handshake.extend_from_slice(&[0u8; 8]); // Reserved 8 bytes
+        handshake.extend_from_slice(&self.reserved);
         handshake.extend_from_slice(&self.info_hash);
         handshake.extend_from_slice(&self.peer_id);
         handshake.freeze()
@@ -34,13 +98,13 @@ impl HandshakeRequest {
             false
         } else {
             let protocol_len = bytes[0] as usize;
-            let bittorrent = std::str::from_utf8(&bytes[1..=19]).unwrap();
+            let bittorrent = &bytes[1..=19];
             let _reserved = &bytes[20..28];
             let info_hash = &bytes[28..48];
             // let peer_id = &bytes[48..68]; // The remote peer id
 
-            protocol_len == BIT_TORRENT_PROTOCOL_STRING.as_bytes().len()
-                && bittorrent == BIT_TORRENT_PROTOCOL_STRING
+            protocol_len == BIT_TORRENT_PROTOCOL_STRING.len()
+                && bittorrent == BIT_TORRENT_PROTOCOL_STRING.as_bytes()
                 // && _reserved == [0u8; 8] // it might be different from peer to peer protocol
                 && info_hash == self.info_hash
             // No need to compare the peer_id's, because each peer has it's own peer_id
@@ -62,4 +126,35 @@ mod tests {
         let request_content = handshake.as_bytes();
         assert_eq!(request_content.len(), HANDSHAKE_SIZE);
     }
+
+    #[test]
+    fn parse_remote_handshake() {
+        let handshake = HandshakeRequest::create([1u8; 20], [2u8; 20]).as_bytes();
+        let parsed = HandshakeRequest::from_bytes(&handshake).unwrap();
+        assert_eq!(parsed.info_hash(), [1u8; 20]);
+        assert_eq!(parsed.peer_id(), [2u8; 20]);
+        assert!(!parsed.supports_fast_extension());
+        assert!(!parsed.supports_extension_protocol());
+
+        let fast = HandshakeRequest::create([1u8; 20], [2u8; 20])
+            .with_fast_extension()
+            .as_bytes();
+        assert_eq!(fast[27], 0x04);
+        assert!(HandshakeRequest::from_bytes(&fast)
+            .unwrap()
+            .supports_fast_extension());
+
+        let extended = HandshakeRequest::create([1u8; 20], [2u8; 20])
+            .with_extension_protocol()
+            .as_bytes();
+        assert_eq!(extended[25], 0x10);
+        assert!(HandshakeRequest::from_bytes(&extended)
+            .unwrap()
+            .supports_extension_protocol());
+
+        let mut other_protocol = handshake.to_vec();
+        other_protocol[1] = b'b';
+        assert!(HandshakeRequest::from_bytes(&other_protocol).is_err());
+        assert!(HandshakeRequest::from_bytes(&handshake[..40]).is_err());
+    }
 }
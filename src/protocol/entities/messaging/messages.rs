@@ -24,7 +24,7 @@ pub enum PeerState {
 /// All of the remaining messages in the protocol take the form of
 /// `<length prefix><messageID><payload>`. The length prefix is a four byte big-endian value.
 /// The message ID is a single decimal byte. The payload is message dependent.
-#[derive(Debug, Eq, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd)]
 pub enum MessageType {
     /// keep-alive: <len=0000>
     ///      The keep-alive message is a message with zero bytes, specified with the length prefix
@@ -76,81 +76,271 @@ pub enum MessageType {
     ///     - index: integer specifying the zero-based piece index
     ///     - begin: integer specifying the zero-based byte offset within the piece
     ///     - block: block of data, which is a subset of the piece specified by index.
-    Piece,
+    Piece(u32, u32, Bytes),
 
     /// cancel: <len=0013><id=8><index><begin><length>. The cancel message is fixed length, and is
     /// used to cancel block requests. The payload is identical to that of the "request" message.
     /// It is typically used during "End Game"
-    Cancel,
+    Cancel(u32, u32, u32),
+
     /// port: <len=0003><id=9><listen-port>. The port message is sent by newer versions of the
     /// Mainline that implements a DHT tracker. The listen port is the port this peer's DHT node
     /// is listening on. This peer should be inserted in the local routing table
     /// (if DHT tracker is supported).
-    Port,
+    Port(u16),
+
+    /// suggest piece: <len=0005><id=13><piece index>. The fast extension (BEP 6) message, which
+    /// advises the peer to download the piece. It's advisory only.
+    SuggestPiece(u32),
+
+    /// have all: <len=0001><id=14>. The fast extension message, which replaces the bitfield
+    /// when the peer has all the pieces.
+    HaveAll,
+
+    /// have none: <len=0001><id=15>. The fast extension message, which replaces the bitfield
+    /// when the peer has no pieces.
+    HaveNone,
+
+    /// reject request: <len=0013><id=16><index><begin><length>. The fast extension message,
+    /// which tells that the requested block won't be sent. The payload is identical to that of
+    /// the "request" message.
+    RejectRequest(u32, u32, u32),
+
+    /// allowed fast: <len=0005><id=17><piece index>. The fast extension message, which tells
+    /// that the blocks of the piece are sent even if the peer is choked.
+    AllowedFast(u32),
+
+    /// extended: <len=0002+X><id=20><extended message id><payload>. The extension protocol
+    /// (BEP 10) message. The id 0 is the extension handshake, see
+    /// [crate::protocol::entities::ExtensionHandshake], the other ids are the ones the peer
+    /// has assigned to the extensions.
+    Extended(u8, Bytes),
 }
 
 impl MessageType {
     fn build_have_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Self, String> {
-        todo!()
+        let index = cursor
+            .read_u32::<BigEndian>()
+            .map_err(|e| format!("Malformed have message: {}", e))?;
+
+        Ok(MessageType::Have(index))
+    }
+
+    fn read_piece_index(cursor: &mut Cursor<&[u8]>) -> Result<u32, String> {
+        cursor
+            .read_u32::<BigEndian>()
+            .map_err(|e| format!("Malformed fast extension message: {}", e))
     }
 
     fn build_bitfield_from_cursor(cursor: &mut Cursor<&[u8]>, len: u32) -> Result<Self, String> {
-        let mut result = vec![false; len as usize];
+        if cursor.remaining() < len as usize {
+            return Err("Malformed bitfield message".to_string());
+        }
+
+        let mut result = Vec::with_capacity(len as usize * 8);
 
-        for item in &mut result {
-            *item = (cursor.get_u8() == u8::MAX);
+        for _ in 0..len {
+            let byte = cursor.get_u8();
+            // The high bit in the first byte corresponds to piece index 0
+            for bit in (0..8).rev() {
+                result.push(byte & (1 << bit) != 0);
+            }
         }
 
         Ok(MessageType::Bitfield(result))
     }
 
+    fn build_triple_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<(u32, u32, u32), String> {
+        let mut read = || {
+            cursor
+                .read_u32::<BigEndian>()
+                .map_err(|e| format!("Malformed block message: {}", e))
+        };
+
+        Ok((read()?, read()?, read()?))
+    }
+
     fn build_request_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Self, String> {
-        todo!()
+        let (index, begin, length) = MessageType::build_triple_from_cursor(cursor)?;
+        Ok(MessageType::Request(index, begin, length))
     }
 
     fn build_piece_from_cursor(cursor: &mut Cursor<&[u8]>, len: u32) -> Result<Self, String> {
-        todo!()
+        // The payload length is the message length without the id and two u32 fields
+        let block_len = len
+            .checked_sub(9)
+            .ok_or_else(|| "Malformed piece message".to_string())? as usize;
+
+        let index = cursor
+            .read_u32::<BigEndian>()
+            .map_err(|e| format!("Malformed piece message: {}", e))?;
+        let begin = cursor
+            .read_u32::<BigEndian>()
+            .map_err(|e| format!("Malformed piece message: {}", e))?;
+
+        if cursor.remaining() < block_len {
+            return Err("Malformed piece message: block is truncated".to_string());
+        }
+
+        let block = cursor.copy_to_bytes(block_len);
+        Ok(MessageType::Piece(index, begin, block))
     }
 
     fn build_cancel_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Self, String> {
-        todo!()
+        let (index, begin, length) = MessageType::build_triple_from_cursor(cursor)?;
+        Ok(MessageType::Cancel(index, begin, length))
     }
 
     fn build_port_from_cursor(cursor: &mut Cursor<&[u8]>) -> Result<Self, String> {
-        todo!()
+        let port = cursor
+            .read_u16::<BigEndian>()
+            .map_err(|e| format!("Malformed port message: {}", e))?;
+
+        Ok(MessageType::Port(port))
+    }
+
+    fn build_extended_from_cursor(cursor: &mut Cursor<&[u8]>, len: u32) -> Result<Self, String> {
+        // The payload length is the message length without the id and the extended id
+        let payload_len = len as usize - 2;
+        let id = cursor
+            .read_u8()
+            .map_err(|e| format!("Malformed extended message: {}", e))?;
+
+        if cursor.remaining() < payload_len {
+            return Err("Malformed extended message: payload is truncated".to_string());
+        }
+
+        Ok(MessageType::Extended(id, cursor.copy_to_bytes(payload_len)))
+    }
+
+    /// Returns the full frame length (length prefix included) of the first message in the
+    /// given buffer, or `None` if the buffer doesn't contain the length prefix yet.
+    pub fn frame_length(bytes: &[u8]) -> Option<usize> {
+        if bytes.len() < 4 {
+            None
+        } else {
+            let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            Some(length as usize + 4)
+        }
+    }
+
+    fn fixed(id: u8) -> Bytes {
+        let mut message = BytesMut::with_capacity(5);
+        message.put_u32(1); // len
+        message.put_u8(id);
+        message.freeze()
+    }
+
+    fn with_index(id: u8, index: u32) -> Bytes {
+        let mut message = BytesMut::with_capacity(9);
+        message.put_u32(5); // len
+        message.put_u8(id);
+        message.put_u32(index);
+        message.freeze()
+    }
+
+    fn triple(id: u8, index: u32, begin: u32, length: u32) -> Bytes {
+        let mut message = BytesMut::with_capacity(17);
+        message.put_u32(13); // len
+        message.put_u8(id);
+        message.put_u32(index);
+        message.put_u32(begin);
+        message.put_u32(length);
+        assert_eq!(message.len(), 17);
+        message.freeze()
     }
 
     pub fn to_bytes(&self) -> Bytes {
         match self {
-            MessageType::Interested => {
-                let mut interested = BytesMut::with_capacity(5);
-                interested.extend_from_slice(&[0, 0, 0, 0]);
-                interested.extend_from_slice(&[2]);
-                assert_eq!(interested.len(), 5);
-                interested.freeze()
+            MessageType::KeepAlive => Bytes::from_static(&[0, 0, 0, 0]),
+            MessageType::Choke => MessageType::fixed(0),
+            MessageType::Unchoke => MessageType::fixed(1),
+            MessageType::Interested => MessageType::fixed(2),
+            MessageType::NotInterested => MessageType::fixed(3),
+
+            MessageType::Have(index) => {
+                let mut have = BytesMut::with_capacity(9);
+                have.put_u32(5); // len
+                have.put_u8(4); // id
+                have.put_u32(*index);
+                have.freeze()
+            }
+
+            MessageType::Bitfield(pieces) => {
+                let payload: Vec<u8> = pieces
+                    .chunks(8)
+                    .map(|chunk| {
+                        chunk
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, have)| **have)
+                            .fold(0u8, |byte, (bit, _)| byte | (0x80 >> bit))
+                    })
+                    .collect();
+
+                let mut bitfield = BytesMut::with_capacity(5 + payload.len());
+                bitfield.put_u32(1 + payload.len() as u32); // len
+                bitfield.put_u8(5); // id
+                bitfield.extend_from_slice(&payload);
+                bitfield.freeze()
             }
 
-            MessageType::Request(index, start, len) => {
-                let mut request = BytesMut::with_capacity(17);
-                request.put_u32(13); // len
-                request.put_u8(6); // id
-                request.put_u32(*index);
-                request.put_u32(*start);
-                request.put_u32(*len);
-                assert_eq!(request.len(), 17);
-                request.freeze()
+            MessageType::Request(index, start, len) => MessageType::triple(6, *index, *start, *len),
+
+            MessageType::Piece(index, begin, block) => {
+                let mut piece = BytesMut::with_capacity(13 + block.len());
+                piece.put_u32(9 + block.len() as u32); // len
+                piece.put_u8(7); // id
+                piece.put_u32(*index);
+                piece.put_u32(*begin);
+                piece.extend_from_slice(block);
+                piece.freeze()
+            }
+
+            MessageType::Cancel(index, start, len) => MessageType::triple(8, *index, *start, *len),
+
+            MessageType::Port(port) => {
+                let mut message = BytesMut::with_capacity(7);
+                message.put_u32(3); // len
+                message.put_u8(9); // id
+                message.put_u16(*port);
+                message.freeze()
+            }
+
+            MessageType::SuggestPiece(index) => MessageType::with_index(13, *index),
+            MessageType::HaveAll => MessageType::fixed(14),
+            MessageType::HaveNone => MessageType::fixed(15),
+            MessageType::RejectRequest(index, start, len) => {
+                MessageType::triple(16, *index, *start, *len)
+            }
+            MessageType::AllowedFast(index) => MessageType::with_index(17, *index),
+
+            MessageType::Extended(id, payload) => {
+                let mut message = BytesMut::with_capacity(6 + payload.len());
+                message.put_u32(2 + payload.len() as u32); // len
+                message.put_u8(20); // id
+                message.put_u8(*id);
+                message.extend_from_slice(payload);
+                message.freeze()
             }
-            _ => todo!(),
         }
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
         let mut cursor = Cursor::new(bytes);
-        let length: u32 = cursor.read_u32::<BigEndian>().unwrap();
-        let id: u8 = cursor.read_u8().unwrap();
+        let length: u32 = cursor
+            .read_u32::<BigEndian>()
+            .map_err(|_| "Message length prefix is missing".to_string())?;
+
+        if length == 0 {
+            return Ok(Self::KeepAlive);
+        }
+
+        let id: u8 = cursor
+            .read_u8()
+            .map_err(|_| "Message id is missing".to_string())?;
 
         match (length, id) {
-            (0, _) => Ok(Self::KeepAlive),
             (1, 0) => Ok(Self::Choke),
             (1, 1) => Ok(Self::Unchoke),
             (1, 2) => Ok(Self::Interested),
@@ -161,6 +351,15 @@ impl MessageType {
             (len, 7) => MessageType::build_piece_from_cursor(&mut cursor, len),
             (13, 8) => MessageType::build_cancel_from_cursor(&mut cursor),
             (3, 9) => MessageType::build_port_from_cursor(&mut cursor),
+            (5, 13) => MessageType::read_piece_index(&mut cursor).map(Self::SuggestPiece),
+            (1, 14) => Ok(Self::HaveAll),
+            (1, 15) => Ok(Self::HaveNone),
+            (13, 16) => {
+                let (index, begin, length) = MessageType::build_triple_from_cursor(&mut cursor)?;
+                Ok(Self::RejectRequest(index, begin, length))
+            }
+            (5, 17) => MessageType::read_piece_index(&mut cursor).map(Self::AllowedFast),
+            (len, 20) if len >= 2 => MessageType::build_extended_from_cursor(&mut cursor, len),
             (_, _) => Err("Unsupported message type".to_string()),
         }
     }
@@ -169,12 +368,13 @@ impl MessageType {
 #[cfg(test)]
 mod tests {
     use crate::protocol::entities::MessageType;
+    use bytes::Bytes;
 
     #[test]
     fn test_build_interested_request() {
         let bytes = MessageType::Interested.to_bytes();
 
-        assert_eq!(bytes.to_vec(), vec![0, 0, 0, 0, 2]);
+        assert_eq!(bytes.to_vec(), vec![0, 0, 0, 1, 2]);
     }
 
     #[test]
@@ -188,7 +388,8 @@ mod tests {
 
         match bitfield {
             MessageType::Bitfield(bit) => {
-                let len = 24;
+                // 24 bytes of payload, one bit per piece
+                let len = 24 * 8;
                 let mut expected = vec![true; len];
                 expected[len - 1] = false;
 
@@ -204,4 +405,77 @@ mod tests {
 
         println!("{:?}", bytes.to_vec());
     }
+
+    #[test]
+    fn test_bitfield_roundtrip() {
+        let pieces = vec![false, true, true, false, false, true, false, false, true];
+        let bytes = MessageType::Bitfield(pieces.clone()).to_bytes();
+
+        assert_eq!(
+            bytes.to_vec(),
+            vec![0, 0, 0, 3, 5, 0b0110_0100, 0b1000_0000]
+        );
+
+        match MessageType::from_bytes(&bytes).unwrap() {
+            MessageType::Bitfield(bit) => assert_eq!(&bit[0..pieces.len()], pieces.as_slice()),
+            _ => panic!("Unexpected message type"),
+        }
+    }
+
+    #[test]
+    fn test_piece_and_cancel_roundtrip() {
+        let piece = MessageType::Piece(3, 16384, Bytes::from_static(&[1, 2, 3, 4]));
+        let bytes = piece.to_bytes();
+
+        assert_eq!(MessageType::frame_length(&bytes), Some(bytes.len()));
+        assert_eq!(MessageType::from_bytes(&bytes).unwrap(), piece);
+
+        let cancel = MessageType::Cancel(3, 16384, 16384);
+        assert_eq!(MessageType::from_bytes(&cancel.to_bytes()).unwrap(), cancel);
+    }
+
+    #[test]
+    fn test_fast_extension_roundtrip() {
+        assert_eq!(
+            MessageType::HaveAll.to_bytes().to_vec(),
+            vec![0, 0, 0, 1, 14]
+        );
+        assert_eq!(
+            MessageType::AllowedFast(258).to_bytes().to_vec(),
+            vec![0, 0, 0, 5, 17, 0, 0, 1, 2]
+        );
+
+        for message in [
+            MessageType::SuggestPiece(7),
+            MessageType::HaveAll,
+            MessageType::HaveNone,
+            MessageType::RejectRequest(1, 16384, 16384),
+            MessageType::AllowedFast(3),
+        ] {
+            assert_eq!(
+                MessageType::from_bytes(&message.to_bytes()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_extended_roundtrip() {
+        let extended = MessageType::Extended(0, Bytes::from_static(b"de"));
+        let bytes = extended.to_bytes();
+
+        assert_eq!(bytes.to_vec(), vec![0, 0, 0, 4, 20, 0, b'd', b'e']);
+        assert_eq!(MessageType::from_bytes(&bytes).unwrap(), extended);
+        assert!(MessageType::from_bytes(&[0, 0, 0, 1, 20]).is_err());
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        assert!(MessageType::from_bytes(&[0, 0]).is_err());
+        assert!(MessageType::from_bytes(&[0, 0, 0, 13, 7, 0, 0, 0, 1, 0, 0, 0, 0, 1]).is_err());
+        assert_eq!(
+            MessageType::from_bytes(&[0, 0, 0, 0]).unwrap(),
+            MessageType::KeepAlive
+        );
+    }
 }
@@ -1,9 +1,11 @@
 mod announce;
+mod extension;
 mod handshake;
 mod messages;
 mod requests;
 
 pub use announce::*;
+pub use extension::*;
 pub use handshake::*;
 pub use messages::*;
 pub use requests::*;
@@ -4,10 +4,11 @@ use std::convert::TryFrom;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use url::Url;
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+#[derive(Debug, Default, Eq, PartialEq, Hash)]
 pub enum TrackerProtocol {
     HTTP,
     TCP,
+    #[default]
     UDP,
     WSS,
 }
@@ -48,12 +49,6 @@ impl Display for TrackerProtocol {
     }
 }
 
-impl Default for TrackerProtocol {
-    fn default() -> Self {
-        Self::UDP
-    }
-}
-
 #[derive(Debug)]
 pub struct TrackerUrl {
     pub protocol: TrackerProtocol,
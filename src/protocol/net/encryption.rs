@@ -0,0 +1,648 @@
+use crate::protocol::net::PeerStream;
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The prime of the Diffie-Hellman key exchange, the generator is 2
+const DH_PRIME: &str =
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22\
+    514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36\
+    210000000000090563";
+
+/// The length of the public keys and of the shared secret
+const KEY_LENGTH: usize = 96;
+
+/// The longest padding allowed by the protocol
+const MAX_PADDING: usize = 512;
+
+/// The verification constant, it's sent encrypted so the peers can find the start of the
+/// encrypted data after the random padding
+const VC: [u8; 8] = [0; 8];
+
+/// The RC4 key stream starts with the weak bytes, so the first 1 KiB of it is dropped
+const RC4_DISCARD: usize = 1024;
+
+/// The payload goes in plaintext after the encrypted handshake, see [EncryptionPolicy]
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+
+/// The payload is encrypted with RC4
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether the peer connections use the Message Stream Encryption. Even if the payload goes in
+/// plaintext, the encrypted handshake hides the connection from the traffic shaping, which
+/// looks for the plaintext BitTorrent handshake.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EncryptionPolicy {
+    /// Plaintext connections only
+    Disabled,
+    /// Both kinds of connections. An outgoing connection tries the encryption first and
+    /// reconnects in plaintext if the peer doesn't support it.
+    Enabled,
+    /// Encrypted connections only, the payload is always encrypted with RC4
+    Forced,
+}
+
+impl EncryptionPolicy {
+    /// The methods offered to the peer we connect to
+    pub fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => 0,
+            EncryptionPolicy::Enabled => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+        }
+    }
+
+    /// Picks one of the methods offered by the peer connected to us, RC4 is preferred
+    pub fn crypto_select(self, provide: u32) -> Option<u32> {
+        match self {
+            EncryptionPolicy::Disabled => None,
+            _ if provide & CRYPTO_RC4 != 0 => Some(CRYPTO_RC4),
+            EncryptionPolicy::Enabled if provide & CRYPTO_PLAINTEXT != 0 => Some(CRYPTO_PLAINTEXT),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            _ => Err(format!(
+                "Unknown encryption policy {}, expected disabled, enabled or forced",
+                value
+            )),
+        }
+    }
+}
+
+impl Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let name = match self {
+            EncryptionPolicy::Disabled => "disabled",
+            EncryptionPolicy::Enabled => "enabled",
+            EncryptionPolicy::Forced => "forced",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The RC4 stream cipher. The same operation encrypts and decrypts the data.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// The cipher of the handshake: the key is derived from the shared secret and the info hash,
+    /// and the weak start of the key stream is dropped
+    fn handshake(name: &[u8], secret: &[u8; KEY_LENGTH], info_hash: &[u8; 20]) -> Self {
+        let mut cipher = Rc4::new(&hash(&[name, secret, info_hash]));
+        cipher.apply(&mut [0u8; RC4_DISCARD]);
+        cipher
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// The Diffie-Hellman key pair of a single handshake
+struct DhKey {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl DhKey {
+    fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(2u32).modpow(&private, &prime());
+        DhKey {
+            private,
+            public: to_key(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote: &[u8; KEY_LENGTH]) -> Result<[u8; KEY_LENGTH], String> {
+        let prime = prime();
+        let remote = BigUint::from_bytes_be(remote);
+        if remote <= BigUint::from(1u32) || remote >= &prime - 1u32 {
+            return Err("Invalid public key of the peer".to_string());
+        }
+
+        Ok(to_key(&remote.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME.as_bytes(), 16).expect("Invalid prime")
+}
+
+/// Pads the number with zeros up to the key length
+fn to_key(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut result = a;
+    for (x, y) in result.iter_mut().zip(b) {
+        *x ^= y;
+    }
+    result
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let length = rng.gen_range(0..=MAX_PADDING);
+    (0..length).map(|_| rng.gen()).collect()
+}
+
+fn io_error(e: io::Error) -> String {
+    format!("Encrypted handshake failed: {}", e)
+}
+
+/// Reads the stream until the given bytes are found, the bytes before them are skipped. Gives
+/// up after the longest padding.
+async fn synchronize<S>(stream: &mut S, pattern: &[u8]) -> Result<(), String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut window = vec![0u8; pattern.len()];
+    stream.read_exact(&mut window).await.map_err(io_error)?;
+
+    for _ in 0..MAX_PADDING {
+        if window == pattern {
+            return Ok(());
+        }
+
+        window.remove(0);
+        window.push(stream.read_u8().await.map_err(io_error)?);
+    }
+
+    if window == pattern {
+        Ok(())
+    } else {
+        Err("The peer doesn't support the encryption".to_string())
+    }
+}
+
+/// Reads and decrypts the given number of bytes
+async fn read_encrypted<S>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    length: usize,
+) -> Result<Vec<u8>, String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut data = vec![0u8; length];
+    stream.read_exact(&mut data).await.map_err(io_error)?;
+    cipher.apply(&mut data);
+    Ok(data)
+}
+
+/// Makes the encrypted handshake with the peer we connect to, offering the given methods, see
+/// [CRYPTO_PLAINTEXT] and [CRYPTO_RC4]. The BitTorrent handshake goes through the returned
+/// stream then.
+pub async fn initiate_encryption<S>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    crypto_provide: u32,
+) -> Result<EncryptedStream<S>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = DhKey::generate();
+    let mut message = key.public.to_vec();
+    message.extend(random_padding());
+    stream.write_all(&message).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let mut remote = [0u8; KEY_LENGTH];
+    stream.read_exact(&mut remote).await.map_err(io_error)?;
+    let secret = key.shared_secret(&remote)?;
+    let mut encrypt = Rc4::handshake(b"keyA", &secret, &info_hash);
+    let mut decrypt = Rc4::handshake(b"keyB", &secret, &info_hash);
+
+    // The padding isn't needed, the public key has been padded already. The initial payload is
+    // empty, the BitTorrent handshake follows the negotiation.
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", &info_hash]), hash(&[b"req3", &secret])));
+    let mut negotiation = VC.to_vec();
+    negotiation.extend(crypto_provide.to_be_bytes());
+    negotiation.extend(0u16.to_be_bytes());
+    negotiation.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut negotiation);
+    message.extend(negotiation);
+    stream.write_all(&message).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    synchronize(&mut stream, &vc).await?;
+    decrypt.apply(&mut VC.clone());
+
+    let reply = read_encrypted(&mut stream, &mut decrypt, 6).await?;
+    let crypto_select = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+    let padding = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if padding > MAX_PADDING {
+        return Err("Too long padding of the encrypted handshake".to_string());
+    }
+    read_encrypted(&mut stream, &mut decrypt, padding).await?;
+
+    match crypto_select {
+        CRYPTO_RC4 if crypto_provide & CRYPTO_RC4 != 0 => {
+            Ok(EncryptedStream::encrypted(stream, encrypt, decrypt, vec![]))
+        }
+        CRYPTO_PLAINTEXT if crypto_provide & CRYPTO_PLAINTEXT != 0 => {
+            Ok(EncryptedStream::plaintext(stream, vec![]))
+        }
+        _ => Err(format!(
+            "The peer selected an unsupported encryption method {}",
+            crypto_select
+        )),
+    }
+}
+
+/// Answers the encrypted handshake of the peer connected to us. The `received` bytes were
+/// already read from the stream to tell the encrypted handshake from the plaintext one. The
+/// torrent is found among the given info hashes. Returns the stream and the info hash.
+pub async fn accept_encryption<S>(
+    mut stream: S,
+    received: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(EncryptedStream<S>, [u8; 20]), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if received.len() > KEY_LENGTH {
+        return Err("Invalid encrypted handshake".to_string());
+    }
+
+    let mut remote = [0u8; KEY_LENGTH];
+    remote[..received.len()].copy_from_slice(received);
+    stream
+        .read_exact(&mut remote[received.len()..])
+        .await
+        .map_err(io_error)?;
+
+    let key = DhKey::generate();
+    let mut message = key.public.to_vec();
+    message.extend(random_padding());
+    stream.write_all(&message).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let secret = key.shared_secret(&remote)?;
+    synchronize(&mut stream, &hash(&[b"req1", &secret])).await?;
+
+    let mut obfuscated = [0u8; 20];
+    stream.read_exact(&mut obfuscated).await.map_err(io_error)?;
+    let req2 = xor(obfuscated, hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == req2)
+        .ok_or_else(|| "Unknown info hash".to_string())?;
+
+    let mut decrypt = Rc4::handshake(b"keyA", &secret, &info_hash);
+    let mut encrypt = Rc4::handshake(b"keyB", &secret, &info_hash);
+    let request = read_encrypted(&mut stream, &mut decrypt, 14).await?;
+    if request[..8] != VC {
+        return Err("Invalid verification constant".to_string());
+    }
+
+    let crypto_provide = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+    let padding = u16::from_be_bytes([request[12], request[13]]) as usize;
+    if padding > MAX_PADDING {
+        return Err("Too long padding of the encrypted handshake".to_string());
+    }
+    read_encrypted(&mut stream, &mut decrypt, padding).await?;
+
+    // The initial payload is the beginning of the stream, usually the BitTorrent handshake
+    let length = read_encrypted(&mut stream, &mut decrypt, 2).await?;
+    let initial = read_encrypted(
+        &mut stream,
+        &mut decrypt,
+        u16::from_be_bytes([length[0], length[1]]) as usize,
+    )
+    .await?;
+
+    let crypto_select = policy
+        .crypto_select(crypto_provide)
+        .ok_or_else(|| "No acceptable encryption method offered".to_string())?;
+    let mut reply = VC.to_vec();
+    reply.extend(crypto_select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let stream = if crypto_select == CRYPTO_RC4 {
+        EncryptedStream::encrypted(stream, encrypt, decrypt, initial)
+    } else {
+        EncryptedStream::plaintext(stream, initial)
+    };
+    Ok((stream, info_hash))
+}
+
+/// A peer connection, which encrypts the data with RC4 once the encrypted handshake has
+/// selected it. The data goes as is otherwise.
+pub struct EncryptedStream<S = PeerStream> {
+    inner: S,
+    /// The decrypted data already read from the stream, it's returned first
+    received: BytesMut,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    /// The encrypted data not written to the stream yet
+    pending: BytesMut,
+}
+
+impl<S> EncryptedStream<S> {
+    /// The `received` data was read from the stream already, e.g. to tell the plaintext
+    /// handshake from the encrypted one
+    pub fn plaintext(inner: S, received: Vec<u8>) -> Self {
+        EncryptedStream {
+            inner,
+            received: BytesMut::from(&received[..]),
+            encrypt: None,
+            decrypt: None,
+            pending: BytesMut::new(),
+        }
+    }
+
+    fn encrypted(inner: S, encrypt: Rc4, decrypt: Rc4, received: Vec<u8>) -> Self {
+        EncryptedStream {
+            encrypt: Some(encrypt),
+            decrypt: Some(decrypt),
+            ..EncryptedStream::plaintext(inner, received)
+        }
+    }
+
+    /// Returns true if the payload is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Debug> Debug for EncryptedStream<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("EncryptedStream")
+            .field("inner", &self.inner)
+            .field("encrypted", &self.is_encrypted())
+            .finish()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> EncryptedStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EncryptedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.received.is_empty() {
+            let length = this.received.len().min(buf.remaining());
+            buf.put_slice(&this.received.split_to(length));
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.decrypt {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+
+        // The data is encrypted once, so the encrypted bytes are kept until they are written
+        ready!(this.poll_pending(cx))?;
+        let mut encrypted = data.to_vec();
+        if let Some(cipher) = &mut this.encrypt {
+            cipher.apply(&mut encrypted);
+        }
+        this.pending.extend_from_slice(&encrypted);
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    #[test]
+    fn rc4_test_vectors() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(
+            data,
+            [0x45, 0xa0, 0x1f, 0x64, 0x5f, 0xc3, 0x5b, 0x38, 0x35, 0x52, 0x54, 0x4b, 0x9b, 0xf5]
+        );
+    }
+
+    #[test]
+    fn diffie_hellman_agrees_on_secret() {
+        let (a, b) = (DhKey::generate(), DhKey::generate());
+        assert_eq!(
+            a.shared_secret(&b.public).unwrap(),
+            b.shared_secret(&a.public).unwrap()
+        );
+        assert!(a.shared_secret(&[0u8; KEY_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn crypto_negotiation() {
+        use EncryptionPolicy::*;
+        assert_eq!(
+            Enabled.crypto_select(CRYPTO_PLAINTEXT | CRYPTO_RC4),
+            Some(CRYPTO_RC4)
+        );
+        assert_eq!(
+            Enabled.crypto_select(CRYPTO_PLAINTEXT),
+            Some(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(Forced.crypto_select(CRYPTO_PLAINTEXT), None);
+        assert_eq!(
+            Forced.crypto_select(Forced.crypto_provide()),
+            Some(CRYPTO_RC4)
+        );
+        assert_eq!(Disabled.crypto_select(CRYPTO_RC4), None);
+        assert_eq!("forced".parse::<EncryptionPolicy>(), Ok(Forced));
+    }
+
+    /// Runs both sides of the handshake over an in-memory pipe, the answering side gets the
+    /// first bytes separately as the listener does
+    async fn negotiate(
+        crypto_provide: u32,
+        policy: EncryptionPolicy,
+    ) -> Result<
+        (
+            EncryptedStream<tokio::io::DuplexStream>,
+            EncryptedStream<tokio::io::DuplexStream>,
+        ),
+        String,
+    > {
+        let (client, mut server) = duplex(4096);
+        let outgoing = tokio::spawn(initiate_encryption(client, INFO_HASH, crypto_provide));
+
+        let mut first = [0u8; 20];
+        server.read_exact(&mut first).await.unwrap();
+        let incoming = accept_encryption(server, &first, &[[1; 20], INFO_HASH], policy).await;
+        let outgoing = outgoing.await.unwrap();
+
+        let (incoming, info_hash) = incoming?;
+        assert_eq!(info_hash, INFO_HASH);
+        Ok((outgoing?, incoming))
+    }
+
+    #[tokio::test]
+    async fn encrypted_streams_exchange_data() {
+        let (mut client, mut server) =
+            negotiate(CRYPTO_PLAINTEXT | CRYPTO_RC4, EncryptionPolicy::Enabled)
+                .await
+                .unwrap();
+        assert!(client.is_encrypted() && server.is_encrypted());
+
+        client.write_all(b"BitTorrent protocol").await.unwrap();
+        client.flush().await.unwrap();
+        let mut received = [0u8; 19];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"BitTorrent protocol");
+
+        server.write_all(b"reply").await.unwrap();
+        server.flush().await.unwrap();
+        let mut received = [0u8; 5];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"reply");
+    }
+
+    #[tokio::test]
+    async fn plaintext_payload_after_encrypted_handshake() {
+        let (client, server) = negotiate(CRYPTO_PLAINTEXT, EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert!(!client.is_encrypted() && !server.is_encrypted());
+
+        assert!(negotiate(CRYPTO_PLAINTEXT, EncryptionPolicy::Forced)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_torrent_is_refused() {
+        let (client, mut server) = duplex(4096);
+        let outgoing = tokio::spawn(initiate_encryption(client, [7; 20], CRYPTO_RC4));
+
+        let mut first = [0u8; 20];
+        server.read_exact(&mut first).await.unwrap();
+        let incoming = accept_encryption(server, &first, &[INFO_HASH], EncryptionPolicy::Enabled);
+        assert!(incoming.await.is_err());
+        assert!(outgoing.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_peer_is_detected() {
+        let (client, mut server) = duplex(4096);
+        let outgoing = tokio::spawn(initiate_encryption(client, INFO_HASH, CRYPTO_RC4));
+
+        // A peer not supporting the encryption answers with its own handshake and waits
+        let mut request = [0u8; 68];
+        server.read_exact(&mut request).await.unwrap();
+        server.write_all(&[19u8; 1024]).await.unwrap();
+        assert!(outgoing.await.unwrap().is_err());
+    }
+}
@@ -1,21 +1,25 @@
 use crate::protocol::entities::*;
 use crate::protocol::net::{NetworkClient, Peer};
+use async_trait::async_trait;
 
 #[derive(Debug, Default)]
 pub struct HttpClient {}
 
 impl HttpClient {}
 
+#[async_trait]
 impl NetworkClient for HttpClient {
-    fn obtain_connection_id(&self, _tracker: &TrackerUrl) -> Result<i64, String> {
-        unimplemented!()
+    async fn obtain_connection_id(&self, _tracker: &TrackerUrl) -> Result<i64, String> {
+        Err("HTTP trackers are not supported yet".to_string())
     }
 
-    fn get_peers_list(
+    async fn get_peers_list(
         &self,
         _torrent: &Torrent,
         _tracker_url: &TrackerUrl,
+        _peer_id: [u8; 20],
+        _port: u16,
     ) -> Result<Vec<Peer>, String> {
-        unimplemented!()
+        Err("HTTP trackers are not supported yet".to_string())
     }
 }
@@ -1,12 +1,21 @@
 mod download_from_peer;
+mod encryption;
 mod http_client;
 mod network_client;
+mod peer_connection;
+mod transport;
 mod udp_client;
+mod utp;
 
+pub use encryption::*;
 pub use http_client::*;
 pub use network_client::*;
+pub use peer_connection::*;
 use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+pub use transport::{PeerStream, Transport};
 pub use udp_client::*;
+pub use utp::{UtpSocket, UtpStream};
 
 #[derive(Debug)]
 pub struct Peer {
@@ -21,9 +30,15 @@ impl Display for Peer {
 }
 
 impl Peer {
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        format!("{}", self)
+            .parse()
+            .map_err(|e| format!("Unable create Socket address {}", e))
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Peer>, String> {
         let mut peers: Vec<Peer> = vec![];
-        if bytes.len() % 6 != 0 {
+        if !bytes.len().is_multiple_of(6) {
             return Err("Malformed byte array".to_string());
         }
 
@@ -1,8 +1,18 @@
 use crate::protocol::entities::{Torrent, TrackerUrl};
 use crate::protocol::net::Peer;
+use async_trait::async_trait;
 
-pub trait NetworkClient {
-    fn obtain_connection_id(&self, tracker: &TrackerUrl) -> Result<i64, String>;
+#[async_trait]
+pub trait NetworkClient: Send + Sync {
+    async fn obtain_connection_id(&self, tracker: &TrackerUrl) -> Result<i64, String>;
 
-    fn get_peers_list(&self, torrent: &Torrent, tracker: &TrackerUrl) -> Result<Vec<Peer>, String>;
+    /// Announces the torrent to the tracker and returns the peers. The peers are told to
+    /// connect to us on the given port.
+    async fn get_peers_list(
+        &self,
+        torrent: &Torrent,
+        tracker: &TrackerUrl,
+        peer_id: [u8; 20],
+        port: u16,
+    ) -> Result<Vec<Peer>, String>;
 }
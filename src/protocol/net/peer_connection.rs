@@ -0,0 +1,168 @@
+use crate::protocol::entities::{HandshakeRequest, MessageType, HANDSHAKE_SIZE};
+use crate::protocol::net::{initiate_encryption, EncryptedStream, EncryptionPolicy, Transport};
+use bytes::{Buf, BytesMut};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// The longest message we're ready to accept from a peer. The biggest legitimate message is the
+/// piece message with a 16 KiB block, but some clients send bigger blocks, and the bitfield of
+/// a huge torrent might be long as well.
+pub const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+/// Splits the byte stream of a peer connection into separate messages
+#[derive(Debug, Default)]
+pub struct PeerCodec {}
+
+impl Decoder for PeerCodec {
+    type Item = MessageType;
+    type Error = Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match MessageType::frame_length(buffer) {
+            Some(length) if length > MAX_MESSAGE_LENGTH => {
+                Err(Error::new(ErrorKind::InvalidData, "Too long message"))
+            }
+            Some(length) if length <= buffer.len() => {
+                let message = MessageType::from_bytes(&buffer[0..length]);
+                buffer.advance(length);
+                message
+                    .map(Some)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+            Some(length) => {
+                buffer.reserve(length - buffer.len());
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<MessageType> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: MessageType, buffer: &mut BytesMut) -> Result<(), Self::Error> {
+        buffer.extend_from_slice(&message.to_bytes());
+        Ok(())
+    }
+}
+
+/// A connection with a remote peer, which has passed the handshake
+pub type PeerConnection<S = EncryptedStream> = Framed<S, PeerCodec>;
+
+/// Sends our handshake, which advertises the supported extensions, and waits for the peer's
+/// one. Returns the handshake of the remote peer.
+pub async fn handshake<S>(
+    stream: &mut S,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Result<HandshakeRequest, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = HandshakeRequest::create(info_hash, peer_id)
+        .with_fast_extension()
+        .with_extension_protocol();
+    stream
+        .write_all(&handshake.as_bytes())
+        .await
+        .map_err(|e| format!("Unable to write to TCP connection: {}", e))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| format!("Unable to write to TCP connection: {}", e))?;
+
+    let mut response = [0u8; HANDSHAKE_SIZE];
+    stream
+        .read_exact(&mut response)
+        .await
+        .map_err(|e| format!("Unable to read from TCP connection: {}", e))?;
+
+    if !handshake.is_valid_response(&response) {
+        return Err("Invalid response from peer".to_string());
+    }
+
+    HandshakeRequest::from_bytes(&response)
+}
+
+/// Waits for the handshake of an incoming connection and answers it, if we run the torrent.
+/// The `peer_id` gives our peer id for the info hash, or `None` if the torrent is unknown.
+/// Returns the handshake of the remote peer.
+pub async fn accept_handshake<S>(
+    stream: &mut S,
+    peer_id: impl FnOnce(&[u8; 20]) -> Option<[u8; 20]>,
+) -> Result<HandshakeRequest, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = [0u8; HANDSHAKE_SIZE];
+    stream
+        .read_exact(&mut request)
+        .await
+        .map_err(|e| format!("Unable to read from TCP connection: {}", e))?;
+
+    let remote = HandshakeRequest::from_bytes(&request)?;
+    let info_hash = remote.info_hash();
+    let our_id = peer_id(&info_hash).ok_or_else(|| "Unknown info hash".to_string())?;
+
+    let handshake = HandshakeRequest::create(info_hash, our_id)
+        .with_fast_extension()
+        .with_extension_protocol();
+    stream
+        .write_all(&handshake.as_bytes())
+        .await
+        .map_err(|e| format!("Unable to write to TCP connection: {}", e))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| format!("Unable to write to TCP connection: {}", e))?;
+
+    Ok(remote)
+}
+
+/// Opens a connection to the peer, over uTP or TCP as the transport decides, and makes the
+/// handshake. The connection is encrypted according to the policy: with
+/// [EncryptionPolicy::Enabled] the peer that fails the encrypted handshake is connected once
+/// more in plaintext. Every step is limited by the timeout. Returns the connection and the
+/// handshake of the remote peer.
+pub async fn connect(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    encryption: EncryptionPolicy,
+    transport: &Transport,
+    connect_timeout: Duration,
+) -> Result<(PeerConnection, HandshakeRequest), String> {
+    let stream = transport.connect(addr, connect_timeout).await?;
+    let mut stream = match encryption {
+        EncryptionPolicy::Disabled => EncryptedStream::plaintext(stream, vec![]),
+        _ => {
+            let encrypted = timeout(
+                connect_timeout,
+                initiate_encryption(stream, info_hash, encryption.crypto_provide()),
+            )
+            .await
+            .unwrap_or_else(|_| Err("Encrypted handshake timed out".to_string()));
+
+            match encrypted {
+                Ok(stream) => stream,
+                Err(e) if encryption == EncryptionPolicy::Forced => {
+                    return Err(format!("Unable to encrypt connection to {}: {}", addr, e))
+                }
+                Err(_) => {
+                    let stream = transport.connect(addr, connect_timeout).await?;
+                    EncryptedStream::plaintext(stream, vec![])
+                }
+            }
+        }
+    };
+
+    let remote = timeout(connect_timeout, handshake(&mut stream, info_hash, peer_id))
+        .await
+        .map_err(|_| format!("Handshake with {} timed out", addr))??;
+    Ok((Framed::new(stream, PeerCodec::default()), remote))
+}
@@ -0,0 +1,152 @@
+use crate::protocol::net::{UtpSocket, UtpStream};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// The byte stream of a peer connection, the rest of the peer protocol doesn't depend on it
+#[derive(Debug)]
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerStream {
+    pub fn is_utp(&self) -> bool {
+        matches!(self, PeerStream::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens the connections to the peers. With uTP enabled a peer is connected over uTP first,
+/// and over TCP if it doesn't answer. Such a peer is connected over TCP right away next time.
+/// It also knows the port the peers connect to us on, which is told to the trackers and peers.
+#[derive(Clone, Default)]
+pub struct Transport {
+    utp_enabled: bool,
+    utp: Arc<Mutex<Option<UtpSocket>>>,
+    /// Zero until the listener is bound
+    listen_port: Arc<AtomicU16>,
+}
+
+impl Transport {
+    pub fn new(utp_enabled: bool) -> Self {
+        Transport {
+            utp_enabled,
+            utp: Arc::default(),
+            listen_port: Arc::default(),
+        }
+    }
+
+    /// The port the listener has bound, if it's listening
+    pub fn listen_port(&self) -> Option<u16> {
+        match self.listen_port.load(Ordering::Relaxed) {
+            0 => None,
+            port => Some(port),
+        }
+    }
+
+    pub fn set_listen_port(&self, port: u16) {
+        self.listen_port.store(port, Ordering::Relaxed);
+    }
+
+    fn utp(&self) -> MutexGuard<'_, Option<UtpSocket>> {
+        self.utp.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sets the socket the uTP connections are opened from. It's the listening one usually,
+    /// otherwise a socket is bound to a random port on the first connection.
+    pub fn set_utp_socket(&self, socket: UtpSocket) {
+        *self.utp() = Some(socket);
+    }
+
+    fn utp_socket(&self) -> Result<UtpSocket, String> {
+        let mut utp = self.utp();
+        match utp.as_ref() {
+            Some(socket) => Ok(socket.clone()),
+            None => {
+                let socket = UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                *utp = Some(socket.clone());
+                Ok(socket)
+            }
+        }
+    }
+
+    fn utp_reachable(&self, socket: &UtpSocket, addr: &SocketAddr) -> bool {
+        let same_family = socket
+            .local_addr()
+            .is_ok_and(|local| local.is_ipv4() == addr.is_ipv4());
+        same_family && !socket.is_unreachable(addr)
+    }
+
+    /// Connects to the peer, every attempt is limited by the timeout
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        connect_timeout: Duration,
+    ) -> Result<PeerStream, String> {
+        if self.utp_enabled {
+            match self.utp_socket() {
+                Ok(socket) if self.utp_reachable(&socket, &addr) => {
+                    match timeout(connect_timeout, socket.connect(addr)).await {
+                        Ok(Ok(stream)) => return Ok(PeerStream::Utp(stream)),
+                        _ => socket.set_unreachable(addr),
+                    }
+                }
+                Ok(_) => {}
+                Err(msg) => println!("{}, connecting over TCP", msg),
+            }
+        }
+
+        let stream = timeout(connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format!("Connection to {} timed out", addr))?
+            .map_err(|e| format!("Unable open TCP connection to host {}", e))?;
+        Ok(PeerStream::Tcp(stream))
+    }
+}
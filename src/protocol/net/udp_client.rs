@@ -1,16 +1,29 @@
-use crate::engine::generate_peer_id;
 use crate::protocol::entities::*;
 use crate::protocol::net::{NetworkClient, Peer};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use async_trait::async_trait;
+use std::net::SocketAddr;
 use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
 
 const DEFAULT_BUFFER_SIZE: usize = 32767;
 
 #[derive(Debug, Default)]
 pub struct UdpClient {}
 
+/// UDP is `unreliable` protocol, so we don't wait for the tracker response forever
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn resolve_tracker_address(tracker: &TrackerUrl) -> Result<SocketAddr, String> {
+    lookup_host(format!("{}:{}", tracker.url, tracker.port))
+        .await
+        .map_err(|e| format!("Unable create remote host address: {}", e))?
+        .next()
+        .ok_or_else(|| format!("Unable resolve tracker address {}", tracker.url))
+}
+
 impl UdpClient {
-    fn make_request(
+    async fn make_request(
         &self,
         request_content: &[u8],
         tracker: &TrackerUrl,
@@ -23,10 +36,7 @@ impl UdpClient {
             ));
         }
 
-        let remote_address: SocketAddr = format!("{}:{}", tracker.url, tracker.port)
-            .to_socket_addrs()
-            .expect("Unable create remote host address")
-            .as_slice()[0];
+        let remote_address: SocketAddr = resolve_tracker_address(tracker).await?;
 
         // We'll bind our UDP socket to a local IP/port, but for now we basically let the OS
         // pick both of those.
@@ -36,37 +46,35 @@ impl UdpClient {
             "[::]:0"
         };
 
-        let socket = UdpSocket::bind(bind_addr).expect("Unable open UDP socket");
-
-        socket
-            .set_read_timeout(Some(Duration::from_secs(10)))
-            .map_err(|e| format!("{}", e))?;
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|e| format!("Unable open UDP socket: {}", e))?;
 
         let _ = socket
             .send_to(request_content, remote_address)
+            .await
             .map_err(|e| format!("{}", e))?;
         let mut buffer = [0u8; DEFAULT_BUFFER_SIZE];
 
-        let (size, _) = socket
-            .recv_from(&mut buffer)
+        let (size, _) = timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| "Tracker response timeout".to_string())?
             .map_err(|e| format!("{}", e))?;
 
         Ok(buffer[0..size].to_vec())
     }
 }
 
+#[async_trait]
 impl NetworkClient for UdpClient {
-    fn obtain_connection_id(&self, tracker: &TrackerUrl) -> Result<i64, String> {
+    async fn obtain_connection_id(&self, tracker: &TrackerUrl) -> Result<i64, String> {
         // generating a default connection request structure
         let request = ConnectionRequest::default();
         // convert request body to binary array
         let request_content = bincode::serialize(&request).unwrap();
 
         // create a socket address to the tracker
-        let remote_address: SocketAddr = format!("{}:{}", tracker.url, tracker.port)
-            .to_socket_addrs()
-            .expect("Unable create remote host address")
-            .as_slice()[0];
+        let remote_address: SocketAddr = resolve_tracker_address(tracker).await?;
 
         // We'll bind our UDP socket to a local IP/port,
         // but for now we basically let the OS pick both of those.
@@ -76,16 +84,14 @@ impl NetworkClient for UdpClient {
         };
 
         // Open an udp socket
-        let socket = UdpSocket::bind(bind_addr).expect("Unable open UDP socket");
-
-        // set timeout for the udp protocol, as udp is `unreliable` protocol
-        socket
-            .set_read_timeout(Some(Duration::from_secs(10)))
-            .map_err(|e| format!("{}", e))?;
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|e| format!("Unable open UDP socket: {}", e))?;
 
         // send the request
         let send_bytes = socket
             .send_to(&request_content, remote_address)
+            .await
             .map_err(|e| format!("{}", e))?;
 
         // make sure the number of bytes sent is the same as the number of bytes in request body
@@ -93,9 +99,11 @@ impl NetworkClient for UdpClient {
 
         let mut buffer = [0u8; DEFAULT_BUFFER_SIZE];
 
-        // read response from the Tracker to buffer
-        let (size, _) = socket
-            .recv_from(&mut buffer)
+        // read response from the Tracker to buffer, the timeout is set as udp is `unreliable`
+        // protocol
+        let (size, _) = timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| "Tracker response timeout".to_string())?
             .map_err(|e| format!("{}", e))?;
 
         let response_content = &buffer[0..size];
@@ -104,35 +112,36 @@ impl NetworkClient for UdpClient {
         let response: ConnectionResponse =
             bincode::deserialize(response_content).map_err(|e| format!("{}", e))?;
 
-        assert_eq!(request.transaction_id, response.transaction_id);
-        assert_eq!(request.action, response.action);
+        if request.transaction_id != response.transaction_id || request.action != response.action {
+            return Err("Unexpected response from the tracker".to_string());
+        }
         // IDK why, but all torrent tracker never return back this magic number
         // assert_eq!(request.protocol_id, 4497486125440);
 
-        let connection_id = response.connection_id;
-        dbg!(connection_id);
-
-        Ok(connection_id)
+        Ok(response.connection_id)
     }
 
-    fn get_peers_list(
+    async fn get_peers_list(
         &self,
         torrent: &Torrent,
         tracker_url: &TrackerUrl,
+        peer_id: [u8; 20],
+        port: u16,
     ) -> Result<Vec<Peer>, String> {
-        let connection_id = self.obtain_connection_id(tracker_url)?;
+        let connection_id = self.obtain_connection_id(tracker_url).await?;
 
         let info_hash: [u8; 20] = torrent.info_hash()?;
-        let peer_id: [u8; 20] = generate_peer_id();
         let total_size: u64 = torrent.total_size();
-        // TODO: generate the port value
-        let port: u16 = 6881;
 
         let request: AnnounceRequest =
             AnnounceRequest::announce(connection_id, info_hash, peer_id, total_size, port);
 
         let request_content = bincode::serialize(&request).unwrap();
-        let response_raw: Vec<u8> = self.make_request(&request_content, tracker_url)?;
+        let response_raw: Vec<u8> = self.make_request(&request_content, tracker_url).await?;
+
+        if response_raw.len() < 20 {
+            return Err("Malformed announce response".to_string());
+        }
 
         let peers = Peer::from_bytes(&response_raw[20..])?;
         Ok(peers)
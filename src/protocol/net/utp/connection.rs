@@ -0,0 +1,592 @@
+use crate::protocol::net::utp::ledbat::{Ledbat, MAX_PAYLOAD};
+use crate::protocol::net::utp::packet::{Packet, PacketType};
+use crate::protocol::net::utp::socket::Shared;
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, OwnedPermit};
+use tokio::sync::oneshot;
+use tokio::time::{sleep_until, Instant};
+
+/// How much data we accept ahead of the reader
+const RECEIVE_WINDOW: usize = 1024 * 1024;
+
+/// How much data the writer may buffer, before it waits for the peer to ack it
+const SEND_BUFFER: usize = 256 * 1024;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The connection fails after this many timeouts in a row
+const MAX_TIMEOUTS: u32 = 6;
+
+/// A packet is lost once this many packets sent after it are acked
+const DUPLICATE_ACKS: u32 = 3;
+
+/// The most packets in flight, and the farthest packet accepted ahead of the acked one. It
+/// keeps the sequence numbers comparable after they wrap around.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// A packet sent, but not acked yet
+#[derive(Debug)]
+struct SentPacket {
+    packet_type: PacketType,
+    seq_nr: u16,
+    payload: Bytes,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Acked selectively, the cumulative ack hasn't reached it yet
+    acked: bool,
+    /// Considered lost, it waits to be sent again
+    lost: bool,
+}
+
+/// The state of a single uTP connection. It's driven by its own task, which gets the packets
+/// from the socket, the data to send from the [crate::protocol::net::UtpStream] and passes
+/// the received data back to the stream.
+pub(crate) struct Connection {
+    shared: Arc<Shared>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// Waits for the answer to our SYN, the connection is established then
+    connecting: Option<oneshot::Sender<Result<(), String>>>,
+    /// The sequence number of the next packet
+    seq_nr: u16,
+    /// The last packet received in order
+    ack_nr: u16,
+    last_ack: u16,
+    in_flight: VecDeque<SentPacket>,
+    send_buffer: BytesMut,
+    write_closed: bool,
+    fin_sent: bool,
+    out_of_order: HashMap<u16, Bytes>,
+    /// The data received in order, which the reader hasn't taken yet
+    received: VecDeque<Bytes>,
+    remote_fin: Option<u16>,
+    /// Everything up to the remote FIN is received
+    eof: bool,
+    ack_pending: bool,
+    ledbat: Ledbat,
+    remote_window: usize,
+    /// The delay of the last packet received, it's sent back to the peer
+    reply_micro: u32,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    timeouts: u32,
+    duplicate_acks: u32,
+    last_loss: Option<Instant>,
+}
+
+impl Connection {
+    fn new(shared: Arc<Shared>, remote: SocketAddr, recv_id: u16, send_id: u16) -> Self {
+        Connection {
+            shared,
+            remote,
+            recv_id,
+            send_id,
+            connecting: None,
+            seq_nr: 1,
+            ack_nr: 0,
+            last_ack: 0,
+            in_flight: VecDeque::new(),
+            send_buffer: BytesMut::new(),
+            write_closed: false,
+            fin_sent: false,
+            out_of_order: HashMap::new(),
+            received: VecDeque::new(),
+            remote_fin: None,
+            eof: false,
+            ack_pending: false,
+            ledbat: Ledbat::default(),
+            remote_window: MAX_PAYLOAD,
+            reply_micro: 0,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            timeouts: 0,
+            duplicate_acks: 0,
+            last_loss: None,
+        }
+    }
+
+    /// Opens the connection to the peer, the result is sent once the peer answers the SYN
+    pub fn outgoing(
+        shared: Arc<Shared>,
+        remote: SocketAddr,
+        recv_id: u16,
+        connecting: oneshot::Sender<Result<(), String>>,
+    ) -> Self {
+        let mut connection = Connection::new(shared, remote, recv_id, recv_id.wrapping_add(1));
+        connection.connecting = Some(connecting);
+        connection.in_flight.push_back(SentPacket {
+            packet_type: PacketType::Syn,
+            seq_nr: 1,
+            payload: Bytes::new(),
+            sent_at: Instant::now(),
+            transmissions: 0,
+            acked: false,
+            lost: true,
+        });
+        connection.seq_nr = 2;
+        connection
+    }
+
+    /// Answers the SYN of the peer
+    pub fn incoming(shared: Arc<Shared>, remote: SocketAddr, syn: &Packet) -> Self {
+        let mut connection = Connection::new(
+            shared,
+            remote,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+        );
+        connection.seq_nr = rand::random();
+        connection.ack_nr = syn.seq_nr;
+        connection.remote_window = syn.window as usize;
+        connection.reply_micro = connection.shared.timestamp().wrapping_sub(syn.timestamp);
+        connection.ack_pending = true;
+        connection
+    }
+
+    /// Runs the connection until both sides have closed it, or it fails. The error is passed
+    /// to the reader.
+    pub async fn run(
+        mut self,
+        mut packets: mpsc::UnboundedReceiver<Packet>,
+        mut writes: mpsc::Receiver<Bytes>,
+        reads: mpsc::Sender<io::Result<Bytes>>,
+    ) {
+        let mut reads = Some(reads);
+        if let Err(e) = self.drive(&mut packets, &mut writes, &mut reads).await {
+            if let Some(connecting) = self.connecting.take() {
+                let _ = connecting.send(Err(e.to_string()));
+            } else if let Some(reads) = reads {
+                let _ = reads.try_send(Err(e));
+            }
+        }
+        self.shared.unregister(self.remote, self.recv_id);
+    }
+
+    async fn drive(
+        &mut self,
+        packets: &mut mpsc::UnboundedReceiver<Packet>,
+        writes: &mut mpsc::Receiver<Bytes>,
+        reads: &mut Option<mpsc::Sender<io::Result<Bytes>>>,
+    ) -> io::Result<()> {
+        loop {
+            self.send_packets().await;
+            if self.ack_pending {
+                self.send_state().await;
+            }
+
+            if reads.as_ref().is_some_and(|reads| reads.is_closed()) {
+                self.received.clear();
+                *reads = None;
+            }
+            if self.eof && self.received.is_empty() {
+                *reads = None;
+            }
+            if self.is_finished() && reads.is_none() {
+                return Ok(());
+            }
+            if self
+                .connecting
+                .as_ref()
+                .is_some_and(|connecting| connecting.is_closed())
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The connection is abandoned",
+                ));
+            }
+
+            let deadline = self.deadline();
+            let can_write = self.connecting.is_none()
+                && !self.write_closed
+                && self.send_buffer.len() < SEND_BUFFER;
+            let can_deliver = !self.received.is_empty() && reads.is_some();
+
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => {
+                        self.on_packet(packet)?;
+                        while let Ok(packet) = packets.try_recv() {
+                            self.on_packet(packet)?;
+                        }
+                    }
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            "The uTP socket is closed",
+                        ))
+                    }
+                },
+
+                data = writes.recv(), if can_write => match data {
+                    Some(data) => self.send_buffer.extend_from_slice(&data),
+                    None => self.write_closed = true,
+                },
+
+                permit = reserve(reads.clone()), if can_deliver => match permit {
+                    Some(permit) => {
+                        if let Some(data) = self.received.pop_front() {
+                            permit.send(Ok(data));
+                        }
+                    }
+                    None => {
+                        self.received.clear();
+                        *reads = None;
+                    }
+                },
+
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.on_timeout()?;
+                }
+            }
+        }
+    }
+
+    /// Everything we had to send is acked, including our FIN
+    fn is_finished(&self) -> bool {
+        self.connecting.is_none()
+            && self.write_closed
+            && self.fin_sent
+            && self.send_buffer.is_empty()
+            && self.in_flight.is_empty()
+    }
+
+    /// When the oldest packet in flight times out
+    fn deadline(&self) -> Option<Instant> {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.acked && !sent.lost)
+            .map(|sent| sent.sent_at + self.timeout)
+            .min()
+    }
+
+    fn on_packet(&mut self, packet: Packet) -> io::Result<()> {
+        let now = Instant::now();
+        self.reply_micro = self.shared.timestamp().wrapping_sub(packet.timestamp);
+
+        match packet.packet_type {
+            PacketType::Reset => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "The uTP connection is reset by the peer",
+                ))
+            }
+            // Our answer to the SYN is lost
+            PacketType::Syn => {
+                self.ack_pending = true;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if self.connecting.is_some() {
+            if packet.ack_nr != 1 {
+                return Ok(());
+            }
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            self.last_ack = packet.ack_nr;
+            if let Some(connecting) = self.connecting.take() {
+                let _ = connecting.send(Ok(()));
+            }
+        }
+
+        self.remote_window = packet.window as usize;
+        self.on_ack(&packet, now);
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.on_data(packet);
+        }
+        Ok(())
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked_bytes = 0;
+        while let Some(sent) = self.in_flight.front() {
+            if packet.ack_nr.wrapping_sub(sent.seq_nr) as i16 <= -1 {
+                break;
+            }
+            if let Some(sent) = self.in_flight.pop_front() {
+                if !sent.acked {
+                    acked_bytes += sent.payload.len();
+                    self.update_rtt(&sent, now);
+                }
+            }
+        }
+
+        for seq_nr in packet.selectively_acked() {
+            let index = self
+                .in_flight
+                .iter()
+                .position(|sent| sent.seq_nr == seq_nr && !sent.acked);
+            if let Some(index) = index {
+                let sent = &mut self.in_flight[index];
+                sent.acked = true;
+                sent.lost = false;
+                acked_bytes += sent.payload.len();
+                let sent = &self.in_flight[index];
+                let (sent_at, transmissions) = (sent.sent_at, sent.transmissions);
+                self.update_rtt_sample(sent_at, transmissions, now);
+            }
+        }
+
+        // Fast retransmit: the packets sent later arrive, but this one doesn't
+        let mut lost = false;
+        let mut acked_after = 0;
+        for sent in self.in_flight.iter_mut().rev() {
+            if sent.acked {
+                acked_after += 1;
+            } else if acked_after >= DUPLICATE_ACKS && !sent.lost && sent.transmissions == 1 {
+                sent.lost = true;
+                lost = true;
+            }
+        }
+
+        if packet.ack_nr != self.last_ack {
+            self.duplicate_acks = 0;
+        }
+        if acked_bytes > 0 {
+            self.timeouts = 0;
+            if packet.timestamp_difference != 0 {
+                self.ledbat
+                    .on_ack(acked_bytes, packet.timestamp_difference, now.into_std());
+            }
+        } else if packet.packet_type == PacketType::State
+            && packet.ack_nr == self.last_ack
+            && self.in_flight.iter().any(|sent| !sent.acked)
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                if let Some(sent) = self.in_flight.iter_mut().find(|sent| !sent.acked) {
+                    sent.lost = true;
+                    lost = true;
+                }
+            }
+        }
+        self.last_ack = packet.ack_nr;
+
+        // A burst of losses shrinks the window once
+        let interval = self.rtt.unwrap_or(self.timeout);
+        if lost
+            && self
+                .last_loss
+                .is_none_or(|at| now.duration_since(at) > interval)
+        {
+            self.ledbat.on_loss();
+            self.last_loss = Some(now);
+        }
+    }
+
+    fn update_rtt(&mut self, sent: &SentPacket, now: Instant) {
+        self.update_rtt_sample(sent.sent_at, sent.transmissions, now);
+    }
+
+    /// The round trip time is measured on the packets sent once only, the same way TCP does
+    fn update_rtt_sample(&mut self, sent_at: Instant, transmissions: u32, now: Instant) {
+        if transmissions != 1 {
+            return;
+        }
+
+        let sample = now.duration_since(sent_at);
+        let rtt = match self.rtt {
+            None => {
+                self.rtt_var = sample / 2;
+                sample
+            }
+            Some(rtt) => {
+                self.rtt_var = self.rtt_var * 3 / 4 + rtt.abs_diff(sample) / 4;
+                rtt * 7 / 8 + sample / 8
+            }
+        };
+        self.rtt = Some(rtt);
+        self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        self.ack_pending = true;
+        if self.eof {
+            return;
+        }
+
+        let distance = packet.seq_nr.wrapping_sub(self.ack_nr) as i16;
+        if distance <= 0 || distance as usize > MAX_IN_FLIGHT {
+            return;
+        }
+
+        match packet.packet_type {
+            PacketType::Fin => self.remote_fin = Some(packet.seq_nr),
+            _ => {
+                self.out_of_order.insert(packet.seq_nr, packet.payload);
+            }
+        }
+
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(payload) = self.out_of_order.remove(&next) {
+                self.ack_nr = next;
+                if !payload.is_empty() {
+                    self.received.push_back(payload);
+                }
+            } else if self.remote_fin == Some(next) {
+                self.ack_nr = next;
+                self.eof = true;
+                self.out_of_order.clear();
+                return;
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Every packet in flight is considered lost, they are sent again as the window allows
+    fn on_timeout(&mut self) -> io::Result<()> {
+        self.timeouts += 1;
+        if self.timeouts > MAX_TIMEOUTS {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The uTP peer doesn't answer",
+            ));
+        }
+
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.ledbat.on_timeout();
+        self.remote_window = self.remote_window.max(MAX_PAYLOAD);
+        for sent in self.in_flight.iter_mut().filter(|sent| !sent.acked) {
+            sent.lost = true;
+        }
+        Ok(())
+    }
+
+    /// Sends the lost packets again and the new data, as far as the window allows. The first
+    /// packet is sent even if it doesn't fit, so a tiny window never stalls the connection.
+    async fn send_packets(&mut self) {
+        let now = Instant::now();
+        let window = self.ledbat.window().min(self.remote_window);
+        let mut in_flight: usize = self
+            .in_flight
+            .iter()
+            .filter(|sent| !sent.acked && !sent.lost)
+            .map(|sent| sent.payload.len())
+            .sum();
+        let fits = |in_flight: usize, length: usize| in_flight == 0 || in_flight + length <= window;
+
+        for index in 0..self.in_flight.len() {
+            let sent = &mut self.in_flight[index];
+            if !sent.lost {
+                continue;
+            }
+            if !fits(in_flight, sent.payload.len()) {
+                return;
+            }
+
+            in_flight += sent.payload.len();
+            sent.lost = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            let (packet_type, seq_nr, payload) =
+                (sent.packet_type, sent.seq_nr, sent.payload.clone());
+            let packet = self.packet(packet_type, seq_nr, payload);
+            self.transmit(packet).await;
+        }
+
+        if self.connecting.is_some() {
+            return;
+        }
+
+        while !self.send_buffer.is_empty() && self.in_flight.len() < MAX_IN_FLIGHT {
+            let length = self.send_buffer.len().min(MAX_PAYLOAD);
+            if !fits(in_flight, length) {
+                return;
+            }
+
+            in_flight += length;
+            let payload = self.send_buffer.split_to(length).freeze();
+            self.push(PacketType::Data, payload, now).await;
+        }
+
+        if self.write_closed
+            && self.send_buffer.is_empty()
+            && !self.fin_sent
+            && self.in_flight.len() < MAX_IN_FLIGHT
+        {
+            self.fin_sent = true;
+            self.push(PacketType::Fin, Bytes::new(), now).await;
+        }
+    }
+
+    /// Sends a new packet, it takes the next sequence number
+    async fn push(&mut self, packet_type: PacketType, payload: Bytes, now: Instant) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        let packet = self.packet(packet_type, seq_nr, payload.clone());
+        self.in_flight.push_back(SentPacket {
+            packet_type,
+            seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+            acked: false,
+            lost: false,
+        });
+        self.transmit(packet).await;
+    }
+
+    async fn send_state(&mut self) {
+        let mut packet = self.packet(PacketType::State, self.seq_nr, Bytes::new());
+        let ack_nr = self.ack_nr;
+        packet.selective_ack = Packet::make_selective_ack(
+            self.out_of_order
+                .keys()
+                .map(|seq_nr| seq_nr.wrapping_sub(ack_nr).wrapping_sub(2) as usize),
+        );
+        self.transmit(packet).await;
+    }
+
+    fn packet(&self, packet_type: PacketType, seq_nr: u16, payload: Bytes) -> Packet {
+        let connection_id = match packet_type {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        let buffered: usize = self
+            .out_of_order
+            .values()
+            .chain(self.received.iter())
+            .map(|data| data.len())
+            .sum();
+
+        let mut packet = Packet::new(packet_type, connection_id, seq_nr, self.ack_nr);
+        packet.timestamp = self.shared.timestamp();
+        packet.timestamp_difference = self.reply_micro;
+        packet.window = RECEIVE_WINDOW.saturating_sub(buffered) as u32;
+        packet.payload = payload;
+        packet
+    }
+
+    /// Every packet acks the received data, so no separate ack is needed, unless some packets
+    /// came out of order and the selective ack has to be sent
+    async fn transmit(&mut self, packet: Packet) {
+        if packet.packet_type != PacketType::Syn && self.out_of_order.is_empty() {
+            self.ack_pending = false;
+        }
+        self.shared.send(&packet, self.remote).await;
+    }
+}
+
+/// Waits for a free slot of the reader's channel, `None` if the reader is gone
+async fn reserve(
+    reads: Option<mpsc::Sender<io::Result<Bytes>>>,
+) -> Option<OwnedPermit<io::Result<Bytes>>> {
+    match reads {
+        Some(reads) => reads.reserve_owned().await.ok(),
+        None => std::future::pending().await,
+    }
+}
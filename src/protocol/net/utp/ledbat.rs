@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The largest payload of a single packet. It keeps the datagrams below the usual MTU.
+pub(crate) const MAX_PAYLOAD: usize = 1200;
+
+/// The queuing delay LEDBAT aims for, in microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+
+/// How fast the window grows or shrinks, a window's worth of acks changes it by a packet at most
+const GAIN: f64 = 1.0;
+
+/// The window never goes below a single packet
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+
+const INITIAL_WINDOW: usize = 2 * MAX_PAYLOAD;
+
+const MAX_WINDOW: usize = 1024 * 1024;
+
+/// The base delay is the lowest delay of the last minutes, tracked minute by minute
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+const BASE_DELAY_HISTORY: usize = 10;
+
+/// The current delay is the lowest of the last few samples, which filters the noise out
+const CURRENT_DELAY_SAMPLES: usize = 4;
+
+/// The congestion controller of a uTP connection (LEDBAT, RFC 6817). The window grows while
+/// the one-way delay of our packets stays close to the lowest one seen, and shrinks as soon as
+/// the delay grows. The delay grows when the packets are queued on the way, e.g. by other
+/// traffic on the same link, so the connection yields to it.
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    window: usize,
+    /// The lowest delay of every interval, the oldest first
+    base_delays: VecDeque<(Instant, u32)>,
+    current_delays: VecDeque<u32>,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Ledbat {
+            window: INITIAL_WINDOW,
+            base_delays: VecDeque::new(),
+            current_delays: VecDeque::new(),
+        }
+    }
+}
+
+impl Ledbat {
+    /// The number of bytes, which may be in flight
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Takes the delay of the acked packets, reported by the remote side, and updates the
+    /// window by the distance of the queuing delay from the target
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        self.update_delays(delay, now);
+
+        let base = self.base_delay().unwrap_or(delay);
+        let current = self.current_delays.iter().min().copied().unwrap_or(delay);
+        let queuing = current.saturating_sub(base) as f64;
+
+        let off_target = (TARGET_DELAY - queuing) / TARGET_DELAY;
+        let change =
+            GAIN * off_target * bytes_acked as f64 * MAX_PAYLOAD as f64 / self.window as f64;
+        self.window =
+            (self.window as f64 + change).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// A packet is lost, the window is halved
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2).max(MIN_WINDOW);
+    }
+
+    /// Nothing is acked for too long, the connection starts over with a single packet
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+
+    /// The lowest delay seen, it's the delay of the link without any queuing. The clocks of
+    /// the peers are not in sync, the offset is cancelled out by the subtraction.
+    pub fn base_delay(&self) -> Option<u32> {
+        self.base_delays.iter().map(|(_, delay)| *delay).min()
+    }
+
+    fn update_delays(&mut self, delay: u32, now: Instant) {
+        match self.base_delays.back_mut() {
+            Some((started, lowest)) if now.duration_since(*started) < BASE_DELAY_INTERVAL => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+
+        self.current_delays.push_back(delay);
+        if self.current_delays.len() > CURRENT_DELAY_SAMPLES {
+            self.current_delays.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Acks a window's worth of packets with the given delays, a packet every 10 ms
+    fn ack_window(ledbat: &mut Ledbat, now: &mut Instant, delay: u32) {
+        for _ in 0..ledbat.window() / MAX_PAYLOAD {
+            *now += Duration::from_millis(10);
+            ledbat.on_ack(MAX_PAYLOAD, delay, *now);
+        }
+    }
+
+    #[test]
+    fn window_grows_without_queuing() {
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        for _ in 0..10 {
+            let window = ledbat.window();
+            ack_window(&mut ledbat, &mut now, 20_000);
+            assert!(ledbat.window() > window);
+            // A window's worth of acks grows it by a packet at most
+            assert!(ledbat.window() <= window + MAX_PAYLOAD);
+        }
+    }
+
+    #[test]
+    fn window_shrinks_when_delay_exceeds_target() {
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        for _ in 0..20 {
+            ack_window(&mut ledbat, &mut now, 20_000);
+        }
+        let window = ledbat.window();
+
+        // Other traffic fills the queue, the delay grows by 150 ms
+        ack_window(&mut ledbat, &mut now, 170_000);
+        assert!(ledbat.window() < window);
+        for _ in 0..100 {
+            ack_window(&mut ledbat, &mut now, 170_000);
+        }
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+        assert_eq!(ledbat.base_delay(), Some(20_000));
+
+        // The window stays as is right at the target
+        let mut ledbat = Ledbat::default();
+        ledbat.on_ack(MAX_PAYLOAD, 20_000, now);
+        for _ in 0..CURRENT_DELAY_SAMPLES {
+            ledbat.on_ack(MAX_PAYLOAD, 120_000, now);
+        }
+        let window = ledbat.window();
+        ack_window(&mut ledbat, &mut now, 120_000);
+        assert_eq!(ledbat.window(), window);
+    }
+
+    #[test]
+    fn base_delay_expires() {
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        ledbat.on_ack(MAX_PAYLOAD, 10_000, now);
+        for _ in 1..BASE_DELAY_HISTORY {
+            now += BASE_DELAY_INTERVAL;
+            ledbat.on_ack(MAX_PAYLOAD, 50_000, now);
+            assert_eq!(ledbat.base_delay(), Some(10_000));
+        }
+
+        // The route has changed, the old lowest delay is forgotten
+        now += BASE_DELAY_INTERVAL;
+        ledbat.on_ack(MAX_PAYLOAD, 50_000, now);
+        assert_eq!(ledbat.base_delay(), Some(50_000));
+    }
+
+    #[test]
+    fn losses_shrink_window() {
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        for _ in 0..20 {
+            ack_window(&mut ledbat, &mut now, 0);
+        }
+        let window = ledbat.window();
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), window / 2);
+
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+    }
+}
@@ -0,0 +1,6 @@
+mod connection;
+mod ledbat;
+mod packet;
+mod socket;
+
+pub use socket::{UtpSocket, UtpStream};
@@ -0,0 +1,221 @@
+use bytes::Bytes;
+
+/// The length of the packet header without the extensions
+pub(crate) const HEADER_SIZE: usize = 20;
+
+const VERSION: u8 = 1;
+
+const SELECTIVE_ACK_EXTENSION: u8 = 1;
+
+/// The longest selective ack we send, it covers 512 packets after the acked one
+const MAX_SELECTIVE_ACK: usize = 64;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum PacketType {
+    /// A packet with the payload
+    Data = 0,
+    /// The last packet of the stream, no more data follows
+    Fin = 1,
+    /// Acknowledges the received packets, it has no payload and no sequence number of its own
+    State = 2,
+    /// Terminates the connection
+    Reset = 3,
+    /// Opens a connection
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(format!("Unknown uTP packet type {}", value)),
+        }
+    }
+}
+
+/// A uTP packet (BEP 29). The timestamps are in microseconds, the difference tells the one-way
+/// delay of the last packet received from the remote side, measured by our clock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    /// The free space of the sender's receive buffer, in bytes
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Every bit tells a packet received out of order, the first bit stands for `ack_nr + 2`
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push((self.packet_type as u8) << 4 | VERSION);
+        bytes.push(match self.selective_ack {
+            Some(_) => SELECTIVE_ACK_EXTENSION,
+            None => 0,
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, String> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("Too short uTP packet".to_string());
+        }
+        if bytes[0] & 0x0f != VERSION {
+            return Err(format!("Unsupported uTP version {}", bytes[0] & 0x0f));
+        }
+
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+
+        let mut packet = Packet {
+            packet_type: PacketType::try_from(bytes[0] >> 4)?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: Bytes::new(),
+        };
+
+        // The extensions are chained, every one tells the type of the next one
+        let mut extension = bytes[1];
+        let mut position = HEADER_SIZE;
+        while extension != 0 {
+            if bytes.len() < position + 2 {
+                return Err("Truncated uTP extension".to_string());
+            }
+            let next = bytes[position];
+            let length = bytes[position + 1] as usize;
+            let data = bytes
+                .get(position + 2..position + 2 + length)
+                .ok_or_else(|| "Truncated uTP extension".to_string())?;
+
+            if extension == SELECTIVE_ACK_EXTENSION {
+                packet.selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            position += 2 + length;
+        }
+
+        packet.payload = Bytes::copy_from_slice(&bytes[position..]);
+        Ok(packet)
+    }
+
+    /// The sequence numbers acknowledged by the selective ack
+    pub fn selectively_acked(&self) -> Vec<u16> {
+        let mask = match &self.selective_ack {
+            Some(mask) => mask,
+            None => return vec![],
+        };
+
+        (0..mask.len() * 8)
+            .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+            .map(|bit| self.ack_nr.wrapping_add(2).wrapping_add(bit as u16))
+            .collect()
+    }
+
+    /// Builds the selective ack of the packets received after `ack_nr`, given by their
+    /// distance from `ack_nr + 2`. The mask length is a multiple of 4 bytes.
+    pub fn make_selective_ack(offsets: impl Iterator<Item = usize>) -> Option<Vec<u8>> {
+        let mut mask = vec![];
+        for offset in offsets.filter(|offset| *offset < MAX_SELECTIVE_ACK * 8) {
+            if mask.len() <= offset / 8 {
+                mask.resize((offset / 32 + 1) * 4, 0);
+            }
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+        (!mask.is_empty()).then_some(mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 100, 50);
+        packet.timestamp = 123_456;
+        packet.timestamp_difference = 789;
+        packet.window = 1 << 20;
+        packet.payload = Bytes::from_static(b"payload");
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes.len(), HEADER_SIZE + 7);
+        assert_eq!(Packet::from_bytes(&bytes).unwrap(), packet);
+
+        packet.packet_type = PacketType::State;
+        packet.selective_ack = Packet::make_selective_ack([0, 3, 33].into_iter());
+        packet.payload = Bytes::new();
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 2 + 8);
+        let parsed = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, packet);
+        assert_eq!(parsed.selectively_acked(), vec![52, 55, 85]);
+    }
+
+    #[test]
+    fn selective_ack_wraps_around() {
+        let mut packet = Packet::new(PacketType::State, 1, 1, u16::MAX);
+        packet.selective_ack = Packet::make_selective_ack([0, 1].into_iter());
+        assert_eq!(packet.selectively_acked(), vec![1, 2]);
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        assert!(Packet::from_bytes(&[0x01; 10]).is_err());
+
+        let mut bytes = Packet::new(PacketType::Syn, 1, 1, 0).to_bytes();
+        bytes[0] = 0x42;
+        assert!(Packet::from_bytes(&bytes).is_err());
+        bytes[0] = 0x71;
+        assert!(Packet::from_bytes(&bytes).is_err());
+
+        // The extension is announced, but missing
+        let mut bytes = Packet::new(PacketType::State, 1, 1, 0).to_bytes();
+        bytes[1] = SELECTIVE_ACK_EXTENSION;
+        assert!(Packet::from_bytes(&bytes).is_err());
+    }
+}
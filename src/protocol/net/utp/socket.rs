@@ -0,0 +1,347 @@
+use crate::protocol::net::utp::connection::Connection;
+use crate::protocol::net::utp::packet::{Packet, PacketType};
+use bytes::{Buf, Bytes};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::{CancellationToken, DropGuard, PollSender};
+
+/// The incoming connections waiting to be accepted
+const ACCEPT_QUEUE_SIZE: usize = 32;
+
+/// The chunks of data passed between the stream and its connection
+const STREAM_CHANNEL_SIZE: usize = 16;
+
+/// The longest chunk the stream passes to the connection at once
+const MAX_WRITE: usize = 16 * 1024;
+
+/// The connections are told apart by the remote address and the id of the received packets
+type ConnectionKey = (SocketAddr, u16);
+
+/// The state shared by the socket and its connections
+pub(crate) struct Shared {
+    socket: UdpSocket,
+    /// The packet timestamps are counted from here
+    epoch: Instant,
+    connections: Mutex<HashMap<ConnectionKey, mpsc::UnboundedSender<Packet>>>,
+    accepting: AtomicBool,
+    accepted: mpsc::Sender<UtpStream>,
+    /// The peers, which haven't answered our SYN
+    unreachable: Mutex<HashSet<SocketAddr>>,
+}
+
+impl Shared {
+    fn connections(&self) -> MutexGuard<'_, HashMap<ConnectionKey, mpsc::UnboundedSender<Packet>>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn unreachable(&self) -> MutexGuard<'_, HashSet<SocketAddr>> {
+        self.unreachable.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The current time in microseconds, it wraps around every hour or so
+    pub fn timestamp(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    /// Sends the packet. The errors are ignored, the packet is just lost then.
+    pub async fn send(&self, packet: &Packet, addr: SocketAddr) {
+        let _ = self.socket.send_to(&packet.to_bytes(), addr).await;
+    }
+
+    pub fn unregister(&self, addr: SocketAddr, recv_id: u16) {
+        self.connections().remove(&(addr, recv_id));
+    }
+
+    /// Passes the packet to its connection. A SYN opens a new connection if the socket accepts
+    /// them, and it's refused otherwise.
+    async fn dispatch(self: &Arc<Self>, packet: Packet, addr: SocketAddr) {
+        let recv_id = match packet.packet_type {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+
+        let connection = self.connections().get(&(addr, recv_id)).cloned();
+        if let Some(connection) = connection {
+            let _ = connection.send(packet);
+            return;
+        }
+        if packet.packet_type != PacketType::Syn {
+            return;
+        }
+
+        if !self.accepting.load(Ordering::Relaxed) {
+            let reset = Packet::new(
+                PacketType::Reset,
+                packet.connection_id,
+                rand::random(),
+                packet.seq_nr,
+            );
+            self.send(&reset, addr).await;
+            return;
+        }
+
+        let (sender, packets) = mpsc::unbounded_channel();
+        self.connections().insert((addr, recv_id), sender);
+        let connection = Connection::incoming(self.clone(), addr, &packet);
+        // The connection is closed, if nobody takes it
+        let _ = self.accepted.try_send(spawn(connection, addr, packets));
+    }
+}
+
+/// A UDP socket carrying uTP connections (BEP 29). The same socket opens the connections to
+/// the peers and accepts the incoming ones, it's bound to the listening port usually. The
+/// socket is closed with its connections once every clone is dropped.
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    accepted: Arc<tokio::sync::Mutex<mpsc::Receiver<UtpStream>>>,
+    _guard: Arc<DropGuard>,
+}
+
+impl UtpSocket {
+    /// Binds the socket to the address, it has to be called within the tokio runtime
+    pub fn bind(addr: SocketAddr) -> Result<UtpSocket, String> {
+        let socket = std::net::UdpSocket::bind(addr)
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            })
+            .map_err(|e| format!("Unable to bind UDP socket {}: {}", addr, e))?;
+
+        let (accepted, accepted_receiver) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        let shared = Arc::new(Shared {
+            socket,
+            epoch: Instant::now(),
+            connections: Mutex::new(HashMap::new()),
+            accepting: AtomicBool::new(false),
+            accepted,
+            unreachable: Mutex::new(HashSet::new()),
+        });
+
+        let token = CancellationToken::new();
+        tokio::spawn(receive(shared.clone(), token.clone()));
+        Ok(UtpSocket {
+            shared,
+            accepted: Arc::new(tokio::sync::Mutex::new(accepted_receiver)),
+            _guard: Arc::new(token.drop_guard()),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.shared
+            .socket
+            .local_addr()
+            .map_err(|e| format!("Unable to get the UDP socket address: {}", e))
+    }
+
+    /// Opens the connection to the peer. It fails if the peer refuses it, otherwise it's up
+    /// to the caller to give up on a peer, which doesn't answer.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream, String> {
+        let (sender, packets) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut connections = self.shared.connections();
+            let mut recv_id: u16 = rand::random();
+            while connections.contains_key(&(addr, recv_id)) {
+                recv_id = rand::random();
+            }
+            connections.insert((addr, recv_id), sender);
+            recv_id
+        };
+
+        let (connecting, connected) = oneshot::channel();
+        let connection = Connection::outgoing(self.shared.clone(), addr, recv_id, connecting);
+        let stream = spawn(connection, addr, packets);
+        match connected.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(msg)) => Err(format!(
+                "Unable to open uTP connection to {}: {}",
+                addr, msg
+            )),
+            Err(_) => Err(format!("Unable to open uTP connection to {}", addr)),
+        }
+    }
+
+    /// Waits for the next incoming connection. The socket refuses the incoming connections
+    /// until it's called for the first time.
+    pub async fn accept(&self) -> Option<UtpStream> {
+        self.shared.accepting.store(true, Ordering::Relaxed);
+        self.accepted.lock().await.recv().await
+    }
+
+    /// Returns true if the peer hasn't answered our SYN before, so it likely supports TCP only
+    pub(crate) fn is_unreachable(&self, addr: &SocketAddr) -> bool {
+        self.shared.unreachable().contains(addr)
+    }
+
+    pub(crate) fn set_unreachable(&self, addr: SocketAddr) {
+        self.shared.unreachable().insert(addr);
+    }
+}
+
+/// Reads the datagrams and passes them to the connections until the socket is dropped
+async fn receive(shared: Arc<Shared>, token: CancellationToken) {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let (length, addr) = tokio::select! {
+            _ = token.cancelled() => return,
+            received = shared.socket.recv_from(&mut buffer) => match received {
+                Ok(received) => received,
+                // E.g. an ICMP error of a packet sent before, the socket is fine
+                Err(_) => continue,
+            },
+        };
+
+        if let Ok(packet) = Packet::from_bytes(&buffer[..length]) {
+            shared.dispatch(packet, addr).await;
+        }
+    }
+}
+
+/// Starts the task of the connection, returns the stream for the application
+fn spawn(
+    connection: Connection,
+    remote: SocketAddr,
+    packets: mpsc::UnboundedReceiver<Packet>,
+) -> UtpStream {
+    let (writes, writes_receiver) = mpsc::channel(STREAM_CHANNEL_SIZE);
+    let (reads_sender, reads) = mpsc::channel(STREAM_CHANNEL_SIZE);
+    tokio::spawn(connection.run(packets, writes_receiver, reads_sender));
+
+    UtpStream {
+        remote,
+        reads,
+        buffer: Bytes::new(),
+        writes: PollSender::new(writes),
+    }
+}
+
+/// A uTP connection, the byte stream like the TCP one. The connection is closed gracefully
+/// when the stream is dropped.
+pub struct UtpStream {
+    remote: SocketAddr,
+    reads: mpsc::Receiver<io::Result<Bytes>>,
+    /// The received data not read yet
+    buffer: Bytes,
+    writes: PollSender<Bytes>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote
+    }
+}
+
+impl Debug for UtpStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("UtpStream")
+            .field("remote", &self.remote)
+            .finish()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.buffer.is_empty() {
+            match ready!(this.reads.poll_recv(cx)) {
+                Some(Ok(data)) => this.buffer = data,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                // The peer has closed the connection
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let length = this.buffer.len().min(buf.remaining());
+        buf.put_slice(&this.buffer[..length]);
+        this.buffer.advance(length);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let closed = |_| io::Error::new(io::ErrorKind::BrokenPipe, "The uTP connection is closed");
+        ready!(this.writes.poll_reserve(cx)).map_err(closed)?;
+        let length = buf.len().min(MAX_WRITE);
+        this.writes
+            .send_item(Bytes::copy_from_slice(&buf[..length]))
+            .map_err(closed)?;
+        Poll::Ready(Ok(length))
+    }
+
+    /// The data is passed to the connection as soon as it's written, the connection sends it
+    /// as the congestion window allows
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().writes.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn bind() -> UtpSocket {
+        UtpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn exchange_data_both_ways() {
+        let (server, client) = (bind(), bind());
+        let server_addr = server.local_addr().unwrap();
+
+        let accepted = tokio::spawn(async move {
+            let mut stream = server.accept().await.unwrap();
+            let mut request = vec![0u8; 100_000];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&request[..10]).await.unwrap();
+            stream.shutdown().await.unwrap();
+            request
+        });
+
+        let mut stream = client.connect(server_addr).await.unwrap();
+        assert_eq!(stream.peer_addr(), server_addr);
+        let request: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        stream.write_all(&request).await.unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, &request[..10]);
+        assert_eq!(accepted.await.unwrap(), request);
+    }
+
+    #[tokio::test]
+    async fn connection_is_refused_until_accepting() {
+        let (server, client) = (bind(), bind());
+        let result = client.connect(server.local_addr().unwrap()).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,624 @@
+use crate::storage::{FileMetadata, Preallocation, Storage, StorageLayout, StorageLocation};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// The memory limits of the disk cache of a single torrent. Zero disables the cache.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CacheConfig {
+    /// The bytes of the downloaded blocks kept in memory before they are written to disk
+    pub write_size: usize,
+    /// The bytes of the pieces kept in memory to serve the upload requests
+    pub read_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            write_size: 16 * 1024 * 1024,
+            read_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct CacheStatistics {
+    /// Blocks written to the cache
+    pub blocks_written: u64,
+    /// Writes passed to the underlying storage, after the adjacent blocks were merged
+    pub disk_writes: u64,
+    /// Reads served from memory
+    pub read_hits: u64,
+    /// Reads, which had to go to the underlying storage
+    pub read_misses: u64,
+    /// Bytes waiting to be written
+    pub dirty_bytes: u64,
+    /// Bytes of the pieces in the read cache
+    pub read_bytes: u64,
+}
+
+/// The blocks of a piece, which are not written yet
+#[derive(Default)]
+struct DirtyPiece {
+    /// The blocks by their offset within the piece
+    blocks: BTreeMap<u32, Vec<u8>>,
+    /// The last time the piece was written to, the oldest pieces are flushed first
+    touched: u64,
+}
+
+impl DirtyPiece {
+    fn size(&self) -> usize {
+        self.blocks.values().map(|b| b.len()).sum()
+    }
+
+    /// Whether the data would overlap a block other than the one at the same offset
+    fn overlaps(&self, offset: u32, length: usize) -> bool {
+        let end = offset as u64 + length as u64;
+        self.blocks.iter().any(|(start, block)| {
+            let block_end = *start as u64 + block.len() as u64;
+            (*start as u64) < end
+                && block_end > offset as u64
+                && (*start != offset || block.len() != length)
+        })
+    }
+
+    /// Copies the range if the blocks cover it completely
+    fn read(&self, offset: u32, length: u32) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(length as usize);
+        let end = offset as u64 + length as u64;
+        let mut position = offset as u64;
+
+        for (start, block) in self.blocks.range(..end as u32) {
+            let start = *start as u64;
+            let block_end = start + block.len() as u64;
+            if block_end <= position {
+                continue;
+            }
+            if start > position {
+                return None;
+            }
+
+            let to = block_end.min(end);
+            data.extend_from_slice(&block[(position - start) as usize..(to - start) as usize]);
+            position = to;
+        }
+
+        (position == end).then_some(data)
+    }
+
+    /// Merges the adjacent blocks, so they are written at once
+    fn coalesce(&self) -> Vec<(u32, Vec<u8>)> {
+        let mut runs: Vec<(u32, Vec<u8>)> = vec![];
+        for (offset, block) in &self.blocks {
+            match runs.last_mut() {
+                Some((start, run)) if *start as usize + run.len() == *offset as usize => {
+                    run.extend_from_slice(block)
+                }
+                _ => runs.push((*offset, block.clone())),
+            }
+        }
+        runs
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    dirty: HashMap<u32, DirtyPiece>,
+    dirty_bytes: usize,
+    /// The pieces being written to the underlying storage, their blocks serve the reads until
+    /// they are written
+    flushing: HashMap<u32, Arc<DirtyPiece>>,
+    /// The pieces being read ahead, and whether they were written to meanwhile
+    reading: HashMap<u32, bool>,
+    /// Whole pieces read ahead for the uploads
+    pieces: HashMap<u32, Arc<Vec<u8>>>,
+    /// The pieces of the read cache, the least recently used first
+    recent: VecDeque<u32>,
+    read_bytes: usize,
+    clock: u64,
+    statistics: CacheStatistics,
+}
+
+/// A write-back cache in front of another storage. The downloaded blocks are kept in memory,
+/// so the hash check doesn't touch the disk, and a piece is written at once after it's
+/// verified, with the adjacent blocks merged into a single write. The blocks are written
+/// earlier only if the memory limit is reached, the oldest pieces first.
+///
+/// The reads, which can't be served from the written blocks, read the whole piece ahead,
+/// as the peers usually request all blocks of a piece one after another.
+///
+/// The cache isn't locked while the underlying storage reads or writes, so an upload reading
+/// a piece ahead doesn't hold back a download writing another one. The blocks are flushed on
+/// the thread calling [Storage::write], [Storage::piece_checked] or [Storage::flush], so the
+/// torrent calls them on the blocking thread pool only.
+pub struct CachedStorage {
+    inner: Arc<dyn Storage>,
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+    /// Wakes up the threads waiting for a piece to be written to the underlying storage
+    flushed: Condvar,
+}
+
+impl CachedStorage {
+    pub fn new(inner: Arc<dyn Storage>, config: CacheConfig) -> Self {
+        CachedStorage {
+            inner,
+            config,
+            state: Mutex::new(CacheState::default()),
+            flushed: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().expect("Cache lock is poisoned")
+    }
+
+    /// Waits until the piece isn't being written to the underlying storage, so the storage
+    /// has all of its data
+    fn wait_flushed<'a>(
+        &self,
+        mut state: MutexGuard<'a, CacheState>,
+        piece: u32,
+    ) -> MutexGuard<'a, CacheState> {
+        while state.flushing.contains_key(&piece) {
+            state = self.flushed.wait(state).expect("Cache lock is poisoned");
+        }
+        state
+    }
+
+    /// Writes the blocks of the piece to the underlying storage. The cache is unlocked
+    /// meanwhile, and it's locked again once the piece is written.
+    fn flush_piece<'a>(
+        &'a self,
+        state: MutexGuard<'a, CacheState>,
+        piece: u32,
+    ) -> Result<MutexGuard<'a, CacheState>, String> {
+        let mut state = self.wait_flushed(state, piece);
+        let dirty = match state.dirty.remove(&piece) {
+            Some(dirty) => Arc::new(dirty),
+            None => return Ok(state),
+        };
+        state.dirty_bytes -= dirty.size();
+        state.flushing.insert(piece, dirty.clone());
+        drop(state);
+
+        let mut writes = 0;
+        let result = dirty.coalesce().into_iter().try_for_each(|(offset, data)| {
+            self.inner.write(piece, offset, &data)?;
+            writes += 1;
+            Ok(())
+        });
+
+        let mut state = self.state();
+        state.flushing.remove(&piece);
+        state.statistics.disk_writes += writes;
+        if result.is_err() {
+            Self::restore_dirty(&mut state, piece, dirty);
+        }
+        self.flushed.notify_all();
+        result.map(|_| state)
+    }
+
+    /// Puts the blocks, which failed to be written, back into the cache, so they aren't lost.
+    /// The blocks written to the piece meanwhile are newer, so they stay.
+    fn restore_dirty(state: &mut CacheState, piece: u32, failed: Arc<DirtyPiece>) {
+        let failed = Arc::try_unwrap(failed).unwrap_or_else(|failed| DirtyPiece {
+            blocks: failed.blocks.clone(),
+            touched: failed.touched,
+        });
+        let dirty = state.dirty.entry(piece).or_default();
+        dirty.touched = dirty.touched.max(failed.touched);
+
+        let mut restored = 0;
+        for (offset, block) in failed.blocks {
+            if !dirty.blocks.contains_key(&offset) && !dirty.overlaps(offset, block.len()) {
+                restored += block.len();
+                dirty.blocks.insert(offset, block);
+            }
+        }
+        state.dirty_bytes += restored;
+    }
+
+    /// Flushes the oldest pieces until the dirty blocks fit into the limit
+    fn enforce_write_limit<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, CacheState>,
+    ) -> Result<(), String> {
+        while state.dirty_bytes > self.config.write_size {
+            let oldest = state
+                .dirty
+                .iter()
+                .min_by_key(|(_, dirty)| dirty.touched)
+                .map(|(piece, _)| *piece)
+                .expect("Dirty bytes belong to a piece");
+            state = self.flush_piece(state, oldest)?;
+        }
+        Ok(())
+    }
+
+    fn forget_read_piece(state: &mut CacheState, piece: u32) {
+        if let Some(data) = state.pieces.remove(&piece) {
+            state.read_bytes -= data.len();
+            state.recent.retain(|p| *p != piece);
+        }
+    }
+
+    /// Reads the whole piece into the read cache, the cache is unlocked meanwhile. Returns
+    /// `None` if the piece can't be read, e.g. it's not downloaded completely, or it was
+    /// written to meanwhile.
+    fn read_ahead(
+        &self,
+        mut state: MutexGuard<'_, CacheState>,
+        piece: u32,
+    ) -> Option<Arc<Vec<u8>>> {
+        let size = self.inner.layout().piece_size(piece);
+        if size > self.config.read_size as u64 || state.reading.contains_key(&piece) {
+            return None;
+        }
+        state.reading.insert(piece, false);
+        drop(state);
+
+        let data = self.inner.read(piece, 0, size as u32);
+        let mut state = self.state();
+        let written = state.reading.remove(&piece).unwrap_or(true);
+        let data = match data {
+            Ok(data) if !written => Arc::new(data),
+            _ => return None,
+        };
+
+        state.read_bytes += data.len();
+        state.pieces.insert(piece, data.clone());
+        state.recent.push_back(piece);
+
+        while state.read_bytes > self.config.read_size {
+            match state.recent.pop_front() {
+                Some(oldest) => Self::forget_read_piece(&mut state, oldest),
+                None => break,
+            }
+        }
+        Some(data)
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        let state = self.state();
+        CacheStatistics {
+            dirty_bytes: state.dirty_bytes as u64,
+            read_bytes: state.read_bytes as u64,
+            ..state.statistics.clone()
+        }
+    }
+}
+
+impl Storage for CachedStorage {
+    fn layout(&self) -> &StorageLayout {
+        self.inner.layout()
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        let mut state = self.state();
+        Self::forget_read_piece(&mut state, piece);
+        if let Some(written) = state.reading.get_mut(&piece) {
+            *written = true;
+        }
+
+        if self.config.write_size == 0 {
+            drop(state);
+            return self.inner.write(piece, offset, data);
+        }
+
+        // Blocks are always written at the same offsets, anything else is written through
+        if state
+            .dirty
+            .get(&piece)
+            .is_some_and(|dirty| dirty.overlaps(offset, data.len()))
+        {
+            state = self.flush_piece(state, piece)?;
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+        let dirty = state.dirty.entry(piece).or_default();
+        dirty.touched = clock;
+        let replaced = dirty.blocks.insert(offset, data.to_vec());
+
+        state.dirty_bytes += data.len();
+        state.dirty_bytes -= replaced.map(|b| b.len()).unwrap_or_default();
+        state.statistics.blocks_written += 1;
+
+        self.enforce_write_limit(state)
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        let mut state = self.state();
+
+        if let Some(dirty) = state.dirty.get(&piece) {
+            if let Some(data) = dirty.read(offset, length) {
+                state.statistics.read_hits += 1;
+                return Ok(data);
+            }
+            state = self.flush_piece(state, piece)?;
+        }
+
+        if let Some(data) = state
+            .flushing
+            .get(&piece)
+            .and_then(|flushing| flushing.read(offset, length))
+        {
+            state.statistics.read_hits += 1;
+            return Ok(data);
+        }
+        let mut state = self.wait_flushed(state, piece);
+
+        let end = offset as usize + length as usize;
+        if let Some(data) = state.pieces.get(&piece).cloned() {
+            if end <= data.len() {
+                state.statistics.read_hits += 1;
+                state.recent.retain(|p| *p != piece);
+                state.recent.push_back(piece);
+                return Ok(data[offset as usize..end].to_vec());
+            }
+        }
+
+        state.statistics.read_misses += 1;
+        match self.read_ahead(state, piece) {
+            Some(data) if end <= data.len() => Ok(data[offset as usize..end].to_vec()),
+            _ => self.inner.read(piece, offset, length),
+        }
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let mut state = self.state();
+        let pieces: Vec<u32> = state.dirty.keys().copied().collect();
+        for piece in pieces {
+            state = self.flush_piece(state, piece)?;
+        }
+
+        // The pieces written by the other threads are waited for as well
+        while !state.flushing.is_empty() {
+            state = self.flushed.wait(state).expect("Cache lock is poisoned");
+        }
+        drop(state);
+        self.inner.flush()
+    }
+
+    fn piece_checked(&self, piece: u32, valid: bool) -> Result<(), String> {
+        let mut state = self.state();
+        if valid {
+            state = self.flush_piece(state, piece)?;
+        } else if let Some(dirty) = state.dirty.remove(&piece) {
+            // The piece is downloaded again, there is no need to write the broken data
+            state.dirty_bytes -= dirty.size();
+        }
+        drop(state);
+        self.inner.piece_checked(piece, valid)
+    }
+
+    fn cache_statistics(&self) -> Option<CacheStatistics> {
+        Some(self.statistics())
+    }
+
+    fn set_skipped_files(&self, skipped: &[bool]) -> Result<(), String> {
+        self.inner.set_skipped_files(skipped)
+    }
+
+    fn preallocate(&self, mode: Preallocation) -> Result<(), String> {
+        self.inner.preallocate(mode)
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        self.inner.available_space()
+    }
+
+    fn file_completed(&self, file: usize) -> Result<(), String> {
+        self.inner.file_completed(file)
+    }
+
+    fn location(&self) -> Option<StorageLocation> {
+        self.inner.location()
+    }
+
+    fn move_to(&self, root: &Path) -> Result<(), String> {
+        self.inner.move_to(root)
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> Result<(), String> {
+        self.inner.rename_file(file, path)
+    }
+
+    fn metadata(&self, file: usize) -> Option<FileMetadata> {
+        self.inner.metadata(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FileStorage, MemoryStorage};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Holds the first read until the test lets it go
+    struct SlowReads {
+        inner: MemoryStorage,
+        barrier: Barrier,
+        held: AtomicBool,
+    }
+
+    impl Storage for SlowReads {
+        fn layout(&self) -> &StorageLayout {
+            self.inner.layout()
+        }
+
+        fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+            self.inner.write(piece, offset, data)
+        }
+
+        fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+            if !self.held.swap(true, Ordering::SeqCst) {
+                self.barrier.wait();
+                self.barrier.wait();
+            }
+            self.inner.read(piece, offset, length)
+        }
+
+        fn flush(&self) -> Result<(), String> {
+            self.inner.flush()
+        }
+    }
+
+    /// Fails the writes while the disk is full
+    struct FullDisk {
+        inner: MemoryStorage,
+        full: AtomicBool,
+    }
+
+    impl Storage for FullDisk {
+        fn layout(&self) -> &StorageLayout {
+            self.inner.layout()
+        }
+
+        fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+            if self.full.load(Ordering::SeqCst) {
+                return Err("No space left on device".to_string());
+            }
+            self.inner.write(piece, offset, data)
+        }
+
+        fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+            self.inner.read(piece, offset, length)
+        }
+
+        fn flush(&self) -> Result<(), String> {
+            self.inner.flush()
+        }
+    }
+
+    fn storage(dir: &std::path::Path, config: CacheConfig) -> (Arc<FileStorage>, CachedStorage) {
+        let layout = StorageLayout::new(vec![(PathBuf::from("file.bin"), 40)], 16);
+        let inner = Arc::new(FileStorage::new(dir, layout));
+        let cached = CachedStorage::new(inner.clone(), config);
+        (inner, cached)
+    }
+
+    #[test]
+    fn writes_verified_pieces_coalesced() {
+        let dir = tempfile::tempdir().unwrap();
+        let (inner, cached) = storage(dir.path(), CacheConfig::default());
+
+        cached.write(0, 8, &[2; 8]).unwrap();
+        cached.write(0, 0, &[1; 8]).unwrap();
+        cached.write(1, 0, &[3; 8]).unwrap();
+        assert!(inner.read(0, 0, 16).is_err());
+        assert_eq!(cached.read(0, 4, 8).unwrap(), [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        cached.piece_checked(0, true).unwrap();
+        cached.piece_checked(1, false).unwrap();
+        assert_eq!(inner.read(0, 0, 16).unwrap(), [[1; 8], [2; 8]].concat());
+
+        let statistics = cached.statistics();
+        assert_eq!(statistics.blocks_written, 3);
+        assert_eq!(statistics.disk_writes, 1);
+        assert_eq!(statistics.dirty_bytes, 0);
+        assert_eq!(statistics.read_hits, 1);
+    }
+
+    #[test]
+    fn flushes_oldest_pieces_over_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig {
+            write_size: 20,
+            read_size: 0,
+        };
+        let (inner, cached) = storage(dir.path(), config);
+
+        cached.write(0, 0, &[1; 16]).unwrap();
+        cached.write(1, 0, &[2; 8]).unwrap();
+        assert_eq!(inner.read(0, 0, 16).unwrap(), [1; 16]);
+        assert_eq!(cached.statistics().dirty_bytes, 8);
+
+        cached.flush().unwrap();
+        assert_eq!(inner.read(1, 0, 8).unwrap(), [2; 8]);
+    }
+
+    #[test]
+    fn failed_flush_keeps_blocks() {
+        let layout = StorageLayout::new(vec![(PathBuf::from("file.bin"), 40)], 16);
+        let inner = Arc::new(FullDisk {
+            inner: MemoryStorage::new(layout),
+            full: AtomicBool::new(true),
+        });
+        let cached = CachedStorage::new(inner.clone(), CacheConfig::default());
+
+        cached.write(0, 0, &[1; 8]).unwrap();
+        cached.write(0, 8, &[2; 8]).unwrap();
+        assert!(cached.piece_checked(0, true).is_err());
+        assert_eq!(cached.statistics().dirty_bytes, 16);
+        assert_eq!(cached.read(0, 4, 8).unwrap(), [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        inner.full.store(false, Ordering::SeqCst);
+        cached.piece_checked(0, true).unwrap();
+        assert_eq!(cached.statistics().dirty_bytes, 0);
+        assert_eq!(inner.read(0, 0, 16).unwrap(), [[1; 8], [2; 8]].concat());
+    }
+
+    #[test]
+    fn reads_whole_pieces_ahead() {
+        let dir = tempfile::tempdir().unwrap();
+        let (inner, cached) = storage(dir.path(), CacheConfig::default());
+        for piece in 0..3 {
+            let size = inner.layout().piece_size(piece) as usize;
+            inner.write(piece, 0, &vec![7; size]).unwrap();
+        }
+
+        assert_eq!(cached.read(1, 0, 4).unwrap(), [7; 4]);
+        assert_eq!(cached.read(1, 4, 12).unwrap(), [7; 12]);
+        assert_eq!(cached.read(2, 0, 8).unwrap(), [7; 8]);
+
+        let statistics = cached.statistics();
+        assert_eq!(statistics.read_misses, 2);
+        assert_eq!(statistics.read_hits, 1);
+        assert_eq!(statistics.read_bytes, 24);
+
+        cached.write(1, 0, &[9; 4]).unwrap();
+        assert_eq!(cached.read(1, 0, 4).unwrap(), [9; 4]);
+        assert_eq!(cached.statistics().read_bytes, 8);
+    }
+
+    #[test]
+    fn writes_while_reading_ahead() {
+        let layout = StorageLayout::new(vec![(PathBuf::from("file.bin"), 40)], 16);
+        let inner = Arc::new(SlowReads {
+            inner: MemoryStorage::with_content(layout, &[7; 40]).unwrap(),
+            barrier: Barrier::new(2),
+            held: AtomicBool::new(false),
+        });
+        let cached = Arc::new(CachedStorage::new(inner.clone(), CacheConfig::default()));
+
+        let reader = cached.clone();
+        let read = thread::spawn(move || reader.read(1, 0, 4));
+        inner.barrier.wait();
+
+        // The blocks are written while the piece is being read
+        let (sender, receiver) = mpsc::channel();
+        let writer = cached.clone();
+        thread::spawn(move || {
+            let _ = sender.send(
+                writer
+                    .write(0, 0, &[1; 16])
+                    .and_then(|_| writer.write(1, 0, &[2; 4])),
+            );
+        });
+        let written = receiver.recv_timeout(Duration::from_secs(5));
+        assert_eq!(written, Ok(Ok(())));
+
+        inner.barrier.wait();
+        assert_eq!(read.join().unwrap().unwrap(), [7; 4]);
+
+        // The piece written meanwhile isn't cached
+        assert_eq!(cached.statistics().read_bytes, 0);
+        assert_eq!(cached.read(1, 0, 4).unwrap(), [2; 4]);
+    }
+}
@@ -0,0 +1,1089 @@
+use crate::storage::{
+    FileMetadata, FileSlice, IncompleteFiles, PartFile, Preallocation, Storage, StorageLayout,
+    StorageLocation,
+};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+/// The size of the zero-filled chunks written by the full preallocation
+const ZERO_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Stores the torrent content in the regular files under the output folder. The files and
+/// their parent folders are created on the first write. The data of the skipped files is kept
+/// in the hidden part file, see [PartFile].
+///
+/// The files start at the paths of the layout, but they might be moved and renamed while the
+/// torrent is running, see [Storage::move_to]. The incomplete files might be kept aside until
+/// they are complete, see [IncompleteFiles].
+#[derive(Debug)]
+pub struct FileStorage {
+    location: Mutex<StorageLocation>,
+    incomplete: IncompleteFiles,
+    /// Whether the file is at its final path, found out on the first access
+    completed: Mutex<Vec<Option<bool>>>,
+    part_file_name: String,
+    layout: StorageLayout,
+    /// Files opened so far, by their index in the layout
+    handles: Mutex<HashMap<usize, File>>,
+    skipped: Mutex<Vec<bool>>,
+    part_file: Mutex<PartFile>,
+    /// The slices written while the files are copied to another folder, see [Storage::move_to]
+    moving: Mutex<Option<Vec<WrittenSlice>>>,
+}
+
+/// The file, the offset within it and the length of the written data
+type WrittenSlice = (usize, u64, u64);
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: StorageLayout) -> Self {
+        let root = root.into();
+        let name = layout
+            .files
+            .first()
+            .and_then(|file| file.path.iter().next())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let part_file_name = format!(".{}.parts", name);
+        let part_file = PartFile::new(
+            root.join(&part_file_name),
+            layout.pieces_count() as u32,
+            layout.piece_length,
+        );
+
+        FileStorage {
+            location: Mutex::new(StorageLocation {
+                root,
+                files: layout.files.iter().map(|file| file.path.clone()).collect(),
+            }),
+            part_file_name,
+            incomplete: IncompleteFiles::default(),
+            completed: Mutex::new(vec![None; layout.files.len()]),
+            handles: Mutex::new(HashMap::new()),
+            skipped: Mutex::new(vec![false; layout.files.len()]),
+            part_file: Mutex::new(part_file),
+            moving: Mutex::new(None),
+            layout,
+        }
+    }
+
+    fn current(&self) -> MutexGuard<'_, StorageLocation> {
+        self.location.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.current().root.clone()
+    }
+
+    /// Keeps the incomplete files aside, see [IncompleteFiles]
+    pub fn with_incomplete(mut self, incomplete: IncompleteFiles) -> Self {
+        self.incomplete = incomplete;
+        self
+    }
+
+    /// The current path of the file, which is the incomplete one until the file is complete
+    pub fn file_path(&self, file: usize) -> PathBuf {
+        let location = self.current();
+        self.path_in(&location, file)
+    }
+
+    fn path_in(&self, location: &StorageLocation, file: usize) -> PathBuf {
+        if self.is_complete(location, file) {
+            location.root.join(&location.files[file])
+        } else {
+            self.incomplete_path(location, file)
+        }
+    }
+
+    fn incomplete_path(&self, location: &StorageLocation, file: usize) -> PathBuf {
+        let folder = self.incomplete.dir.as_ref().unwrap_or(&location.root);
+        let mut path = folder.join(&location.files[file]).into_os_string();
+        if let Some(suffix) = &self.incomplete.suffix {
+            path.push(suffix);
+        }
+        PathBuf::from(path)
+    }
+
+    /// The file is complete if it's found at the final path only. The files are always
+    /// complete if they aren't kept aside.
+    fn is_complete(&self, location: &StorageLocation, file: usize) -> bool {
+        if !self.incomplete.is_enabled() {
+            return true;
+        }
+
+        let mut completed = self.completed.lock().unwrap_or_else(|e| e.into_inner());
+        *completed[file].get_or_insert_with(|| {
+            !self.incomplete_path(location, file).exists()
+                && location.root.join(&location.files[file]).exists()
+        })
+    }
+
+    fn sync_files(handles: &HashMap<usize, File>) -> Result<(), String> {
+        for handle in handles.values() {
+            handle
+                .sync_data()
+                .map_err(|e| format!("Unable flush data to disk: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Links or copies the files to the new place, while the old ones are still in use.
+    /// Returns the files done and whether they were copied. The copies are removed if any of
+    /// the files fails.
+    fn copy_files(
+        &self,
+        location: &StorageLocation,
+        target: &StorageLocation,
+    ) -> Result<Vec<CopiedFile>, String> {
+        let mut done: Vec<CopiedFile> = vec![];
+        for file in 0..location.files.len() {
+            let from = self.path_in(location, file);
+            let to = self.path_in(target, file);
+            match link_or_copy(&from, &to) {
+                Ok(Some(copied)) => done.push(CopiedFile {
+                    file,
+                    from,
+                    to,
+                    copied,
+                }),
+                Ok(None) => {}
+                Err(msg) => {
+                    for file in &done {
+                        let _ = fs::remove_file(&file.to);
+                    }
+                    return Err(msg);
+                }
+            }
+        }
+
+        Ok(done)
+    }
+
+    /// Repeats the writes made to the old files after they were copied
+    fn repeat_writes(done: &[CopiedFile], written: &[WrittenSlice]) -> Result<(), String> {
+        for file in done.iter().filter(|file| file.copied) {
+            let slices: Vec<_> = written
+                .iter()
+                .filter(|(index, _, _)| *index == file.file)
+                .collect();
+            if slices.is_empty() {
+                continue;
+            }
+
+            let error = |e: std::io::Error| format!("Unable move {:?}: {}", file.from, e);
+            let mut source = File::open(&file.from).map_err(error)?;
+            let mut target = OpenOptions::new()
+                .write(true)
+                .open(&file.to)
+                .map_err(error)?;
+            for (_, offset, length) in slices {
+                let mut data = vec![0u8; *length as usize];
+                source.seek(SeekFrom::Start(*offset)).map_err(error)?;
+                source.read_exact(&mut data).map_err(error)?;
+                target.seek(SeekFrom::Start(*offset)).map_err(error)?;
+                target.write_all(&data).map_err(error)?;
+            }
+            target.sync_all().map_err(error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Notes the file is at its final path, the folders left empty after the incomplete file
+    /// was moved away are removed
+    fn mark_completed(&self, location: &StorageLocation, file: usize, moved_from: Option<&Path>) {
+        if let Some(from) = moved_from {
+            let folder = self.incomplete.dir.as_ref().unwrap_or(&location.root);
+            remove_empty_folders(from, folder);
+        }
+
+        let mut completed = self.completed.lock().unwrap_or_else(|e| e.into_inner());
+        completed[file] = Some(true);
+    }
+
+    pub(super) fn is_skipped(&self, file: usize) -> bool {
+        let skipped = self.skipped.lock().unwrap_or_else(|e| e.into_inner());
+        skipped.get(file).copied().unwrap_or(false)
+    }
+
+    /// The offset of the slice within the piece
+    fn piece_offset(&self, piece: u32, slice: &FileSlice) -> u64 {
+        self.layout.files[slice.file].offset + slice.offset
+            - piece as u64 * self.layout.piece_length
+    }
+
+    /// Moves the parts of the file kept in the part file to the file itself
+    fn restore_from_part_file(&self, file: usize, part_file: &mut PartFile) -> Result<(), String> {
+        let (first, last) = self.layout.file_pieces(file);
+        for piece in first..last {
+            let size = self.layout.piece_size(piece);
+            for slice in self.layout.map(piece, 0, size)? {
+                if slice.file != file {
+                    continue;
+                }
+
+                let mut data = vec![0u8; slice.length as usize];
+                if part_file
+                    .read(piece, self.piece_offset(piece, &slice), &mut data)
+                    .is_ok()
+                {
+                    self.write_at(file, slice.offset, &data)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the move started by [Storage::move_to], once the files are copied
+    fn switch_to(&self, root: &Path, done: Vec<CopiedFile>) -> Result<(), String> {
+        let mut part_file = self.part_file.lock().unwrap_or_else(|e| e.into_inner());
+        let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+        // The files might be renamed or completed during the copy
+        let location = self.current().clone();
+        let target = StorageLocation {
+            root: root.to_path_buf(),
+            files: location.files.clone(),
+        };
+        let (done, stale): (Vec<_>, Vec<_>) = done.into_iter().partition(|file| {
+            file.from == self.path_in(&location, file.file)
+                && file.to == self.path_in(&target, file.file)
+        });
+        let remove_copies = |done: &[CopiedFile]| {
+            for file in done.iter().chain(&stale) {
+                let _ = fs::remove_file(&file.to);
+            }
+        };
+
+        let written = self.moving.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Err(msg) = Self::repeat_writes(&done, written.as_deref().unwrap_or_default()) {
+            remove_copies(&done);
+            return Err(msg);
+        }
+
+        Self::sync_files(&handles)?;
+        handles.clear();
+        part_file.close()?;
+
+        let mut paths: Vec<(PathBuf, PathBuf)> = (0..location.files.len())
+            .filter(|file| !done.iter().any(|done| done.file == *file))
+            .map(|file| (self.path_in(&location, file), self.path_in(&target, file)))
+            .collect();
+        paths.push((
+            location.root.join(&self.part_file_name),
+            root.join(&self.part_file_name),
+        ));
+
+        let mut moved = vec![];
+        for (from, to) in paths {
+            match move_file(&from, &to) {
+                Ok(true) => moved.push((from, to)),
+                Ok(false) => {}
+                Err(msg) => {
+                    for (from, to) in moved.iter().rev() {
+                        let _ = move_file(to, from);
+                    }
+                    remove_copies(&done);
+                    return Err(msg);
+                }
+            }
+        }
+
+        for file in &stale {
+            let _ = fs::remove_file(&file.to);
+        }
+        for file in &done {
+            let _ = fs::remove_file(&file.from);
+            remove_empty_folders(&file.from, &location.root);
+        }
+        for (from, _) in &moved {
+            remove_empty_folders(from, &location.root);
+        }
+
+        part_file.set_path(root.join(&self.part_file_name));
+        self.current().root = root.to_path_buf();
+        Ok(())
+    }
+
+    /// Writes the part of the piece, which belongs to the slice
+    pub(super) fn write_slice(
+        &self,
+        piece: u32,
+        slice: &FileSlice,
+        data: &[u8],
+    ) -> Result<(), String> {
+        if self.is_skipped(slice.file) {
+            let mut part_file = self.part_file.lock().unwrap_or_else(|e| e.into_inner());
+            part_file.write(piece, self.piece_offset(piece, slice), data)
+        } else {
+            self.write_at(slice.file, slice.offset, data)
+        }
+    }
+
+    /// Writes the data to the file. The write is noted while the files are being moved, so it
+    /// can be repeated at the new place.
+    fn write_at(&self, file: usize, offset: u64, data: &[u8]) -> Result<(), String> {
+        self.with_file(file, true, |handle| {
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.write_all(data)?;
+            let mut moving = self.moving.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(written) = moving.as_mut() {
+                written.push((file, offset, data.len() as u64));
+            }
+            Ok(())
+        })
+    }
+
+    pub(super) fn read_slice(
+        &self,
+        piece: u32,
+        slice: &FileSlice,
+        buffer: &mut [u8],
+    ) -> Result<(), String> {
+        if self.is_skipped(slice.file) {
+            let mut part_file = self.part_file.lock().unwrap_or_else(|e| e.into_inner());
+            part_file.read(piece, self.piece_offset(piece, slice), buffer)
+        } else {
+            self.with_file(slice.file, false, |file| {
+                file.seek(SeekFrom::Start(slice.offset))?;
+                file.read_exact(buffer)
+            })
+        }
+    }
+
+    /// Runs the action against the file. Files opened for writing are cached, while files
+    /// that don't exist yet are never created by reading.
+    fn with_file<T>(
+        &self,
+        file: usize,
+        create: bool,
+        action: impl FnOnce(&mut File) -> std::io::Result<T>,
+    ) -> Result<T, String> {
+        let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+        let path = self.file_path(file);
+
+        if let Some(handle) = handles.get_mut(&file) {
+            return action(handle).map_err(|e| format!("{:?}: {}", path, e));
+        }
+
+        if !create {
+            let mut handle =
+                File::open(&path).map_err(|e| format!("Unable open file {:?}: {}", path, e))?;
+            return action(&mut handle).map_err(|e| format!("{:?}: {}", path, e));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Unable create folder {:?}: {}", parent, e))?;
+        }
+
+        let handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("Unable open file {:?}: {}", path, e))?;
+
+        action(handles.entry(file).or_insert(handle)).map_err(|e| format!("{:?}: {}", path, e))
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        let mut written = 0;
+        for slice in self.layout.map(piece, offset, data.len() as u64)? {
+            self.write_slice(
+                piece,
+                &slice,
+                &data[written..written + slice.length as usize],
+            )?;
+            written += slice.length as usize;
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        let mut result = vec![0u8; length as usize];
+        let mut read = 0;
+        for slice in self.layout.map(piece, offset, length as u64)? {
+            self.read_slice(
+                piece,
+                &slice,
+                &mut result[read..read + slice.length as usize],
+            )?;
+            read += slice.length as usize;
+        }
+
+        Ok(result)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        // Empty files never receive any data, so they are created here
+        for (index, file) in self.layout.files.iter().enumerate() {
+            if file.length == 0 && !self.is_skipped(index) && !self.file_path(index).exists() {
+                self.with_file(index, true, |_| Ok(()))?;
+            }
+        }
+
+        // The part file is locked before the handles everywhere else
+        {
+            let handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+            Self::sync_files(&handles)?;
+        }
+
+        let mut part_file = self.part_file.lock().unwrap_or_else(|e| e.into_inner());
+        part_file.flush()
+    }
+
+    /// The files already on disk are never skipped, their data stays where it is. The files
+    /// that are not skipped anymore get their data back from the part file.
+    fn set_skipped_files(&self, skipped: &[bool]) -> Result<(), String> {
+        let mut part_file = self.part_file.lock().unwrap_or_else(|e| e.into_inner());
+        let mut current = self.skipped.lock().unwrap_or_else(|e| e.into_inner());
+
+        for (file, skip) in skipped.iter().enumerate().take(current.len()) {
+            let skip = *skip && !self.file_path(file).exists();
+            if current[file] && !skip {
+                self.restore_from_part_file(file, &mut part_file)?;
+            }
+            current[file] = skip;
+        }
+
+        Ok(())
+    }
+
+    fn preallocate(&self, mode: Preallocation) -> Result<(), String> {
+        if mode == Preallocation::None {
+            return Ok(());
+        }
+
+        for (index, file) in self.layout.files.iter().enumerate() {
+            if self.is_skipped(index) {
+                continue;
+            }
+
+            self.with_file(index, true, |handle| {
+                let current = handle.metadata()?.len();
+                if current >= file.length {
+                    return Ok(());
+                }
+
+                match mode {
+                    Preallocation::Sparse => handle.set_len(file.length),
+                    _ => {
+                        let zeros = vec![0u8; ZERO_CHUNK_SIZE];
+                        handle.seek(SeekFrom::Start(current))?;
+                        let mut left = file.length - current;
+                        while left > 0 {
+                            let chunk = left.min(ZERO_CHUNK_SIZE as u64) as usize;
+                            handle.write_all(&zeros[..chunk])?;
+                            left -= chunk as u64;
+                        }
+                        Ok(())
+                    }
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The incomplete files are written to their own folder, and they are moved to the output
+    /// folder once complete, so the files have to fit into both
+    fn available_space(&self) -> Option<u64> {
+        let space = |folder: &Path| {
+            // The folder might not exist yet
+            let existing = folder
+                .ancestors()
+                .find(|path| path.exists())
+                .unwrap_or_else(|| Path::new("."));
+            fs2::available_space(existing).ok()
+        };
+
+        let root = space(&self.root())?;
+        match &self.incomplete.dir {
+            Some(dir) => space(dir).map(|incomplete| incomplete.min(root)),
+            None => Some(root),
+        }
+    }
+
+    fn location(&self) -> Option<StorageLocation> {
+        Some(self.current().clone())
+    }
+
+    /// The files are linked or copied to the new place first, while the torrent keeps using
+    /// the old ones. The writes made meanwhile are repeated at the new place, and the storage
+    /// switches to it under a short lock. The files changed meanwhile are moved then, and
+    /// everything is moved back if any of them fails.
+    fn move_to(&self, root: &Path) -> Result<(), String> {
+        let location = {
+            let handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+            let location = self.current().clone();
+            if location.root == root {
+                return Ok(());
+            }
+
+            let mut moving = self.moving.lock().unwrap_or_else(|e| e.into_inner());
+            if moving.is_some() {
+                return Err("The files are being moved already".to_string());
+            }
+            Self::sync_files(&handles)?;
+            *moving = Some(vec![]);
+            location
+        };
+
+        let target = StorageLocation {
+            root: root.to_path_buf(),
+            files: location.files.clone(),
+        };
+        let done = self.copy_files(&location, &target);
+        let result = done.and_then(|done| self.switch_to(root, done));
+        *self.moving.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        result
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> Result<(), String> {
+        let is_relative = path.components().next().is_some()
+            && path.components().all(|c| matches!(c, Component::Normal(_)));
+        if !is_relative {
+            return Err(format!("Invalid file path {:?}", path));
+        }
+
+        let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+        let location = self.current().clone();
+        let current = location
+            .files
+            .get(file)
+            .ok_or_else(|| format!("There is no file {}", file))?;
+        if current == path {
+            return Ok(());
+        }
+        if location.files.iter().any(|other| other == path) {
+            return Err(format!("File {:?} already belongs to the torrent", path));
+        }
+
+        if let Some(handle) = handles.remove(&file) {
+            handle
+                .sync_data()
+                .map_err(|e| format!("Unable flush data to disk: {}", e))?;
+        }
+
+        let mut target = location.clone();
+        target.files[file] = path.to_path_buf();
+        let from = self.path_in(&location, file);
+        if move_file(&from, &self.path_in(&target, file))? {
+            remove_empty_folders(&from, &location.root);
+        }
+
+        self.current().files[file] = path.to_path_buf();
+        Ok(())
+    }
+
+    /// The file is renamed under the lock. If the output folder is on another file system, the
+    /// file is copied without the lock, while the torrent keeps reading the incomplete one, and
+    /// the storage switches to the copy under a short lock. The copy starts over if the files
+    /// were moved or renamed meanwhile.
+    fn file_completed(&self, file: usize) -> Result<(), String> {
+        loop {
+            let (location, from, to) = {
+                let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+                let location = self.current().clone();
+                if self.is_complete(&location, file) {
+                    return Ok(());
+                }
+
+                if let Some(handle) = handles.remove(&file) {
+                    handle
+                        .sync_data()
+                        .map_err(|e| format!("Unable flush data to disk: {}", e))?;
+                }
+
+                let from = self.incomplete_path(&location, file);
+                let to = location.root.join(&location.files[file]);
+                if let Some(moved) = try_rename(&from, &to)? {
+                    self.mark_completed(&location, file, moved.then_some(&from));
+                    return Ok(());
+                }
+                (location, from, to)
+            };
+
+            link_or_copy(&from, &to)?;
+            let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+            if *self.current() != location {
+                let _ = fs::remove_file(&to);
+                continue;
+            }
+
+            handles.remove(&file);
+            let _ = fs::remove_file(&from);
+            self.mark_completed(&location, file, Some(&from));
+            return Ok(());
+        }
+    }
+
+    fn metadata(&self, file: usize) -> Option<FileMetadata> {
+        let metadata = fs::metadata(self.file_path(file)).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos() as i64)
+            .unwrap_or_default();
+
+        Some(FileMetadata {
+            length: metadata.len(),
+            allocated: allocated_size(&metadata),
+            modified,
+        })
+    }
+}
+
+/// The bytes taken by the file on disk, up to its length
+#[cfg(unix)]
+fn allocated_size(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.blocks() * 512).min(metadata.len())
+}
+
+#[cfg(not(unix))]
+fn allocated_size(metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// A file linked or copied to the new place by [Storage::move_to]
+#[derive(Debug)]
+struct CopiedFile {
+    file: usize,
+    from: PathBuf,
+    to: PathBuf,
+    /// The link shares the data with the old file, while the copy misses the later writes
+    copied: bool,
+}
+
+/// Links the file to the new place, or copies it if the target is on another file system.
+/// Returns whether the file was copied, or `None` if the file doesn't exist.
+fn link_or_copy(from: &Path, to: &Path) -> Result<Option<bool>, String> {
+    if from == to || !from.exists() {
+        return Ok(None);
+    }
+    if to.exists() {
+        return Err(format!("File {:?} already exists", to));
+    }
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Unable create folder {:?}: {}", parent, e))?;
+    }
+
+    if fs::hard_link(from, to).is_ok() {
+        return Ok(Some(false));
+    }
+
+    let error = |e: std::io::Error| format!("Unable move {:?} to {:?}: {}", from, to, e);
+    fs::copy(from, to)
+        .and_then(|_| File::open(to))
+        .and_then(|file| file.sync_all())
+        .map(|_| Some(true))
+        .map_err(|e| {
+            let _ = fs::remove_file(to);
+            error(e)
+        })
+}
+
+/// Moves the file, copying it if the target is on another file system. Returns `false` if
+/// the file doesn't exist, as it's either moved already or not created yet.
+fn move_file(from: &Path, to: &Path) -> Result<bool, String> {
+    match try_rename(from, to)? {
+        Some(moved) => Ok(moved),
+        None => copy_and_remove(from, to).map(|_| true),
+    }
+}
+
+/// Renames the file like [move_file], but returns `None` if the target is on another file
+/// system, so the file has to be copied
+fn try_rename(from: &Path, to: &Path) -> Result<Option<bool>, String> {
+    if from == to || !from.exists() {
+        return Ok(Some(false));
+    }
+    if to.exists() {
+        return Err(format!("File {:?} already exists", to));
+    }
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Unable create folder {:?}: {}", parent, e))?;
+    }
+
+    match fs::rename(from, to) {
+        Ok(()) => Ok(Some(true)),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => Ok(None),
+        Err(e) => Err(format!("Unable move {:?} to {:?}: {}", from, to, e)),
+    }
+}
+
+/// The target is removed if anything goes wrong, so the file stays at its old place
+fn copy_and_remove(from: &Path, to: &Path) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("Unable move {:?} to {:?}: {}", from, to, e);
+    fs::copy(from, to).map_err(error)?;
+
+    File::open(to)
+        .and_then(|file| file.sync_all())
+        .and_then(|_| fs::remove_file(from))
+        .map_err(|e| {
+            let _ = fs::remove_file(to);
+            error(e)
+        })
+}
+
+/// Removes the folders left empty after the file was moved away, up to the root
+fn remove_empty_folders(file: &Path, root: &Path) {
+    for folder in file.ancestors().skip(1) {
+        if folder == root || !folder.starts_with(root) || fs::remove_dir(folder).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::check_disk_space;
+
+    #[test]
+    fn writes_and_reads_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/a.bin"), 5),
+                (PathBuf::from("torrent/sub/b.bin"), 7),
+            ],
+            4,
+        );
+        let storage = FileStorage::new(dir.path(), layout);
+
+        storage.write(1, 0, &[1, 2, 3, 4]).unwrap();
+        storage.write(0, 0, &[0, 0, 0, 0]).unwrap();
+        storage.write(2, 0, &[5, 6, 7, 8]).unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(storage.read(1, 1, 3).unwrap(), vec![2, 3, 4]);
+        assert_eq!(
+            fs::read(dir.path().join("torrent/a.bin")).unwrap(),
+            vec![0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            fs::read(dir.path().join("torrent/sub/b.bin")).unwrap(),
+            vec![2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn skipped_files_are_kept_in_part_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/a.bin"), 5),
+                (PathBuf::from("torrent/b.bin"), 7),
+            ],
+            4,
+        );
+        let storage = FileStorage::new(dir.path(), layout);
+        storage.set_skipped_files(&[false, true]).unwrap();
+
+        storage.write(1, 0, &[1, 2, 3, 4]).unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(storage.read(1, 0, 4).unwrap(), vec![1, 2, 3, 4]);
+        assert!(!dir.path().join("torrent/b.bin").exists());
+        assert!(dir.path().join(".torrent.parts").exists());
+        assert_eq!(fs::read(dir.path().join("torrent/a.bin")).unwrap()[4], 1);
+
+        storage.set_skipped_files(&[false, false]).unwrap();
+        assert_eq!(
+            fs::read(dir.path().join("torrent/b.bin")).unwrap(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(storage.read(1, 0, 4).unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn preallocates_selected_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/a.bin"), 5),
+                (PathBuf::from("torrent/b.bin"), 3 * 1024 * 1024),
+                (PathBuf::from("torrent/c.bin"), 7),
+            ],
+            4,
+        );
+        let storage = FileStorage::new(dir.path(), layout);
+        storage.set_skipped_files(&[false, false, true]).unwrap();
+        storage.write(0, 0, &[1, 2, 3, 4]).unwrap();
+
+        storage.preallocate(Preallocation::Sparse).unwrap();
+        assert_eq!(fs::metadata(storage.file_path(0)).unwrap().len(), 5);
+        assert!(!storage.file_path(2).exists());
+
+        storage.preallocate(Preallocation::Full).unwrap();
+        let data = fs::read(storage.file_path(1)).unwrap();
+        assert_eq!(data.len(), 3 * 1024 * 1024);
+        assert_eq!(fs::read(storage.file_path(0)).unwrap(), vec![1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn huge_torrent_does_not_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(vec![(PathBuf::from("huge"), 1 << 62)], 1 << 20);
+        let storage = FileStorage::new(dir.path().join("missing/folder"), layout);
+
+        assert!(storage.available_space().is_some());
+        let error = check_disk_space(&storage, &[false]).unwrap_err();
+        assert!(error.starts_with("Not enough disk space"));
+        assert!(check_disk_space(&storage, &[true]).is_ok());
+    }
+
+    #[test]
+    fn sparse_files_need_disk_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let probe = FileStorage::new(dir.path(), StorageLayout::new(vec![], 1));
+        let length = probe.available_space().unwrap() + (1 << 30);
+        let layout = StorageLayout::new(vec![(PathBuf::from("sparse"), length)], 1 << 20);
+        let storage = FileStorage::new(dir.path(), layout);
+
+        storage.preallocate(Preallocation::Sparse).unwrap();
+        let metadata = storage.metadata(0).unwrap();
+        assert_eq!(metadata.length, length);
+        assert!(metadata.allocated < length);
+        assert!(check_disk_space(&storage, &[false]).is_err());
+    }
+
+    #[test]
+    fn incomplete_files_need_disk_space() {
+        // The memory file system is usually smaller than the temporary folder
+        let dir = tempfile::tempdir().unwrap();
+        let incomplete = match tempfile::tempdir_in("/dev/shm") {
+            Ok(incomplete) => incomplete,
+            Err(_) => return,
+        };
+        let probe = |folder: &Path| {
+            FileStorage::new(folder, StorageLayout::new(vec![], 1))
+                .available_space()
+                .unwrap()
+        };
+        let (output_space, incomplete_space) = (probe(dir.path()), probe(incomplete.path()));
+        if incomplete_space + (1 << 30) >= output_space {
+            return;
+        }
+
+        let length = incomplete_space + (1 << 30);
+        let layout = StorageLayout::new(vec![(PathBuf::from("big"), length)], 1 << 20);
+        let storage = FileStorage::new(dir.path(), layout.clone());
+        assert!(check_disk_space(&storage, &[false]).is_ok());
+
+        let storage = FileStorage::new(dir.path(), layout).with_incomplete(IncompleteFiles {
+            dir: Some(incomplete.path().join("missing")),
+            suffix: None,
+        });
+        assert!(check_disk_space(&storage, &[false]).is_err());
+    }
+
+    #[test]
+    fn moves_and_renames_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/sub/a.bin"), 4),
+                (PathBuf::from("torrent/b.bin"), 4),
+                (PathBuf::from("torrent/c.bin"), 4),
+            ],
+            4,
+        );
+        let storage = FileStorage::new(dir.path().join("old"), layout);
+        storage.set_skipped_files(&[false, false, true]).unwrap();
+        storage.write(0, 0, &[1; 4]).unwrap();
+        storage.write(2, 0, &[3; 4]).unwrap();
+
+        let new_root = dir.path().join("new");
+        storage.move_to(&new_root).unwrap();
+        assert!(!dir.path().join("old/torrent").exists());
+        assert!(dir.path().join("old").exists());
+        assert_eq!(
+            fs::read(new_root.join("torrent/sub/a.bin")).unwrap(),
+            [1; 4]
+        );
+        assert!(new_root.join(".torrent.parts").exists());
+
+        storage.write(1, 0, &[2; 4]).unwrap();
+        storage.rename_file(0, Path::new("renamed/a.bin")).unwrap();
+        storage.rename_file(2, Path::new("renamed/c.bin")).unwrap();
+        assert!(storage.rename_file(1, Path::new("../b.bin")).is_err());
+        assert!(storage.rename_file(1, Path::new("renamed/a.bin")).is_err());
+
+        assert!(!new_root.join("torrent/sub").exists());
+        assert_eq!(fs::read(new_root.join("renamed/a.bin")).unwrap(), [1; 4]);
+        assert_eq!(storage.read(0, 0, 4).unwrap(), [1; 4]);
+        assert_eq!(storage.read(2, 0, 4).unwrap(), [3; 4]);
+        assert_eq!(
+            storage.location().unwrap().files,
+            vec![
+                PathBuf::from("renamed/a.bin"),
+                PathBuf::from("torrent/b.bin"),
+                PathBuf::from("renamed/c.bin"),
+            ]
+        );
+
+        // The files already moved by someone else are just picked up
+        let other = dir.path().join("other");
+        fs::rename(&new_root, &other).unwrap();
+        storage.move_to(&other).unwrap();
+        assert_eq!(storage.read(1, 0, 4).unwrap(), [2; 4]);
+    }
+
+    #[test]
+    fn writes_during_move_reach_new_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/a.bin"), 4),
+                (PathBuf::from("torrent/b.bin"), 4),
+            ],
+            4,
+        );
+        let old_root = dir.path().join("old");
+        let new_root = dir.path().join("new");
+        let storage = FileStorage::new(&old_root, layout);
+        storage.write(0, 0, &[1; 4]).unwrap();
+        storage.write(1, 0, &[2; 4]).unwrap();
+
+        // The move is started as move_to does, and the links are replaced with the copies made
+        // on another file system
+        *storage.moving.lock().unwrap() = Some(vec![]);
+        let location = storage.location().unwrap();
+        let target = StorageLocation {
+            root: new_root.clone(),
+            files: location.files.clone(),
+        };
+        let mut done = storage.copy_files(&location, &target).unwrap();
+        for file in done.iter_mut() {
+            fs::remove_file(&file.to).unwrap();
+            fs::copy(&file.from, &file.to).unwrap();
+            file.copied = true;
+        }
+
+        // The torrent keeps using the old files meanwhile
+        storage.write(1, 2, &[3; 2]).unwrap();
+        assert_eq!(storage.read(1, 0, 4).unwrap(), [2, 2, 3, 3]);
+        assert_eq!(fs::read(new_root.join("torrent/b.bin")).unwrap(), [2; 4]);
+
+        storage.switch_to(&new_root, done).unwrap();
+        *storage.moving.lock().unwrap() = None;
+        assert!(!old_root.join("torrent").exists());
+        assert_eq!(fs::read(new_root.join("torrent/a.bin")).unwrap(), [1; 4]);
+        assert_eq!(
+            fs::read(new_root.join("torrent/b.bin")).unwrap(),
+            [2, 2, 3, 3]
+        );
+        assert_eq!(storage.read(1, 0, 4).unwrap(), [2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn completed_files_leave_incomplete_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/sub/a.bin"), 4),
+                (PathBuf::from("torrent/b.bin"), 4),
+            ],
+            4,
+        );
+        let output = dir.path().join("output");
+        let incomplete = dir.path().join("incomplete");
+        let storage = FileStorage::new(&output, layout.clone()).with_incomplete(IncompleteFiles {
+            dir: Some(incomplete.clone()),
+            suffix: Some(".part".to_string()),
+        });
+
+        storage.write(0, 0, &[1; 4]).unwrap();
+        storage.write(1, 0, &[2; 4]).unwrap();
+        assert!(incomplete.join("torrent/sub/a.bin.part").is_file());
+        assert!(!output.exists());
+
+        storage.file_completed(0).unwrap();
+        assert!(!incomplete.join("torrent/sub").exists());
+        assert_eq!(fs::read(output.join("torrent/sub/a.bin")).unwrap(), [1; 4]);
+        assert_eq!(storage.read(0, 0, 4).unwrap(), [1; 4]);
+        assert_eq!(storage.read(1, 0, 4).unwrap(), [2; 4]);
+
+        // The state is found out from the files on disk after a restart
+        let storage = FileStorage::new(&output, layout).with_incomplete(IncompleteFiles {
+            dir: Some(incomplete.clone()),
+            suffix: Some(".part".to_string()),
+        });
+        assert_eq!(storage.file_path(0), output.join("torrent/sub/a.bin"));
+        assert_eq!(storage.file_path(1), incomplete.join("torrent/b.bin.part"));
+        assert_eq!(storage.read(1, 0, 4).unwrap(), [2; 4]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn completed_files_are_copied_across_file_systems() {
+        use std::os::unix::fs::MetadataExt;
+
+        // The memory file system is another one than the temporary folder, if it's there
+        let dir = tempfile::tempdir().unwrap();
+        let incomplete = match tempfile::tempdir_in("/dev/shm") {
+            Ok(incomplete) => incomplete,
+            Err(_) => return,
+        };
+        let device = |path: &Path| fs::metadata(path).unwrap().dev();
+        if device(dir.path()) == device(incomplete.path()) {
+            return;
+        }
+
+        let layout = StorageLayout::new(vec![(PathBuf::from("torrent/a.bin"), 4)], 4);
+        let storage = FileStorage::new(dir.path(), layout).with_incomplete(IncompleteFiles {
+            dir: Some(incomplete.path().to_path_buf()),
+            suffix: None,
+        });
+
+        storage.write(0, 0, &[1; 4]).unwrap();
+        storage.file_completed(0).unwrap();
+        assert!(!incomplete.path().join("torrent").exists());
+        assert_eq!(fs::read(dir.path().join("torrent/a.bin")).unwrap(), [1; 4]);
+        assert_eq!(storage.read(0, 0, 4).unwrap(), [1; 4]);
+        storage.file_completed(0).unwrap();
+    }
+
+    #[test]
+    fn copies_across_file_systems() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.bin");
+        let to = dir.path().join("b.bin");
+        fs::write(&from, [1, 2, 3]).unwrap();
+
+        copy_and_remove(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), [1, 2, 3]);
+        assert!(copy_and_remove(&from, &to).is_err());
+        assert!(to.exists());
+    }
+
+    #[test]
+    fn reading_missing_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(vec![(PathBuf::from("missing"), 4)], 4);
+        let storage = FileStorage::new(dir.path(), layout);
+
+        assert!(storage.read(0, 0, 4).is_err());
+    }
+}
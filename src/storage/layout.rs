@@ -0,0 +1,228 @@
+use crate::protocol::entities::Torrent;
+use crate::storage::{sanitize_component, sanitize_paths};
+use std::path::PathBuf;
+
+/// A file of the torrent content and its place in the content byte stream
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileEntry {
+    /// The path relative to the output folder. The first component is the torrent name.
+    pub path: PathBuf,
+    pub length: u64,
+    /// The offset of the first file byte within the whole torrent content
+    pub offset: u64,
+}
+
+/// A part of a piece range, which falls into a single file
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileSlice {
+    pub file: usize,
+    /// The offset within the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The torrent content is a single byte stream split into pieces, while on the disk it's a
+/// list of files. The layout maps the piece ranges onto the files.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StorageLayout {
+    pub files: Vec<FileEntry>,
+    pub piece_length: u64,
+    pub total_length: u64,
+}
+
+impl StorageLayout {
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u64) -> Self {
+        let mut offset = 0;
+        let files: Vec<FileEntry> = files
+            .into_iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path,
+                    length,
+                    offset,
+                };
+                offset += length;
+                entry
+            })
+            .collect();
+
+        StorageLayout {
+            files,
+            piece_length,
+            total_length: offset,
+        }
+    }
+
+    /// Builds the layout following the `TorrentFile::path` components. The content of the
+    /// multi-file torrent is placed in the folder named after the torrent. The paths come from
+    /// an untrusted source, so they are sanitized first, see [sanitize_paths]. The torrent name,
+    /// which is empty after the sanitization, is replaced with the info hash, so the content
+    /// never lands right in the output folder.
+    pub fn from_torrent(torrent: &Torrent) -> Result<Self, String> {
+        let piece_length = torrent.info.piece_length;
+        if piece_length <= 0 || piece_length > u32::MAX as i64 {
+            return Err(format!("Invalid piece length {}", piece_length));
+        }
+        let pieces_count = torrent.total_size().div_ceil(piece_length as u64);
+        if torrent.info.pieces.len() as u64 != pieces_count * 20 {
+            return Err(format!(
+                "Expected {} piece hashes, got {} bytes of them",
+                pieces_count,
+                torrent.info.pieces.len()
+            ));
+        }
+
+        let name = match sanitize_component(&torrent.info.name) {
+            Some(_) => torrent.info.name.clone(),
+            None => torrent
+                .info_hash()?
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        };
+        let (paths, lengths): (Vec<Vec<String>>, Vec<u64>) = match &torrent.info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    let mut path = vec![name.clone()];
+                    path.extend(file.path.iter().cloned());
+                    (path, file.length)
+                })
+                .unzip(),
+            None => (vec![vec![name.clone()]], vec![torrent.total_size()]),
+        };
+
+        if let Some(file) = torrent.info.files.iter().flatten().find(|file| {
+            file.path
+                .iter()
+                .all(|component| sanitize_component(component).is_none())
+        }) {
+            return Err(format!(
+                "File path {:?} is empty after sanitization",
+                file.path
+            ));
+        }
+
+        let files = sanitize_paths(&paths)?.into_iter().zip(lengths).collect();
+        Ok(StorageLayout::new(files, piece_length as u64))
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    pub fn piece_size(&self, piece: u32) -> u64 {
+        let offset = piece as u64 * self.piece_length;
+        self.total_length
+            .saturating_sub(offset)
+            .min(self.piece_length)
+    }
+
+    /// Maps the range of the piece onto the files
+    pub fn map(&self, piece: u32, offset: u32, length: u64) -> Result<Vec<FileSlice>, String> {
+        let start = piece as u64 * self.piece_length + offset as u64;
+        let end = start + length;
+        if offset as u64 + length > self.piece_size(piece) {
+            return Err(format!(
+                "Range {}+{} is out of the piece {} bounds",
+                offset, length, piece
+            ));
+        }
+
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= start);
+
+        Ok(self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, file)| file.offset < end)
+            .map(|(index, file)| {
+                let slice_start = start.max(file.offset);
+                let slice_end = end.min(file.offset + file.length);
+                FileSlice {
+                    file: first + index,
+                    offset: slice_start - file.offset,
+                    length: slice_end - slice_start,
+                }
+            })
+            .filter(|slice| slice.length > 0)
+            .collect())
+    }
+
+    /// The range of pieces the file belongs to, the first one inclusive and the last one
+    /// exclusive
+    pub fn file_pieces(&self, file: usize) -> (u32, u32) {
+        let entry = &self.files[file];
+        let first = entry.offset / self.piece_length;
+        if entry.length == 0 {
+            return (first as u32, first as u32);
+        }
+
+        let last = (entry.offset + entry.length).div_ceil(self.piece_length);
+        (first as u32, last as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> StorageLayout {
+        StorageLayout::new(
+            vec![
+                (PathBuf::from("a"), 10),
+                (PathBuf::from("empty"), 0),
+                (PathBuf::from("b"), 4),
+                (PathBuf::from("c"), 20),
+            ],
+            8,
+        )
+    }
+
+    #[test]
+    fn maps_range_within_one_file() {
+        assert_eq!(
+            layout().map(0, 2, 4).unwrap(),
+            vec![FileSlice {
+                file: 0,
+                offset: 2,
+                length: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn maps_range_across_files() {
+        assert_eq!(
+            layout().map(1, 0, 8).unwrap(),
+            vec![
+                FileSlice {
+                    file: 0,
+                    offset: 8,
+                    length: 2
+                },
+                FileSlice {
+                    file: 2,
+                    offset: 0,
+                    length: 4
+                },
+                FileSlice {
+                    file: 3,
+                    offset: 0,
+                    length: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn last_piece_is_shorter() {
+        let layout = layout();
+        assert_eq!(layout.pieces_count(), 5);
+        assert_eq!(layout.piece_size(4), 2);
+        assert!(layout.map(4, 0, 3).is_err());
+        assert_eq!(layout.file_pieces(3), (1, 5));
+        assert_eq!(layout.file_pieces(1), (1, 1));
+    }
+}
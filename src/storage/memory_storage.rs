@@ -0,0 +1,140 @@
+use crate::storage::{Storage, StorageLayout};
+use std::sync::{Mutex, MutexGuard};
+
+/// Keeps the torrent content in memory, nothing ever touches the disk. A file takes memory
+/// after the first write to it only, so the skipped files cost nothing. The data is available
+/// through [MemoryStorage::with_file] while the storage is shared with the running torrent.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: StorageLayout,
+    /// The content of every file, empty until the file is written to
+    files: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: StorageLayout) -> Self {
+        MemoryStorage {
+            files: Mutex::new(vec![vec![]; layout.files.len()]),
+            layout,
+        }
+    }
+
+    /// Creates the storage with the complete content of the torrent, e.g. for a seeder
+    pub fn with_content(layout: StorageLayout, content: &[u8]) -> Result<Self, String> {
+        if content.len() as u64 != layout.total_length {
+            return Err(format!(
+                "Expected {} bytes of content, got {}",
+                layout.total_length,
+                content.len()
+            ));
+        }
+
+        let files = layout
+            .files
+            .iter()
+            .map(|file| {
+                content[file.offset as usize..(file.offset + file.length) as usize].to_vec()
+            })
+            .collect();
+
+        Ok(MemoryStorage {
+            files: Mutex::new(files),
+            layout,
+        })
+    }
+
+    fn files(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs the action against the content of the file. Returns `None` if there is no such
+    /// file, or nothing was written to it yet.
+    pub fn with_file<T>(&self, file: usize, action: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        let files = self.files();
+        let length = self.layout.files.get(file)?.length;
+        let data = files.get(file)?;
+        (data.len() as u64 == length).then(|| action(data))
+    }
+
+    /// A copy of the file content, see [MemoryStorage::with_file]
+    pub fn file(&self, file: usize) -> Option<Vec<u8>> {
+        self.with_file(file, |data| data.to_vec())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        let mut files = self.files();
+        let mut written = 0;
+        for slice in self.layout.map(piece, offset, data.len() as u64)? {
+            let content = &mut files[slice.file];
+            content.resize(self.layout.files[slice.file].length as usize, 0);
+
+            let start = slice.offset as usize;
+            let length = slice.length as usize;
+            content[start..start + length].copy_from_slice(&data[written..written + length]);
+            written += length;
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        let files = self.files();
+        let mut result = Vec::with_capacity(length as usize);
+        for slice in self.layout.map(piece, offset, length as u64)? {
+            let content = &files[slice.file];
+            if content.is_empty() {
+                return Err(format!(
+                    "File {:?} has no data",
+                    self.layout.files[slice.file].path
+                ));
+            }
+
+            let start = slice.offset as usize;
+            result.extend_from_slice(&content[start..start + slice.length as usize]);
+        }
+
+        Ok(result)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn keeps_files_in_memory() {
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("t/a"), 6),
+                (PathBuf::from("t/b"), 4),
+                (PathBuf::from("t/c"), 5),
+            ],
+            8,
+        );
+        let storage = MemoryStorage::new(layout.clone());
+
+        storage.write(0, 4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(storage.file(0), Some(vec![0, 0, 0, 0, 1, 2]));
+        assert_eq!(storage.with_file(1, |data| data.len()), Some(4));
+        assert_eq!(storage.file(2), None);
+        assert!(storage.read(1, 0, 7).is_err());
+        assert_eq!(storage.read(0, 5, 2).unwrap(), vec![2, 3]);
+
+        let content: Vec<u8> = (0..15).collect();
+        let seeder = MemoryStorage::with_content(layout.clone(), &content).unwrap();
+        assert_eq!(seeder.read(1, 0, 7).unwrap(), &content[8..]);
+        assert_eq!(seeder.file(1), Some(vec![6, 7, 8, 9]));
+        assert!(MemoryStorage::with_content(layout, &content[1..]).is_err());
+    }
+}
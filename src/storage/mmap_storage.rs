@@ -0,0 +1,292 @@
+use crate::storage::{
+    FileMetadata, FileSlice, FileStorage, Preallocation, Storage, StorageLayout, StorageLocation,
+};
+use memmap2::{MmapMut, MmapOptions};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The files are mapped in chunks of this size, so the huge files don't need that much of
+/// the address space at once
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The chunk offsets have to be aligned to the page size, this works for all common ones
+const CHUNK_ALIGNMENT: u64 = 64 * 1024;
+
+/// Stores the torrent content in the same files as [FileStorage] does, but reads and writes
+/// them through the memory mappings. A file is extended to its full size on the first write,
+/// and it's mapped chunk by chunk, as the pieces touch the chunks.
+///
+/// A write to the mapped memory fails with a signal, not an error, if the disk is full, so
+/// the full preallocation is the safe choice for this storage.
+pub struct MmapStorage {
+    /// Serves the skipped files, the files shorter than the layout says, and everything, which
+    /// isn't about the data itself
+    files: FileStorage,
+    chunk_size: u64,
+    /// The mapped chunks by the file index and the chunk number
+    maps: Mutex<HashMap<(usize, u64), MmapMut>>,
+}
+
+impl MmapStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: StorageLayout) -> Self {
+        Self::with_chunk_size(root, layout, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// The chunk size is rounded up to the page size
+    pub fn with_chunk_size(root: impl Into<PathBuf>, layout: StorageLayout, size: u64) -> Self {
+        MmapStorage {
+            chunk_size: size.max(1).next_multiple_of(CHUNK_ALIGNMENT),
+            ..Self::from_files(FileStorage::new(root, layout))
+        }
+    }
+
+    /// Maps the files of the storage, which keeps serving everything but the data
+    pub fn from_files(files: FileStorage) -> Self {
+        MmapStorage {
+            files,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn file_path(&self, file: usize) -> PathBuf {
+        self.files.file_path(file)
+    }
+
+    /// Maps the chunk of the file. Returns `None` if the file can't be read this way, as it
+    /// doesn't exist or it's shorter than expected.
+    fn map_chunk(&self, file: usize, chunk: u64, create: bool) -> Result<Option<MmapMut>, String> {
+        let path = self.file_path(file);
+        let length = self.layout().files[file].length;
+
+        if !create {
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.len() >= length => {}
+                _ => return Ok(None),
+            }
+        } else if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Unable create folder {:?}: {}", parent, e))?;
+        }
+
+        let handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("Unable open file {:?}: {}", path, e))?;
+
+        let current = handle.metadata().map(|m| m.len()).unwrap_or_default();
+        if current < length {
+            handle
+                .set_len(length)
+                .map_err(|e| format!("Unable extend file {:?}: {}", path, e))?;
+        }
+
+        let start = chunk * self.chunk_size;
+        let map = unsafe {
+            // The files belong to the torrent, nobody is expected to truncate them meanwhile
+            MmapOptions::new()
+                .offset(start)
+                .len(self.chunk_size.min(length - start) as usize)
+                .map_mut(&handle)
+        }
+        .map_err(|e| format!("Unable map file {:?}: {}", path, e))?;
+
+        Ok(Some(map))
+    }
+
+    /// Flushes and drops the mappings of the files
+    fn unmap(
+        maps: &mut HashMap<(usize, u64), MmapMut>,
+        file: impl Fn(usize) -> bool,
+    ) -> Result<(), String> {
+        for ((_, _), map) in maps.iter().filter(|((index, _), _)| file(*index)) {
+            map.flush()
+                .map_err(|e| format!("Unable flush data to disk: {}", e))?;
+        }
+        maps.retain(|(index, _), _| !file(*index));
+        Ok(())
+    }
+
+    /// Runs the action against the mapped memory of the slice, chunk by chunk. The action gets
+    /// the memory and its position within the slice. Returns `false` if the file can't be
+    /// mapped for reading.
+    fn with_mapped(
+        &self,
+        slice: &FileSlice,
+        create: bool,
+        mut action: impl FnMut(&mut [u8], usize),
+    ) -> Result<bool, String> {
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        let end = slice.offset + slice.length;
+        let mut position = slice.offset;
+
+        while position < end {
+            let chunk = position / self.chunk_size;
+            let key = (slice.file, chunk);
+            let map = match maps.entry(key) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match self.map_chunk(slice.file, chunk, create)? {
+                    Some(map) => entry.insert(map),
+                    None => return Ok(false),
+                },
+            };
+            let from = position - chunk * self.chunk_size;
+            let to = (end - chunk * self.chunk_size).min(map.len() as u64);
+            action(
+                &mut map[from as usize..to as usize],
+                (position - slice.offset) as usize,
+            );
+            position += to - from;
+        }
+
+        Ok(true)
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &StorageLayout {
+        self.files.layout()
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        let mut written = 0;
+        for slice in self.layout().map(piece, offset, data.len() as u64)? {
+            let chunk = &data[written..written + slice.length as usize];
+            if self.files.is_skipped(slice.file) {
+                self.files.write_slice(piece, &slice, chunk)?;
+            } else {
+                self.with_mapped(&slice, true, |memory, position| {
+                    memory.copy_from_slice(&chunk[position..position + memory.len()])
+                })?;
+            }
+            written += slice.length as usize;
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        let mut result = vec![0u8; length as usize];
+        let mut read = 0;
+        for slice in self.layout().map(piece, offset, length as u64)? {
+            let chunk = &mut result[read..read + slice.length as usize];
+            let mapped = !self.files.is_skipped(slice.file)
+                && self.with_mapped(&slice, false, |memory, position| {
+                    chunk[position..position + memory.len()].copy_from_slice(memory)
+                })?;
+
+            if !mapped {
+                self.files.read_slice(piece, &slice, chunk)?;
+            }
+            read += slice.length as usize;
+        }
+
+        Ok(result)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        for map in maps.values() {
+            map.flush()
+                .map_err(|e| format!("Unable flush data to disk: {}", e))?;
+        }
+
+        self.files.flush()
+    }
+
+    fn set_skipped_files(&self, skipped: &[bool]) -> Result<(), String> {
+        self.files.set_skipped_files(skipped)
+    }
+
+    fn preallocate(&self, mode: Preallocation) -> Result<(), String> {
+        self.files.preallocate(mode)
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        self.files.available_space()
+    }
+
+    fn location(&self) -> Option<StorageLocation> {
+        self.files.location()
+    }
+
+    /// The mappings are dropped, as the files might be copied to another file system
+    fn move_to(&self, root: &Path) -> Result<(), String> {
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        Self::unmap(&mut maps, |_| true)?;
+        self.files.move_to(root)
+    }
+
+    fn rename_file(&self, file: usize, path: &Path) -> Result<(), String> {
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        Self::unmap(&mut maps, |index| index == file)?;
+        self.files.rename_file(file, path)
+    }
+
+    /// The maps aren't locked while the file is moved, as it might be copied. The file might be
+    /// mapped again by a read meanwhile, so it's unmapped once more at the end.
+    fn file_completed(&self, file: usize) -> Result<(), String> {
+        let unmap = || {
+            let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+            Self::unmap(&mut maps, |index| index == file)
+        };
+        unmap()?;
+        self.files.file_completed(file)?;
+        unmap()
+    }
+
+    fn metadata(&self, file: usize) -> Option<FileMetadata> {
+        self.files.metadata(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_across_chunks_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(
+            vec![
+                (PathBuf::from("torrent/a.bin"), 100_000),
+                (PathBuf::from("torrent/b.bin"), 200_000),
+            ],
+            65536,
+        );
+        let storage = MmapStorage::with_chunk_size(dir.path(), layout, 1);
+        assert_eq!(storage.chunk_size, CHUNK_ALIGNMENT);
+
+        let data: Vec<u8> = (0..65536u32).map(|i| (i % 251) as u8).collect();
+        storage.write(1, 0, &data).unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(storage.read(1, 0, 65536).unwrap(), data);
+        assert_eq!(fs::metadata(storage.file_path(0)).unwrap().len(), 100_000);
+        assert_eq!(fs::metadata(storage.file_path(1)).unwrap().len(), 200_000);
+
+        let file = fs::read(storage.file_path(0)).unwrap();
+        assert_eq!(&file[65536..], &data[..100_000 - 65536]);
+        assert!(storage.read(5, 0, 10).is_err());
+    }
+
+    #[test]
+    fn reads_short_files_without_mapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = StorageLayout::new(vec![(PathBuf::from("file.bin"), 1000)], 512);
+        FileStorage::new(dir.path(), layout.clone())
+            .write(0, 0, &[5; 512])
+            .unwrap();
+
+        let storage = MmapStorage::new(dir.path(), layout);
+        assert_eq!(storage.read(0, 0, 512).unwrap(), vec![5; 512]);
+        assert!(storage.read(1, 0, 488).is_err());
+        assert_eq!(fs::metadata(storage.file_path(0)).unwrap().len(), 512);
+    }
+}
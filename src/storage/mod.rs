@@ -0,0 +1,303 @@
+mod cache;
+mod file_storage;
+mod layout;
+mod memory_storage;
+#[cfg(feature = "mmap")]
+mod mmap_storage;
+mod part_file;
+mod sanitize;
+
+pub use cache::{CacheConfig, CacheStatistics, CachedStorage};
+pub use file_storage::FileStorage;
+pub use layout::{FileEntry, FileSlice, StorageLayout};
+pub use memory_storage::MemoryStorage;
+#[cfg(feature = "mmap")]
+pub use mmap_storage::{MmapStorage, DEFAULT_CHUNK_SIZE};
+pub use part_file::PartFile;
+pub use sanitize::{
+    check_path_lengths, sanitize_component, sanitize_paths, MAX_COMPONENT_LENGTH, MAX_PATH_LENGTH,
+};
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The place the torrent content is kept in. The content is addressed the same way as in the
+/// peer protocol: by the piece index and the offset within the piece. It's up to the storage
+/// how the pieces are mapped onto the files, see [StorageLayout].
+///
+/// The storage is shared between the torrent coordinator and the hashing jobs, so all methods
+/// take `&self`, and the implementations have to take care of the synchronisation.
+pub trait Storage: Send + Sync {
+    fn layout(&self) -> &StorageLayout;
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String>;
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String>;
+
+    /// Makes sure all written data reached the backing store
+    fn flush(&self) -> Result<(), String>;
+
+    /// Tells the storage the result of the hash check of the piece, e.g. a cache writes the
+    /// valid pieces out and drops the broken ones
+    fn piece_checked(&self, _piece: u32, _valid: bool) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Marks the files, which are not downloaded. The parts of the pieces shared with the
+    /// downloaded files are still written, but the storage might keep them aside, so the
+    /// skipped files are never created.
+    fn set_skipped_files(&self, _skipped: &[bool]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Reserves the space for the files, which are not skipped
+    fn preallocate(&self, _mode: Preallocation) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The free space left for the storage, `None` if it's unknown
+    fn available_space(&self) -> Option<u64> {
+        None
+    }
+
+    /// The statistics of the storages keeping the data in memory, see [CachedStorage]
+    fn cache_statistics(&self) -> Option<CacheStatistics> {
+        None
+    }
+
+    /// Tells the storage that all pieces of the file are verified, so the file might be moved
+    /// to its final place, see [IncompleteFiles]
+    fn file_completed(&self, _file: usize) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Where the files are, `None` for the storages without the files on disk
+    fn location(&self) -> Option<StorageLocation> {
+        None
+    }
+
+    /// Moves the files to the new root folder. The files, which don't exist at the old place,
+    /// are expected at the new one, so the same call points the storage to the files moved
+    /// before.
+    fn move_to(&self, _root: &Path) -> Result<(), String> {
+        Err("The storage can't be moved".to_string())
+    }
+
+    /// Renames the file, the path is relative to the root folder. The same as
+    /// [Storage::move_to], a file missing at the old path is expected at the new one.
+    fn rename_file(&self, _file: usize, _path: &Path) -> Result<(), String> {
+        Err("The storage files can't be renamed".to_string())
+    }
+
+    /// The size and the modification time of the file. `None` means the file doesn't exist,
+    /// which is also the answer of the storages without the files on disk.
+    fn metadata(&self, _file: usize) -> Option<FileMetadata> {
+        None
+    }
+}
+
+/// The state of a file on disk, used to find out if it was changed behind our back
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileMetadata {
+    pub length: u64,
+    /// The bytes taken on disk, a sparse file takes less than its length
+    pub allocated: u64,
+    /// Nanoseconds since the Unix epoch
+    pub modified: i64,
+}
+
+/// The root folder of the storage and the paths of the files relative to it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StorageLocation {
+    pub root: PathBuf,
+    pub files: Vec<PathBuf>,
+}
+
+/// Where the files are kept until all their pieces are verified, so nobody picks up a half
+/// written file. A complete file is moved to its path under the output folder.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct IncompleteFiles {
+    /// The folder for the incomplete files, the output folder is used if not set
+    pub dir: Option<PathBuf>,
+    /// Appended to the names of the incomplete files, e.g. `.part`
+    pub suffix: Option<String>,
+}
+
+impl IncompleteFiles {
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some() || self.suffix.as_ref().is_some_and(|s| !s.is_empty())
+    }
+}
+
+/// How the space for the files is reserved before the download starts
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Preallocation {
+    /// The files are created on the first write and grow as the pieces arrive
+    #[default]
+    None,
+    /// The files are created with their full size, but the space isn't allocated on the file
+    /// systems supporting the sparse files
+    Sparse,
+    /// The files are filled with zeros up front. It takes time, but the files are never
+    /// fragmented.
+    Full,
+}
+
+impl FromStr for Preallocation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Preallocation::None),
+            "sparse" => Ok(Preallocation::Sparse),
+            "full" => Ok(Preallocation::Full),
+            _ => Err(format!(
+                "Unknown preallocation mode {}, expected none, sparse or full",
+                value
+            )),
+        }
+    }
+}
+
+impl Display for Preallocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let name = match self {
+            Preallocation::None => "none",
+            Preallocation::Sparse => "sparse",
+            Preallocation::Full => "full",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The storages keeping the content in the regular files
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum StorageBackend {
+    /// Reads and writes the files with the system calls, see [FileStorage]
+    #[default]
+    Buffered,
+    /// Maps the files into memory, available with the `mmap` feature only
+    Mmap,
+}
+
+impl StorageBackend {
+    /// Creates the storage of the files under the root folder
+    pub fn open(
+        self,
+        root: PathBuf,
+        layout: StorageLayout,
+        incomplete: IncompleteFiles,
+    ) -> Result<Arc<dyn Storage>, String> {
+        check_path_lengths(&root, &layout, &incomplete)?;
+        let files = FileStorage::new(root, layout).with_incomplete(incomplete);
+        match self {
+            StorageBackend::Buffered => Ok(Arc::new(files)),
+            #[cfg(feature = "mmap")]
+            StorageBackend::Mmap => Ok(Arc::new(MmapStorage::from_files(files))),
+            #[cfg(not(feature = "mmap"))]
+            StorageBackend::Mmap => {
+                Err("The mmap storage isn't available, it needs the mmap feature".to_string())
+            }
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "buffered" => Ok(StorageBackend::Buffered),
+            "mmap" => Ok(StorageBackend::Mmap),
+            _ => Err(format!(
+                "Unknown storage {}, expected buffered or mmap",
+                value
+            )),
+        }
+    }
+}
+
+impl Display for StorageBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let name = match self {
+            StorageBackend::Buffered => "buffered",
+            StorageBackend::Mmap => "mmap",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Makes sure the files, which are not skipped, fit into the free space. The data already on
+/// disk is taken into account, so a partially downloaded torrent needs only the rest. The
+/// sparse files need the space they don't take yet.
+pub fn check_disk_space(storage: &dyn Storage, skipped: &[bool]) -> Result<(), String> {
+    let available = match storage.available_space() {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+
+    let needed: u64 = storage
+        .layout()
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !skipped.get(*index).copied().unwrap_or(false))
+        .map(|(index, file)| {
+            let existing = storage.metadata(index).map(|m| m.allocated).unwrap_or(0);
+            file.length.saturating_sub(existing)
+        })
+        .sum();
+
+    if needed > available {
+        return Err(format!(
+            "Not enough disk space: {} needed, only {} available",
+            format_size(needed),
+            format_size(available)
+        ));
+    }
+
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn parses_preallocation_modes() {
+        for mode in [
+            Preallocation::None,
+            Preallocation::Sparse,
+            Preallocation::Full,
+        ] {
+            assert_eq!(mode.to_string().parse::<Preallocation>(), Ok(mode));
+        }
+        assert!("lazy".parse::<Preallocation>().is_err());
+    }
+}
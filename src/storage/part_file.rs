@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Keeps the parts of the pieces, which belong to the skipped files. A piece might be shared
+/// by a downloaded and a skipped file, and the whole piece is needed for the hash check, but
+/// the skipped file should never be created.
+///
+/// The file starts with a header of one big-endian `u32` per torrent piece: zero for the pieces
+/// without a slot, otherwise the slot number plus one. The slots of the piece length follow
+/// the header, and a slot keeps the bytes at the same offsets as they are in the piece.
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    pieces_count: u32,
+    piece_length: u64,
+    /// The slot of every piece having one
+    slots: HashMap<u32, u32>,
+    /// Opened on the first access, the file is created on the first write only
+    file: Option<File>,
+}
+
+impl PartFile {
+    pub fn new(path: PathBuf, pieces_count: u32, piece_length: u64) -> Self {
+        PartFile {
+            path,
+            pieces_count,
+            piece_length,
+            slots: HashMap::new(),
+            file: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file is opened again at the new path on the next access
+    pub fn set_path(&mut self, path: PathBuf) {
+        self.file = None;
+        self.path = path;
+    }
+
+    /// Flushes and closes the file, e.g. before it's moved
+    pub fn close(&mut self) -> Result<(), String> {
+        self.flush()?;
+        self.file = None;
+        Ok(())
+    }
+
+    fn header_length(&self) -> u64 {
+        self.pieces_count as u64 * 4
+    }
+
+    /// Opens the existing file and loads its header
+    fn open(&mut self, create: bool) -> Result<Option<&mut File>, String> {
+        if self.file.is_none() {
+            if !create && !self.path.exists() {
+                return Ok(None);
+            }
+
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false)
+                .open(&self.path)
+                .map_err(|e| format!("Unable open part file {:?}: {}", self.path, e))?;
+
+            let mut header = vec![0u8; self.header_length() as usize];
+            let length = file.metadata().map(|m| m.len()).unwrap_or_default();
+            if length >= header.len() as u64 {
+                file.read_exact(&mut header)
+                    .map_err(|e| format!("Unable read part file {:?}: {}", self.path, e))?;
+            }
+
+            self.slots = header
+                .chunks(4)
+                .enumerate()
+                .filter_map(|(piece, entry)| {
+                    let entry = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                    (entry > 0).then(|| (piece as u32, entry - 1))
+                })
+                .collect();
+            self.file = Some(file);
+        }
+
+        Ok(self.file.as_mut())
+    }
+
+    fn slot_offset(&self, slot: u32, offset: u64) -> u64 {
+        self.header_length() + slot as u64 * self.piece_length + offset
+    }
+
+    /// Writes the data at the offset within the piece
+    pub fn write(&mut self, piece: u32, offset: u64, data: &[u8]) -> Result<(), String> {
+        self.open(true)?;
+
+        let slot = match self.slots.get(&piece) {
+            Some(slot) => *slot,
+            None => {
+                let slot = self.slots.values().max().map(|s| s + 1).unwrap_or(0);
+                self.slots.insert(piece, slot);
+
+                let entry = (slot + 1).to_be_bytes();
+                let file = self.file.as_mut().expect("The part file is open");
+                file.seek(SeekFrom::Start(piece as u64 * 4))
+                    .and_then(|_| file.write_all(&entry))
+                    .map_err(|e| format!("Unable write part file {:?}: {}", self.path, e))?;
+                slot
+            }
+        };
+
+        let position = self.slot_offset(slot, offset);
+        let file = self.file.as_mut().expect("The part file is open");
+        file.seek(SeekFrom::Start(position))
+            .and_then(|_| file.write_all(data))
+            .map_err(|e| format!("Unable write part file {:?}: {}", self.path, e))
+    }
+
+    /// Reads the data at the offset within the piece
+    pub fn read(&mut self, piece: u32, offset: u64, buffer: &mut [u8]) -> Result<(), String> {
+        self.open(false)?;
+
+        let slot = *self
+            .slots
+            .get(&piece)
+            .ok_or_else(|| format!("Piece {} isn't in the part file", piece))?;
+
+        let position = self.slot_offset(slot, offset);
+        let file = self.file.as_mut().expect("The part file is open");
+        file.seek(SeekFrom::Start(position))
+            .and_then(|_| file.read_exact(buffer))
+            .map_err(|e| format!("Unable read part file {:?}: {}", self.path, e))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        match &self.file {
+            Some(file) => file
+                .sync_data()
+                .map_err(|e| format!("Unable flush part file {:?}: {}", self.path, e)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_pieces_across_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".parts");
+
+        let mut part_file = PartFile::new(path.clone(), 10, 8);
+        assert!(part_file.read(3, 0, &mut [0u8; 2]).is_err());
+        assert!(!path.exists());
+
+        part_file.write(7, 6, &[1, 2]).unwrap();
+        part_file.write(3, 0, &[3, 4, 5]).unwrap();
+        part_file.flush().unwrap();
+
+        let mut part_file = PartFile::new(path, 10, 8);
+        let mut buffer = [0u8; 3];
+        part_file.read(3, 0, &mut buffer).unwrap();
+        assert_eq!(buffer, [3, 4, 5]);
+
+        let mut buffer = [0u8; 2];
+        part_file.read(7, 6, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2]);
+    }
+}
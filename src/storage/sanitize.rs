@@ -0,0 +1,259 @@
+use crate::storage::{IncompleteFiles, StorageLayout};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// The longest file name most of the file systems accept, in bytes
+pub const MAX_COMPONENT_LENGTH: usize = 255;
+
+/// The longest path we're ready to create, in bytes. The paths relative to the output folder
+/// are limited by it, and then the full paths of the files, see [check_path_lengths].
+pub const MAX_PATH_LENGTH: usize = 4096;
+
+/// Names, which refer to devices on Windows, no matter the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes a single path component safe to use on any platform. Separators, control and
+/// characters forbidden on Windows are replaced, the trailing dots and spaces are dropped,
+/// reserved device names get a prefix and too long names are truncated. Returns `None` if
+/// nothing is left of the component, e.g. for `..`.
+pub fn sanitize_component(component: &str) -> Option<String> {
+    let replaced: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let trimmed = replaced.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    let stem = trimmed.split('.').next().unwrap_or_default();
+    let result = if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        format!("_{}", trimmed)
+    } else {
+        trimmed.to_string()
+    };
+
+    Some(truncate(&result, ""))
+}
+
+/// Truncates the name to [MAX_COMPONENT_LENGTH] bytes, keeping the extension and the suffix
+fn truncate(name: &str, suffix: &str) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let mut limit = MAX_COMPONENT_LENGTH.saturating_sub(extension.len() + suffix.len());
+    if stem.len() <= limit {
+        return format!("{}{}{}", stem, suffix, extension);
+    }
+
+    while !stem.is_char_boundary(limit) {
+        limit -= 1;
+    }
+    format!("{}{}{}", &stem[..limit], suffix, extension)
+}
+
+/// Sanitizes the paths of all torrent files, see [sanitize_component]. Every path is given as
+/// the list of components, the first one is the torrent name. The paths, which differ in case
+/// only, or clash with a folder of another file, are made unique by adding a number to the
+/// name, so the content never gets mixed up on the case-insensitive file systems.
+pub fn sanitize_paths(paths: &[Vec<String>]) -> Result<Vec<PathBuf>, String> {
+    // The keys are the lowercased paths joined with '/'
+    let mut files: HashSet<String> = HashSet::new();
+    let mut folders: HashMap<String, String> = HashMap::new();
+    let mut result = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let components: Vec<String> = path.iter().filter_map(|c| sanitize_component(c)).collect();
+
+        if components.is_empty() {
+            return Err(format!("File path {:?} is empty after sanitization", path));
+        }
+
+        let mut key = String::new();
+        let mut sanitized = PathBuf::new();
+        for (index, component) in components.iter().enumerate() {
+            let is_file = index + 1 == components.len();
+            let prefix = key.clone();
+            let mut candidate = component.clone();
+            let mut counter = 0;
+
+            loop {
+                key = format!("{}/{}", prefix, candidate.to_lowercase());
+                let taken = if is_file {
+                    files.contains(&key) || folders.contains_key(&key)
+                } else {
+                    files.contains(&key)
+                };
+
+                if !taken {
+                    break;
+                }
+
+                counter += 1;
+                candidate = truncate(component, &format!(" ({})", counter));
+            }
+
+            if is_file {
+                files.insert(key.clone());
+                sanitized.push(&candidate);
+            } else {
+                // Folders differing in case only are merged into the first one
+                let folder = folders.entry(key.clone()).or_insert(candidate);
+                sanitized.push(folder.as_str());
+            }
+        }
+
+        let length = sanitized.as_os_str().len();
+        if length > MAX_PATH_LENGTH {
+            return Err(format!(
+                "File path {:?} is too long: {} bytes",
+                sanitized, length
+            ));
+        }
+
+        result.push(sanitized);
+    }
+
+    Ok(result)
+}
+
+/// Makes sure the full paths of the files under the root folder fit into [MAX_PATH_LENGTH],
+/// so the files don't fail to open in the middle of the download. The incomplete files are
+/// checked at their own place.
+pub fn check_path_lengths(
+    root: &Path,
+    layout: &StorageLayout,
+    incomplete: &IncompleteFiles,
+) -> Result<(), String> {
+    let absolute = |folder: &Path| std::path::absolute(folder).unwrap_or(folder.to_path_buf());
+    let root = absolute(root);
+    let incomplete_root = incomplete.dir.as_deref().map(absolute);
+    let suffix = incomplete.suffix.as_deref().unwrap_or_default();
+
+    for file in &layout.files {
+        let path = root.join(&file.path);
+        let mut length = path.as_os_str().len();
+        if incomplete.is_enabled() {
+            let folder = incomplete_root.as_ref().unwrap_or(&root);
+            length = length.max(folder.join(&file.path).as_os_str().len() + suffix.len());
+        }
+
+        if length > MAX_PATH_LENGTH {
+            return Err(format!(
+                "File path {:?} is too long: {} bytes",
+                path, length
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&[&str]]) -> Vec<Vec<String>> {
+        paths
+            .iter()
+            .map(|p| p.iter().map(|c| c.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_dangerous_components() {
+        assert_eq!(sanitize_component(".."), None);
+        assert_eq!(sanitize_component(". ."), None);
+        assert_eq!(sanitize_component(""), None);
+        assert_eq!(sanitize_component("a/../b"), Some("a_.._b".to_string()));
+        assert_eq!(sanitize_component("a\0b"), Some("a_b".to_string()));
+        assert_eq!(sanitize_component("C:"), Some("C_".to_string()));
+        assert_eq!(sanitize_component("name. "), Some("name".to_string()));
+        assert_eq!(sanitize_component("con"), Some("_con".to_string()));
+        assert_eq!(
+            sanitize_component("LPT1.txt"),
+            Some("_LPT1.txt".to_string())
+        );
+        assert_eq!(sanitize_component("console"), Some("console".to_string()));
+        assert_eq!(sanitize_component(".hidden"), Some(".hidden".to_string()));
+    }
+
+    #[test]
+    fn truncates_long_names_keeping_extension() {
+        let name = format!("{}.mkv", "я".repeat(200));
+        let sanitized = sanitize_component(&name).unwrap();
+
+        assert!(sanitized.len() <= MAX_COMPONENT_LENGTH);
+        assert!(sanitized.ends_with("я.mkv"));
+    }
+
+    #[test]
+    fn renames_case_duplicates() {
+        let result = sanitize_paths(&paths(&[
+            &["t", "Dir", "file.txt"],
+            &["t", "dir", "FILE.txt"],
+            &["t", "DIR", "other"],
+            &["t", "dir", "file.txt", "nested"],
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            result,
+            vec![
+                PathBuf::from("t/Dir/file.txt"),
+                PathBuf::from("t/Dir/FILE (1).txt"),
+                PathBuf::from("t/Dir/other"),
+                PathBuf::from("t/Dir/file (2).txt/nested"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_too_long_paths() {
+        assert!(sanitize_paths(&paths(&[&["..", "."]])).is_err());
+
+        let component = "a".repeat(MAX_COMPONENT_LENGTH);
+        let long: Vec<&str> = std::iter::repeat_n(component.as_str(), 20).collect();
+        assert!(sanitize_paths(&paths(&[&long])).is_err());
+    }
+
+    #[test]
+    fn full_paths_fit_into_limit() {
+        let component = "a".repeat(MAX_COMPONENT_LENGTH);
+        let path: Vec<&str> = std::iter::repeat_n(component.as_str(), 15).collect();
+        let path = sanitize_paths(&paths(&[&path])).unwrap().remove(0);
+        let length = path.as_os_str().len();
+        let layout = StorageLayout::new(vec![(path, 1)], 1);
+
+        let fits = |root: &str, incomplete: IncompleteFiles| {
+            check_path_lengths(Path::new(root), &layout, &incomplete).is_ok()
+        };
+        let short = "/".to_string();
+        let long = format!("/{}", "r".repeat(MAX_PATH_LENGTH - length - 2));
+        let too_long = format!("/{}", "r".repeat(MAX_PATH_LENGTH - length - 1));
+        assert!(fits(&short, IncompleteFiles::default()));
+        assert!(fits(&long, IncompleteFiles::default()));
+        assert!(!fits(&too_long, IncompleteFiles::default()));
+
+        let incomplete = |dir: &str, suffix: &str| IncompleteFiles {
+            dir: Some(PathBuf::from(dir)),
+            suffix: Some(suffix.to_string()),
+        };
+        assert!(fits(&short, incomplete(&short, ".part")));
+        assert!(!fits(&short, incomplete(&too_long, "")));
+        assert!(!fits(&long, incomplete(&long, ".part")));
+    }
+}
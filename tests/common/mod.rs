@@ -0,0 +1,261 @@
+#![allow(dead_code)]
+
+use futures::{SinkExt, StreamExt};
+use serde_bytes::ByteBuf;
+use serde_derive::Serialize;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+use torrentino::engine::{TorrentEngine, TorrentHandle, TorrentOptions};
+use torrentino::protocol::entities::{
+    HandshakeRequest, MessageType, Torrent, TorrentFile, TorrentInfo,
+};
+use torrentino::protocol::net::PeerCodec;
+use torrentino::storage::{MemoryStorage, StorageLayout};
+
+/// Builds a torrent for the given content. The content is split into the given files.
+pub fn make_torrent(content: &[u8], piece_length: usize, files: &[(&str, usize)]) -> Torrent {
+    let pieces: Vec<u8> = content
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    let (length, files) = if files.len() == 1 {
+        (Some(content.len() as i64), None)
+    } else {
+        let files = files
+            .iter()
+            .map(|(path, length)| TorrentFile {
+                path: path.split('/').map(|s| s.to_string()).collect(),
+                length: *length as u64,
+                md5sum: None,
+            })
+            .collect();
+        (None, Some(files))
+    };
+
+    Torrent {
+        announce: None,
+        announce_list: None,
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        info: TorrentInfo {
+            name: files
+                .as_ref()
+                .map(|_| "content".to_string())
+                .unwrap_or_else(|| "content.bin".to_string()),
+            md5sum: None,
+            length,
+            files,
+            pieces: ByteBuf::from(pieces),
+            piece_length: piece_length as i64,
+            private: None,
+        },
+        nodes: None,
+        httpseeds: None,
+    }
+}
+
+/// A torrent of the single file with the content, in the pieces of 32 KiB
+pub fn single_file_torrent(content: &[u8]) -> Torrent {
+    make_torrent(content, 32768, &[("content.bin", content.len())])
+}
+
+/// Starts seeding the content of the [single_file_torrent] from memory. Returns the handle
+/// and the address the peers connect to.
+pub async fn start_seeder(
+    engine: &TorrentEngine,
+    content: &[u8],
+    options: TorrentOptions,
+) -> (TorrentHandle, SocketAddr) {
+    let torrent = single_file_torrent(content);
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let options = TorrentOptions {
+        storage: Some(Arc::new(
+            MemoryStorage::with_content(layout, content).unwrap(),
+        )),
+        seed: true,
+        ..options
+    };
+
+    let addr = engine.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[])
+        .unwrap();
+    (handle, addr)
+}
+
+/// Saves the torrent as a .torrent file, so it can be passed to the command line
+pub fn write_torrent_file(torrent: &Torrent, path: &Path) {
+    #[derive(Serialize)]
+    struct TorrentFileData<'a> {
+        announce: &'a str,
+        info: &'a TorrentInfo,
+    }
+
+    let data = TorrentFileData {
+        announce: torrent.announce.as_deref().unwrap_or("udp://127.0.0.1:1"),
+        info: &torrent.info,
+    };
+    std::fs::write(path, serde_bencode::to_bytes(&data).unwrap()).unwrap();
+}
+
+pub fn make_content(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// Starts a minimal seeder, which has the whole content and unchokes everybody. Every answer
+/// is delayed by the given duration.
+pub async fn spawn_seeder(torrent: &Torrent, content: Vec<u8>, delay: Duration) -> SocketAddr {
+    spawn_seeder_at("127.0.0.1:0", torrent, content, delay).await
+}
+
+/// Starts a seeder, which sends every block with all the bits flipped. It listens on another
+/// loopback IP, so it can be banned without the honest seeders.
+pub async fn spawn_corrupt_seeder(torrent: &Torrent, content: &[u8]) -> SocketAddr {
+    let corrupt = content.iter().map(|b| !b).collect();
+    spawn_seeder_at("127.0.0.2:0", torrent, corrupt, Duration::ZERO).await
+}
+
+async fn spawn_seeder_at(
+    addr: &str,
+    torrent: &Torrent,
+    content: Vec<u8>,
+    delay: Duration,
+) -> SocketAddr {
+    let info_hash = torrent.info_hash().unwrap();
+    let piece_length = torrent.info.piece_length as usize;
+    let greeting = vec![
+        MessageType::Bitfield(vec![true; torrent.pieces_count()]),
+        MessageType::Unchoke,
+    ];
+    let content = Arc::new(content);
+
+    let handshake = HandshakeRequest::create(info_hash, [b's'; 20]);
+    spawn_peer(addr, handshake, greeting, delay, move || {
+        let content = content.clone();
+        move |message| match message {
+            MessageType::Request(index, begin, length) => {
+                let start = index as usize * piece_length + begin as usize;
+                let block = content[start..start + length as usize].to_vec();
+                vec![MessageType::Piece(index, begin, block.into())]
+            }
+            _ => vec![],
+        }
+    })
+    .await
+}
+
+/// Starts a peer, which has the whole content and unchokes everybody, but never sends a block
+pub async fn spawn_silent_peer(torrent: &Torrent) -> SocketAddr {
+    let greeting = vec![
+        MessageType::Bitfield(vec![true; torrent.pieces_count()]),
+        MessageType::Unchoke,
+    ];
+    let handshake = HandshakeRequest::create(torrent.info_hash().unwrap(), [b'z'; 20]);
+    spawn_peer("127.0.0.1:0", handshake, greeting, Duration::ZERO, || {
+        |_| vec![]
+    })
+    .await
+}
+
+/// Starts a seeder supporting the fast extension, which never unchokes anybody, but allows
+/// every piece to be requested while choked. The first request of every block is rejected.
+pub async fn spawn_fast_seeder(torrent: &Torrent, content: Vec<u8>) -> SocketAddr {
+    let info_hash = torrent.info_hash().unwrap();
+    let piece_length = torrent.info.piece_length as usize;
+    let mut greeting = vec![MessageType::HaveAll];
+    greeting.extend((0..torrent.pieces_count() as u32).map(MessageType::AllowedFast));
+    let content = Arc::new(content);
+
+    let handshake = HandshakeRequest::create(info_hash, [b'f'; 20]).with_fast_extension();
+    spawn_peer(
+        "127.0.0.1:0",
+        handshake,
+        greeting,
+        Duration::ZERO,
+        move || {
+            let content = content.clone();
+            let mut rejected = HashSet::new();
+            move |message| match message {
+                MessageType::Request(index, begin, length) if rejected.insert((index, begin)) => {
+                    vec![MessageType::RejectRequest(index, begin, length)]
+                }
+                MessageType::Request(index, begin, length) => {
+                    let start = index as usize * piece_length + begin as usize;
+                    let block = content[start..start + length as usize].to_vec();
+                    vec![MessageType::Piece(index, begin, block.into())]
+                }
+                _ => vec![],
+            }
+        },
+    )
+    .await
+}
+
+/// Starts a peer listening on the address. Every connection gets the handshake and the
+/// greeting messages, then a handler made by `new_handler` answers each received message.
+/// Every answer is delayed by the given duration. A peer supporting the fast extension
+/// expects the other side to support it too.
+async fn spawn_peer<F, H>(
+    addr: &str,
+    handshake: HandshakeRequest,
+    greeting: Vec<MessageType>,
+    delay: Duration,
+    new_handler: F,
+) -> SocketAddr
+where
+    F: Fn() -> H + Send + 'static,
+    H: FnMut(MessageType) -> Vec<MessageType> + Send + 'static,
+{
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fast = handshake.supports_fast_extension();
+    let response = handshake.as_bytes();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let response = response.clone();
+            let greeting = greeting.clone();
+            let mut handler = new_handler();
+            tokio::spawn(async move {
+                let mut handshake = [0u8; 68];
+                stream.read_exact(&mut handshake).await?;
+                if fast {
+                    assert!(HandshakeRequest::from_bytes(&handshake)
+                        .unwrap()
+                        .supports_fast_extension());
+                }
+                stream.write_all(&response).await?;
+
+                let mut connection = Framed::new(stream, PeerCodec::default());
+                for message in greeting {
+                    connection.send(message).await?;
+                }
+
+                while let Some(Ok(message)) = connection.next().await {
+                    let answer = handler(message);
+                    if answer.is_empty() {
+                        continue;
+                    }
+                    tokio::time::sleep(delay).await;
+                    for message in answer {
+                        connection.send(message).await?;
+                    }
+                }
+
+                Ok::<(), std::io::Error>(())
+            });
+        }
+    });
+
+    addr
+}
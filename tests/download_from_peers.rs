@@ -0,0 +1,416 @@
+mod common;
+
+use common::{
+    make_content, make_torrent, spawn_corrupt_seeder, spawn_fast_seeder, spawn_seeder,
+    spawn_silent_peer,
+};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use torrentino::engine::{
+    EngineConfig, Priority, ResumeData, TorrentEngine, TorrentOptions, BLOCK_SIZE,
+};
+use torrentino::storage::{
+    CacheConfig, IncompleteFiles, MemoryStorage, Preallocation, Storage, StorageLayout,
+};
+
+#[tokio::test]
+async fn download_from_many_peers() {
+    let content = make_content(5 * 32768 + 1000);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let pieces_count = torrent.pieces_count() as u32;
+    let output = tempfile::tempdir().unwrap();
+
+    let fast = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let slow = spawn_seeder(&torrent, content.clone(), Duration::from_millis(300)).await;
+
+    let engine = TorrentEngine::with_config(EngineConfig {
+        max_connections_per_torrent: 2,
+        ..EngineConfig::default()
+    });
+    let options = TorrentOptions {
+        output: output.path().to_path_buf(),
+        ..TorrentOptions::default()
+    };
+
+    let statistics = engine
+        .add_torrent_with_peers(torrent, options, &[fast, slow])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    assert_eq!(statistics.pieces_verified, pieces_count);
+    assert_eq!(statistics.hash_failed, 0);
+    assert_eq!(
+        statistics.downloaded - statistics.duplicate,
+        content.len() as u64
+    );
+    assert_eq!(
+        std::fs::read(output.path().join("content.bin")).unwrap(),
+        content
+    );
+}
+
+#[tokio::test]
+async fn download_multi_file_torrent() {
+    let content = make_content(3 * 32768);
+    let files = [("a.bin", 40000), ("empty.bin", 0), ("nested/b.bin", 58304)];
+    let torrent = make_torrent(&content, 32768, &files);
+    let output = tempfile::tempdir().unwrap();
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+
+    let engine = TorrentEngine::start();
+    let options = TorrentOptions {
+        output: output.path().to_path_buf(),
+        ..TorrentOptions::default()
+    };
+
+    engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    let root = output.path().join("content");
+    assert_eq!(
+        std::fs::read(root.join("a.bin")).unwrap(),
+        &content[..40000]
+    );
+    assert!(root.join("empty.bin").is_file());
+    assert_eq!(
+        std::fs::read(root.join("nested").join("b.bin")).unwrap(),
+        &content[40000..]
+    );
+}
+
+#[tokio::test]
+async fn skipped_files_are_not_created() {
+    let content = make_content(4 * 32768);
+    let files = [("a.bin", 40000), ("b.bin", 60000), ("c.bin", 31072)];
+    let torrent = make_torrent(&content, 32768, &files);
+    let output = tempfile::tempdir().unwrap();
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let options = TorrentOptions {
+        output: output.path().to_path_buf(),
+        file_priorities: vec![Priority::Normal, Priority::Skip, Priority::Normal],
+        ..TorrentOptions::default()
+    };
+
+    let statistics = TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    // The third piece belongs to the skipped file only
+    assert_eq!(statistics.pieces_verified, 3);
+
+    let root = output.path().join("content");
+    assert_eq!(
+        std::fs::read(root.join("a.bin")).unwrap(),
+        &content[..40000]
+    );
+    assert_eq!(
+        std::fs::read(root.join("c.bin")).unwrap(),
+        &content[100000..]
+    );
+    assert!(!root.join("b.bin").exists());
+    assert!(output.path().join(".content.parts").is_file());
+}
+
+#[tokio::test]
+async fn download_into_preallocated_files() {
+    let content = make_content(2 * 32768 + 100);
+    let files = [("a.bin", 50000), ("b.bin", 15636)];
+    let torrent = make_torrent(&content, 32768, &files);
+    let output = tempfile::tempdir().unwrap();
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+
+    let engine = TorrentEngine::start();
+    let options = TorrentOptions {
+        output: output.path().to_path_buf(),
+        preallocation: Preallocation::Full,
+        ..TorrentOptions::default()
+    };
+
+    engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    let root = output.path().join("content");
+    assert_eq!(
+        std::fs::read(root.join("a.bin")).unwrap(),
+        &content[..50000]
+    );
+    assert_eq!(
+        std::fs::read(root.join("b.bin")).unwrap(),
+        &content[50000..]
+    );
+}
+
+#[tokio::test]
+async fn download_into_memory() {
+    let content = make_content(3 * 32768 + 500);
+    let files = [("a.bin", 70000), ("b.bin", 28804)];
+    let torrent = make_torrent(&content, 32768, &files);
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let storage = Arc::new(MemoryStorage::new(layout));
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+
+    TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    assert_eq!(storage.file(0).unwrap(), &content[..70000]);
+    assert_eq!(
+        storage.with_file(1, |data| data == &content[70000..]),
+        Some(true)
+    );
+}
+
+/// Holds the writes while the disk is stuck
+struct StuckDisk {
+    inner: MemoryStorage,
+    stuck: AtomicBool,
+    /// The writes, which have reached the disk
+    writes: AtomicUsize,
+}
+
+impl Storage for StuckDisk {
+    fn layout(&self) -> &StorageLayout {
+        self.inner.layout()
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        while self.stuck.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.inner.write(piece, offset, data)
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        self.inner.read(piece, offset, length)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+/// Downloads the torrent, while the disk doesn't write anything for a while. The torrent has
+/// to keep answering meanwhile.
+async fn download_to_stuck_disk(cache: CacheConfig) {
+    let content = make_content(3 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let storage = Arc::new(StuckDisk {
+        inner: MemoryStorage::new(layout),
+        stuck: AtomicBool::new(true),
+        writes: AtomicUsize::new(0),
+    });
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let engine = TorrentEngine::with_config(EngineConfig {
+        cache,
+        ..EngineConfig::default()
+    });
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap();
+
+    // A worker blocked by the disk would stall the timers of the runtime, so the test waits
+    // on its own threads
+    let handle_ref = &handle;
+    let answered = std::thread::scope(|scope| {
+        while storage.writes.load(Ordering::SeqCst) == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        scope.spawn(move || sender.send(futures::executor::block_on(handle_ref.peers())));
+        let answered = receiver.recv_timeout(Duration::from_secs(2)).is_ok();
+        storage.stuck.store(false, Ordering::SeqCst);
+        answered
+    });
+    assert!(answered, "The torrent waits for the disk");
+
+    handle.wait().await.expect("Unable download torrent");
+    assert_eq!(storage.inner.file(0).unwrap(), content);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_disk_does_not_block_torrent() {
+    download_to_stuck_disk(CacheConfig {
+        write_size: 0,
+        read_size: 0,
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_disk_does_not_block_cached_torrent() {
+    // The pieces are flushed over the limit as well as after their hash check
+    download_to_stuck_disk(CacheConfig {
+        write_size: BLOCK_SIZE as usize,
+        ..CacheConfig::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn complete_files_leave_incomplete_folder() {
+    let content = make_content(3 * 32768);
+    let files = [("a.bin", 40000), ("b.bin", 58304)];
+    let torrent = make_torrent(&content, 32768, &files);
+    let output = tempfile::tempdir().unwrap();
+    let incomplete = output.path().join("incomplete");
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let options = TorrentOptions {
+        output: output.path().join("done"),
+        incomplete: IncompleteFiles {
+            dir: Some(incomplete.clone()),
+            suffix: Some(".part".to_string()),
+        },
+        ..TorrentOptions::default()
+    };
+
+    TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    let root = output.path().join("done").join("content");
+    assert_eq!(
+        std::fs::read(root.join("a.bin")).unwrap(),
+        &content[..40000]
+    );
+    assert_eq!(
+        std::fs::read(root.join("b.bin")).unwrap(),
+        &content[40000..]
+    );
+    assert!(!incomplete.join("content").exists());
+}
+
+#[tokio::test]
+async fn snubbing_peer_is_dropped_for_a_better_one() {
+    let content = make_content(3 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+
+    // The silent peer is tried first and takes the only connection slot
+    let silent = spawn_silent_peer(&torrent).await;
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+
+    let engine = TorrentEngine::with_config(EngineConfig {
+        max_connections_per_torrent: 1,
+        rechoke_interval: Duration::from_millis(100),
+        snub_timeout: Duration::from_millis(300),
+        retry_backoff: Duration::from_secs(60),
+        ..EngineConfig::default()
+    });
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[silent, seeder])
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.wait())
+        .await
+        .expect("The snubbing peer wasn't dropped")
+        .expect("Unable download torrent");
+    assert_eq!(storage.file(0).unwrap(), content);
+}
+
+#[tokio::test]
+async fn corrupt_peer_is_banned() {
+    let content = make_content(16 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let info_hash = torrent.info_hash().unwrap();
+
+    // The honest seeder is slow, so the corrupt one gets its share of the blocks
+    let corrupt = spawn_corrupt_seeder(&torrent, &content).await;
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::from_millis(10)).await;
+
+    let resume_dir = tempfile::tempdir().unwrap();
+    let engine = TorrentEngine::with_config(EngineConfig {
+        resume_dir: Some(resume_dir.path().to_path_buf()),
+        ..EngineConfig::default()
+    });
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+
+    let statistics = tokio::time::timeout(
+        Duration::from_secs(20),
+        engine
+            .add_torrent_with_peers(torrent, options, &[corrupt, seeder])
+            .unwrap()
+            .wait(),
+    )
+    .await
+    .expect("The corrupt peer wasn't banned")
+    .expect("Unable download torrent");
+    assert_eq!(storage.file(0).unwrap(), content);
+    assert!(statistics.hash_failed >= 2 * 32768, "{}", statistics);
+
+    let resume = ResumeData::load(&ResumeData::path(resume_dir.path(), &info_hash)).unwrap();
+    assert_eq!(resume.banned, vec![corrupt.ip().to_string()]);
+    assert_eq!(corrupt.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+}
+
+#[tokio::test]
+async fn download_allowed_fast_pieces_while_choked() {
+    let content = make_content(4 * 32768 + 100);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let seeder = spawn_fast_seeder(&torrent, content.clone()).await;
+
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+
+    let handle = TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.wait())
+        .await
+        .expect("The allowed fast pieces weren't downloaded")
+        .expect("Unable download torrent");
+    assert_eq!(storage.file(0).unwrap(), content);
+}
@@ -5,7 +5,7 @@ use std::process::Command;
 fn download_torrent_file() {
     Command::cargo_bin("torrentino")
         .unwrap()
-        .args(&[
+        .args([
             "-f",
             "resources/test_file_one_tracker.torrent",
             "-t",
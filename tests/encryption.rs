@@ -0,0 +1,146 @@
+mod common;
+
+use common::{make_content, single_file_torrent, start_seeder};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use torrentino::engine::{EngineConfig, TorrentEngine, TorrentOptions};
+use torrentino::protocol::net::{handshake, EncryptionPolicy};
+use torrentino::storage::{MemoryStorage, StorageLayout};
+
+/// An engine, which accepts the incoming connections according to the policy
+fn seeding_engine(encryption: EncryptionPolicy) -> TorrentEngine {
+    TorrentEngine::with_config(EngineConfig {
+        incoming_encryption: encryption,
+        ..EngineConfig::default()
+    })
+}
+
+/// Downloads the content from the peer with the given outgoing policy
+async fn download(content: &[u8], peer: SocketAddr, encryption: EncryptionPolicy) -> Vec<u8> {
+    let torrent = single_file_torrent(content);
+    let engine = TorrentEngine::with_config(EngineConfig {
+        outgoing_encryption: encryption,
+        ..EngineConfig::default()
+    });
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[peer])
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.wait())
+        .await
+        .expect("The download timed out")
+        .expect("Unable download torrent");
+    storage.file(0).unwrap()
+}
+
+/// Forwards the connections to the target and records everything sent in both directions
+async fn spawn_recording_proxy(target: SocketAddr) -> (SocketAddr, Arc<Mutex<Vec<u8>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+
+    let wire = recorded.clone();
+    tokio::spawn(async move {
+        while let Ok((incoming, _)) = listener.accept().await {
+            let outgoing = TcpStream::connect(target).await.unwrap();
+            let (incoming_read, incoming_write) = incoming.into_split();
+            let (outgoing_read, outgoing_write) = outgoing.into_split();
+            tokio::spawn(forward(incoming_read, outgoing_write, wire.clone()));
+            tokio::spawn(forward(outgoing_read, incoming_write, wire.clone()));
+        }
+    });
+    (addr, recorded)
+}
+
+async fn forward(
+    mut from: tokio::net::tcp::OwnedReadHalf,
+    mut to: tokio::net::tcp::OwnedWriteHalf,
+    recorded: Arc<Mutex<Vec<u8>>>,
+) -> std::io::Result<()> {
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = from.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        recorded.lock().unwrap().extend_from_slice(&buffer[..read]);
+        to.write_all(&buffer[..read]).await?;
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[tokio::test]
+async fn forced_encryption_hides_the_traffic() {
+    let content = make_content(3 * 32768 + 100);
+    let seeder = seeding_engine(EncryptionPolicy::Forced);
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+    let (proxy, recorded) = spawn_recording_proxy(addr).await;
+
+    let downloaded = download(&content, proxy, EncryptionPolicy::Forced).await;
+    assert_eq!(downloaded, content);
+
+    let recorded = recorded.lock().unwrap();
+    assert!(recorded.len() > content.len());
+    assert!(!contains(&recorded, b"BitTorrent protocol"));
+    let info_hash = single_file_torrent(&content).info_hash().unwrap();
+    assert!(!contains(&recorded, &info_hash));
+    assert!(!contains(&recorded, &content[32768..32768 + 64]));
+}
+
+#[tokio::test]
+async fn enabled_encryption_falls_back_to_plaintext() {
+    let content = make_content(2 * 32768);
+    let seeder = seeding_engine(EncryptionPolicy::Disabled);
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    let downloaded = download(&content, addr, EncryptionPolicy::Enabled).await;
+    assert_eq!(downloaded, content);
+}
+
+#[tokio::test]
+async fn enabled_encryption_accepts_both() {
+    let content = make_content(2 * 32768 + 10);
+    let seeder = seeding_engine(EncryptionPolicy::Enabled);
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    assert_eq!(
+        download(&content, addr, EncryptionPolicy::Forced).await,
+        content
+    );
+    assert_eq!(
+        download(&content, addr, EncryptionPolicy::Disabled).await,
+        content
+    );
+}
+
+#[tokio::test]
+async fn plaintext_peer_is_refused_by_forced_encryption() {
+    let content = make_content(32768);
+    let seeder = seeding_engine(EncryptionPolicy::Forced);
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    let info_hash = single_file_torrent(&content).info_hash().unwrap();
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        handshake(&mut stream, info_hash, [b'p'; 20]),
+    )
+    .await
+    .expect("The connection wasn't dropped");
+    assert!(result.is_err());
+}
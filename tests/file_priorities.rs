@@ -0,0 +1,127 @@
+mod common;
+
+use common::{make_content, make_torrent, spawn_seeder};
+use std::sync::Arc;
+use std::time::Duration;
+use torrentino::engine::{EngineConfig, Priority, ResumeData, TorrentEngine, TorrentOptions};
+use torrentino::storage::{MemoryStorage, Storage, StorageLayout};
+
+#[tokio::test]
+async fn priorities_are_changed_while_running() {
+    let content = make_content(4 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("a.bin", 65536), ("b.bin", 65536)]);
+    let info_hash = torrent.info_hash().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let resume_dir = output.path().join("resume");
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::from_millis(100)).await;
+    let engine = TorrentEngine::with_config(EngineConfig {
+        resume_dir: Some(resume_dir.clone()),
+        ..EngineConfig::default()
+    });
+    let options = TorrentOptions {
+        output: output.path().to_path_buf(),
+        file_priorities: vec![Priority::Normal, Priority::Skip],
+        ..TorrentOptions::default()
+    };
+
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap();
+
+    handle.set_file_priority(1, Priority::High).await.unwrap();
+    handle.set_piece_priority(0, Priority::Low).await.unwrap();
+    assert!(handle.set_piece_priority(4, Priority::Low).await.is_err());
+    assert!(handle.set_file_priority(2, Priority::Low).await.is_err());
+
+    assert_eq!(
+        handle.file_priorities().await.unwrap(),
+        vec![Priority::Normal, Priority::High]
+    );
+    assert_eq!(
+        handle.piece_priorities().await.unwrap(),
+        vec![
+            Priority::Low,
+            Priority::Normal,
+            Priority::High,
+            Priority::High
+        ]
+    );
+
+    let statistics = handle.wait().await.expect("Unable download torrent");
+    assert_eq!(statistics.pieces_verified, 4);
+    assert_eq!(
+        std::fs::read(output.path().join("content").join("b.bin")).unwrap(),
+        &content[65536..]
+    );
+
+    let resume = ResumeData::load(&ResumeData::path(&resume_dir, &info_hash)).unwrap();
+    assert_eq!(resume.file_priorities.as_slice(), &[2, 3]);
+    assert_eq!(resume.piece_priorities.as_slice(), &[1, 2, 3, 3]);
+}
+
+/// A memory storage on a disk with little free space
+struct SmallDisk(MemoryStorage);
+
+impl Storage for SmallDisk {
+    fn layout(&self) -> &StorageLayout {
+        self.0.layout()
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        self.0.write(piece, offset, data)
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        self.0.read(piece, offset, length)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.0.flush()
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        Some(70_000)
+    }
+}
+
+#[tokio::test]
+async fn resumed_skipped_files_need_no_space() {
+    let content = make_content(4 * 32768);
+    let files = [("a.bin", 65536), ("b.bin", 65536)];
+    let torrent = make_torrent(&content, 32768, &files);
+    let output = tempfile::tempdir().unwrap();
+    let engine = TorrentEngine::with_config(EngineConfig {
+        resume_dir: Some(output.path().join("resume")),
+        ..EngineConfig::default()
+    });
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let options = TorrentOptions {
+        output: output.path().to_path_buf(),
+        file_priorities: vec![Priority::Normal, Priority::Skip],
+        ..TorrentOptions::default()
+    };
+    engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    // Only the first file fits, the second one is skipped by the saved priorities
+    let torrent = make_torrent(&content, 32768, &files);
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let options = TorrentOptions {
+        storage: Some(Arc::new(SmallDisk(MemoryStorage::new(layout)))),
+        ..TorrentOptions::default()
+    };
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap();
+    assert_eq!(
+        handle.file_priorities().await.unwrap(),
+        vec![Priority::Normal, Priority::Skip]
+    );
+    handle.wait().await.expect("Unable download torrent");
+}
@@ -1,6 +1,6 @@
 use std::convert::TryFrom;
 use std::path::PathBuf;
-use torrentino::engine::generate_peer_id;
+use torrentino::engine::{generate_peer_id, CLIENT_CODE};
 use torrentino::protocol::entities::{AnnounceRequest, Torrent};
 
 #[test]
@@ -10,7 +10,7 @@ fn generate_announce_request() {
     let torrent = Torrent::try_from(file).expect("Unable parse torrent file");
 
     let connection_id: i64 = -1;
-    let peer_id: [u8; 20] = generate_peer_id();
+    let peer_id: [u8; 20] = generate_peer_id(CLIENT_CODE);
 
     let info_hash = torrent
         .info_hash()
@@ -0,0 +1,190 @@
+mod common;
+
+use common::{make_content, make_torrent, spawn_seeder};
+use std::path::Component;
+use std::sync::Arc;
+use std::time::Duration;
+use torrentino::engine::{TorrentEngine, TorrentOptions};
+use torrentino::protocol::entities::{Torrent, TorrentFile};
+use torrentino::storage::{MemoryStorage, StorageLayout, MAX_COMPONENT_LENGTH};
+
+/// Builds a multi-file torrent with the given raw name and file paths
+fn hostile_torrent(name: &str, paths: &[Vec<&str>]) -> Torrent {
+    let files: Vec<(&str, usize)> = paths.iter().map(|_| ("file", 100)).collect();
+    let content = make_content(files.len() * 100);
+    let mut torrent = make_torrent(&content, 64, &files);
+
+    torrent.info.name = name.to_string();
+    torrent.info.length = None;
+    torrent.info.files = Some(
+        paths
+            .iter()
+            .map(|path| TorrentFile {
+                path: path.iter().map(|c| c.to_string()).collect(),
+                length: 100,
+                md5sum: None,
+            })
+            .collect(),
+    );
+    torrent
+}
+
+fn assert_contained(layout: &StorageLayout) {
+    for file in &layout.files {
+        assert!(
+            file.path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_))),
+            "{:?} escapes the output folder",
+            file.path
+        );
+        for component in file.path.iter() {
+            let component = component.to_str().unwrap();
+            assert!(component.len() <= MAX_COMPONENT_LENGTH);
+            assert!(!component.contains('\0'));
+        }
+    }
+}
+
+#[test]
+fn hostile_paths_stay_inside_output_folder() {
+    let long = "x".repeat(1000);
+    let corpus: Vec<(&str, Vec<Vec<&str>>)> = vec![
+        ("..", vec![vec!["..", "..", "etc", "passwd"], vec!["ok"]]),
+        (
+            "/abs",
+            vec![vec!["/etc/passwd"], vec!["C:\\Windows", "system32"]],
+        ),
+        (
+            "name",
+            vec![vec!["a\0b"], vec!["\\\\server\\share"], vec!["..\\..\\x"]],
+        ),
+        (
+            "name",
+            vec![
+                vec!["CON"],
+                vec!["aux.txt"],
+                vec!["nul ", "x"],
+                vec!["dir."],
+            ],
+        ),
+        (
+            "name",
+            vec![vec![long.as_str()], vec!["a", long.as_str(), "b"]],
+        ),
+        ("name", vec![vec![".", "..", "file"], vec!["", "file2"]]),
+    ];
+
+    for (name, paths) in corpus {
+        let torrent = hostile_torrent(name, &paths);
+        let layout = StorageLayout::from_torrent(&torrent).unwrap();
+        assert_contained(&layout);
+    }
+}
+
+#[test]
+fn case_duplicates_get_distinct_paths() {
+    let torrent = hostile_torrent("name", &[vec!["README"], vec!["readme"], vec!["ReadMe"]]);
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+
+    let mut paths: Vec<String> = layout
+        .files
+        .iter()
+        .map(|f| f.path.to_string_lossy().to_lowercase())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    assert_eq!(paths.len(), 3);
+}
+
+#[test]
+fn empty_paths_are_rejected() {
+    let torrent = hostile_torrent("..", &[vec!["..", "."]]);
+    assert!(StorageLayout::from_torrent(&torrent).is_err());
+
+    let too_deep: Vec<&str> = vec!["deep"; 2000];
+    let torrent = hostile_torrent("name", &[too_deep]);
+    assert!(StorageLayout::from_torrent(&torrent).is_err());
+}
+
+#[tokio::test]
+async fn invalid_pieces_are_rejected() {
+    let mut torrent = hostile_torrent("name", &[vec!["file"]]);
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+
+    torrent.info.piece_length = 0;
+    assert!(StorageLayout::from_torrent(&torrent).is_err());
+    torrent.info.piece_length = -64;
+    assert!(StorageLayout::from_torrent(&torrent).is_err());
+
+    torrent.info.piece_length = 64;
+    torrent.info.pieces = torrent.info.pieces[..30].to_vec().into();
+    assert!(StorageLayout::from_torrent(&torrent).is_err());
+
+    // A custom storage doesn't save the engine from the broken torrent
+    torrent.info.piece_length = 0;
+    let options = TorrentOptions {
+        storage: Some(Arc::new(MemoryStorage::new(layout))),
+        ..TorrentOptions::default()
+    };
+    assert!(TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[])
+        .is_err());
+}
+
+#[tokio::test]
+async fn hostile_torrent_is_downloaded_inside_output_folder() {
+    let torrent = hostile_torrent("..", &[vec!["..", "escaped"], vec!["/", "tmp", "absolute"]]);
+    let info_hash: String = torrent
+        .info_hash()
+        .unwrap()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let content = make_content(200);
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output");
+
+    let seeder = spawn_seeder(&torrent, content, Duration::ZERO).await;
+    let options = TorrentOptions {
+        output: output.clone(),
+        ..TorrentOptions::default()
+    };
+
+    TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    let entries: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, vec!["output"]);
+
+    // The name is empty after the sanitization, the info hash names the folder instead
+    let folder = output.join(info_hash);
+    assert!(folder.join("escaped").is_file());
+    assert!(folder.join("_").join("tmp").join("absolute").is_file());
+}
+
+#[test]
+fn path_too_long_under_output_folder_is_rejected() {
+    let component = "x".repeat(MAX_COMPONENT_LENGTH);
+    let deep: Vec<&str> = vec![component.as_str(); 14];
+    let torrent = hostile_torrent("name", &[deep]);
+    assert!(StorageLayout::from_torrent(&torrent).is_ok());
+
+    let dir = tempfile::tempdir().unwrap();
+    let options = TorrentOptions {
+        output: dir.path().join(&component).join(&component),
+        ..TorrentOptions::default()
+    };
+    let error = TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[])
+        .err()
+        .unwrap();
+    assert!(error.contains("is too long"), "{}", error);
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::{make_content, single_file_torrent, spawn_seeder, start_seeder};
+use futures::StreamExt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+use torrentino::engine::{EngineConfig, ListenPort, TorrentEngine, TorrentOptions};
+use torrentino::protocol::entities::{
+    ExtensionHandshake, HandshakeRequest, MessageType, EXTENSION_HANDSHAKE_ID,
+};
+use torrentino::protocol::net::PeerCodec;
+use torrentino::storage::{MemoryStorage, StorageLayout};
+
+const LOCALHOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+fn engine_on(listen_port: ListenPort) -> TorrentEngine {
+    TorrentEngine::with_config(EngineConfig {
+        listen_port,
+        ..EngineConfig::default()
+    })
+}
+
+/// Starts a UDP tracker, which answers every announce with the given peers. Returns the
+/// tracker address and the ports announced to it.
+async fn spawn_tracker(peers: Vec<SocketAddr>) -> (SocketAddr, mpsc::UnboundedReceiver<u16>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1024];
+        while let Ok((length, client)) = socket.recv_from(&mut buffer).await {
+            let request = &buffer[..length];
+            // The action and the transaction id are echoed back
+            let mut response = request[8..16].to_vec();
+            match length {
+                16 => response.extend_from_slice(&[7u8; 8]),
+                98 => {
+                    let _ = sender.send(u16::from_be_bytes([request[96], request[97]]));
+                    // The interval, leechers and seeders
+                    response.extend_from_slice(&[0u8; 12]);
+                    for peer in &peers {
+                        if let SocketAddr::V4(peer) = peer {
+                            response.extend_from_slice(&peer.ip().octets());
+                            response.extend_from_slice(&peer.port().to_be_bytes());
+                        }
+                    }
+                }
+                _ => continue,
+            }
+            let _ = socket.send_to(&response, client).await;
+        }
+    });
+
+    (addr, receiver)
+}
+
+#[tokio::test]
+async fn listens_on_random_port_of_range() {
+    let engine = engine_on(ListenPort::Random(42000, 42999));
+    let addr = engine.listen_on(LOCALHOST).await.unwrap();
+    assert!((42000..=42999).contains(&addr.port()));
+
+    // The port is taken, a range of the single port has nothing else to try
+    let taken = engine_on(ListenPort::Fixed(addr.port()));
+    assert!(taken.listen_on(LOCALHOST).await.is_err());
+    let taken = engine_on(ListenPort::Random(addr.port(), addr.port()));
+    assert!(taken.listen_on(LOCALHOST).await.is_err());
+}
+
+#[tokio::test]
+async fn bound_port_is_announced() {
+    let content = make_content(2 * 32768);
+    let mut torrent = single_file_torrent(&content);
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let (tracker, mut announced) = spawn_tracker(vec![seeder]).await;
+    torrent.announce = Some(format!("udp://{}/announce", tracker));
+
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+    let engine = engine_on(ListenPort::Fixed(0));
+    let addr = engine.listen_on(LOCALHOST).await.unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let handle = engine.add_torrent(torrent, options).await.unwrap();
+    assert_eq!(announced.recv().await, Some(addr.port()));
+    tokio::time::timeout(Duration::from_secs(10), handle.wait())
+        .await
+        .expect("The download timed out")
+        .expect("Unable download torrent");
+    assert_eq!(storage.file(0).unwrap(), content);
+}
+
+#[tokio::test]
+async fn seeder_starts_without_peers() {
+    let content = make_content(2 * 32768);
+    let mut torrent = single_file_torrent(&content);
+    let (tracker, mut announced) = spawn_tracker(vec![]).await;
+    torrent.announce = Some(format!("udp://{}/announce", tracker));
+
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let options = TorrentOptions {
+        storage: Some(Arc::new(
+            MemoryStorage::with_content(layout.clone(), &content).unwrap(),
+        )),
+        seed: true,
+        ..TorrentOptions::default()
+    };
+    let seeder = engine_on(ListenPort::Fixed(0));
+    let addr = seeder.listen_on(LOCALHOST).await.unwrap();
+    let _seeding = seeder.add_torrent(torrent, options).await.unwrap();
+    assert_eq!(announced.recv().await, Some(addr.port()));
+
+    let storage = Arc::new(MemoryStorage::new(layout));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+    let handle = TorrentEngine::start()
+        .add_torrent_with_peers(single_file_torrent(&content), options, &[addr])
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.wait())
+        .await
+        .expect("The download timed out")
+        .expect("Unable download torrent");
+    assert_eq!(storage.file(0).unwrap(), content);
+}
+
+#[tokio::test]
+async fn extension_handshake_tells_listen_port() {
+    let content = make_content(32768);
+    let info_hash = single_file_torrent(&content).info_hash().unwrap();
+    let engine = engine_on(ListenPort::Fixed(0));
+    let (_seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let handshake = HandshakeRequest::create(info_hash, [b'e'; 20]).with_extension_protocol();
+    stream.write_all(&handshake.as_bytes()).await.unwrap();
+    let mut response = [0u8; 68];
+    stream.read_exact(&mut response).await.unwrap();
+    assert!(HandshakeRequest::from_bytes(&response)
+        .unwrap()
+        .supports_extension_protocol());
+
+    let mut connection = Framed::new(stream, PeerCodec::default());
+    let message = tokio::time::timeout(Duration::from_secs(5), connection.next())
+        .await
+        .expect("No extension handshake")
+        .unwrap()
+        .unwrap();
+    match message {
+        MessageType::Extended(EXTENSION_HANDSHAKE_ID, payload) => {
+            let handshake = ExtensionHandshake::from_bytes(&payload).unwrap();
+            assert_eq!(handshake.p, Some(addr.port()));
+        }
+        message => panic!("Unexpected message {:?}", message),
+    }
+}
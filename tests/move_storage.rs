@@ -0,0 +1,55 @@
+mod common;
+
+use common::{make_content, make_torrent, spawn_seeder};
+use std::time::Duration;
+use torrentino::engine::{EngineConfig, ResumeData, TorrentEngine, TorrentOptions};
+
+#[tokio::test]
+async fn files_are_moved_while_downloading() {
+    let content = make_content(4 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("a.bin", 65536), ("b.bin", 65536)]);
+    let info_hash = torrent.info_hash().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let resume_dir = dir.path().join("resume");
+    let archive = dir.path().join("archive");
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::from_millis(100)).await;
+    let engine = TorrentEngine::with_config(EngineConfig {
+        resume_dir: Some(resume_dir.clone()),
+        ..EngineConfig::default()
+    });
+    let options = TorrentOptions {
+        output: dir.path().join("scratch"),
+        ..TorrentOptions::default()
+    };
+
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[seeder])
+        .unwrap();
+
+    handle.move_storage(&archive).await.unwrap();
+    handle.rename_root("renamed").await.unwrap();
+    handle.rename_file(1, "renamed/sub/b.bin").await.unwrap();
+    assert!(handle.rename_file(0, "../a.bin").await.is_err());
+    assert!(handle.rename_root("a/b").await.is_err());
+
+    let statistics = handle.wait().await.expect("Unable download torrent");
+    assert_eq!(statistics.pieces_verified, 4);
+
+    assert!(!dir.path().join("scratch").join("content").exists());
+    assert_eq!(
+        std::fs::read(archive.join("renamed").join("a.bin")).unwrap(),
+        &content[..65536]
+    );
+    assert_eq!(
+        std::fs::read(archive.join("renamed").join("sub").join("b.bin")).unwrap(),
+        &content[65536..]
+    );
+
+    let resume = ResumeData::load(&ResumeData::path(&resume_dir, &info_hash)).unwrap();
+    assert_eq!(resume.root, Some(archive.to_string_lossy().to_string()));
+    assert_eq!(
+        resume.file_paths,
+        vec!["renamed/a.bin", "renamed/sub/b.bin"]
+    );
+}
@@ -1,17 +1,44 @@
-use torrentino::protocol::entities::{TrackerProtocol, TrackerUrl};
+use std::net::UdpSocket;
+use std::thread;
+use torrentino::protocol::entities::{
+    ConnectionRequest, ConnectionResponse, TrackerProtocol, TrackerUrl,
+};
 use torrentino::protocol::net::{NetworkClient, UdpClient};
 
-#[test]
-fn obtain_connection_id() {
+/// Answers a single connect request the same way as a real UDP tracker does
+fn spawn_tracker() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("Unable bind tracker socket");
+    let port = socket.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let mut buffer = [0u8; 1024];
+        let (size, remote) = socket.recv_from(&mut buffer).unwrap();
+        let request: ConnectionRequest = bincode::deserialize(&buffer[0..size]).unwrap();
+
+        let response = ConnectionResponse {
+            action: request.action,
+            transaction_id: request.transaction_id,
+            connection_id: 42,
+        };
+        let content = bincode::serialize(&response).unwrap();
+        socket.send_to(&content, remote).unwrap();
+    });
+
+    port
+}
+
+#[tokio::test]
+async fn obtain_connection_id() {
     // read tracker url info from .torrent file. See, previous section
     let tracker: TrackerUrl = TrackerUrl {
         protocol: TrackerProtocol::UDP,
-        url: "localhost".to_string(),
-        port: 6969,
+        url: "127.0.0.1".to_string(),
+        port: spawn_tracker(),
     };
 
     let client = UdpClient::default();
     client
         .obtain_connection_id(&tracker)
+        .await
         .expect("Unable establish connection");
 }
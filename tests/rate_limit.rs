@@ -0,0 +1,116 @@
+mod common;
+
+use common::{make_content, single_file_torrent, start_seeder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use torrentino::engine::{EngineConfig, TorrentEngine, TorrentHandle, TorrentOptions};
+use torrentino::storage::{MemoryStorage, StorageLayout};
+
+const KIB: u64 = 1024;
+
+/// Starts downloading the content from the peer. Returns the handle and the storage.
+fn start_download(
+    engine: &TorrentEngine,
+    content: &[u8],
+    peer: SocketAddr,
+    options: TorrentOptions,
+) -> (TorrentHandle, Arc<MemoryStorage>) {
+    let torrent = single_file_torrent(content);
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..options
+    };
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[peer])
+        .unwrap();
+    (handle, storage)
+}
+
+/// Waits for the download and returns how long it has taken since the start
+async fn finish(
+    handle: TorrentHandle,
+    storage: &MemoryStorage,
+    content: &[u8],
+    start: Instant,
+) -> Duration {
+    tokio::time::timeout(Duration::from_secs(20), handle.wait())
+        .await
+        .expect("The download timed out")
+        .expect("Unable download torrent");
+    assert_eq!(storage.file(0).unwrap(), content);
+    start.elapsed()
+}
+
+#[tokio::test]
+async fn engine_download_limit() {
+    // A second's worth of burst and a block over the limit, then 64 KiB per second
+    let content = make_content(6 * 32768);
+    let seeder = TorrentEngine::start();
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    let engine = TorrentEngine::with_config(EngineConfig {
+        download_limit: 64 * KIB,
+        ..EngineConfig::default()
+    });
+    let start = Instant::now();
+    let (handle, storage) = start_download(&engine, &content, addr, TorrentOptions::default());
+    let elapsed = finish(handle, &storage, &content, start).await;
+    assert!(elapsed >= Duration::from_millis(1500), "took {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn peer_upload_limit() {
+    let content = make_content(6 * 32768);
+    let seeder = TorrentEngine::start();
+    let (seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+    seeding.set_peer_rate_limits(0, 64 * KIB).await.unwrap();
+
+    let engine = TorrentEngine::start();
+    let start = Instant::now();
+    let (handle, storage) = start_download(&engine, &content, addr, TorrentOptions::default());
+    let elapsed = finish(handle, &storage, &content, start).await;
+    assert!(elapsed >= Duration::from_millis(1500), "took {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn torrent_limit_is_lifted_at_runtime() {
+    // It takes 15 seconds at 16 KiB per second
+    let content = make_content(8 * 32768);
+    let seeder = TorrentEngine::start();
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    let engine = TorrentEngine::start();
+    let options = TorrentOptions {
+        download_limit: 16 * KIB,
+        ..TorrentOptions::default()
+    };
+    let start = Instant::now();
+    let (handle, storage) = start_download(&engine, &content, addr, options);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    handle.set_rate_limits(0, 0);
+
+    let elapsed = finish(handle, &storage, &content, start).await;
+    assert!(elapsed < Duration::from_secs(5), "took {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn upload_limit_does_not_hold_back_requests() {
+    // The requests and the other messages would take more than 8 seconds at this rate
+    let content = make_content(16 * 32768);
+    let seeder = TorrentEngine::start();
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    let engine = TorrentEngine::with_config(EngineConfig {
+        upload_limit: 64,
+        limit_overhead: true,
+        ..EngineConfig::default()
+    });
+    let start = Instant::now();
+    let (handle, storage) = start_download(&engine, &content, addr, TorrentOptions::default());
+    let elapsed = finish(handle, &storage, &content, start).await;
+    assert!(elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+}
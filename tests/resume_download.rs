@@ -0,0 +1,94 @@
+mod common;
+
+use common::{make_content, make_torrent, spawn_seeder};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+use torrentino::engine::{EngineConfig, ResumeData, TorrentEngine, TorrentOptions};
+
+fn engine(resume_dir: &Path) -> TorrentEngine {
+    TorrentEngine::with_config(EngineConfig {
+        resume_dir: Some(resume_dir.to_path_buf()),
+        ..EngineConfig::default()
+    })
+}
+
+fn options(output: &Path) -> TorrentOptions {
+    TorrentOptions {
+        output: output.to_path_buf(),
+        ..TorrentOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn completed_torrent_is_resumed_without_download() {
+    let content = make_content(4 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let info_hash = torrent.info_hash().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let resume_dir = output.path().join("resume");
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    engine(&resume_dir)
+        .add_torrent_with_peers(torrent, options(output.path()), &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    let resume = ResumeData::load(&ResumeData::path(&resume_dir, &info_hash)).unwrap();
+    assert_eq!(resume.piece_bitfield(4), vec![true; 4]);
+
+    // No peers at all, the saved progress is the only way to complete the torrent
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let statistics = engine(&resume_dir)
+        .add_torrent_with_peers(torrent, options(output.path()), &[])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Torrent should be resumed");
+
+    assert_eq!(statistics.downloaded, 0);
+}
+
+#[tokio::test]
+async fn modified_files_are_rechecked() {
+    let content = make_content(4 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let output = tempfile::tempdir().unwrap();
+    let resume_dir = output.path().join("resume");
+
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    engine(&resume_dir)
+        .add_torrent_with_peers(torrent, options(output.path()), &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    // Corrupt the third piece, the file size stays the same but the modification time changes
+    std::thread::sleep(Duration::from_millis(20));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(output.path().join("content.bin"))
+        .unwrap();
+    file.seek(SeekFrom::Start(2 * 32768 + 10)).unwrap();
+    file.write_all(b"corrupted").unwrap();
+    drop(file);
+
+    let torrent = make_torrent(&content, 32768, &[("content.bin", content.len())]);
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+    let statistics = engine(&resume_dir)
+        .add_torrent_with_peers(torrent, options(output.path()), &[seeder])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    assert_eq!(statistics.pieces_verified, 1);
+    assert_eq!(
+        std::fs::read(output.path().join("content.bin")).unwrap(),
+        content
+    );
+}
@@ -0,0 +1,462 @@
+mod common;
+
+use common::{make_content, single_file_torrent, start_seeder};
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+use torrentino::engine::{
+    allowed_fast_set, Choker, ChokerPeer, ClientVersion, EngineConfig, TorrentEngine,
+    TorrentOptions, ALLOWED_FAST_SET_SIZE, BLOCK_SIZE,
+};
+use torrentino::protocol::entities::{HandshakeRequest, MessageType};
+use torrentino::protocol::net::{PeerCodec, PeerConnection};
+use torrentino::storage::{CacheConfig, MemoryStorage, Storage, StorageLayout};
+
+/// Connects to the seeder as a bare peer, which supports no extensions
+async fn connect(addr: SocketAddr, info_hash: [u8; 20]) -> PeerConnection<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let handshake = HandshakeRequest::create(info_hash, [b'p'; 20]);
+    stream.write_all(&handshake.as_bytes()).await.unwrap();
+    let mut response = [0u8; 68];
+    stream.read_exact(&mut response).await.unwrap();
+    assert!(HandshakeRequest::from_bytes(&response)
+        .unwrap()
+        .supports_fast_extension());
+    Framed::new(stream, PeerCodec::default())
+}
+
+/// Connects to the seeder as a peer supporting the fast extension
+async fn connect_fast(addr: SocketAddr, info_hash: [u8; 20]) -> PeerConnection<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let handshake = HandshakeRequest::create(info_hash, [b'f'; 20]).with_fast_extension();
+    stream.write_all(&handshake.as_bytes()).await.unwrap();
+    let mut response = [0u8; 68];
+    stream.read_exact(&mut response).await.unwrap();
+    Framed::new(stream, PeerCodec::default())
+}
+
+#[tokio::test]
+async fn download_from_another_engine() {
+    let content = make_content(5 * 32768 + 1000);
+    let torrent = single_file_torrent(&content);
+    let seeder = TorrentEngine::start();
+    let (seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+    TorrentEngine::start()
+        .add_torrent_with_peers(torrent, options, &[addr])
+        .unwrap()
+        .wait()
+        .await
+        .expect("Unable download torrent");
+
+    assert_eq!(storage.file(0).unwrap(), content);
+    seeding.stop();
+    assert!(seeding.wait().await.is_err());
+}
+
+#[tokio::test]
+async fn serves_requests_and_cancels() {
+    let content = make_content(3 * 32768);
+    let torrent = single_file_torrent(&content);
+    let engine = TorrentEngine::start();
+    let (_seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+
+    let mut peer = connect(addr, torrent.info_hash().unwrap()).await;
+    match peer.next().await {
+        Some(Ok(MessageType::Bitfield(pieces))) => assert!(pieces[..3].iter().all(|p| *p)),
+        other => panic!("Expected the bitfield, got {:?}", other),
+    }
+
+    peer.send(MessageType::Interested).await.unwrap();
+    assert!(matches!(peer.next().await, Some(Ok(MessageType::Unchoke))));
+
+    // The second request is cancelled while the first one is being read
+    peer.feed(MessageType::Request(0, 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    peer.feed(MessageType::Request(0, BLOCK_SIZE, BLOCK_SIZE))
+        .await
+        .unwrap();
+    peer.send(MessageType::Cancel(0, BLOCK_SIZE, BLOCK_SIZE))
+        .await
+        .unwrap();
+    peer.send(MessageType::Request(2, 100, 50)).await.unwrap();
+
+    match peer.next().await {
+        Some(Ok(MessageType::Piece(0, 0, data))) => {
+            assert_eq!(&data[..], &content[..BLOCK_SIZE as usize])
+        }
+        other => panic!("Expected the first block, got {:?}", other),
+    }
+    match peer.next().await {
+        Some(Ok(MessageType::Piece(2, 100, data))) => {
+            assert_eq!(&data[..], &content[2 * 32768 + 100..2 * 32768 + 150])
+        }
+        other => panic!("Expected the last block, got {:?}", other),
+    }
+
+    // A block over the piece end closes the connection
+    peer.send(MessageType::Request(2, 32000, 1000))
+        .await
+        .unwrap();
+    assert!(matches!(peer.next().await, None | Some(Err(_))));
+}
+
+#[tokio::test]
+async fn rejects_oversized_requests_and_unknown_torrents() {
+    let content = make_content(2 * 32768);
+    let torrent = single_file_torrent(&content);
+    let engine = TorrentEngine::start();
+    let (_seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+
+    let mut peer = connect(addr, torrent.info_hash().unwrap()).await;
+    peer.send(MessageType::Interested).await.unwrap();
+    peer.send(MessageType::Request(0, 0, 32768)).await.unwrap();
+    loop {
+        match peer.next().await {
+            Some(Ok(MessageType::Piece(..))) => panic!("Oversized block was sent"),
+            Some(Ok(_)) => continue,
+            _ => break,
+        }
+    }
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let handshake = HandshakeRequest::create([7u8; 20], [b'p'; 20]);
+    stream.write_all(&handshake.as_bytes()).await.unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response).await;
+    assert!(response.is_empty());
+}
+
+/// Refuses to upload to anybody
+struct ChokeEverybody;
+
+impl Choker for ChokeEverybody {
+    fn rechoke(&mut self, _: &[ChokerPeer], seeding: bool, _: Instant) -> Vec<SocketAddr> {
+        assert!(seeding);
+        vec![]
+    }
+}
+
+#[tokio::test]
+async fn custom_choker_decides_at_rechoke() {
+    let content = make_content(32768);
+    let torrent = single_file_torrent(&content);
+    let engine = TorrentEngine::with_config(EngineConfig {
+        rechoke_interval: Duration::from_millis(100),
+        ..EngineConfig::default()
+    });
+    let options = TorrentOptions {
+        choker: Some(Arc::new(|| Box::new(ChokeEverybody))),
+        ..TorrentOptions::default()
+    };
+    let (_seeding, addr) = start_seeder(&engine, &content, options).await;
+
+    // The free upload slot is given right away, and taken back by the choker
+    let mut peer = connect(addr, torrent.info_hash().unwrap()).await;
+    peer.send(MessageType::Interested).await.unwrap();
+    let mut messages = vec![];
+    while messages.last() != Some(&MessageType::Choke) {
+        messages.push(peer.next().await.unwrap().unwrap());
+    }
+    assert!(messages.contains(&MessageType::Unchoke));
+}
+
+#[tokio::test]
+async fn banned_peers_are_refused() {
+    let content = make_content(32768);
+    let torrent = single_file_torrent(&content);
+    let engine = TorrentEngine::start();
+    let (seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+    let localhost = addr.ip();
+
+    seeding.ban_peer(localhost).await.unwrap();
+    assert_eq!(seeding.banned_peers().await.unwrap(), vec![localhost]);
+
+    let mut peer = connect(addr, torrent.info_hash().unwrap()).await;
+    assert!(matches!(peer.next().await, None | Some(Err(_))));
+
+    seeding.unban_peer(localhost).await.unwrap();
+    assert!(seeding.banned_peers().await.unwrap().is_empty());
+
+    let mut peer = connect(addr, torrent.info_hash().unwrap()).await;
+    assert!(matches!(
+        peer.next().await,
+        Some(Ok(MessageType::Bitfield(_)))
+    ));
+}
+
+#[tokio::test]
+async fn peer_list_tells_clients() {
+    let content = make_content(32768);
+    let torrent = single_file_torrent(&content);
+    let engine = TorrentEngine::start();
+    let (seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+    assert!(seeding.peers().await.unwrap().is_empty());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let handshake =
+        HandshakeRequest::create(torrent.info_hash().unwrap(), *b"-qB4250-abcdefghijkl");
+    stream.write_all(&handshake.as_bytes()).await.unwrap();
+    let mut response = [0u8; 68];
+    stream.read_exact(&mut response).await.unwrap();
+    let response = HandshakeRequest::from_bytes(&response).unwrap();
+    assert_eq!(&response.peer_id()[..3], b"-TO");
+
+    let mut peer = Framed::new(stream, PeerCodec::default());
+    assert!(matches!(
+        peer.next().await,
+        Some(Ok(MessageType::Bitfield(_)))
+    ));
+    let peers = seeding.peers().await.unwrap();
+    assert_eq!(peers.len(), 1);
+    assert!(peers[0].incoming);
+    assert_eq!(
+        peers[0].client,
+        Some(ClientVersion {
+            name: "qBittorrent".to_string(),
+            version: "4.2.5".to_string()
+        })
+    );
+}
+
+#[tokio::test]
+async fn fast_peer_gets_allowed_fast_pieces_and_rejects() {
+    let content = make_content(16 * 32768);
+    let torrent = single_file_torrent(&content);
+    let info_hash = torrent.info_hash().unwrap();
+    let engine = TorrentEngine::start();
+    let (_seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+
+    let mut peer = connect_fast(addr, info_hash).await;
+    assert_eq!(peer.next().await.unwrap().unwrap(), MessageType::HaveAll);
+    let mut allowed = vec![];
+    for _ in 0..ALLOWED_FAST_SET_SIZE {
+        match peer.next().await {
+            Some(Ok(MessageType::AllowedFast(piece))) => allowed.push(piece),
+            other => panic!("Expected an allowed fast piece, got {:?}", other),
+        }
+    }
+    assert_eq!(
+        allowed,
+        allowed_fast_set(addr.ip(), &info_hash, 16, ALLOWED_FAST_SET_SIZE)
+    );
+
+    // Still choked: the allowed fast piece is served, the other one is rejected
+    let other = (0..16).find(|piece| !allowed.contains(piece)).unwrap();
+    peer.send(MessageType::Request(other, 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    peer.send(MessageType::Request(allowed[0], 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    assert_eq!(
+        peer.next().await.unwrap().unwrap(),
+        MessageType::RejectRequest(other, 0, BLOCK_SIZE)
+    );
+    match peer.next().await {
+        Some(Ok(MessageType::Piece(piece, 0, data))) => {
+            assert_eq!(piece, allowed[0]);
+            let start = piece as usize * 32768;
+            assert_eq!(&data[..], &content[start..start + BLOCK_SIZE as usize]);
+        }
+        other => panic!("Expected the allowed fast block, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn fast_peer_gets_have_none() {
+    let content = make_content(2 * 32768);
+    let torrent = single_file_torrent(&content);
+    let info_hash = torrent.info_hash().unwrap();
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let options = TorrentOptions {
+        storage: Some(Arc::new(MemoryStorage::new(layout))),
+        seed: true,
+        ..TorrentOptions::default()
+    };
+
+    let engine = TorrentEngine::start();
+    let addr = engine.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let _handle = engine
+        .add_torrent_with_peers(torrent, options, &[])
+        .unwrap();
+
+    let mut peer = connect_fast(addr, info_hash).await;
+    assert_eq!(peer.next().await.unwrap().unwrap(), MessageType::HaveNone);
+
+    // Nothing to serve, so the request is rejected
+    peer.send(MessageType::Request(0, 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    assert_eq!(
+        peer.next().await.unwrap().unwrap(),
+        MessageType::RejectRequest(0, 0, BLOCK_SIZE)
+    );
+}
+
+/// Serves the content from memory, until the piece can't be read anymore
+struct UnreadablePiece {
+    inner: MemoryStorage,
+    piece: u32,
+    broken: AtomicBool,
+}
+
+impl Storage for UnreadablePiece {
+    fn layout(&self) -> &StorageLayout {
+        self.inner.layout()
+    }
+
+    fn write(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), String> {
+        self.inner.write(piece, offset, data)
+    }
+
+    fn read(&self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, String> {
+        if piece == self.piece && self.broken.load(Ordering::SeqCst) {
+            return Err("Bad sector".to_string());
+        }
+        self.inner.read(piece, offset, length)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[tokio::test]
+async fn unreadable_block_is_rejected() {
+    let content = make_content(2 * 32768);
+    let torrent = single_file_torrent(&content);
+    let info_hash = torrent.info_hash().unwrap();
+    let layout = StorageLayout::from_torrent(&torrent).unwrap();
+    let storage = Arc::new(UnreadablePiece {
+        inner: MemoryStorage::with_content(layout, &content).unwrap(),
+        piece: 1,
+        broken: AtomicBool::new(false),
+    });
+    // Without the read cache every request reads the storage
+    let config = EngineConfig {
+        cache: CacheConfig {
+            write_size: 0,
+            read_size: 0,
+        },
+        ..EngineConfig::default()
+    };
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        seed: true,
+        ..TorrentOptions::default()
+    };
+
+    let engine = TorrentEngine::with_config(config);
+    let addr = engine.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let handle = engine
+        .add_torrent_with_peers(torrent, options, &[])
+        .unwrap();
+
+    let mut peer = connect_fast(addr, info_hash).await;
+    peer.send(MessageType::Interested).await.unwrap();
+    while !matches!(peer.next().await, Some(Ok(MessageType::Unchoke))) {}
+    storage.broken.store(true, Ordering::SeqCst);
+
+    // The failed read rejects the request only, the other blocks are still served
+    peer.send(MessageType::Request(1, 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    peer.send(MessageType::Request(0, 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    assert_eq!(
+        peer.next().await.unwrap().unwrap(),
+        MessageType::RejectRequest(1, 0, BLOCK_SIZE)
+    );
+    match peer.next().await {
+        Some(Ok(MessageType::Piece(0, 0, data))) => {
+            assert_eq!(&data[..], &content[..BLOCK_SIZE as usize])
+        }
+        other => panic!("Expected the readable block, got {:?}", other),
+    }
+    assert!(handle.peers().await.is_ok());
+}
+
+#[tokio::test]
+async fn held_back_blocks_are_dropped_with_their_requests() {
+    let content = make_content(16 * 32768);
+    let torrent = single_file_torrent(&content);
+    let info_hash = torrent.info_hash().unwrap();
+    let engine = TorrentEngine::start();
+    let (seeding, addr) = start_seeder(&engine, &content, TorrentOptions::default()).await;
+    // A second's worth of burst and a block over the limit go right away, then a block per
+    // second
+    seeding.set_peer_rate_limits(0, 16 * 1024).await.unwrap();
+
+    let mut peer = connect_fast(addr, info_hash).await;
+    let mut allowed = vec![];
+    peer.send(MessageType::Interested).await.unwrap();
+    loop {
+        match peer.next().await {
+            Some(Ok(MessageType::AllowedFast(piece))) => allowed.push(piece),
+            Some(Ok(MessageType::Unchoke)) => break,
+            Some(Ok(_)) => {}
+            other => panic!("Expected the unchoke, got {:?}", other),
+        }
+    }
+
+    let pieces: Vec<u32> = (0..16).filter(|p| !allowed.contains(p)).take(2).collect();
+    let blocks = [
+        (pieces[0], 0),
+        (pieces[0], BLOCK_SIZE),
+        (pieces[1], 0),
+        (pieces[1], BLOCK_SIZE),
+    ];
+    for (piece, offset) in blocks {
+        peer.send(MessageType::Request(piece, offset, BLOCK_SIZE))
+            .await
+            .unwrap();
+    }
+    for offset in [0, BLOCK_SIZE] {
+        match peer.next().await {
+            Some(Ok(MessageType::Piece(piece, begin, _))) => {
+                assert_eq!((piece, begin), (pieces[0], offset))
+            }
+            other => panic!("Expected the first piece, got {:?}", other),
+        }
+    }
+
+    // The cancelled block is rejected, the choke rejects the rest
+    peer.send(MessageType::Cancel(pieces[1], 0, BLOCK_SIZE))
+        .await
+        .unwrap();
+    assert_eq!(
+        peer.next().await.unwrap().unwrap(),
+        MessageType::RejectRequest(pieces[1], 0, BLOCK_SIZE)
+    );
+    peer.send(MessageType::NotInterested).await.unwrap();
+    assert_eq!(peer.next().await.unwrap().unwrap(), MessageType::Choke);
+
+    let mut rejected = vec![];
+    let deadline = Instant::now() + Duration::from_millis(2500);
+    while let Ok(message) = tokio::time::timeout_at(deadline.into(), peer.next()).await {
+        match message {
+            Some(Ok(MessageType::RejectRequest(piece, offset, _))) => {
+                rejected.push((piece, offset))
+            }
+            Some(Ok(MessageType::KeepAlive)) => {}
+            other => panic!("Expected the rejects only, got {:?}", other),
+        }
+    }
+    rejected.sort();
+    assert_eq!(rejected, vec![blocks[3]]);
+}
@@ -1,11 +1,15 @@
+mod common;
+
 use assert_cmd::prelude::*;
+use common::{make_content, make_torrent, write_torrent_file};
 use std::process::Command;
+use torrentino::storage::{FileStorage, Storage, StorageLayout};
 
 #[test]
 fn no_torrent_file() {
     Command::cargo_bin("torrentino")
         .unwrap()
-        .args(&["-f", "no_torrent_file", "-t", "1", "-o", "target"])
+        .args(["-f", "no_torrent_file", "-t", "1", "-o", "target"])
         .assert()
         .failure()
         .code(1);
@@ -15,8 +19,71 @@ fn no_torrent_file() {
 fn no_torrent_file_specified() {
     Command::cargo_bin("torrentino")
         .unwrap()
-        .args(&["-t", "1", "-o", "target"])
+        .args(["-t", "1", "-o", "target"])
         .assert()
         .failure()
         .code(1);
 }
+
+#[test]
+fn verify_reports_file_completeness() {
+    let content = make_content(3 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("a.bin", 40000), ("b.bin", 58304)]);
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_file = dir.path().join("content.torrent");
+    write_torrent_file(&torrent, &torrent_file);
+
+    let folder = dir.path().join("content");
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::write(folder.join("a.bin"), &content[..40000]).unwrap();
+
+    let verify = || {
+        let mut command = Command::cargo_bin("torrentino").unwrap();
+        command.arg("-f").arg(&torrent_file);
+        command.arg("-o").arg(dir.path()).arg("verify");
+        command
+    };
+
+    // The second piece lies in both files, so only the first one is valid
+    verify()
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(predicates::str::contains("81.9%"))
+        .stdout(predicates::str::contains("1 of 3 pieces are valid"));
+
+    std::fs::write(folder.join("b.bin"), &content[40000..]).unwrap();
+    verify()
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("3 of 3 pieces are valid"));
+}
+
+#[test]
+fn verify_reads_skipped_files_from_part_file() {
+    let content = make_content(3 * 32768);
+    let torrent = make_torrent(&content, 32768, &[("a.bin", 40000), ("b.bin", 58304)]);
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_file = dir.path().join("content.torrent");
+    write_torrent_file(&torrent, &torrent_file);
+
+    // The second file was excluded from the download, its data stays in the part file
+    let storage = FileStorage::new(dir.path(), StorageLayout::from_torrent(&torrent).unwrap());
+    storage.set_skipped_files(&[false, true]).unwrap();
+    for (piece, data) in content.chunks(32768).enumerate() {
+        storage.write(piece as u32, 0, data).unwrap();
+    }
+    storage.flush().unwrap();
+    assert!(!dir.path().join("content").join("b.bin").exists());
+
+    Command::cargo_bin("torrentino")
+        .unwrap()
+        .arg("-f")
+        .arg(&torrent_file)
+        .arg("-o")
+        .arg(dir.path())
+        .args(["--exclude", "b\\.bin$", "verify"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("3 of 3 pieces are valid"));
+}
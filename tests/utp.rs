@@ -0,0 +1,172 @@
+mod common;
+
+use common::{make_content, single_file_torrent, spawn_seeder, start_seeder};
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use torrentino::engine::{EngineConfig, TorrentEngine, TorrentOptions};
+use torrentino::protocol::net::UtpSocket;
+use torrentino::storage::{MemoryStorage, StorageLayout};
+
+/// What the shim does to the datagrams
+#[derive(Clone, Copy)]
+struct Impairment {
+    /// Every n-th datagram of each direction is dropped
+    drop_every: usize,
+    delay: Duration,
+    /// The extra random delay, it reorders the datagrams
+    jitter: Duration,
+}
+
+/// Forwards the datagrams of a single client to the target and back, impaired. Returns the
+/// address the client sends to and the number of datagrams forwarded.
+async fn spawn_udp_shim(
+    target: SocketAddr,
+    impairment: Impairment,
+) -> (SocketAddr, Arc<AtomicUsize>) {
+    let client_side = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let target_side = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = client_side.local_addr().unwrap();
+    let forwarded = Arc::new(AtomicUsize::new(0));
+    let (client_sender, mut client) = tokio::sync::watch::channel(None);
+
+    let (from, to, counter) = (client_side.clone(), target_side.clone(), forwarded.clone());
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 2048];
+        let mut received = 0;
+        while let Ok((length, sender)) = from.recv_from(&mut buffer).await {
+            client_sender.send_replace(Some(sender));
+            received += 1;
+            if received % impairment.drop_every != 0 {
+                counter.fetch_add(1, Ordering::Relaxed);
+                delayed_send(to.clone(), buffer[..length].to_vec(), target, impairment);
+            }
+        }
+    });
+
+    let (from, to) = (target_side, client_side);
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 2048];
+        let mut received = 0;
+        while let Ok((length, _)) = from.recv_from(&mut buffer).await {
+            let client = match *client.borrow_and_update() {
+                Some(client) => client,
+                None => continue,
+            };
+            received += 1;
+            if received % impairment.drop_every != 0 {
+                delayed_send(to.clone(), buffer[..length].to_vec(), client, impairment);
+            }
+        }
+    });
+
+    (addr, forwarded)
+}
+
+fn delayed_send(socket: Arc<UdpSocket>, datagram: Vec<u8>, to: SocketAddr, impairment: Impairment) {
+    let jitter = rand::thread_rng().gen_range(0..=impairment.jitter.as_micros() as u64);
+    let delay = impairment.delay + Duration::from_micros(jitter);
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = socket.send_to(&datagram, to).await;
+    });
+}
+
+fn utp_config() -> EngineConfig {
+    EngineConfig {
+        utp: true,
+        connect_timeout: Duration::from_secs(1),
+        ..EngineConfig::default()
+    }
+}
+
+/// Downloads the content from the peer with uTP enabled
+async fn download(content: &[u8], peer: SocketAddr) -> Vec<u8> {
+    let torrent = single_file_torrent(content);
+    let storage = Arc::new(MemoryStorage::new(
+        StorageLayout::from_torrent(&torrent).unwrap(),
+    ));
+    let options = TorrentOptions {
+        storage: Some(storage.clone()),
+        ..TorrentOptions::default()
+    };
+
+    let handle = TorrentEngine::with_config(utp_config())
+        .add_torrent_with_peers(torrent, options, &[peer])
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(20), handle.wait())
+        .await
+        .expect("The download timed out")
+        .expect("Unable download torrent");
+    storage.file(0).unwrap()
+}
+
+#[tokio::test]
+async fn stream_survives_loss_and_reordering() {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let impairment = Impairment {
+        drop_every: 10,
+        delay: Duration::from_millis(10),
+        jitter: Duration::from_millis(10),
+    };
+    let (shim, forwarded) = spawn_udp_shim(server.local_addr().unwrap(), impairment).await;
+
+    let data: Vec<u8> = (0..200_000).map(|i| (i * 7 % 256) as u8).collect();
+    let expected = data.clone();
+    let accepted = tokio::spawn(async move {
+        let mut stream = server.accept().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        stream.write_all(b"done").await.unwrap();
+        stream.shutdown().await.unwrap();
+        received
+    });
+
+    let transfer = async {
+        let mut stream = client.connect(shim).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    };
+    let response = tokio::time::timeout(Duration::from_secs(30), transfer)
+        .await
+        .expect("The transfer timed out");
+
+    assert_eq!(response, b"done");
+    assert_eq!(accepted.await.unwrap(), expected);
+    assert!(forwarded.load(Ordering::Relaxed) > data.len() / 1500);
+}
+
+#[tokio::test]
+async fn download_over_utp() {
+    let content = make_content(4 * 32768 + 100);
+    let seeder = TorrentEngine::with_config(utp_config());
+    let (_seeding, addr) = start_seeder(&seeder, &content, TorrentOptions::default()).await;
+
+    // There is no TCP listener behind the shim, the peer is reachable over uTP only
+    let impairment = Impairment {
+        drop_every: 20,
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(5),
+    };
+    let (shim, forwarded) = spawn_udp_shim(addr, impairment).await;
+
+    assert_eq!(download(&content, shim).await, content);
+    assert!(forwarded.load(Ordering::Relaxed) > content.len() / 1500);
+}
+
+#[tokio::test]
+async fn peer_without_utp_is_connected_over_tcp() {
+    let content = make_content(2 * 32768);
+    let torrent = single_file_torrent(&content);
+    let seeder = spawn_seeder(&torrent, content.clone(), Duration::ZERO).await;
+
+    assert_eq!(download(&content, seeder).await, content);
+}